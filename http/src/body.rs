@@ -0,0 +1,496 @@
+use std::{fmt, io::{self, Error, ErrorKind, Read}, sync::{Arc, Mutex}};
+
+use super::{HttpContent, MAX_HEAD_SIZE};
+
+/// The longest chunk size or trailer line accepted in a chunked body
+const MAX_LINE: usize = 4096;
+
+/// How the end of a request body is found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing
+{
+    /// `Content-Length` bytes follow the head
+    Length(u64),
+    /// `Transfer-Encoding: chunked`
+    Chunked,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State
+{
+    Length(u64),
+    /// Expecting a chunk size line
+    ChunkSize,
+    /// Inside a chunk with this many bytes left
+    Chunk(u64),
+    /// The body ends where the reader does, as on an HTTP/2 stream
+    Eof,
+    Done,
+    /// `finish` handed the connection back, reads would take from the next request
+    Finished,
+}
+
+/// Reads a request body off the connection, following its framing.
+///
+/// Bytes read past the body, such as a pipelined request, stay in the
+/// buffer so the connection can be reused.
+pub(crate) struct BodySource
+{
+    reader: Box<dyn Read + Send>,
+    buf: Vec<u8>,
+    pos: usize,
+    state: State,
+    limit: usize,
+    total: usize,
+    /// The body went past `limit`
+    exceeded: bool,
+}
+
+/// A request body streamed to the handler as it arrives.
+///
+/// Reads fail with `ErrorKind::InvalidData` once the body grows past the
+/// route's size limit, and the server then answers 413.
+pub struct BodyReader
+{
+    source: Arc<Mutex<BodySource>>
+}
+
+/// A response body read while it is sent instead of held in memory, such
+/// as a large file.
+///
+/// Reads fail with `ErrorKind::UnexpectedEof` when the reader ends before
+/// `len` bytes, since the length was already sent to the client.
+pub(crate) struct BodyStream
+{
+    reader: Box<dyn Read + Send>,
+    len: u64,
+    left: u64,
+}
+
+impl Framing
+{
+    /// Gets the framing a request declares for its body
+    pub fn of(content: &HttpContent) -> Result<Self, Error>
+    {
+        if let Some(encoding) = content.header("Transfer-Encoding")
+        {
+            // A repeated header could only apply chunked twice or after another coding
+            if content.header_values("Transfer-Encoding").nth(1).is_some()
+            {
+                return Err(Error::new(ErrorKind::InvalidData, "Repeated Transfer-Encoding"));
+            }
+            // Chunked must be the final and, without other codings, only encoding
+            if !encoding.trim().eq_ignore_ascii_case("chunked")
+            {
+                return Err(Error::new(ErrorKind::Unsupported, "Transfer-Encoding is not supported"));
+            }
+            if content.header("Content-Length").is_some()
+            {
+                return Err(Error::new(ErrorKind::InvalidData, "Both Content-Length and Transfer-Encoding"));
+            }
+            return Ok(Framing::Chunked);
+        }
+        Ok(Framing::Length(content.content_length()? as u64))
+    }
+}
+
+impl BodySource
+{
+    /// Creates a source reading from `buf` first, then from `reader`
+    pub fn new(reader: Box<dyn Read + Send>, buf: Vec<u8>, framing: Framing, limit: usize) -> Self
+    {
+        let state = match framing
+        {
+            Framing::Length(len) => State::Length(len),
+            Framing::Chunked => State::ChunkSize,
+        };
+        Self
+        {
+            reader: reader,
+            buf: buf,
+            pos: 0,
+            state: state,
+            limit: limit,
+            total: 0,
+            exceeded: false
+        }
+    }
+
+    /// Creates a source reading a body that ends with `reader`
+    pub fn until_eof(reader: Box<dyn Read + Send>, limit: usize) -> Self
+    {
+        Self
+        {
+            state: State::Eof,
+            ..Self::new(reader, Vec::new(), Framing::Length(0), limit)
+        }
+    }
+
+    /// Checks whether the body went past its size limit
+    pub fn exceeded(&self) -> bool
+    {
+        self.exceeded
+    }
+
+    /// Discards the rest of the body and hands back the reader and the
+    /// bytes read past the body.
+    ///
+    /// Reads fail with `ErrorKind::ConnectionAborted` afterwards, so a
+    /// handler still holding the body never mistakes the cut for its end.
+    pub fn finish(&mut self) -> Result<(Box<dyn Read + Send>, Vec<u8>), Error>
+    {
+        io::copy(self, &mut io::sink())?;
+        self.state = State::Finished;
+        let reader = std::mem::replace(&mut self.reader, Box::new(io::empty()));
+        let mut rest = std::mem::take(&mut self.buf);
+        rest.drain(..self.pos);
+        self.pos = 0;
+        Ok((reader, rest))
+    }
+
+    /// Reads more bytes from the connection into the buffer, dropping the
+    /// bytes already consumed
+    fn fill(&mut self) -> Result<(), Error>
+    {
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+        let mut chunk = [0u8; 8192];
+        let n = self.reader.read(&mut chunk)?;
+        if n == 0
+        {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed in the body"));
+        }
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(())
+    }
+
+    /// Reads a CRLF terminated line of a chunked body
+    fn line(&mut self) -> Result<String, Error>
+    {
+        loop
+        {
+            if let Some(end) = self.buf[self.pos..].windows(2).position(|w| w == b"\r\n")
+            {
+                let line = String::from_utf8_lossy(&self.buf[self.pos..self.pos + end]).into_owned();
+                self.pos += end + 2;
+                return Ok(line);
+            }
+            if self.buf.len() - self.pos > MAX_LINE
+            {
+                return Err(Error::new(ErrorKind::InvalidData, "Chunk line too long"));
+            }
+            self.fill()?;
+        }
+    }
+
+    /// Copies at most `max` body bytes into `out`
+    fn take(&mut self, out: &mut [u8], max: u64) -> Result<usize, Error>
+    {
+        let want = out.len().min(max.min(usize::MAX as u64) as usize);
+        if want == 0
+        {
+            return Ok(0);
+        }
+        let n = if self.pos < self.buf.len()
+        {
+            let n = want.min(self.buf.len() - self.pos);
+            out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+            self.pos += n;
+            n
+        }
+        else
+        {
+            // Read straight from the connection so nothing past the body is consumed
+            match self.reader.read(&mut out[..want])?
+            {
+                0 if self.state == State::Eof => return Ok(0),
+                0 => return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed in the body")),
+                n => n,
+            }
+        };
+        self.total += n;
+        if self.total > self.limit
+        {
+            self.exceeded = true;
+            self.state = State::Done;
+            return Err(Error::new(ErrorKind::InvalidData, "Request body too large"));
+        }
+        Ok(n)
+    }
+}
+
+impl Read for BodySource
+{
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize>
+    {
+        loop
+        {
+            match self.state
+            {
+                State::Done | State::Length(0) => return Ok(0),
+                State::Finished =>
+                {
+                    return Err(Error::new(ErrorKind::ConnectionAborted, "The connection moved on past the body"));
+                },
+                State::Length(left) =>
+                {
+                    let n = self.take(out, left)?;
+                    self.state = State::Length(left - n as u64);
+                    return Ok(n);
+                },
+                State::Eof =>
+                {
+                    let n = self.take(out, u64::MAX)?;
+                    if n == 0 && !out.is_empty()
+                    {
+                        self.state = State::Done;
+                    }
+                    return Ok(n);
+                },
+                State::ChunkSize =>
+                {
+                    let line = self.line()?;
+                    // Chunk extensions are ignored
+                    let size = line.split(';').next().unwrap_or("").trim();
+                    let size = u64::from_str_radix(size, 16)
+                    .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid chunk size"))?;
+                    if size > 0
+                    {
+                        self.state = State::Chunk(size);
+                        continue;
+                    }
+                    // Skip the trailer section, held to the size of a head
+                    let mut trailer = 0;
+                    loop
+                    {
+                        let line = self.line()?;
+                        if line.is_empty()
+                        {
+                            break;
+                        }
+                        trailer += line.len() + 2;
+                        if trailer > MAX_HEAD_SIZE
+                        {
+                            return Err(Error::new(ErrorKind::InvalidData, "Trailer section too large"));
+                        }
+                    }
+                    self.state = State::Done;
+                    return Ok(0);
+                },
+                State::Chunk(left) =>
+                {
+                    let n = self.take(out, left)?;
+                    if n as u64 == left
+                    {
+                        if !self.line()?.is_empty()
+                        {
+                            return Err(Error::new(ErrorKind::InvalidData, "Missing CRLF after chunk"));
+                        }
+                        self.state = State::ChunkSize;
+                    }
+                    else
+                    {
+                        self.state = State::Chunk(left - n as u64);
+                    }
+                    return Ok(n);
+                },
+            }
+        }
+    }
+}
+
+impl BodyReader
+{
+    pub(crate) fn new(source: Arc<Mutex<BodySource>>) -> Self
+    {
+        Self
+        {
+            source: source
+        }
+    }
+
+    /// Creates a reader over a body that was already received in full
+    pub fn buffered(body: Vec<u8>) -> Self
+    {
+        let len = body.len() as u64;
+        let source = BodySource::new(Box::new(io::empty()), body, Framing::Length(len), usize::MAX);
+        Self::new(Arc::new(Mutex::new(source)))
+    }
+
+    /// Gets the size limit enforced on the body
+    pub fn limit(&self) -> usize
+    {
+        self.source.lock().unwrap().limit
+    }
+}
+
+impl Read for BodyReader
+{
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize>
+    {
+        self.source.lock().unwrap().read(out)
+    }
+}
+
+impl BodyStream
+{
+    pub fn new(reader: Box<dyn Read + Send>, len: u64) -> Self
+    {
+        Self
+        {
+            reader: reader,
+            len: len,
+            left: len
+        }
+    }
+
+    /// Gets the length of the whole body
+    pub fn len(&self) -> u64
+    {
+        self.len
+    }
+
+    /// Gets how many bytes are left to read
+    pub fn remaining(&self) -> u64
+    {
+        self.left
+    }
+}
+
+impl Read for BodyStream
+{
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize>
+    {
+        let want = out.len().min(self.left.min(usize::MAX as u64) as usize);
+        if want == 0
+        {
+            return Ok(0);
+        }
+        match self.reader.read(&mut out[..want])?
+        {
+            0 => Err(Error::new(ErrorKind::UnexpectedEof, "Response body shorter than its length")),
+            n =>
+            {
+                self.left -= n as u64;
+                Ok(n)
+            },
+        }
+    }
+}
+
+impl fmt::Debug for BodyStream
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        f.debug_struct("BodyStream").field("len", &self.len).field("left", &self.left).finish_non_exhaustive()
+    }
+}
+
+impl fmt::Debug for BodyReader
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        f.debug_struct("BodyReader").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HttpRequest;
+
+    fn source(input: &[u8], framing: Framing, limit: usize) -> BodySource
+    {
+        // Feed the connection one byte at a time to exercise refills
+        struct Trickle(Vec<u8>, usize);
+        impl Read for Trickle
+        {
+            fn read(&mut self, out: &mut [u8]) -> io::Result<usize>
+            {
+                if self.1 == self.0.len() || out.is_empty()
+                {
+                    return Ok(0);
+                }
+                out[0] = self.0[self.1];
+                self.1 += 1;
+                Ok(1)
+            }
+        }
+        BodySource::new(Box::new(Trickle(input.to_vec(), 0)), Vec::new(), framing, limit)
+    }
+
+    #[test]
+    fn test_chunked()
+    {
+        let mut body = source(b"4;ext=1\r\nWiki\r\n6\r\npedia \r\n0\r\nTrailer: x\r\n\r\nGET / HTTP/1.1\r\n", Framing::Chunked, 100);
+        let mut out = String::new();
+        body.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "Wikipedia ");
+        let (_, rest) = body.finish().unwrap();
+        // Whatever was buffered past the body is handed back
+        assert!(b"GET / HTTP/1.1\r\n".starts_with(&rest));
+        assert_eq!(body.read(&mut [0; 4]).unwrap_err().kind(), ErrorKind::ConnectionAborted);
+        let mut bad = source(b"zz\r\n", Framing::Chunked, 100);
+        assert_eq!(bad.read_to_end(&mut Vec::new()).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_chunked_memory()
+    {
+        // Only the unread part of a body made of many small chunks is kept
+        let input = b"1\r\nx\r\n".repeat(10000);
+        let mut body = BodySource::new(Box::new(io::Cursor::new(input)), Vec::new(), Framing::Chunked, usize::MAX);
+        let mut out = [0u8; 1];
+        for _ in 0..10000
+        {
+            assert_eq!(body.read(&mut out).unwrap(), 1);
+            assert!(body.buf.len() <= 8192 + MAX_LINE);
+        }
+        // Trailer lines are not read forever
+        let mut input = b"0\r\n".to_vec();
+        input.extend(b"X-Trailer: 0123456789\r\n".repeat(MAX_HEAD_SIZE / 10));
+        input.extend(b"\r\n");
+        let mut trailers = BodySource::new(Box::new(io::Cursor::new(input)), Vec::new(), Framing::Chunked, usize::MAX);
+        let e = trailers.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert!(trailers.buf.len() <= 8192 + MAX_LINE);
+    }
+
+    #[test]
+    fn test_framing()
+    {
+        let framing = |head: &str| Framing::of(HttpRequest::parse_head(head.as_bytes()).unwrap().unwrap().0.content());
+        assert_eq!(framing("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\n").unwrap(), Framing::Length(5));
+        assert_eq!(framing("POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 5, 5\r\n\r\n").unwrap(), Framing::Length(5));
+        assert_eq!(framing("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n").unwrap(), Framing::Chunked);
+        // Framing that two parsers could read differently is refused
+        for head in [
+            "POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: 5, 6\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: chunked\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n",
+        ]
+        {
+            assert_eq!(framing(head).unwrap_err().kind(), ErrorKind::InvalidData, "{head}");
+        }
+    }
+
+    #[test]
+    fn test_length_and_limit()
+    {
+        let mut body = BodySource::new(Box::new(&b"lo world"[..]), b"hel".to_vec(), Framing::Length(5), 100);
+        let mut out = String::new();
+        body.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "hello");
+        let (mut reader, rest) = body.finish().unwrap();
+        assert!(rest.is_empty());
+        let mut next = String::new();
+        reader.read_to_string(&mut next).unwrap();
+        assert_eq!(next, " world");
+        let mut big = source(b"5\r\nabcde\r\n5\r\nfghij\r\n0\r\n\r\n", Framing::Chunked, 8);
+        assert!(big.read_to_end(&mut Vec::new()).is_err());
+        assert!(big.exceeded());
+        let mut short = source(b"abc", Framing::Length(5), 100);
+        assert_eq!(short.read_to_end(&mut Vec::new()).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,39 @@
+use std::{io::{Error, ErrorKind}, sync::{atomic::{AtomicBool, Ordering}, Arc}};
+
+/// Tells a handler that its request was given up on, such as when it ran
+/// past the timeout of its route. Nobody waits for the response of a
+/// cancelled handler, so handlers doing long work should check it and stop.
+#[derive(Debug, Clone, Default)]
+pub struct Cancellation
+{
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Cancellation
+{
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+
+    pub(crate) fn cancel(&self)
+    {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool
+    {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Fails with `ErrorKind::TimedOut` once cancelled, to stop between the
+    /// steps of a handler with `?`
+    pub fn check(&self) -> Result<(), Error>
+    {
+        match self.is_cancelled()
+        {
+            true => Err(Error::new(ErrorKind::TimedOut, "The request was cancelled")),
+            false => Ok(()),
+        }
+    }
+}
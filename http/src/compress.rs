@@ -0,0 +1,226 @@
+use super::{conditional::EntityTag, negotiate::parse_quality_list, HttpResponse};
+
+pub mod deflate;
+
+/// A content coding the server can apply to a response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding
+{
+    Gzip,
+    Deflate,
+    Identity,
+}
+
+/// Compresses responses for clients that accept it
+#[derive(Debug, Clone)]
+pub struct Compression
+{
+    /// Bodies smaller than this many bytes are sent as they are
+    threshold: usize,
+    /// The codings offered, most preferred first
+    encodings: Vec<Encoding>,
+}
+
+impl Encoding
+{
+    /// Gets the name of the coding in `Content-Encoding`
+    pub fn name(&self) -> &'static str
+    {
+        match self
+        {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+            Encoding::Identity => "identity",
+        }
+    }
+
+    /// Encodes a body with the coding
+    pub fn encode(&self, data: &[u8]) -> Vec<u8>
+    {
+        match self
+        {
+            Encoding::Gzip => deflate::gzip(data),
+            Encoding::Deflate => deflate::zlib(data),
+            Encoding::Identity => data.to_vec(),
+        }
+    }
+}
+
+/// Picks the coding for a response from an `Accept-Encoding` header, RFC
+/// 9110 section 12.5.3.
+///
+/// The supported coding with the highest weight wins, earlier ones on a
+/// tie. Identity is chosen when nothing else is acceptable, or when the
+/// client weighs it above every supported coding.
+pub fn negotiate_encoding(accept_encoding: &str, supported: &[Encoding]) -> Encoding
+{
+    let weights = parse_quality_list(accept_encoding);
+    let weight = |name: &str| weights.iter()
+    .find(|item| item.value == name || (name == "gzip" && item.value == "x-gzip"))
+    .or(weights.iter().find(|item| item.value == "*"))
+    .map(|item| item.quality);
+    let mut best = (Encoding::Identity, 0.0);
+    for encoding in supported.iter().filter(|e| **e != Encoding::Identity)
+    {
+        let q = weight(encoding.name()).unwrap_or(0.0);
+        if q > best.1
+        {
+            best = (*encoding, q);
+        }
+    }
+    let identity = weights.iter().find(|item| item.value == "identity").map(|item| item.quality);
+    match identity
+    {
+        Some(q) if q > best.1 => Encoding::Identity,
+        _ => best.0,
+    }
+}
+
+/// Whether a media type is worth compressing. Images, audio, video and
+/// archives are usually compressed already.
+pub fn is_compressible(content_type: &str) -> bool
+{
+    let media_type = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    media_type.starts_with("text/")
+    || media_type.ends_with("+json")
+    || media_type.ends_with("+xml")
+    || matches!(media_type.as_str(),
+        "application/json" | "application/javascript" | "application/xml" | "application/wasm" | "image/x-icon")
+}
+
+impl Default for Compression
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl Compression
+{
+    /// Offers gzip then deflate for bodies of at least 1 KiB
+    pub fn new() -> Self
+    {
+        Self
+        {
+            threshold: 1024,
+            encodings: vec![Encoding::Gzip, Encoding::Deflate],
+        }
+    }
+
+    /// Sets the smallest body that is compressed, in bytes
+    pub fn with_threshold(mut self, threshold: usize) -> Self
+    {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Sets the codings offered, most preferred first
+    pub fn with_encodings(mut self, encodings: &[Encoding]) -> Self
+    {
+        self.encodings = encodings.to_vec();
+        self
+    }
+
+    /// Compresses a response with the coding the client prefers.
+    ///
+    /// Only successful, complete responses of a compressible type are
+    /// touched, and they get `Vary: Accept-Encoding` even when sent as they
+    /// are. A strong ETag becomes weak, as the bytes no longer match it,
+    /// and byte ranges are no longer advertised.
+    pub fn apply(&self, accept_encoding: Option<&str>, mut response: HttpResponse) -> HttpResponse
+    {
+        let status = response.status;
+        if !(200..300).contains(&status) || status == 204 || status == 206 || response.header("Content-Encoding").is_some()
+        {
+            return response;
+        }
+        if !response.header("Content-Type").is_some_and(is_compressible)
+        {
+            return response;
+        }
+        let varies = response.headers.iter()
+        .filter_map(|line| line.split_once(':'))
+        .filter(|(name, _)| name.trim().eq_ignore_ascii_case("Vary"))
+        .any(|(_, value)| value.split(',').any(|v| v.trim() == "*" || v.trim().eq_ignore_ascii_case("Accept-Encoding")));
+        if !varies
+        {
+            response = response.with_header("Vary", "Accept-Encoding");
+        }
+        let encoding = accept_encoding.map_or(Encoding::Identity, |accept| negotiate_encoding(accept, &self.encodings));
+        // Streamed bodies are too large to encode in memory
+        if response.stream.is_some() || response.body.len() < self.threshold || encoding == Encoding::Identity
+        {
+            return response;
+        }
+        let encoded = encoding.encode(&response.body);
+        if encoded.len() >= response.body.len()
+        {
+            return response;
+        }
+        response.body = encoded;
+        response.headers = response.headers.into_iter()
+        .filter_map(|line|
+        {
+            let (name, value) = line.split_once(':')?;
+            let name = name.trim();
+            if name.eq_ignore_ascii_case("Content-Length") || name.eq_ignore_ascii_case("Accept-Ranges")
+            {
+                return None;
+            }
+            if name.eq_ignore_ascii_case("ETag")
+            {
+                if let Some(etag) = EntityTag::parse(value).filter(|etag| !etag.is_weak())
+                {
+                    return Some(format!("{name}: {}", EntityTag::weak(etag.tag())));
+                }
+            }
+            Some(line)
+        })
+        .collect();
+        response.with_header("Content-Encoding", encoding.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate()
+    {
+        let supported = [Encoding::Gzip, Encoding::Deflate];
+        assert_eq!(negotiate_encoding("gzip, deflate, br", &supported), Encoding::Gzip);
+        assert_eq!(negotiate_encoding("gzip;q=0.5, deflate", &supported), Encoding::Deflate);
+        assert_eq!(negotiate_encoding("br", &supported), Encoding::Identity);
+        assert_eq!(negotiate_encoding("*;q=0.3, gzip;q=0", &supported), Encoding::Deflate);
+        assert_eq!(negotiate_encoding("x-gzip", &supported), Encoding::Gzip);
+        assert_eq!(negotiate_encoding("gzip;q=0.4, identity;q=0.9", &supported), Encoding::Identity);
+        assert_eq!(negotiate_encoding("gzip;q=bad", &supported), Encoding::Identity);
+        assert_eq!(negotiate_encoding("", &supported), Encoding::Identity);
+        assert_eq!(negotiate_encoding("gzip, deflate", &[Encoding::Deflate]), Encoding::Deflate);
+    }
+
+    #[test]
+    fn test_apply()
+    {
+        let compression = Compression::new().with_threshold(100);
+        let html = "<p>hello</p>\n".repeat(50);
+        let response = HttpResponse::new(200)
+        .with_header("Content-Type", "text/html")
+        .with_header("ETag", "\"v1\"")
+        .with_body(html.clone());
+        let compressed = compression.apply(Some("gzip"), response);
+        assert_eq!(compressed.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(compressed.header("Vary"), Some("Accept-Encoding"));
+        assert_eq!(compressed.header("ETag"), Some("W/\"v1\""));
+        assert!(compressed.body().len() < html.len() / 4);
+        // Small bodies, other types and clients without gzip get the body as it is
+        let small = compression.apply(Some("gzip"), HttpResponse::new(200).with_header("Content-Type", "text/html").with_body("hi"));
+        assert_eq!((small.header("Content-Encoding"), small.header("Vary")), (None, Some("Accept-Encoding")));
+        let image = compression.apply(Some("gzip"), HttpResponse::new(200).with_header("Content-Type", "image/png").with_body(html.clone()));
+        assert_eq!((image.header("Content-Encoding"), image.header("Vary")), (None, None));
+        let plain = compression.apply(None, HttpResponse::new(200).with_header("Content-Type", "text/html").with_body(html.clone()));
+        assert_eq!(plain.body(), html.as_bytes());
+    }
+}
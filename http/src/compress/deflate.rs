@@ -0,0 +1,246 @@
+/// Base lengths of length codes 257 to 285, RFC 1951 section 3.2.5
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+/// Base distances of distance codes 0 to 29
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+const WINDOW_SIZE: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: u32 = 15;
+/// How many earlier positions with the same hash are tried for a match
+const MAX_CHAIN: usize = 64;
+
+/// Writes bits least significant first, as DEFLATE packs them
+struct BitWriter
+{
+    out: Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+impl BitWriter
+{
+    fn new(capacity: usize) -> Self
+    {
+        Self
+        {
+            out: Vec::with_capacity(capacity),
+            bits: 0,
+            count: 0,
+        }
+    }
+
+    fn write(&mut self, value: u32, count: u32)
+    {
+        self.bits |= (value as u64) << self.count;
+        self.count += count;
+        while self.count >= 8
+        {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Writes a Huffman code, which is packed most significant bit first
+    fn write_code(&mut self, code: u32, len: u32)
+    {
+        self.write(code.reverse_bits() >> (32 - len), len);
+    }
+
+    fn finish(mut self) -> Vec<u8>
+    {
+        if self.count > 0
+        {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+
+    /// Writes a literal or length symbol with the fixed Huffman code
+    fn write_literal(&mut self, symbol: u16)
+    {
+        let symbol = symbol as u32;
+        match symbol
+        {
+            0..=143 => self.write_code(0x30 + symbol, 8),
+            144..=255 => self.write_code(0x190 + symbol - 144, 9),
+            256..=279 => self.write_code(symbol - 256, 7),
+            _ => self.write_code(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    fn write_match(&mut self, length: usize, distance: usize)
+    {
+        let code = LENGTH_BASE.iter().rposition(|base| *base as usize <= length).unwrap();
+        self.write_literal(257 + code as u16);
+        self.write((length - LENGTH_BASE[code] as usize) as u32, LENGTH_EXTRA[code] as u32);
+        let code = DISTANCE_BASE.iter().rposition(|base| *base as usize <= distance).unwrap();
+        self.write_code(code as u32, 5);
+        self.write((distance - DISTANCE_BASE[code] as usize) as u32, DISTANCE_EXTRA[code] as u32);
+    }
+}
+
+fn hash(data: &[u8]) -> usize
+{
+    let key = (data[0] as u32) << 16 | (data[1] as u32) << 8 | data[2] as u32;
+    (key.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+/// Compresses data into a raw DEFLATE stream, RFC 1951.
+///
+/// Matches are found greedily over a 32 KiB window and coded in a single
+/// block with the fixed Huffman codes, which favours speed over ratio.
+pub fn deflate(data: &[u8]) -> Vec<u8>
+{
+    let mut writer = BitWriter::new(data.len() / 2 + 16);
+    // BFINAL set, BTYPE 01 for fixed Huffman codes
+    writer.write(1, 1);
+    writer.write(1, 2);
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; WINDOW_SIZE];
+    let insert = |pos: usize, head: &mut Vec<usize>, prev: &mut Vec<usize>|
+    {
+        if pos + MIN_MATCH <= data.len()
+        {
+            let h = hash(&data[pos..]);
+            prev[pos % WINDOW_SIZE] = head[h];
+            head[h] = pos;
+        }
+    };
+    let mut pos = 0;
+    while pos < data.len()
+    {
+        let mut best = (0, 0);
+        if pos + MIN_MATCH <= data.len()
+        {
+            let max = MAX_MATCH.min(data.len() - pos);
+            let mut candidate = head[hash(&data[pos..])];
+            let mut chain = 0;
+            while candidate != usize::MAX && pos - candidate <= WINDOW_SIZE && chain < MAX_CHAIN
+            {
+                let len = data[candidate..].iter().zip(&data[pos..pos + max]).take_while(|(a, b)| a == b).count();
+                if len > best.0
+                {
+                    best = (len, pos - candidate);
+                    if len == max
+                    {
+                        break;
+                    }
+                }
+                let next = prev[candidate % WINDOW_SIZE];
+                // Chains only ever point backwards, anything else is a stale slot
+                if next >= candidate
+                {
+                    break;
+                }
+                candidate = next;
+                chain += 1;
+            }
+        }
+        let (len, distance) = best;
+        if len >= MIN_MATCH
+        {
+            writer.write_match(len, distance);
+            for p in pos..pos + len
+            {
+                insert(p, &mut head, &mut prev);
+            }
+            pos += len;
+        }
+        else
+        {
+            writer.write_literal(data[pos] as u16);
+            insert(pos, &mut head, &mut prev);
+            pos += 1;
+        }
+    }
+    writer.write_literal(256);
+    writer.finish()
+}
+
+/// Computes the CRC-32 used by gzip, ISO 3309
+pub fn crc32(data: &[u8]) -> u32
+{
+    let mut table = [0u32; 256];
+    for (i, entry) in table.iter_mut().enumerate()
+    {
+        *entry = (0..8).fold(i as u32, |c, _| if c & 1 == 1 { 0xedb8_8320 ^ (c >> 1) } else { c >> 1 });
+    }
+    !data.iter().fold(!0u32, |crc, byte| table[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8))
+}
+
+/// Computes the Adler-32 checksum used by zlib, RFC 1950 section 9
+pub fn adler32(data: &[u8]) -> u32
+{
+    const MOD: u32 = 65_521;
+    let (mut a, mut b) = (1u32, 0u32);
+    // 5552 bytes is the most that can be summed before the u32s overflow
+    for chunk in data.chunks(5552)
+    {
+        for byte in chunk
+        {
+            a += *byte as u32;
+            b += a;
+        }
+        a %= MOD;
+        b %= MOD;
+    }
+    b << 16 | a
+}
+
+/// Wraps a DEFLATE stream in the gzip format, RFC 1952
+pub fn gzip(data: &[u8]) -> Vec<u8>
+{
+    // No file name or modification time, operating system unknown
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+    out.extend_from_slice(&deflate(data));
+    out.extend_from_slice(&crc32(data).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+/// Wraps a DEFLATE stream in the zlib format, RFC 1950, which is what the
+/// `deflate` content coding means
+pub fn zlib(data: &[u8]) -> Vec<u8>
+{
+    // A 32 KiB window and a header check making 0x7801 a multiple of 31
+    let mut out = vec![0x78, 0x01];
+    out.extend_from_slice(&deflate(data));
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksums()
+    {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+        assert_eq!(adler32(&[0xff; 100_000]), 0x149a_302c);
+    }
+
+    #[test]
+    fn test_deflate()
+    {
+        // An empty input is just the end of block symbol
+        assert_eq!(deflate(b""), [0x03, 0x00]);
+        // The same bytes zlib produces with fixed codes
+        assert_eq!(deflate(b"a"), [0x4b, 0x04, 0x00]);
+        // A literal, then a match of 9 reaching back one byte
+        assert_eq!(deflate(b"aaaaaaaaaa"), [0x4b, 0x84, 0x03, 0x00]);
+        let text = "<li>item</li>\n".repeat(1000);
+        assert!(gzip(text.as_bytes()).len() < text.len() / 20);
+    }
+}
@@ -0,0 +1,382 @@
+use std::{fmt, time::{SystemTime, UNIX_EPOCH}};
+
+use super::{crypto::{base64url, sha256}, date::parse_http_date, HttpRequest, HttpResponse};
+
+/// Response headers a 304 keeps from the 200 it replaces, RFC 9110 section 15.4.5
+const NOT_MODIFIED_HEADERS: [&str; 8] =
+    ["Cache-Control", "Content-Location", "Date", "ETag", "Expires", "Last-Modified", "Vary", "Set-Cookie"];
+
+/// An entity tag naming one version of a representation, RFC 9110 section 8.8.3
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntityTag
+{
+    weak: bool,
+    tag: String,
+}
+
+/// What to do with a request after evaluating its preconditions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precondition
+{
+    /// Perform the method as usual
+    Proceed,
+    /// Answer a GET or HEAD with 304 Not Modified
+    NotModified,
+    /// Answer with 412 Precondition Failed
+    Failed,
+}
+
+/// The conditional headers of a request.
+///
+/// They are copied out of the request so they can still be evaluated after
+/// the request itself has been handed to a handler.
+#[derive(Debug, Clone, Default)]
+pub struct Preconditions
+{
+    /// Whether the method is GET or HEAD
+    safe: bool,
+    if_match: Option<String>,
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+    if_unmodified_since: Option<String>,
+}
+
+/// The members of an `If-Match` or `If-None-Match` header
+enum TagList
+{
+    Any,
+    Tags(Vec<EntityTag>),
+}
+
+impl EntityTag
+{
+    /// Creates a strong tag. Characters not allowed in a tag are dropped.
+    pub fn strong(tag: &str) -> Self
+    {
+        Self
+        {
+            weak: false,
+            tag: sanitize(tag)
+        }
+    }
+
+    /// Creates a weak tag. Characters not allowed in a tag are dropped.
+    pub fn weak(tag: &str) -> Self
+    {
+        Self
+        {
+            weak: true,
+            tag: sanitize(tag)
+        }
+    }
+
+    /// Creates a strong tag from a hash of the content
+    pub fn from_content(content: &[u8]) -> Self
+    {
+        Self::strong(&base64url(&sha256(content)[..16]))
+    }
+
+    /// Creates a weak tag from a file's modification time and size
+    pub fn from_metadata(modified: SystemTime, size: u64) -> Self
+    {
+        let since_epoch = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+        Self::weak(&format!("{size:x}-{:x}.{:x}", since_epoch.as_secs(), since_epoch.subsec_nanos()))
+    }
+
+    /// Parses a single tag such as `"abc"` or `W/"abc"`
+    pub fn parse(value: &str) -> Option<Self>
+    {
+        match parse_tag(value.trim())
+        {
+            Some((tag, "")) => Some(tag),
+            _ => None,
+        }
+    }
+
+    pub fn is_weak(&self) -> bool
+    {
+        self.weak
+    }
+
+    pub fn tag(&self) -> &str
+    {
+        &self.tag
+    }
+
+    /// Strong comparison: both tags are strong and identical
+    pub fn strong_eq(&self, other: &EntityTag) -> bool
+    {
+        !self.weak && !other.weak && self.tag == other.tag
+    }
+
+    /// Weak comparison: the tags are identical, ignoring weakness
+    pub fn weak_eq(&self, other: &EntityTag) -> bool
+    {
+        self.tag == other.tag
+    }
+}
+
+impl fmt::Display for EntityTag
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        if self.weak
+        {
+            write!(f, "W/")?;
+        }
+        write!(f, "\"{}\"", self.tag)
+    }
+}
+
+/// Keeps the characters allowed in an entity tag, `etagc` in RFC 9110
+fn sanitize(tag: &str) -> String
+{
+    tag.chars().filter(|c| *c == '!' || (*c >= '#' && *c != '\x7f')).collect()
+}
+
+/// Parses one tag at the start of `input` and returns it with the rest
+fn parse_tag(input: &str) -> Option<(EntityTag, &str)>
+{
+    let (weak, input) = match input.strip_prefix("W/")
+    {
+        Some(rest) => (true, rest),
+        None => (false, input),
+    };
+    let input = input.strip_prefix('"')?;
+    let end = input.find('"')?;
+    let tag = &input[..end];
+    if sanitize(tag) != tag
+    {
+        return None;
+    }
+    let tag = EntityTag
+    {
+        weak: weak,
+        tag: tag.to_string()
+    };
+    Some((tag, &input[end + 1..]))
+}
+
+impl TagList
+{
+    /// Parses a `*` or a comma separated list of tags. Tags after a
+    /// malformed member are ignored.
+    fn parse(value: &str) -> Self
+    {
+        if value.trim() == "*"
+        {
+            return TagList::Any;
+        }
+        let mut tags = Vec::new();
+        let mut rest = value;
+        loop
+        {
+            rest = rest.trim_start_matches([' ', '\t', ',']);
+            let Some((tag, remaining)) = parse_tag(rest) else
+            {
+                break;
+            };
+            tags.push(tag);
+            rest = remaining;
+        }
+        TagList::Tags(tags)
+    }
+
+    /// Checks the list against the current tag with the given comparison.
+    /// `*` matches whenever a current representation exists.
+    fn matches(&self, current: Option<&EntityTag>, exists: bool, strong: bool) -> bool
+    {
+        match self
+        {
+            TagList::Any => exists,
+            TagList::Tags(tags) => current.is_some_and(|current| tags.iter().any(|tag|
+            {
+                if strong { tag.strong_eq(current) } else { tag.weak_eq(current) }
+            })),
+        }
+    }
+}
+
+/// Truncates a time to whole seconds, the precision of HTTP dates
+fn whole_seconds(time: SystemTime) -> u64
+{
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl Preconditions
+{
+    /// Copies the conditional headers of a request
+    pub fn of(request: &HttpRequest) -> Self
+    {
+        let content = request.content();
+        let header = |name: &str| content.header(name).map(str::to_string);
+        Self
+        {
+            safe: matches!(request, HttpRequest::Get(_) | HttpRequest::Head(_)),
+            if_match: header("If-Match"),
+            if_none_match: header("If-None-Match"),
+            if_modified_since: header("If-Modified-Since"),
+            if_unmodified_since: header("If-Unmodified-Since"),
+        }
+    }
+
+    /// Whether the request carried no conditional headers
+    pub fn is_empty(&self) -> bool
+    {
+        self.if_match.is_none() && self.if_none_match.is_none()
+        && self.if_modified_since.is_none() && self.if_unmodified_since.is_none()
+    }
+
+    /// Evaluates the preconditions against the current validators of the
+    /// target resource, in the order of RFC 9110 section 13.2.2.
+    ///
+    /// A representation is taken to exist when it has a validator, so
+    /// handlers of missing resources pass `None` for both.
+    pub fn evaluate(&self, etag: Option<&EntityTag>, last_modified: Option<SystemTime>) -> Precondition
+    {
+        let exists = etag.is_some() || last_modified.is_some();
+        // If-Match, or If-Unmodified-Since when it is absent
+        if let Some(if_match) = &self.if_match
+        {
+            if !TagList::parse(if_match).matches(etag, exists, true)
+            {
+                return Precondition::Failed;
+            }
+        }
+        else if let (Some(since), Some(modified)) = (self.if_unmodified_since.as_deref().and_then(parse_http_date), last_modified)
+        {
+            if whole_seconds(modified) > whole_seconds(since)
+            {
+                return Precondition::Failed;
+            }
+        }
+        // If-None-Match, or If-Modified-Since for GET and HEAD when it is absent
+        if let Some(if_none_match) = &self.if_none_match
+        {
+            if TagList::parse(if_none_match).matches(etag, exists, false)
+            {
+                return if self.safe { Precondition::NotModified } else { Precondition::Failed };
+            }
+        }
+        else if let (true, Some(since), Some(modified)) = (self.safe, self.if_modified_since.as_deref().and_then(parse_http_date), last_modified)
+        {
+            if whole_seconds(modified) <= whole_seconds(since)
+            {
+                return Precondition::NotModified;
+            }
+        }
+        Precondition::Proceed
+    }
+
+    /// Evaluates the preconditions of a GET or HEAD against the `ETag` and
+    /// `Last-Modified` headers of a handler's response, replacing it with a
+    /// 304 or 412 when they say so.
+    ///
+    /// Only successful responses are affected. Handlers of other methods
+    /// must call [`Preconditions::evaluate`] before changing any state.
+    pub fn apply(&self, response: HttpResponse) -> HttpResponse
+    {
+        if !self.safe || self.is_empty() || !(200..300).contains(&response.status)
+        {
+            return response;
+        }
+        let etag = response.header("ETag").and_then(EntityTag::parse);
+        let last_modified = response.header("Last-Modified").and_then(parse_http_date);
+        match self.evaluate(etag.as_ref(), last_modified)
+        {
+            Precondition::Proceed => response,
+            Precondition::NotModified => not_modified(&response),
+            Precondition::Failed => HttpResponse::new(412),
+        }
+    }
+}
+
+/// Builds the 304 that replaces a response
+pub(crate) fn not_modified(response: &HttpResponse) -> HttpResponse
+{
+    let mut not_modified = HttpResponse::new(304);
+    not_modified.headers = response.headers.iter()
+    .filter(|line|
+    {
+        let name = line.split_once(':').map_or("", |(name, _)| name.trim());
+        NOT_MODIFIED_HEADERS.iter().any(|keep| keep.eq_ignore_ascii_case(name))
+    })
+    .cloned()
+    .collect();
+    not_modified
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn get(headers: &[(&str, &str)]) -> Preconditions
+    {
+        let head: String = headers.iter().map(|(name, value)| format!("{name}: {value}\r\n")).collect();
+        let request = HttpRequest::new(format!("GET / HTTP/1.1\r\n{head}\r\n").as_bytes()).unwrap();
+        Preconditions::of(&request)
+    }
+
+    #[test]
+    fn test_entity_tags()
+    {
+        let strong = EntityTag::parse("\"abc\"").unwrap();
+        let weak = EntityTag::parse("W/\"abc\"").unwrap();
+        assert!(!strong.is_weak() && weak.is_weak());
+        assert!(strong.weak_eq(&weak) && !strong.strong_eq(&weak) && strong.strong_eq(&strong));
+        assert_eq!(weak.to_string(), "W/\"abc\"");
+        assert_eq!(EntityTag::parse("abc"), None);
+        assert_eq!(EntityTag::parse("\"a\"b"), None);
+        assert_eq!(EntityTag::strong("a\"b c").to_string(), "\"abc\"");
+        assert_eq!(EntityTag::from_content(b"x"), EntityTag::from_content(b"x"));
+        assert_ne!(EntityTag::from_content(b"x"), EntityTag::from_content(b"y"));
+    }
+
+    #[test]
+    fn test_evaluate()
+    {
+        let etag = EntityTag::strong("v2");
+        let modified = UNIX_EPOCH + Duration::from_millis(784_111_777_500);
+        let validators = (Some(&etag), Some(modified));
+        let evaluate = |headers: &[(&str, &str)]| get(headers).evaluate(validators.0, validators.1);
+        assert_eq!(evaluate(&[]), Precondition::Proceed);
+        assert_eq!(evaluate(&[("If-None-Match", "\"v1\", W/\"v2\"")]), Precondition::NotModified);
+        assert_eq!(evaluate(&[("If-None-Match", "\"v1\"")]), Precondition::Proceed);
+        assert_eq!(evaluate(&[("If-None-Match", "*")]), Precondition::NotModified);
+        assert_eq!(evaluate(&[("If-Match", "W/\"v2\"")]), Precondition::Failed);
+        assert_eq!(evaluate(&[("If-Match", "\"v1\",\"v2\"")]), Precondition::Proceed);
+        assert_eq!(evaluate(&[("If-Modified-Since", "Sun, 06 Nov 1994 08:49:37 GMT")]), Precondition::NotModified);
+        assert_eq!(evaluate(&[("If-Modified-Since", "Sun, 06 Nov 1994 08:49:36 GMT")]), Precondition::Proceed);
+        assert_eq!(evaluate(&[("If-Modified-Since", "not a date")]), Precondition::Proceed);
+        assert_eq!(evaluate(&[("If-Unmodified-Since", "Sun, 06 Nov 1994 08:49:36 GMT")]), Precondition::Failed);
+        // If-None-Match takes precedence over If-Modified-Since
+        assert_eq!(evaluate(&[("If-None-Match", "\"v1\""), ("If-Modified-Since", "Sun, 06 Nov 1994 08:49:37 GMT")]), Precondition::Proceed);
+        // If-Match takes precedence over If-Unmodified-Since
+        assert_eq!(evaluate(&[("If-Match", "*"), ("If-Unmodified-Since", "Sun, 06 Nov 1994 08:49:36 GMT")]), Precondition::Proceed);
+        // A missing resource fails If-Match: * and unsafe methods fail If-None-Match
+        assert_eq!(get(&[("If-Match", "*")]).evaluate(None, None), Precondition::Failed);
+        let put = HttpRequest::new(b"PUT / HTTP/1.1\r\nIf-None-Match: *\r\n\r\n".as_slice()).unwrap();
+        assert_eq!(Preconditions::of(&put).evaluate(validators.0, validators.1), Precondition::Failed);
+        assert_eq!(Preconditions::of(&put).evaluate(None, None), Precondition::Proceed);
+    }
+
+    #[test]
+    fn test_apply()
+    {
+        let response = HttpResponse::new(200)
+        .with_header("ETag", "\"v2\"")
+        .with_header("Content-Type", "text/plain")
+        .with_header("Cache-Control", "no-cache")
+        .with_body("hello");
+        let not_modified = get(&[("If-None-Match", "\"v2\"")]).apply(response);
+        assert_eq!(not_modified.status(), 304);
+        assert_eq!(not_modified.headers(), ["ETag: \"v2\"", "Cache-Control: no-cache"]);
+        assert!(not_modified.body().is_empty());
+        let failed = get(&[("If-Match", "\"v1\"")]).apply(HttpResponse::new(200).with_header("ETag", "\"v2\""));
+        assert_eq!(failed.status(), 412);
+        let missing = get(&[("If-Match", "\"v1\"")]).apply(HttpResponse::new(404));
+        assert_eq!(missing.status(), 404);
+    }
+}
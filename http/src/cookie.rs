@@ -0,0 +1,337 @@
+use std::{fmt, time::{Duration, SystemTime}};
+
+use super::{crypto::{base64url, constant_time_eq, hmac_sha256}, date::format_http_date, form::percent_decode};
+
+/// Separates a signed cookie's value from its signature
+const SIGNATURE_SEPARATOR: char = '.';
+
+/// Whether a cookie is sent with cross-site requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite
+{
+    Strict,
+    Lax,
+    /// Requires `Secure`, which the builder adds
+    None,
+}
+
+/// A cookie to send in a `Set-Cookie` header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cookie
+{
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    expires: Option<SystemTime>,
+    max_age: Option<Duration>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+/// A secret for signing cookie values with HMAC-SHA256.
+///
+/// A signed value reads back only if it was issued with the same key and
+/// under the same cookie name.
+#[derive(Clone)]
+pub struct CookieKey
+{
+    secret: Vec<u8>,
+}
+
+/// Checks a cookie name is a token, RFC 6265 section 4.1.1
+fn is_token(name: &str) -> bool
+{
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Checks an attribute value can not end its attribute or the header early
+fn is_attribute_value(value: &str) -> bool
+{
+    !value.bytes().any(|b| b == b';' || b.is_ascii_control())
+}
+
+/// Percent-encodes what a cookie value can not hold, RFC 6265 section 4.1.1,
+/// and `%` itself so that the value reads back unchanged
+fn encode_value(value: &str) -> String
+{
+    let mut out = String::with_capacity(value.len());
+    for byte in value.bytes()
+    {
+        match byte
+        {
+            0x21..=0x7e if !b"%\",;\\".contains(&byte) => out.push(byte as char),
+            _ => out.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    out
+}
+
+/// Parses a `Cookie` request header into its name and value pairs.
+/// Percent escapes in values are decoded.
+pub fn parse_cookies(header: &str) -> Vec<(String, String)>
+{
+    header.split(';')
+    .filter_map(|pair|
+    {
+        let (name, value) = pair.split_once('=')?;
+        let name = name.trim();
+        if name.is_empty()
+        {
+            return None;
+        }
+        // Values may be wrapped in double quotes, RFC 6265 section 4.1.1
+        let value = value.trim();
+        let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+        let value = percent_decode(value, false).unwrap_or_else(|_| value.to_string());
+        Some((name.to_string(), value))
+    })
+    .collect()
+}
+
+impl Cookie
+{
+    /// Creates a cookie. Characters a cookie value can not hold, such as
+    /// `;` or spaces, are sent percent-encoded and decoded when read back.
+    ///
+    /// # Panics
+    ///
+    /// When the name is not a token, such as one with `=`, `;` or spaces
+    pub fn new(name: &str, value: &str) -> Self
+    {
+        assert!(is_token(name), "invalid cookie name: {name:?}");
+        Self
+        {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: None,
+            domain: None,
+            expires: None,
+            max_age: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    /// Creates a cookie that makes the client delete the cookie `name`.
+    /// Path and Domain must match the ones it was set with.
+    pub fn removal(name: &str) -> Self
+    {
+        Self::new(name, "").with_max_age(Duration::ZERO).with_expires(SystemTime::UNIX_EPOCH)
+    }
+
+    /// # Panics
+    ///
+    /// When the path has `;` or control characters
+    pub fn with_path(mut self, path: &str) -> Self
+    {
+        assert!(is_attribute_value(path), "invalid cookie path: {path:?}");
+        self.path = Some(path.to_string());
+        self
+    }
+
+    /// # Panics
+    ///
+    /// When the domain has `;` or control characters
+    pub fn with_domain(mut self, domain: &str) -> Self
+    {
+        assert!(is_attribute_value(domain), "invalid cookie domain: {domain:?}");
+        self.domain = Some(domain.to_string());
+        self
+    }
+
+    pub fn with_expires(mut self, expires: SystemTime) -> Self
+    {
+        self.expires = Some(expires);
+        self
+    }
+
+    pub fn with_max_age(mut self, max_age: Duration) -> Self
+    {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn with_secure(mut self, secure: bool) -> Self
+    {
+        self.secure = secure;
+        self
+    }
+
+    pub fn with_http_only(mut self, http_only: bool) -> Self
+    {
+        self.http_only = http_only;
+        self
+    }
+
+    pub fn with_same_site(mut self, same_site: SameSite) -> Self
+    {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    pub fn name(&self) -> &str
+    {
+        &self.name
+    }
+
+    pub fn value(&self) -> &str
+    {
+        &self.value
+    }
+}
+
+impl fmt::Display for Cookie
+{
+    /// Formats the cookie as a `Set-Cookie` header value
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        write!(f, "{}={}", self.name, encode_value(&self.value))?;
+        if let Some(path) = &self.path
+        {
+            write!(f, "; Path={path}")?;
+        }
+        if let Some(domain) = &self.domain
+        {
+            write!(f, "; Domain={domain}")?;
+        }
+        if let Some(expires) = self.expires
+        {
+            write!(f, "; Expires={}", format_http_date(expires))?;
+        }
+        if let Some(max_age) = self.max_age
+        {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        // Browsers reject SameSite=None without Secure
+        if self.secure || self.same_site == Some(SameSite::None)
+        {
+            write!(f, "; Secure")?;
+        }
+        if self.http_only
+        {
+            write!(f, "; HttpOnly")?;
+        }
+        match self.same_site
+        {
+            Some(SameSite::Strict) => write!(f, "; SameSite=Strict"),
+            Some(SameSite::Lax) => write!(f, "; SameSite=Lax"),
+            Some(SameSite::None) => write!(f, "; SameSite=None"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl CookieKey
+{
+    /// Creates a key from a secret, which should hold at least 32 random bytes
+    pub fn new(secret: &[u8]) -> Self
+    {
+        Self
+        {
+            secret: secret.to_vec()
+        }
+    }
+
+    /// Gets the signature of a value issued under `name`
+    fn signature(&self, name: &str, value: &str) -> String
+    {
+        base64url(&hmac_sha256(&self.secret, format!("{name}={value}").as_bytes()))
+    }
+
+    /// Appends a signature to the cookie's value
+    pub fn sign(&self, mut cookie: Cookie) -> Cookie
+    {
+        let signature = self.signature(&cookie.name, &cookie.value);
+        cookie.value = format!("{}{SIGNATURE_SEPARATOR}{signature}", cookie.value);
+        cookie
+    }
+
+    /// Gets the original value of a signed cookie, or `None` if the
+    /// signature does not match
+    pub fn verify(&self, name: &str, signed_value: &str) -> Option<String>
+    {
+        let (value, signature) = signed_value.rsplit_once(SIGNATURE_SEPARATOR)?;
+        let expected = self.signature(name, value);
+        if constant_time_eq(expected.as_bytes(), signature.as_bytes())
+        {
+            return Some(value.to_string());
+        }
+        None
+    }
+}
+
+impl fmt::Debug for CookieKey
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        // Keep the secret out of logs
+        f.debug_struct("CookieKey").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse()
+    {
+        let cookies = parse_cookies("a=1; b=\"two words\";c=; =x; junk; d=e=f");
+        let pairs: Vec<(&str, &str)> = cookies.iter().map(|(n, v)| (n.as_str(), v.as_str())).collect();
+        assert_eq!(pairs, [("a", "1"), ("b", "two words"), ("c", ""), ("d", "e=f")]);
+        assert_eq!(parse_cookies("a=1%3B%20b; c=50%")[..], [("a".to_string(), "1; b".to_string()), ("c".to_string(), "50%".to_string())]);
+    }
+
+    #[test]
+    fn test_injection()
+    {
+        // Values can not add attributes or headers, and read back unchanged
+        let value = "x; Domain=evil\r\nSet-Cookie: a=\"b\",c\\ 100%";
+        let cookie = Cookie::new("id", value).to_string();
+        assert_eq!(cookie, "id=x%3B%20Domain=evil%0D%0ASet-Cookie:%20a=%22b%22%2Cc%5C%20100%25");
+        assert_eq!(parse_cookies(&cookie)[0].1, value);
+        let key = CookieKey::new(b"0123456789abcdef0123456789abcdef");
+        let signed = key.sign(Cookie::new("id", "a b")).to_string();
+        let (_, sent) = parse_cookies(&signed).remove(0);
+        assert_eq!(key.verify("id", &sent).as_deref(), Some("a b"));
+        for name in ["", "a b", "a=b", "a;b", "a\r\n", "a\"b"]
+        {
+            assert!(std::panic::catch_unwind(|| Cookie::new(name, "x")).is_err());
+        }
+        assert!(std::panic::catch_unwind(|| Cookie::new("id", "x").with_path("/; Max-Age=0")).is_err());
+        assert!(std::panic::catch_unwind(|| Cookie::new("id", "x").with_domain("a.com\r\nX: y")).is_err());
+    }
+
+    #[test]
+    fn test_set_cookie()
+    {
+        let cookie = Cookie::new("id", "abc")
+        .with_path("/")
+        .with_domain("example.com")
+        .with_expires(SystemTime::UNIX_EPOCH + Duration::from_secs(784_111_777))
+        .with_max_age(Duration::from_secs(3600))
+        .with_http_only(true)
+        .with_same_site(SameSite::Lax);
+        assert_eq!(cookie.to_string(),
+            "id=abc; Path=/; Domain=example.com; Expires=Sun, 06 Nov 1994 08:49:37 GMT; Max-Age=3600; HttpOnly; SameSite=Lax");
+        assert_eq!(Cookie::new("a", "b").with_same_site(SameSite::None).to_string(), "a=b; Secure; SameSite=None");
+        assert_eq!(Cookie::removal("id").to_string(), "id=; Expires=Thu, 01 Jan 1970 00:00:00 GMT; Max-Age=0");
+    }
+
+    #[test]
+    fn test_signed()
+    {
+        let key = CookieKey::new(b"0123456789abcdef0123456789abcdef");
+        let signed = key.sign(Cookie::new("user", "42.admin"));
+        assert_eq!(key.verify("user", signed.value()).as_deref(), Some("42.admin"));
+        // Tampered values, other names and other keys are rejected
+        let tampered = signed.value().replacen("42", "43", 1);
+        assert!(key.verify("user", &tampered).is_none());
+        assert!(key.verify("other", signed.value()).is_none());
+        assert!(CookieKey::new(b"another secret").verify("user", signed.value()).is_none());
+        assert!(key.verify("user", "42").is_none());
+    }
+}
@@ -0,0 +1,400 @@
+use std::{collections::BTreeMap, fmt, io::{Error, ErrorKind}};
+
+use super::{form::FormError, json::{Json, JsonError}, reason_phrase, server::routes::UrlError, template::TemplateError, HttpResponse};
+
+/// An error a handler answers with, carrying its status and message.
+///
+/// Errors are sent as plain text, or as `application/problem+json` (RFC
+/// 9457) once they have a type, an instance or extension members.
+///
+/// ```
+/// use http::{error::{HttpError, IntoResponse}, HttpResponse};
+///
+/// fn withdraw(balance: u64, amount: u64) -> Result<HttpResponse, HttpError>
+/// {
+///     if amount > balance
+///     {
+///         return Err(HttpError::new(403, "Your balance is too low")
+///         .with_type("https://example.com/probs/out-of-credit")
+///         .with_extension("balance", balance));
+///     }
+///     Ok(HttpResponse::new(204))
+/// }
+///
+/// let response = withdraw(30, 50).into_response();
+/// assert_eq!(response.status(), 403);
+/// assert_eq!(response.header("Content-Type"), Some("application/problem+json"));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpError
+{
+    status: u16,
+    /// Sent to the client as the detail of the error
+    message: String,
+    /// Why a server error happened, logged but never sent
+    cause: Option<String>,
+    /// Problem details members other than the title, status and detail
+    members: BTreeMap<String, Json>,
+    headers: Vec<(String, String)>,
+}
+
+/// A value a handler can return as its response
+pub trait IntoResponse
+{
+    fn into_response(self) -> HttpResponse;
+}
+
+impl HttpError
+{
+    pub fn new(status: u16, message: impl Into<String>) -> Self
+    {
+        Self
+        {
+            status: status,
+            message: message.into(),
+            cause: None,
+            members: BTreeMap::new(),
+            headers: Vec::new(),
+        }
+    }
+
+    /// A 400 Bad Request
+    pub fn bad_request(message: impl Into<String>) -> Self
+    {
+        Self::new(400, message)
+    }
+
+    /// A 404 Not Found
+    pub fn not_found(message: impl Into<String>) -> Self
+    {
+        Self::new(404, message)
+    }
+
+    /// A 422 Unprocessable Content, for requests that parse but make no sense
+    pub fn unprocessable(message: impl Into<String>) -> Self
+    {
+        Self::new(422, message)
+    }
+
+    /// A 500 Internal Server Error. The cause is logged, not sent.
+    pub fn internal(cause: impl fmt::Display) -> Self
+    {
+        Self::new(500, reason_phrase(500)).with_cause(cause)
+    }
+
+    /// Sets why the error happened, for the server log
+    pub fn with_cause(mut self, cause: impl fmt::Display) -> Self
+    {
+        self.cause = Some(cause.to_string());
+        self
+    }
+
+    /// Sets the URI identifying the kind of problem
+    pub fn with_type(mut self, problem_type: &str) -> Self
+    {
+        self.members.insert("type".to_string(), Json::from(problem_type));
+        self
+    }
+
+    /// Sets the URI identifying this occurrence of the problem
+    pub fn with_instance(mut self, instance: &str) -> Self
+    {
+        self.members.insert("instance".to_string(), Json::from(instance));
+        self
+    }
+
+    /// Adds a member to the problem details. The standard members can not
+    /// be replaced this way.
+    pub fn with_extension(mut self, name: &str, value: impl Into<Json>) -> Self
+    {
+        if !matches!(name, "type" | "title" | "status" | "detail" | "instance")
+        {
+            self.members.insert(name.to_string(), value.into());
+        }
+        self
+    }
+
+    /// Adds a header to the response, such as `Retry-After`
+    pub fn with_header(mut self, name: &str, value: &str) -> Self
+    {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn status(&self) -> u16
+    {
+        self.status
+    }
+
+    pub fn message(&self) -> &str
+    {
+        &self.message
+    }
+
+    /// Whether the error is sent as problem details rather than text
+    pub fn is_problem(&self) -> bool
+    {
+        !self.members.is_empty()
+    }
+
+    /// Gets the problem details object of the error, RFC 9457 section 3
+    pub fn problem(&self) -> Json
+    {
+        let mut members = self.members.clone();
+        members.entry("type".to_string()).or_insert(Json::from("about:blank"));
+        members.insert("title".to_string(), Json::from(reason_phrase(self.status)));
+        members.insert("status".to_string(), Json::from(self.status));
+        members.insert("detail".to_string(), Json::from(self.message.as_str()));
+        Json::Object(members)
+    }
+}
+
+impl fmt::Display for HttpError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        write!(f, "{} {}: {}", self.status, reason_phrase(self.status), self.message)?;
+        if let Some(cause) = &self.cause
+        {
+            write!(f, " ({cause})")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+impl From<Error> for HttpError
+{
+    /// Maps I/O errors to the closest status. Only invalid input keeps its
+    /// message, as others may describe the server.
+    fn from(e: Error) -> Self
+    {
+        match e.kind()
+        {
+            ErrorKind::NotFound => Self::new(404, reason_phrase(404)).with_cause(e),
+            ErrorKind::PermissionDenied => Self::new(403, reason_phrase(403)).with_cause(e),
+            ErrorKind::InvalidData | ErrorKind::InvalidInput => Self::bad_request(e.to_string()),
+            ErrorKind::Unsupported => Self::new(501, reason_phrase(501)).with_cause(e),
+            ErrorKind::TimedOut => Self::new(504, reason_phrase(504)).with_cause(e),
+            _ => Self::internal(e),
+        }
+    }
+}
+
+impl From<JsonError> for HttpError
+{
+    fn from(e: JsonError) -> Self
+    {
+        let error = Self::new(e.status(), e.to_string());
+        match e
+        {
+            JsonError::Syntax { line, column, .. } => error.with_extension("line", line).with_extension("column", column),
+            _ => error,
+        }
+    }
+}
+
+impl From<FormError> for HttpError
+{
+    fn from(e: FormError) -> Self
+    {
+        match &e
+        {
+            FormError::Malformed(_) => Self::bad_request(e.to_string()),
+            FormError::Missing(field) | FormError::Invalid { field, .. } => Self::unprocessable(e.to_string()).with_extension("field", field.as_str()),
+        }
+    }
+}
+
+impl From<TemplateError> for HttpError
+{
+    fn from(e: TemplateError) -> Self
+    {
+        Self::internal(e)
+    }
+}
+
+impl From<UrlError> for HttpError
+{
+    /// A URL that can not be built is a mistake in the server, not the request
+    fn from(e: UrlError) -> Self
+    {
+        Self::internal(e)
+    }
+}
+
+impl IntoResponse for HttpError
+{
+    fn into_response(self) -> HttpResponse
+    {
+        if let Some(cause) = &self.cause
+        {
+            if self.status >= 500
+            {
+                println!("Request failed: {cause}");
+            }
+        }
+        let mut response = HttpResponse::new(self.status);
+        for (name, value) in &self.headers
+        {
+            response = response.with_header(name, value);
+        }
+        if self.is_problem()
+        {
+            return response
+            .with_header("Content-Type", "application/problem+json")
+            .with_body(self.problem().to_string());
+        }
+        let mut response = response
+        .with_header("Content-Type", "text/plain; charset=utf-8")
+        .with_body(format!("{}\n", self.message));
+        response.error = Some(self.message);
+        response
+    }
+}
+
+impl IntoResponse for HttpResponse
+{
+    fn into_response(self) -> HttpResponse
+    {
+        self
+    }
+}
+
+impl IntoResponse for Error
+{
+    fn into_response(self) -> HttpResponse
+    {
+        HttpError::from(self).into_response()
+    }
+}
+
+impl IntoResponse for ()
+{
+    /// Answers with 204 No Content
+    fn into_response(self) -> HttpResponse
+    {
+        HttpResponse::new(204)
+    }
+}
+
+impl IntoResponse for String
+{
+    fn into_response(self) -> HttpResponse
+    {
+        HttpResponse::new(200)
+        .with_header("Content-Type", "text/plain; charset=utf-8")
+        .with_body(self)
+    }
+}
+
+impl IntoResponse for &'static str
+{
+    fn into_response(self) -> HttpResponse
+    {
+        self.to_string().into_response()
+    }
+}
+
+impl IntoResponse for Vec<u8>
+{
+    fn into_response(self) -> HttpResponse
+    {
+        HttpResponse::new(200)
+        .with_header("Content-Type", "application/octet-stream")
+        .with_body(self)
+    }
+}
+
+impl IntoResponse for &'static [u8]
+{
+    fn into_response(self) -> HttpResponse
+    {
+        self.to_vec().into_response()
+    }
+}
+
+impl IntoResponse for Json
+{
+    fn into_response(self) -> HttpResponse
+    {
+        HttpResponse::json(self)
+    }
+}
+
+impl<T: IntoResponse, E: IntoResponse> IntoResponse for Result<T, E>
+{
+    fn into_response(self) -> HttpResponse
+    {
+        match self
+        {
+            Ok(value) => value.into_response(),
+            Err(e) => e.into_response(),
+        }
+    }
+}
+
+impl<T: IntoResponse> IntoResponse for (u16, T)
+{
+    /// Answers with the body and a different status
+    fn into_response(self) -> HttpResponse
+    {
+        let mut response = self.1.into_response();
+        response.status = self.0;
+        response
+    }
+}
+
+impl<T: IntoResponse, const N: usize> IntoResponse for (u16, [(&str, &str); N], T)
+{
+    /// Answers with the body, a different status and extra headers
+    fn into_response(self) -> HttpResponse
+    {
+        let (status, headers, body) = self;
+        let mut response = (status, body).into_response();
+        for (name, value) in headers
+        {
+            response = response.with_header(name, value);
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_responses()
+    {
+        let plain = HttpError::not_found("No user 7").into_response();
+        assert_eq!((plain.status(), plain.header("Content-Type")), (404, Some("text/plain; charset=utf-8")));
+        assert_eq!(plain.body(), b"No user 7\n");
+        let problem = HttpError::new(409, "Name taken").with_instance("/users/ann").with_extension("status", 200).with_header("Retry-After", "5").into_response();
+        assert_eq!((problem.status(), problem.header("Retry-After")), (409, Some("5")));
+        assert_eq!(problem.body(), br#"{"detail":"Name taken","instance":"/users/ann","status":409,"title":"Conflict","type":"about:blank"}"#);
+        // Server errors keep their cause out of the body
+        let internal = HttpError::from(Error::other("disk /srv/db is full")).into_response();
+        assert_eq!((internal.status(), internal.body()), (500, &b"Internal Server Error\n"[..]));
+        assert_eq!(HttpError::from(Error::new(ErrorKind::NotFound, "x")).status(), 404);
+        let form = HttpError::from(FormError::Missing("name".to_string()));
+        assert_eq!((form.status(), form.problem().get("field")), (422, Some(&Json::from("name"))));
+        let json = HttpError::from(Json::parse("[1,").unwrap_err()).into_response();
+        assert_eq!((json.status(), json.header("Content-Type")), (400, Some("application/problem+json")));
+    }
+
+    #[test]
+    fn test_into_response()
+    {
+        let text = "hi".into_response();
+        assert_eq!((text.status(), text.header("Content-Type"), text.body()), (200, Some("text/plain; charset=utf-8"), &b"hi"[..]));
+        let bytes = vec![1u8, 2].into_response();
+        assert_eq!(bytes.header("Content-Type"), Some("application/octet-stream"));
+        let created = (201, [("Location", "/items/1")], Json::from_iter([("id", 1)])).into_response();
+        assert_eq!((created.status(), created.header("Location"), created.body()), (201, Some("/items/1"), &br#"{"id":1}"#[..]));
+        assert_eq!(().into_response().status(), 204);
+        let failed: Result<String, HttpError> = Err(HttpError::bad_request("no"));
+        assert_eq!(failed.into_response().status(), 400);
+    }
+}
@@ -0,0 +1,359 @@
+use std::{any::{type_name, Any}, marker::PhantomData, ops::Deref, sync::Arc};
+
+use super::{cancel::Cancellation, error::{HttpError, IntoResponse}, form::{FromForm, FromFormValue}, json::Json, server::HttpHandler, HttpRequest, HttpResponse};
+
+/// A value a handler can take as an argument, taken from the request.
+/// Failing to extract it answers the request with the error instead.
+pub trait FromRequest: Sized
+{
+    fn from_request(request: &HttpRequest) -> Result<Self, HttpError>;
+}
+
+/// A handler taking extractors as its arguments, optionally followed by the
+/// request itself. [`routes!`](crate::routes) wraps every handler in one.
+///
+/// ```
+/// use http::{extract::{Extractor, Header, Path, Query, UserAgent}, form::{Form, FormError, FromForm}, routes, server::HttpMethodHandler};
+///
+/// struct Page
+/// {
+///     page: u32,
+/// }
+///
+/// impl FromForm for Page
+/// {
+///     fn from_form(form: &Form) -> Result<Self, FormError>
+///     {
+///         Ok(Self { page: form.optional("page")?.unwrap_or(1) })
+///     }
+/// }
+///
+/// fn posts(Path((user,)): Path<(u32,)>, Query(query): Query<Page>, agent: Option<Header<UserAgent>>) -> String
+/// {
+///     let agent = agent.map_or("unknown".to_string(), |Header(UserAgent(agent))| agent);
+///     format!("posts of {user}, page {} for {agent}", query.page)
+/// }
+///
+/// static ROUTES: &[HttpMethodHandler] = routes![
+///     Get "/users/{id}/posts" => posts,
+/// ];
+/// ```
+pub struct Extractor<F, T>
+{
+    handler: F,
+    arguments: PhantomData<fn() -> T>,
+}
+
+impl<F, T> Extractor<F, T>
+{
+    pub const fn new(handler: F) -> Self
+    {
+        Self
+        {
+            handler: handler,
+            arguments: PhantomData,
+        }
+    }
+}
+
+/// Implements `HttpHandler` for handlers taking the extractors, and for
+/// handlers taking the extractors followed by the request
+macro_rules! extractor_handler
+{
+    ($($arg:ident),*) =>
+    {
+        #[allow(non_snake_case)]
+        impl<F, R, $($arg,)*> HttpHandler for Extractor<F, ($($arg,)*)>
+        where
+            F: Fn($($arg),*) -> R + Sync,
+            R: IntoResponse,
+            $($arg: FromRequest,)*
+        {
+            #[allow(unused_variables)]
+            fn call(&self, request: HttpRequest) -> HttpResponse
+            {
+                $(
+                    let $arg = match $arg::from_request(&request)
+                    {
+                        Ok(value) => value,
+                        Err(e) => return e.into_response(),
+                    };
+                )*
+                (self.handler)($($arg),*).into_response()
+            }
+        }
+
+        #[allow(non_snake_case)]
+        impl<F, R, $($arg,)*> HttpHandler for Extractor<F, ($($arg,)* HttpRequest,)>
+        where
+            F: Fn($($arg,)* HttpRequest) -> R + Sync,
+            R: IntoResponse,
+            $($arg: FromRequest,)*
+        {
+            fn call(&self, request: HttpRequest) -> HttpResponse
+            {
+                $(
+                    let $arg = match $arg::from_request(&request)
+                    {
+                        Ok(value) => value,
+                        Err(e) => return e.into_response(),
+                    };
+                )*
+                (self.handler)($($arg,)* request).into_response()
+            }
+        }
+    };
+}
+
+extractor_handler!();
+extractor_handler!(A);
+extractor_handler!(A, B);
+extractor_handler!(A, B, C);
+extractor_handler!(A, B, C, D);
+extractor_handler!(A, B, C, D, E);
+
+/// The values of the route parameters, in the order of the route, such as
+/// `Path<(u32, String)>` for `/users/{id}/posts/{slug}`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Path<T>(pub T);
+
+/// Values the route parameters can be decoded into
+pub trait FromPath: Sized
+{
+    fn from_path(params: &[(String, String)]) -> Result<Self, HttpError>;
+}
+
+/// Parses one route parameter, answering 400 when it does not parse
+fn parse_param<T: FromFormValue>((name, value): &(String, String)) -> Result<T, HttpError>
+{
+    T::from_form_value(value).map_err(|e| HttpError::bad_request(format!("invalid value for path parameter `{name}`: {e}")))
+}
+
+/// Implements `FromPath` for a tuple of as many values as parameters
+macro_rules! from_path_tuple
+{
+    ($count:literal: $($t:ident $i:tt),+) =>
+    {
+        impl<$($t: FromFormValue),+> FromPath for ($($t,)+)
+        {
+            fn from_path(params: &[(String, String)]) -> Result<Self, HttpError>
+            {
+                if params.len() != $count
+                {
+                    return Err(HttpError::internal(format!("the route has {} parameters, the handler takes {}", params.len(), $count)));
+                }
+                Ok(($(parse_param::<$t>(&params[$i])?,)+))
+            }
+        }
+    };
+}
+
+from_path_tuple!(1: A 0);
+from_path_tuple!(2: A 0, B 1);
+from_path_tuple!(3: A 0, B 1, C 2);
+from_path_tuple!(4: A 0, B 1, C 2, D 3);
+
+impl<T: FromPath> FromRequest for Path<T>
+{
+    fn from_request(request: &HttpRequest) -> Result<Self, HttpError>
+    {
+        T::from_path(request.content().params()).map(Path)
+    }
+}
+
+/// The query string of the route, decoded into `T`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query<T>(pub T);
+
+impl<T: FromForm> FromRequest for Query<T>
+{
+    fn from_request(request: &HttpRequest) -> Result<Self, HttpError>
+    {
+        Ok(Query(request.query()?))
+    }
+}
+
+/// An `application/x-www-form-urlencoded` body, decoded into `T`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Form<T>(pub T);
+
+impl<T: FromForm> FromRequest for Form<T>
+{
+    fn from_request(request: &HttpRequest) -> Result<Self, HttpError>
+    {
+        Ok(Form(request.form()?))
+    }
+}
+
+impl FromRequest for Cancellation
+{
+    fn from_request(request: &HttpRequest) -> Result<Self, HttpError>
+    {
+        Ok(request.content().cancellation().clone())
+    }
+}
+
+impl FromRequest for Json
+{
+    fn from_request(request: &HttpRequest) -> Result<Self, HttpError>
+    {
+        Ok(request.json()?)
+    }
+}
+
+/// A header with a known name, parsed from its value
+pub trait TypedHeader: Sized
+{
+    const NAME: &'static str;
+
+    fn from_value(value: &str) -> Result<Self, String>;
+}
+
+/// A request header parsed into `T`. Requests without it are answered with
+/// 400, unless the argument is an `Option<Header<T>>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header<T>(pub T);
+
+/// Parses a header value, answering 400 when it does not parse
+fn parse_header<T: TypedHeader>(value: &str) -> Result<Header<T>, HttpError>
+{
+    T::from_value(value).map(Header).map_err(|e| HttpError::bad_request(format!("invalid header `{}`: {e}", T::NAME)))
+}
+
+impl<T: TypedHeader> FromRequest for Header<T>
+{
+    fn from_request(request: &HttpRequest) -> Result<Self, HttpError>
+    {
+        match request.content().header(T::NAME)
+        {
+            Some(value) => parse_header(value),
+            None => Err(HttpError::bad_request(format!("missing header `{}`", T::NAME))),
+        }
+    }
+}
+
+impl<T: TypedHeader> FromRequest for Option<Header<T>>
+{
+    fn from_request(request: &HttpRequest) -> Result<Self, HttpError>
+    {
+        request.content().header(T::NAME).map(parse_header).transpose()
+    }
+}
+
+/// The `User-Agent` header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserAgent(pub String);
+
+impl TypedHeader for UserAgent
+{
+    const NAME: &'static str = "User-Agent";
+
+    fn from_value(value: &str) -> Result<Self, String>
+    {
+        Ok(UserAgent(value.to_string()))
+    }
+}
+
+/// The media type of the `Content-Type` header, lowercased and without
+/// parameters
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentType(pub String);
+
+impl TypedHeader for ContentType
+{
+    const NAME: &'static str = "Content-Type";
+
+    fn from_value(value: &str) -> Result<Self, String>
+    {
+        match value.split(';').next().unwrap_or("").trim()
+        {
+            "" => Err("empty media type".to_string()),
+            media_type => Ok(ContentType(media_type.to_ascii_lowercase())),
+        }
+    }
+}
+
+/// State shared by the router handling the request, see
+/// [`Router::with_state`](crate::server::router::Router::with_state)
+#[derive(Debug)]
+pub struct State<T>(pub Arc<T>);
+
+impl<T> Deref for State<T>
+{
+    type Target = T;
+
+    fn deref(&self) -> &T
+    {
+        &self.0
+    }
+}
+
+impl<T: Any + Send + Sync> FromRequest for State<T>
+{
+    /// A missing state is a mistake in setting up the routers
+    fn from_request(request: &HttpRequest) -> Result<Self, HttpError>
+    {
+        match request.content().state.get_shared::<T>()
+        {
+            Some(state) => Ok(State(state)),
+            None => Err(HttpError::internal(format!("no router shares state of type {}", type_name::<T>()))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::router::StateMap;
+
+    fn request(head: &str) -> HttpRequest
+    {
+        HttpRequest::new(head.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_extractors()
+    {
+        let mut get = request("GET /users/42/posts?page=x HTTP/1.1\r\nUser-Agent: curl\r\n\r\n");
+        get.content_mut().params = vec![("id".to_string(), "42".to_string()), ("slug".to_string(), "a b".to_string())];
+        assert_eq!(Path::<(u32, String)>::from_request(&get).unwrap(), Path((42, "a b".to_string())));
+        assert_eq!(Path::<(u32, u32)>::from_request(&get).unwrap_err().status(), 400);
+        assert_eq!(Path::<(u32,)>::from_request(&get).unwrap_err().status(), 500);
+        assert_eq!(Header::<UserAgent>::from_request(&get).unwrap(), Header(UserAgent("curl".to_string())));
+        assert_eq!(Header::<ContentType>::from_request(&get).unwrap_err().status(), 400);
+        assert_eq!(Option::<Header<ContentType>>::from_request(&get).unwrap(), None);
+        // Fields present but not parsing are unprocessable
+        #[derive(Debug)]
+        struct Page(u32);
+        impl FromForm for Page
+        {
+            fn from_form(form: &crate::form::Form) -> Result<Self, crate::form::FormError>
+            {
+                Ok(Page(form.required("page")?))
+            }
+        }
+        assert_eq!(Query::<Page>::from_request(&get).unwrap_err().status(), 422);
+        assert_eq!(Form::<Page>::from_request(&get).unwrap_err().status(), 400);
+        let mut post = request("POST /login HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\n\r\n");
+        post.content_mut().body = "page=3".to_string();
+        assert_eq!(Form::<Page>::from_request(&post).unwrap().0.0, 3);
+        assert_eq!(Json::from_request(&post).unwrap_err().status(), 415);
+    }
+
+    #[test]
+    fn test_handlers()
+    {
+        fn user(Path((id,)): Path<(u32,)>, State(greeting): State<String>, request: HttpRequest) -> String
+        {
+            format!("{greeting} {id} on {}", request.content().path())
+        }
+        let mut get = request("GET /users/7 HTTP/1.1\r\n\r\n");
+        get.content_mut().params = vec![("id".to_string(), "7".to_string())];
+        get.content_mut().state = StateMap::new(vec![Arc::new("hello".to_string())]);
+        assert_eq!(Extractor::new(user).call(get).body(), b"hello 7 on /users/7");
+        let mut get = request("GET /users/x HTTP/1.1\r\n\r\n");
+        get.content_mut().params = vec![("id".to_string(), "x".to_string())];
+        assert_eq!(Extractor::new(user).call(get).status(), 400);
+        assert_eq!(Extractor::new(|| "ok").call(request("GET / HTTP/1.1\r\n\r\n")).body(), b"ok");
+    }
+}
@@ -0,0 +1,331 @@
+use std::{collections::VecDeque, fs::{self, File}, io::{Cursor, Error, ErrorKind, Read, Seek, SeekFrom}, path::{Path, PathBuf}, time::SystemTime};
+
+use super::{compress::{negotiate_encoding, Encoding}, conditional::{not_modified, EntityTag, Precondition, Preconditions}, crypto::{hex, sha256}, range::{if_range_matches, parse_range, Ranges}, HttpRequest, HttpResponse};
+
+/// A file served from disk with `ETag` and `Last-Modified` validators, so
+/// clients holding a fresh copy get 304 Not Modified instead of the file
+#[derive(Debug, Clone)]
+pub struct StaticFile
+{
+    path: PathBuf,
+    /// Whether the ETag is a hash of the content rather than the
+    /// modification time and size
+    strong_etag: bool,
+    content_type: Option<String>,
+    /// Whether a `.gz` sibling of the file is served to clients taking gzip
+    precompressed: bool,
+}
+
+/// Guesses the media type of a file from its extension
+pub fn content_type(path: &Path) -> &'static str
+{
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_ascii_lowercase();
+    match extension.as_str()
+    {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "woff2" => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+impl StaticFile
+{
+    pub fn new(path: impl AsRef<Path>) -> Self
+    {
+        Self
+        {
+            path: path.as_ref().to_path_buf(),
+            strong_etag: false,
+            content_type: None,
+            precompressed: false,
+        }
+    }
+
+    /// Uses a hash of the content as the ETag instead of a weak tag from
+    /// the modification time and size. The file is then read into memory on
+    /// every request, including those answered with 304, so this suits
+    /// small files.
+    pub fn with_strong_etag(mut self, strong_etag: bool) -> Self
+    {
+        self.strong_etag = strong_etag;
+        self
+    }
+
+    /// Overrides the media type guessed from the extension
+    pub fn with_content_type(mut self, content_type: &str) -> Self
+    {
+        self.content_type = Some(content_type.to_string());
+        self
+    }
+
+    /// Serves `<path>.gz`, when it exists, to clients accepting gzip
+    pub fn with_precompressed(mut self, precompressed: bool) -> Self
+    {
+        self.precompressed = precompressed;
+        self
+    }
+
+    /// Picks the file to send, returning it with its content coding
+    fn select(&self, request: &HttpRequest) -> (PathBuf, Option<Encoding>)
+    {
+        let accepts_gzip = request.content().header("Accept-Encoding")
+        .is_some_and(|accept| negotiate_encoding(accept, &[Encoding::Gzip]) == Encoding::Gzip);
+        if self.precompressed && accepts_gzip
+        {
+            let mut gz = self.path.clone().into_os_string();
+            gz.push(".gz");
+            let gz = PathBuf::from(gz);
+            if fs::metadata(&gz).is_ok_and(|metadata| metadata.is_file())
+            {
+                return (gz, Some(Encoding::Gzip));
+            }
+        }
+        (self.path.clone(), None)
+    }
+
+    /// Adds the headers describing the selected representation
+    fn with_representation(&self, mut response: HttpResponse, etag: &EntityTag, modified: SystemTime, encoding: Option<Encoding>) -> HttpResponse
+    {
+        response = response
+        .with_header("Accept-Ranges", "bytes")
+        .with_etag(etag)
+        .with_last_modified(modified);
+        if let Some(encoding) = encoding
+        {
+            response = response.with_header("Content-Encoding", encoding.name());
+        }
+        if self.precompressed
+        {
+            response = response.with_header("Vary", "Accept-Encoding");
+        }
+        response
+    }
+
+    /// Answers a request for the file, evaluating its conditional headers
+    /// and serving the byte ranges a GET asks for
+    pub fn respond(&self, request: &HttpRequest) -> Result<HttpResponse, Error>
+    {
+        let metadata = fs::metadata(&self.path)?;
+        if !metadata.is_file()
+        {
+            return Err(Error::new(ErrorKind::NotFound, format!("{} is not a file", self.path.display())));
+        }
+        let (path, encoding) = self.select(request);
+        let metadata = if encoding.is_some() { fs::metadata(&path)? } else { metadata };
+        let modified = metadata.modified()?;
+        let (etag, contents) = if self.strong_etag
+        {
+            let contents = fs::read(&path)?;
+            (EntityTag::from_content(&contents), Some(contents))
+        }
+        else
+        {
+            (EntityTag::from_metadata(modified, metadata.len()), None)
+        };
+        let len = contents.as_ref().map_or(metadata.len(), |contents| contents.len() as u64);
+        let content_type = self.content_type.as_deref().unwrap_or(content_type(&self.path));
+        let response = self.with_representation(HttpResponse::new(200), &etag, modified, encoding)
+        .with_header("Content-Type", content_type);
+        match Preconditions::of(request).evaluate(Some(&etag), Some(modified))
+        {
+            Precondition::Proceed => {},
+            Precondition::NotModified => return Ok(not_modified(&response)),
+            Precondition::Failed => return Ok(HttpResponse::new(412)),
+        }
+        // The file is sent as it is read, whatever its size
+        let mut source: Box<dyn Source> = match contents
+        {
+            Some(contents) => Box::new(Cursor::new(contents)),
+            None => Box::new(File::open(&path)?),
+        };
+        match self.ranges(request, &etag, modified, len)
+        {
+            Ranges::Full => Ok(response.with_body_stream(source, len)),
+            Ranges::Unsatisfiable =>
+            {
+                Ok(HttpResponse::new(416).with_header("Content-Range", &format!("bytes */{len}")))
+            },
+            Ranges::Partial(ranges) =>
+            {
+                let mut partial = self.with_representation(HttpResponse::new(206), &etag, modified, encoding);
+                if let [range] = ranges.as_slice()
+                {
+                    source.seek(SeekFrom::Start(range.start))?;
+                    return Ok(partial
+                    .with_header("Content-Type", content_type)
+                    .with_header("Content-Range", &range.content_range(len))
+                    .with_body_stream(source, range.size()));
+                }
+                // Several ranges go in a multipart/byteranges body, RFC 9110 section 14.6
+                let boundary = hex(&sha256(etag.to_string().as_bytes())[..12]);
+                let mut parts = VecDeque::new();
+                for range in &ranges
+                {
+                    let head = format!("--{boundary}\r\nContent-Type: {content_type}\r\nContent-Range: {}\r\n\r\n",
+                        range.content_range(len));
+                    parts.push_back(Part::Bytes(Cursor::new(head.into_bytes())));
+                    parts.push_back(Part::Range { start: range.start, left: range.size(), seeked: false });
+                    parts.push_back(Part::Bytes(Cursor::new(b"\r\n".to_vec())));
+                }
+                parts.push_back(Part::Bytes(Cursor::new(format!("--{boundary}--\r\n").into_bytes())));
+                let body_len = parts.iter().map(Part::len).sum();
+                partial = partial.with_header("Content-Type", &format!("multipart/byteranges; boundary={boundary}"));
+                Ok(partial.with_body_stream(ByteRanges { source: source, parts: parts }, body_len))
+            },
+        }
+    }
+
+    /// Gets the ranges a request asks for. Only GET requests have ranges,
+    /// and only while their `If-Range` names the current file.
+    fn ranges(&self, request: &HttpRequest, etag: &EntityTag, modified: SystemTime, len: u64) -> Ranges
+    {
+        let content = match request
+        {
+            HttpRequest::Get(content) => content,
+            _ => return Ranges::Full,
+        };
+        let Some(range) = content.header("Range") else
+        {
+            return Ranges::Full;
+        };
+        if let Some(if_range) = content.header("If-Range")
+        {
+            if !if_range_matches(if_range, Some(etag), Some(modified))
+            {
+                return Ranges::Full;
+            }
+        }
+        parse_range(range, len)
+    }
+}
+
+/// What the bytes of a file response are read from
+trait Source: Read + Seek + Send {}
+
+impl<S: Read + Seek + Send> Source for S {}
+
+/// A piece of a multipart/byteranges body
+enum Part
+{
+    /// A part head or delimiter
+    Bytes(Cursor<Vec<u8>>),
+    /// A range of the file, which is seeked to when its turn comes
+    Range { start: u64, left: u64, seeked: bool },
+}
+
+/// Reads a multipart/byteranges body, one range of the file at a time
+struct ByteRanges
+{
+    source: Box<dyn Source>,
+    parts: VecDeque<Part>,
+}
+
+impl Part
+{
+    fn len(&self) -> u64
+    {
+        match self
+        {
+            Part::Bytes(bytes) => bytes.get_ref().len() as u64,
+            Part::Range { left, .. } => *left,
+        }
+    }
+}
+
+impl Read for ByteRanges
+{
+    fn read(&mut self, out: &mut [u8]) -> Result<usize, Error>
+    {
+        while let Some(part) = self.parts.front_mut()
+        {
+            let n = match part
+            {
+                Part::Bytes(bytes) => bytes.read(out)?,
+                Part::Range { left: 0, .. } => 0,
+                Part::Range { start, left, seeked } =>
+                {
+                    if !*seeked
+                    {
+                        self.source.seek(SeekFrom::Start(*start))?;
+                        *seeked = true;
+                    }
+                    let want = out.len().min((*left).min(usize::MAX as u64) as usize);
+                    // The file may have shrunk between its metadata and its read
+                    match self.source.read(&mut out[..want])?
+                    {
+                        0 => return Err(Error::new(ErrorKind::UnexpectedEof, "File shorter than its range")),
+                        n =>
+                        {
+                            *left -= n as u64;
+                            n
+                        },
+                    }
+                },
+            };
+            if n > 0 || out.is_empty()
+            {
+                return Ok(n);
+            }
+            self.parts.pop_front();
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(path: &Path, range: Option<&str>) -> HttpResponse
+    {
+        let range = range.map_or(String::new(), |range| format!("Range: {range}\r\n"));
+        let request = HttpRequest::new(format!("GET / HTTP/1.1\r\n{range}\r\n").as_bytes()).unwrap();
+        StaticFile::new(path).respond(&request).unwrap()
+    }
+
+    /// Writes a response out and splits off its body
+    fn sent_body(response: HttpResponse) -> Vec<u8>
+    {
+        let mut bytes = Vec::new();
+        response.write_to(&mut bytes).unwrap();
+        let end = bytes.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        bytes.split_off(end)
+    }
+
+    #[test]
+    fn test_streamed()
+    {
+        let path = std::env::temp_dir().join(format!("static-file-{}.bin", std::process::id()));
+        let contents: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, &contents).unwrap();
+        // Bodies are read as they are written, not when the response is built
+        let full = get(&path, None);
+        assert!(full.body().is_empty());
+        assert_eq!(full.body_len(), contents.len() as u64);
+        assert_eq!(sent_body(full), contents);
+        let open = get(&path, Some("bytes=100-"));
+        assert_eq!(open.header("Content-Range"), Some("bytes 100-299999/300000"));
+        assert_eq!(sent_body(open), &contents[100..]);
+        let multi = get(&path, Some("bytes=0-1,299998-"));
+        let len = multi.body_len();
+        let body = sent_body(multi);
+        assert_eq!(body.len() as u64, len);
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains("Content-Range: bytes 0-1/300000\r\n\r\n\u{0}\u{1}\r\n"));
+        assert!(body.contains(&format!("Content-Range: bytes 299998-299999/300000\r\n\r\n{}{}\r\n", contents[299_998] as char, contents[299_999] as char)));
+        fs::remove_file(path).unwrap();
+    }
+}
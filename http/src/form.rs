@@ -0,0 +1,311 @@
+use std::{fmt, io::{Error, ErrorKind}, str::FromStr};
+
+/// Why a form or query string could not be decoded into a value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FormError
+{
+    /// The encoding itself is broken, such as a bad percent escape
+    Malformed(String),
+    /// A required field is absent
+    Missing(String),
+    /// A field is present but its value does not parse
+    Invalid
+    {
+        field: String,
+        message: String,
+    },
+}
+
+/// Decoded `name=value` pairs of an `application/x-www-form-urlencoded`
+/// body or a URL query string, in their original order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Form
+{
+    pairs: Vec<(String, String)>,
+}
+
+/// A value that can be parsed from a single form field
+pub trait FromFormValue: Sized
+{
+    fn from_form_value(value: &str) -> Result<Self, String>;
+}
+
+/// A type built from the fields of a form, usually a handler's own struct
+///
+/// ```
+/// use http::form::{Form, FormError, FromForm};
+///
+/// struct Search { q: String, page: u32, tags: Vec<String> }
+///
+/// impl FromForm for Search
+/// {
+///     fn from_form(form: &Form) -> Result<Self, FormError>
+///     {
+///         Ok(Self
+///         {
+///             q: form.required("q")?,
+///             page: form.optional("page")?.unwrap_or(1),
+///             tags: form.all("tag")?,
+///         })
+///     }
+/// }
+///
+/// let search: Search = Form::parse("q=rust&tag=a&tag=b").unwrap().extract().unwrap();
+/// assert_eq!((search.page, search.tags.len()), (1, 2));
+/// ```
+pub trait FromForm: Sized
+{
+    fn from_form(form: &Form) -> Result<Self, FormError>;
+}
+
+impl fmt::Display for FormError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            FormError::Malformed(message) => write!(f, "malformed form data: {message}"),
+            FormError::Missing(field) => write!(f, "missing field `{field}`"),
+            FormError::Invalid { field, message } => write!(f, "invalid value for field `{field}`: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for FormError {}
+
+impl From<FormError> for Error
+{
+    fn from(e: FormError) -> Self
+    {
+        Error::new(ErrorKind::InvalidData, e)
+    }
+}
+
+fn hex_value(c: u8) -> Option<u8>
+{
+    match c
+    {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Decodes percent escapes, and `+` as a space when `plus_as_space`
+pub fn percent_decode(input: &str, plus_as_space: bool) -> Result<String, FormError>
+{
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len()
+    {
+        match bytes[i]
+        {
+            b'%' =>
+            {
+                let high = bytes.get(i + 1).copied().and_then(hex_value);
+                let low = bytes.get(i + 2).copied().and_then(hex_value);
+                let (Some(high), Some(low)) = (high, low) else
+                {
+                    let escape: String = input[i..].chars().take(3).collect();
+                    return Err(FormError::Malformed(format!("invalid percent escape `{escape}`")));
+                };
+                out.push(high << 4 | low);
+                i += 3;
+                continue;
+            },
+            b'+' if plus_as_space => out.push(b' '),
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8(out).map_err(|_| FormError::Malformed("escapes do not decode to UTF-8".to_string()))
+}
+
+/// Percent-encodes everything but unreserved characters, RFC 3986 section 2.3
+pub fn percent_encode(input: &str) -> String
+{
+    let mut out = String::with_capacity(input.len());
+    for byte in input.bytes()
+    {
+        match byte
+        {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => out.push(byte as char),
+            _ => out.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    out
+}
+
+impl Form
+{
+    /// Parses `a=1&b=2` pairs. Keys without `=` get an empty value.
+    pub fn parse(input: &str) -> Result<Self, FormError>
+    {
+        let mut pairs = Vec::new();
+        for pair in input.split('&').filter(|pair| !pair.is_empty())
+        {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            pairs.push((percent_decode(name, true)?, percent_decode(value, true)?));
+        }
+        Ok(Self
+        {
+            pairs: pairs
+        })
+    }
+
+    pub fn pairs(&self) -> &[(String, String)]
+    {
+        &self.pairs
+    }
+
+    /// Gets the first value of a field
+    pub fn get(&self, name: &str) -> Option<&str>
+    {
+        self.pairs.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    /// Gets every value of a repeated field
+    pub fn get_all(&self, name: &str) -> Vec<&str>
+    {
+        self.pairs.iter().filter(|(key, _)| key == name).map(|(_, value)| value.as_str()).collect()
+    }
+
+    /// Parses the first value of a field that must be present
+    pub fn required<T: FromFormValue>(&self, name: &str) -> Result<T, FormError>
+    {
+        self.optional(name)?.ok_or(FormError::Missing(name.to_string()))
+    }
+
+    /// Parses the first value of a field that may be absent
+    pub fn optional<T: FromFormValue>(&self, name: &str) -> Result<Option<T>, FormError>
+    {
+        self.get(name).map(|value| parse_field(name, value)).transpose()
+    }
+
+    /// Parses every value of a repeated field
+    pub fn all<T: FromFormValue>(&self, name: &str) -> Result<Vec<T>, FormError>
+    {
+        self.get_all(name).into_iter().map(|value| parse_field(name, value)).collect()
+    }
+
+    /// Extracts a user type from the form
+    pub fn extract<T: FromForm>(&self) -> Result<T, FormError>
+    {
+        T::from_form(self)
+    }
+}
+
+fn parse_field<T: FromFormValue>(name: &str, value: &str) -> Result<T, FormError>
+{
+    T::from_form_value(value).map_err(|message| FormError::Invalid
+    {
+        field: name.to_string(),
+        message: message,
+    })
+}
+
+impl FromForm for Form
+{
+    fn from_form(form: &Form) -> Result<Self, FormError>
+    {
+        Ok(form.clone())
+    }
+}
+
+impl FromFormValue for String
+{
+    fn from_form_value(value: &str) -> Result<Self, String>
+    {
+        Ok(value.to_string())
+    }
+}
+
+impl FromFormValue for bool
+{
+    /// Accepts what HTML checkboxes and common conventions send
+    fn from_form_value(value: &str) -> Result<Self, String>
+    {
+        match value.to_ascii_lowercase().as_str()
+        {
+            "true" | "on" | "yes" | "1" => Ok(true),
+            "false" | "off" | "no" | "0" | "" => Ok(false),
+            _ => Err(format!("expected a boolean, got `{value}`")),
+        }
+    }
+}
+
+/// Implements `FromFormValue` through `FromStr`
+macro_rules! from_str_value
+{
+    ($($t:ty),*) =>
+    {
+        $(
+            impl FromFormValue for $t
+            {
+                fn from_form_value(value: &str) -> Result<Self, String>
+                {
+                    <$t>::from_str(value.trim()).map_err(|e| format!("{e} (got `{value}`)"))
+                }
+            }
+        )*
+    };
+}
+
+from_str_value!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64, char);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Search
+    {
+        q: String,
+        page: u32,
+        tags: Vec<String>,
+        exact: bool,
+    }
+
+    impl FromForm for Search
+    {
+        fn from_form(form: &Form) -> Result<Self, FormError>
+        {
+            Ok(Self
+            {
+                q: form.required("q")?,
+                page: form.optional("page")?.unwrap_or(1),
+                tags: form.all("tag")?,
+                exact: form.optional("exact")?.unwrap_or(false),
+            })
+        }
+    }
+
+    #[test]
+    fn test_parse()
+    {
+        let form = Form::parse("q=caf%C3%A9+au+lait&tag=a&tag=b%26c&empty&=x&plus=%2B").unwrap();
+        assert_eq!(form.get("q"), Some("café au lait"));
+        assert_eq!(form.get_all("tag"), ["a", "b&c"]);
+        assert_eq!(form.get("empty"), Some(""));
+        assert_eq!(form.get(""), Some("x"));
+        assert_eq!(form.get("plus"), Some("+"));
+        assert_eq!(Form::parse("a=%zz").unwrap_err(), FormError::Malformed("invalid percent escape `%zz`".to_string()));
+        assert!(Form::parse("a=%4").is_err());
+        assert!(Form::parse("a=%ff").is_err());
+        assert_eq!(percent_decode("a+b", false).unwrap(), "a+b");
+        assert_eq!(percent_encode("a b/é"), "a%20b%2F%C3%A9");
+    }
+
+    #[test]
+    fn test_extract()
+    {
+        let search: Search = Form::parse("q=rust&tag=x&tag=y&exact=on").unwrap().extract().unwrap();
+        assert_eq!(search, Search { q: "rust".to_string(), page: 1, tags: vec!["x".to_string(), "y".to_string()], exact: true });
+        let missing = Form::parse("page=2").unwrap().extract::<Search>().unwrap_err();
+        assert_eq!(missing.to_string(), "missing field `q`");
+        let invalid = Form::parse("q=a&page=two").unwrap().extract::<Search>().unwrap_err();
+        assert_eq!(invalid.to_string(), "invalid value for field `page`: invalid digit found in string (got `two`)");
+    }
+}
@@ -0,0 +1,1121 @@
+pub mod frame;
+pub mod hpack;
+mod huffman;
+
+use std::{collections::{BTreeMap, VecDeque}, io::{self, Error, ErrorKind, Read}, sync::{Arc, Condvar, Mutex}};
+
+use frame::{ErrorCode, Frame, FrameType, ACK, DEFAULT_MAX_FRAME_SIZE, END_HEADERS, END_STREAM, PRIORITY, SETTINGS_ENABLE_PUSH, SETTINGS_INITIAL_WINDOW_SIZE, SETTINGS_MAX_CONCURRENT_STREAMS, SETTINGS_MAX_FRAME_SIZE, SETTINGS_MAX_HEADER_LIST_SIZE};
+use hpack::{Decoder, Encoder};
+
+use super::{body::BodyStream, server::KEEP_ALIVE_TIMEOUT, HttpRequest, HttpResponse, MAX_HEAD_SIZE};
+
+/// The client connection preface, RFC 9113 section 3.4
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+/// Streams a client may have open at once
+pub const MAX_CONCURRENT_STREAMS: u32 = 100;
+/// The initial flow control window of connections and streams
+const DEFAULT_WINDOW: i64 = 65_535;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+/// Request body bytes a stream may have waiting for its handler
+const STREAM_WINDOW: i64 = 256 * 1024;
+/// Request body bytes the streams of a connection may have waiting for
+/// their handlers together
+const CONNECTION_WINDOW: i64 = 4 * 1024 * 1024;
+/// Bytes of a streamed response body read at once
+const STREAM_CHUNK: usize = 64 * 1024;
+/// Output held before response bodies wait for it to be written
+const OUTPUT_LIMIT: usize = 256 * 1024;
+/// Headers that only have a meaning on an HTTP/1.1 connection
+const CONNECTION_HEADERS: [&str; 5] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+/// Called from the threads reading request bodies, so whoever drives the
+/// connection can give their window back with `Connection::release`
+pub type Wake = Arc<dyn Fn() + Send + Sync>;
+
+/// An error that ends the connection with a GOAWAY
+#[derive(Debug)]
+struct ConnectionError(ErrorCode, &'static str);
+
+struct Stream
+{
+    /// Where the request body waits for the handler
+    body: Arc<Pipe>,
+    /// The client sent END_STREAM
+    recv_closed: bool,
+    /// What the client may still send on the stream
+    recv_window: i64,
+    /// Body bytes the handler read whose window was not given back yet
+    unreturned: i64,
+    send_window: i64,
+    /// The handler has not answered yet
+    handling: bool,
+    /// Response body waiting for flow control window
+    pending: Option<Pending>,
+}
+
+/// The part of a response body read so far, how much of it was sent, and
+/// the stream the rest is read from
+struct Pending
+{
+    data: Vec<u8>,
+    sent: usize,
+    stream: Option<BodyStream>,
+}
+
+/// A request body on its way from the connection to the handler
+struct Pipe
+{
+    state: Mutex<PipeState>,
+    ready: Condvar,
+    wake: Wake,
+}
+
+#[derive(Default)]
+struct PipeState
+{
+    data: VecDeque<u8>,
+    /// The client ended the stream
+    ended: bool,
+    /// The stream was reset or the connection closed
+    reset: bool,
+    /// Bytes read since the connection last counted them
+    consumed: usize,
+}
+
+/// The body of a request on an HTTP/2 stream, read as the client sends it.
+///
+/// Reads fail with `ErrorKind::ConnectionReset` once the stream is reset or
+/// the connection closes, and with `ErrorKind::TimedOut` when the client
+/// sends nothing for as long as an idle connection is kept.
+pub struct RequestBody(Arc<Pipe>);
+
+/// The server side of an HTTP/2 connection.
+///
+/// The connection does no I/O of its own: bytes read from the client go
+/// to `receive`, which hands back the requests to serve, and `output` gives
+/// the bytes to write. Streams are answered through `respond` in any order,
+/// so a slow handler does not hold up the others.
+pub struct Connection
+{
+    /// Bytes of a frame that did not arrive whole yet
+    input: Vec<u8>,
+    /// The client connection preface was received
+    preface: bool,
+    decoder: Decoder,
+    encoder: Encoder,
+    streams: BTreeMap<u32, Stream>,
+    last_stream_id: u32,
+    /// The peer's SETTINGS_MAX_FRAME_SIZE
+    max_frame_size: usize,
+    /// The peer's SETTINGS_INITIAL_WINDOW_SIZE
+    initial_window: i64,
+    /// What the connection flow control window lets us send
+    send_window: i64,
+    /// What the client may still send on the connection
+    recv_window: i64,
+    /// Body bytes read or dropped whose window was not given back yet
+    unreturned: i64,
+    /// A header block awaiting CONTINUATION frames: stream, fragment and END_STREAM
+    continuation: Option<(u32, Vec<u8>, bool)>,
+    /// The peer sent GOAWAY
+    going_away: bool,
+    /// We sent GOAWAY, the connection ends once the output is written
+    closed: bool,
+    /// Requests whose header block is complete, for `receive` to hand out
+    opened: Vec<(u32, HttpRequest, RequestBody)>,
+    wake: Wake,
+    out: Vec<u8>,
+}
+
+/// Decodes the base64url `HTTP2-Settings` header of an upgrade request
+pub fn decode_settings_header(value: &str) -> Option<Vec<(u16, u32)>>
+{
+    let mut bits: u32 = 0;
+    let mut nbits = 0;
+    let mut payload = Vec::new();
+    for c in value.trim().trim_end_matches('=').bytes()
+    {
+        let sextet = match c
+        {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'-' | b'+' => 62,
+            b'_' | b'/' => 63,
+            _ => return None,
+        };
+        bits = (bits << 6) | sextet as u32;
+        nbits += 6;
+        if nbits >= 8
+        {
+            nbits -= 8;
+            payload.push((bits >> nbits) as u8);
+        }
+    }
+    Frame::parse_settings(&payload).ok()
+}
+
+/// Checks whether a request asks to upgrade to cleartext HTTP/2
+pub fn is_upgrade(request: &HttpRequest) -> bool
+{
+    let content = request.content();
+    let upgrade = content.header("Upgrade").unwrap_or("");
+    content.http_version == "HTTP/1.1" &&
+    upgrade.split(',').any(|p| p.trim().eq_ignore_ascii_case("h2c")) &&
+    content.header("HTTP2-Settings").is_some()
+}
+
+impl Pipe
+{
+    fn new(wake: Wake, ended: bool) -> Arc<Self>
+    {
+        let state = PipeState
+        {
+            ended: ended,
+            ..Default::default()
+        };
+        Arc::new(Self
+        {
+            state: Mutex::new(state),
+            ready: Condvar::new(),
+            wake: wake,
+        })
+    }
+
+    fn push(&self, data: &[u8], end: bool)
+    {
+        let mut state = self.state.lock().unwrap();
+        state.data.extend(data);
+        state.ended |= end;
+        self.ready.notify_all();
+    }
+
+    /// Takes the count of bytes read since the last call
+    fn take_consumed(&self) -> usize
+    {
+        std::mem::take(&mut self.state.lock().unwrap().consumed)
+    }
+
+    /// Drops the rest of the body, returning the bytes whose window the
+    /// connection has not counted yet
+    fn reset(&self) -> usize
+    {
+        let mut state = self.state.lock().unwrap();
+        state.reset = true;
+        let dropped = state.data.len() + state.consumed;
+        state.data.clear();
+        state.consumed = 0;
+        self.ready.notify_all();
+        dropped
+    }
+}
+
+impl Read for RequestBody
+{
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize>
+    {
+        let pipe = &self.0;
+        let mut state = pipe.state.lock().unwrap();
+        loop
+        {
+            if state.reset
+            {
+                return Err(Error::new(ErrorKind::ConnectionReset, "The stream was reset"));
+            }
+            if !state.data.is_empty() || state.ended || out.is_empty()
+            {
+                let n = state.data.read(out)?;
+                // One wake covers every read until the connection counts them
+                let wake = state.consumed == 0 && n > 0;
+                state.consumed += n;
+                drop(state);
+                if wake
+                {
+                    (pipe.wake)();
+                }
+                return Ok(n);
+            }
+            let (next, waited) = pipe.ready.wait_timeout(state, KEEP_ALIVE_TIMEOUT).unwrap();
+            state = next;
+            if waited.timed_out() && state.data.is_empty() && !state.ended && !state.reset
+            {
+                return Err(Error::new(ErrorKind::TimedOut, "The client stopped sending the body"));
+            }
+        }
+    }
+}
+
+impl Connection
+{
+    /// Creates a connection calling `wake` when handlers read request bodies.
+    ///
+    /// With `upgrade`, the settings from the `HTTP2-Settings` header of an
+    /// HTTP/1.1 request asking for `Upgrade: h2c`. That request becomes
+    /// stream 1 and is answered through `respond`, after the
+    /// `101 Switching Protocols` response is written.
+    pub fn new(wake: Wake, upgrade: Option<&[(u16, u32)]>) -> Self
+    {
+        let mut connection = Self
+        {
+            input: Vec::new(),
+            preface: false,
+            decoder: Decoder::new().with_max_list_size(MAX_HEAD_SIZE),
+            encoder: Encoder,
+            streams: BTreeMap::new(),
+            last_stream_id: 0,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            initial_window: DEFAULT_WINDOW,
+            send_window: DEFAULT_WINDOW,
+            recv_window: CONNECTION_WINDOW,
+            unreturned: 0,
+            continuation: None,
+            going_away: false,
+            closed: false,
+            opened: Vec::new(),
+            wake: wake,
+            out: Vec::new(),
+        };
+        connection.send(Frame::settings(&[
+            (SETTINGS_MAX_CONCURRENT_STREAMS, MAX_CONCURRENT_STREAMS),
+            (SETTINGS_MAX_HEADER_LIST_SIZE, MAX_HEAD_SIZE as u32),
+            (SETTINGS_INITIAL_WINDOW_SIZE, STREAM_WINDOW as u32),
+        ]));
+        connection.send(Frame::window_update(0, (CONNECTION_WINDOW - DEFAULT_WINDOW) as u32));
+        if let Some(settings) = upgrade
+        {
+            if let Err(e) = connection.apply_settings(settings)
+            {
+                connection.fail(e);
+                return connection;
+            }
+            connection.last_stream_id = 1;
+            connection.open_stream(1, true);
+        }
+        connection
+    }
+
+    /// Takes bytes read from the client, returning the requests whose
+    /// header block is complete along with the body each of them reads
+    pub fn receive(&mut self, bytes: &[u8]) -> Vec<(u32, HttpRequest, RequestBody)>
+    {
+        if self.closed
+        {
+            return Vec::new();
+        }
+        self.input.extend_from_slice(bytes);
+        let mut pos = 0;
+        if !self.preface
+        {
+            let n = self.input.len().min(PREFACE.len());
+            if self.input[..n] != PREFACE[..n]
+            {
+                self.fail(ConnectionError(ErrorCode::ProtocolError, "Invalid connection preface"));
+                return Vec::new();
+            }
+            if n < PREFACE.len()
+            {
+                return Vec::new();
+            }
+            self.preface = true;
+            pos = n;
+        }
+        while !self.closed
+        {
+            match Frame::parse(&self.input[pos..], DEFAULT_MAX_FRAME_SIZE)
+            {
+                Ok(Some((frame, len))) =>
+                {
+                    pos += len;
+                    if let Err(e) = self.handle(frame)
+                    {
+                        self.fail(e);
+                    }
+                },
+                Ok(None) => break,
+                Err(_) => self.fail(ConnectionError(ErrorCode::FrameSizeError, "Frame too large")),
+            }
+        }
+        self.input.drain(..pos);
+        std::mem::take(&mut self.opened)
+    }
+
+    /// Sends the response to a stream, queueing the body behind flow
+    /// control. Streams the client reset are not answered.
+    pub fn respond(&mut self, id: u32, response: HttpResponse)
+    {
+        if self.closed
+        {
+            return;
+        }
+        match self.streams.get_mut(&id)
+        {
+            Some(stream) if stream.handling => stream.handling = false,
+            _ => return,
+        }
+        let status = response.status.to_string();
+        let mut lowered: Vec<(String, String)> = response.headers.iter()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .filter(|(name, _)| !CONNECTION_HEADERS.contains(&name.as_str()))
+        .collect();
+        if !lowered.iter().any(|(name, _)| name == "content-length")
+        {
+            lowered.push(("content-length".to_string(), response.body_len().to_string()));
+        }
+        let fields = std::iter::once((":status", status.as_str()))
+        .chain(lowered.iter().map(|(n, v)| (n.as_str(), v.as_str())));
+        let block = self.encoder.encode(fields);
+        // Split the block into HEADERS and CONTINUATION frames
+        let mut chunks = block.chunks(self.max_frame_size).peekable();
+        let mut kind = FrameType::Headers;
+        let end_stream = match response.body_len() == 0
+        {
+            true => END_STREAM,
+            false => 0,
+        };
+        let mut first = true;
+        loop
+        {
+            let chunk = chunks.next().unwrap_or(&[]);
+            let mut flags = if first { end_stream } else { 0 };
+            if chunks.peek().is_none()
+            {
+                flags |= END_HEADERS;
+            }
+            self.send(Frame::new(kind, flags, id, chunk.to_vec()));
+            kind = FrameType::Continuation;
+            first = false;
+            if flags & END_HEADERS != 0
+            {
+                break;
+            }
+        }
+        if response.body_len() == 0
+        {
+            self.close_stream(id);
+            return;
+        }
+        if let Some(stream) = self.streams.get_mut(&id)
+        {
+            stream.pending = Some(Pending
+            {
+                data: response.body,
+                sent: 0,
+                stream: response.stream,
+            });
+        }
+    }
+
+    /// Gives back the flow control window of the request body bytes that
+    /// handlers read since the last call
+    pub fn release(&mut self)
+    {
+        let mut consumed = 0;
+        for (id, stream) in self.streams.iter_mut()
+        {
+            let read = stream.body.take_consumed() as i64;
+            consumed += read;
+            stream.unreturned += read;
+            // Half a window at a time keeps the client sending without a frame per read
+            if !stream.recv_closed && stream.unreturned >= STREAM_WINDOW / 2
+            {
+                Frame::window_update(*id, stream.unreturned as u32).encode(&mut self.out);
+                stream.recv_window += stream.unreturned;
+                stream.unreturned = 0;
+            }
+        }
+        self.return_window(consumed);
+    }
+
+    /// Gets the bytes to write to the client, reading more of the response
+    /// bodies as flow control allows
+    pub fn output(&mut self) -> Vec<u8>
+    {
+        if !self.closed
+        {
+            self.send_pending();
+            // After a GOAWAY, close once the responses in flight are out
+            if self.going_away && self.streams.is_empty()
+            {
+                self.go_away();
+            }
+        }
+        std::mem::take(&mut self.out)
+    }
+
+    /// Sends GOAWAY, taking no more streams
+    pub fn go_away(&mut self)
+    {
+        if !self.closed
+        {
+            self.send(Frame::goaway(self.last_stream_id, ErrorCode::NoError));
+            self.closed = true;
+        }
+    }
+
+    /// Checks whether the connection ends once its output is written
+    pub fn is_closed(&self) -> bool
+    {
+        self.closed
+    }
+
+    /// Checks whether a handler is still working on a stream
+    pub fn is_busy(&self) -> bool
+    {
+        self.streams.values().any(|stream| stream.handling)
+    }
+
+    /// Ends the connection after a protocol violation
+    fn fail(&mut self, e: ConnectionError)
+    {
+        println!("HTTP/2 connection error {:?}: {}", e.0, e.1);
+        self.send(Frame::goaway(self.last_stream_id, e.0));
+        self.closed = true;
+    }
+
+    fn send(&mut self, frame: Frame)
+    {
+        frame.encode(&mut self.out);
+    }
+
+    fn open_stream(&mut self, id: u32, recv_closed: bool)
+    {
+        self.streams.insert(id, Stream
+        {
+            body: Pipe::new(Arc::clone(&self.wake), recv_closed),
+            recv_closed: recv_closed,
+            recv_window: STREAM_WINDOW,
+            unreturned: 0,
+            send_window: self.initial_window,
+            handling: true,
+            pending: None,
+        });
+    }
+
+    /// Forgets a stream whose response is complete
+    fn close_stream(&mut self, id: u32)
+    {
+        let Some(stream) = self.streams.remove(&id) else
+        {
+            return;
+        };
+        // The client may stop sending a body the response did without, RFC 9113 section 8.1
+        if !stream.recv_closed
+        {
+            self.send(Frame::rst_stream(id, ErrorCode::NoError));
+        }
+        self.reset_stream(stream);
+    }
+
+    /// Drops what is left of the body of a stream, giving its window back
+    fn reset_stream(&mut self, stream: Stream)
+    {
+        let dropped = stream.body.reset() as i64;
+        self.return_window(dropped);
+    }
+
+    /// Gives back connection window for request body bytes read or dropped
+    fn return_window(&mut self, len: i64)
+    {
+        self.unreturned += len;
+        if self.unreturned >= CONNECTION_WINDOW / 2
+        {
+            self.send(Frame::window_update(0, self.unreturned as u32));
+            self.recv_window += self.unreturned;
+            self.unreturned = 0;
+        }
+    }
+
+    fn handle(&mut self, frame: Frame) -> Result<(), ConnectionError>
+    {
+        // A header block must be continued before anything else
+        if let Some((id, _, _)) = &self.continuation
+        {
+            if frame.kind != FrameType::Continuation || frame.stream_id != *id
+            {
+                return Err(ConnectionError(ErrorCode::ProtocolError, "Expected CONTINUATION"));
+            }
+        }
+        match frame.kind
+        {
+            FrameType::Data => self.on_data(frame),
+            FrameType::Headers => self.on_headers(frame),
+            FrameType::Continuation =>
+            {
+                let Some((id, mut block, end_stream)) = self.continuation.take() else
+                {
+                    return Err(ConnectionError(ErrorCode::ProtocolError, "Unexpected CONTINUATION"));
+                };
+                // Like an HTTP/1.1 head, a header block is never buffered past MAX_HEAD_SIZE
+                if block.len() + frame.payload.len() > MAX_HEAD_SIZE
+                {
+                    return Err(ConnectionError(ErrorCode::EnhanceYourCalm, "Header block too large"));
+                }
+                block.extend_from_slice(&frame.payload);
+                if frame.has(END_HEADERS)
+                {
+                    return self.on_header_block(id, &block, end_stream);
+                }
+                self.continuation = Some((id, block, end_stream));
+                Ok(())
+            },
+            FrameType::Priority =>
+            {
+                if frame.stream_id == 0
+                {
+                    return Err(ConnectionError(ErrorCode::ProtocolError, "PRIORITY on stream 0"));
+                }
+                Ok(())
+            },
+            FrameType::RstStream =>
+            {
+                if frame.stream_id == 0
+                {
+                    return Err(ConnectionError(ErrorCode::ProtocolError, "RST_STREAM on stream 0"));
+                }
+                if frame.payload.len() != 4
+                {
+                    return Err(ConnectionError(ErrorCode::FrameSizeError, "Invalid RST_STREAM"));
+                }
+                if let Some(stream) = self.streams.remove(&frame.stream_id)
+                {
+                    self.reset_stream(stream);
+                }
+                Ok(())
+            },
+            FrameType::Settings =>
+            {
+                if frame.stream_id != 0
+                {
+                    return Err(ConnectionError(ErrorCode::ProtocolError, "SETTINGS on a stream"));
+                }
+                if frame.has(ACK)
+                {
+                    if !frame.payload.is_empty()
+                    {
+                        return Err(ConnectionError(ErrorCode::FrameSizeError, "SETTINGS ACK with a payload"));
+                    }
+                    return Ok(());
+                }
+                let settings = Frame::parse_settings(&frame.payload)
+                .map_err(|code| ConnectionError(code, "Invalid SETTINGS"))?;
+                self.apply_settings(&settings)?;
+                self.send(Frame::new(FrameType::Settings, ACK, 0, Vec::new()));
+                Ok(())
+            },
+            FrameType::PushPromise => Err(ConnectionError(ErrorCode::ProtocolError, "Clients cannot push")),
+            FrameType::Ping =>
+            {
+                if frame.stream_id != 0
+                {
+                    return Err(ConnectionError(ErrorCode::ProtocolError, "PING on a stream"));
+                }
+                if frame.payload.len() != 8
+                {
+                    return Err(ConnectionError(ErrorCode::FrameSizeError, "Invalid PING"));
+                }
+                if !frame.has(ACK)
+                {
+                    self.send(Frame::new(FrameType::Ping, ACK, 0, frame.payload));
+                }
+                Ok(())
+            },
+            FrameType::GoAway =>
+            {
+                self.going_away = true;
+                Ok(())
+            },
+            FrameType::WindowUpdate => self.on_window_update(frame),
+            FrameType::Unknown(_) => Ok(()),
+        }
+    }
+
+    fn apply_settings(&mut self, settings: &[(u16, u32)]) -> Result<(), ConnectionError>
+    {
+        for (id, value) in settings
+        {
+            match *id
+            {
+                SETTINGS_ENABLE_PUSH if *value > 1 =>
+                {
+                    return Err(ConnectionError(ErrorCode::ProtocolError, "Invalid SETTINGS_ENABLE_PUSH"));
+                },
+                SETTINGS_INITIAL_WINDOW_SIZE =>
+                {
+                    let value = *value as i64;
+                    if value > MAX_WINDOW
+                    {
+                        return Err(ConnectionError(ErrorCode::FlowControlError, "Invalid SETTINGS_INITIAL_WINDOW_SIZE"));
+                    }
+                    // The change applies to every open stream, RFC 9113 section 6.9.2
+                    let delta = value - self.initial_window;
+                    for stream in self.streams.values_mut()
+                    {
+                        stream.send_window += delta;
+                        if stream.send_window > MAX_WINDOW
+                        {
+                            return Err(ConnectionError(ErrorCode::FlowControlError, "Window overflow"));
+                        }
+                    }
+                    self.initial_window = value;
+                },
+                SETTINGS_MAX_FRAME_SIZE =>
+                {
+                    let value = *value as usize;
+                    if !(DEFAULT_MAX_FRAME_SIZE..=0xff_ffff).contains(&value)
+                    {
+                        return Err(ConnectionError(ErrorCode::ProtocolError, "Invalid SETTINGS_MAX_FRAME_SIZE"));
+                    }
+                    self.max_frame_size = value;
+                },
+                // The encoder never uses the dynamic table and the server
+                // never pushes, so the remaining settings have no effect
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+
+    fn on_data(&mut self, frame: Frame) -> Result<(), ConnectionError>
+    {
+        let id = frame.stream_id;
+        if id == 0
+        {
+            return Err(ConnectionError(ErrorCode::ProtocolError, "DATA on stream 0"));
+        }
+        let data = frame.unpadded()
+        .map_err(|code| ConnectionError(code, "Invalid padding"))?;
+        // The whole frame counts against flow control, padding included
+        let len = frame.payload.len() as i64;
+        if len > self.recv_window
+        {
+            return Err(ConnectionError(ErrorCode::FlowControlError, "Connection window exceeded"));
+        }
+        self.recv_window -= len;
+        let stream = match self.streams.get_mut(&id)
+        {
+            Some(stream) if !stream.recv_closed => stream,
+            _ if id > self.last_stream_id =>
+            {
+                return Err(ConnectionError(ErrorCode::ProtocolError, "DATA on an idle stream"));
+            },
+            _ =>
+            {
+                self.return_window(len);
+                self.send(Frame::rst_stream(id, ErrorCode::StreamClosed));
+                return Ok(());
+            },
+        };
+        if len > stream.recv_window
+        {
+            if let Some(stream) = self.streams.remove(&id)
+            {
+                self.reset_stream(stream);
+            }
+            self.return_window(len);
+            self.send(Frame::rst_stream(id, ErrorCode::FlowControlError));
+            return Ok(());
+        }
+        stream.recv_window -= len;
+        stream.recv_closed = frame.has(END_STREAM);
+        stream.body.push(data, stream.recv_closed);
+        // Padding never reaches the handler, so its window comes back right away
+        let padding = len - data.len() as i64;
+        stream.unreturned += padding;
+        self.return_window(padding);
+        Ok(())
+    }
+
+    fn on_headers(&mut self, frame: Frame) -> Result<(), ConnectionError>
+    {
+        let id = frame.stream_id;
+        if id == 0 || id % 2 == 0
+        {
+            return Err(ConnectionError(ErrorCode::ProtocolError, "HEADERS on an invalid stream"));
+        }
+        let mut block = frame.unpadded()
+        .map_err(|code| ConnectionError(code, "Invalid padding"))?;
+        if frame.has(PRIORITY)
+        {
+            block = block.get(5..)
+            .ok_or(ConnectionError(ErrorCode::FrameSizeError, "Truncated priority"))?;
+        }
+        if frame.has(END_HEADERS)
+        {
+            return self.on_header_block(id, block, frame.has(END_STREAM));
+        }
+        self.continuation = Some((id, block.to_vec(), frame.has(END_STREAM)));
+        Ok(())
+    }
+
+    fn on_header_block(&mut self, id: u32, block: &[u8], end_stream: bool) -> Result<(), ConnectionError>
+    {
+        // Always decode so the dynamic table stays in sync with the peer
+        let fields = self.decoder.decode(block)
+        .map_err(|e| match e.kind()
+        {
+            ErrorKind::OutOfMemory => ConnectionError(ErrorCode::EnhanceYourCalm, "Header list too large"),
+            _ => ConnectionError(ErrorCode::CompressionError, "Invalid header block"),
+        })?;
+        if id <= self.last_stream_id
+        {
+            // Trailers must end the stream, its handler already has the request
+            if !end_stream
+            {
+                return Err(ConnectionError(ErrorCode::ProtocolError, "Unexpected HEADERS"));
+            }
+            match self.streams.get_mut(&id)
+            {
+                Some(stream) if !stream.recv_closed =>
+                {
+                    stream.recv_closed = true;
+                    stream.body.push(&[], true);
+                },
+                Some(_) => return Err(ConnectionError(ErrorCode::ProtocolError, "HEADERS after END_STREAM")),
+                // The stream was answered while the client was still sending
+                None => self.send(Frame::rst_stream(id, ErrorCode::StreamClosed)),
+            }
+            return Ok(());
+        }
+        self.last_stream_id = id;
+        if self.going_away
+        {
+            return Ok(());
+        }
+        if self.streams.len() >= MAX_CONCURRENT_STREAMS as usize
+        {
+            self.send(Frame::rst_stream(id, ErrorCode::RefusedStream));
+            return Ok(());
+        }
+        let Some(request) = to_request(fields) else
+        {
+            self.send(Frame::rst_stream(id, ErrorCode::ProtocolError));
+            return Ok(());
+        };
+        self.open_stream(id, end_stream);
+        let body = Arc::clone(&self.streams[&id].body);
+        self.opened.push((id, request, RequestBody(body)));
+        Ok(())
+    }
+
+    fn on_window_update(&mut self, frame: Frame) -> Result<(), ConnectionError>
+    {
+        if frame.payload.len() != 4
+        {
+            return Err(ConnectionError(ErrorCode::FrameSizeError, "Invalid WINDOW_UPDATE"));
+        }
+        let p = &frame.payload;
+        let increment = (u32::from_be_bytes([p[0], p[1], p[2], p[3]]) & 0x7fff_ffff) as i64;
+        if frame.stream_id == 0
+        {
+            if increment == 0
+            {
+                return Err(ConnectionError(ErrorCode::ProtocolError, "Zero WINDOW_UPDATE"));
+            }
+            self.send_window += increment;
+            if self.send_window > MAX_WINDOW
+            {
+                return Err(ConnectionError(ErrorCode::FlowControlError, "Window overflow"));
+            }
+        }
+        else if let Some(stream) = self.streams.get_mut(&frame.stream_id)
+        {
+            stream.send_window += increment;
+            if increment == 0 || stream.send_window > MAX_WINDOW
+            {
+                let code = match increment
+                {
+                    0 => ErrorCode::ProtocolError,
+                    _ => ErrorCode::FlowControlError,
+                };
+                if let Some(stream) = self.streams.remove(&frame.stream_id)
+                {
+                    self.reset_stream(stream);
+                }
+                self.send(Frame::rst_stream(frame.stream_id, code));
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends as much queued response data as the flow control windows and
+    /// the output limit allow
+    fn send_pending(&mut self)
+    {
+        let ids: Vec<u32> = self.streams.iter()
+        .filter(|(_, s)| s.pending.is_some())
+        .map(|(id, _)| *id)
+        .collect();
+        for id in ids
+        {
+            let max_frame_size = self.max_frame_size as i64;
+            let Some(stream) = self.streams.get_mut(&id) else
+            {
+                continue;
+            };
+            let Some(pending) = stream.pending.as_mut() else
+            {
+                continue;
+            };
+            let mut failed = false;
+            while self.send_window > 0 && stream.send_window > 0 && self.out.len() < OUTPUT_LIMIT
+            {
+                if pending.sent == pending.data.len()
+                {
+                    // Read the next part of a streamed body once the last is out
+                    let Some(body) = pending.stream.as_mut().filter(|body| body.remaining() > 0) else
+                    {
+                        break;
+                    };
+                    pending.data.clear();
+                    pending.data.resize(STREAM_CHUNK, 0);
+                    match body.read(&mut pending.data)
+                    {
+                        Ok(n) => pending.data.truncate(n),
+                        Err(e) =>
+                        {
+                            println!("Failed to read response body: {e}");
+                            failed = true;
+                            break;
+                        }
+                    }
+                    pending.sent = 0;
+                }
+                let sent = pending.sent;
+                let len = (pending.data.len() - sent) as i64;
+                let len = len.min(self.send_window).min(stream.send_window).min(max_frame_size) as usize;
+                let streamed = pending.stream.as_ref().map_or(0, |body| body.remaining());
+                let last = sent + len == pending.data.len() && streamed == 0;
+                let flags = if last { END_STREAM } else { 0 };
+                Frame::new(FrameType::Data, flags, id, pending.data[sent..sent + len].to_vec()).encode(&mut self.out);
+                pending.sent += len;
+                self.send_window -= len as i64;
+                stream.send_window -= len as i64;
+            }
+            let done = pending.sent == pending.data.len() && pending.stream.as_ref().is_none_or(|body| body.remaining() == 0);
+            if failed
+            {
+                if let Some(stream) = self.streams.remove(&id)
+                {
+                    self.reset_stream(stream);
+                }
+                self.send(Frame::rst_stream(id, ErrorCode::InternalError));
+            }
+            else if done
+            {
+                self.close_stream(id);
+            }
+        }
+    }
+}
+
+impl Drop for Connection
+{
+    fn drop(&mut self)
+    {
+        // Handlers reading a body must not wait for bytes that never come
+        for stream in self.streams.values()
+        {
+            stream.body.reset();
+        }
+    }
+}
+
+/// Maps the fields of a stream onto an `HttpRequest`
+fn to_request(fields: Vec<(String, String)>) -> Option<HttpRequest>
+{
+    let mut method = None;
+    let mut path = None;
+    let mut authority = None;
+    let mut headers = Vec::<String>::new();
+    let mut cookies = Vec::<String>::new();
+    for (name, value) in fields
+    {
+        match name.as_str()
+        {
+            ":method" => method = Some(value),
+            ":path" => path = Some(value),
+            ":authority" => authority = Some(value),
+            ":scheme" => {},
+            _ if name.starts_with(':') => return None,
+            _ if name.bytes().any(|b| b.is_ascii_uppercase()) => return None,
+            // Cookies may be split across fields, RFC 9113 section 8.2.3
+            "cookie" => cookies.push(value),
+            _ => headers.push(format!("{name}: {value}")),
+        }
+    }
+    if let Some(authority) = authority
+    {
+        if !headers.iter().any(|h| h.starts_with("host:"))
+        {
+            headers.push(format!("host: {authority}"));
+        }
+    }
+    if !cookies.is_empty()
+    {
+        headers.push(format!("cookie: {}", cookies.join("; ")));
+    }
+    let start_line = format!("{} {} HTTP/2.0", method?, path?);
+    HttpRequest::from_head(&start_line, headers).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use frame::SETTINGS_HEADER_TABLE_SIZE;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A wake counting its calls
+    fn counter() -> (Wake, Arc<AtomicUsize>)
+    {
+        let count = Arc::new(AtomicUsize::new(0));
+        let woken = Arc::clone(&count);
+        (Arc::new(move || { woken.fetch_add(1, Ordering::SeqCst); }), count)
+    }
+
+    fn frames(mut bytes: &[u8]) -> Vec<Frame>
+    {
+        let mut frames = Vec::new();
+        while let Some(frame) = Frame::read(&mut bytes, 1 << 24).unwrap()
+        {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    #[test]
+    fn test_streams()
+    {
+        let mut input = PREFACE.to_vec();
+        Frame::settings(&[(SETTINGS_INITIAL_WINDOW_SIZE, 4)]).encode(&mut input);
+        Frame::new(FrameType::Ping, 0, 0, b"12345678".to_vec()).encode(&mut input);
+        // Two interleaved requests, the first one with a body
+        let post = Encoder.encode([(":method", "POST"), (":scheme", "http"), (":path", "/a"), (":authority", "x")]);
+        Frame::new(FrameType::Headers, END_HEADERS, 1, post).encode(&mut input);
+        let get = Encoder.encode([(":method", "GET"), (":scheme", "http"), (":path", "/b")]);
+        Frame::new(FrameType::Headers, END_HEADERS | END_STREAM, 3, get).encode(&mut input);
+        Frame::new(FrameType::Data, END_STREAM, 1, b"body".to_vec()).encode(&mut input);
+        Frame::window_update(1, 100).encode(&mut input);
+        let (wake, woken) = counter();
+        let mut connection = Connection::new(wake, None);
+        // The input may arrive split anywhere
+        let (first, second) = input.split_at(30);
+        let mut opened = connection.receive(first);
+        opened.extend(connection.receive(second));
+        let mut seen = Vec::new();
+        for (id, request, mut body) in opened
+        {
+            let mut text = String::new();
+            body.read_to_string(&mut text).unwrap();
+            seen.push(format!("{id} {} {} {:?} [{text}]", request.method(), request.content().route(),
+                request.content().header("host")));
+        }
+        assert_eq!(seen, ["1 POST /a Some(\"x\") [body]", "3 GET /b None []"]);
+        assert_eq!(woken.load(Ordering::SeqCst), 1);
+        // Stream 3 is answered first, but its window only fits 4 of 6 bytes
+        connection.respond(3, HttpResponse::new(200).with_body("/b/b/b"));
+        connection.respond(1, HttpResponse::new(200).with_body("/a/a/a"));
+        connection.release();
+        let out = frames(&connection.output());
+        let kinds: Vec<(FrameType, u32, u8)> = out.iter().map(|f| (f.kind, f.stream_id, f.flags)).collect();
+        assert_eq!(kinds, [
+            (FrameType::Settings, 0, 0),
+            (FrameType::WindowUpdate, 0, 0),
+            (FrameType::Settings, 0, ACK),
+            (FrameType::Ping, 0, ACK),
+            (FrameType::Headers, 3, END_HEADERS),
+            (FrameType::Headers, 1, END_HEADERS),
+            (FrameType::Data, 1, END_STREAM),
+            (FrameType::Data, 3, 0),
+        ]);
+        let mut decoder = Decoder::new();
+        let fields = decoder.decode(&out[4].payload).unwrap();
+        assert_eq!(fields[0], (":status".to_string(), "200".to_string()));
+        assert_eq!(out[6].payload, b"/a/a/a");
+        assert_eq!(out[7].payload, b"/b/b");
+        assert!(!connection.is_busy());
+    }
+
+    #[test]
+    fn test_flow_control()
+    {
+        let mut input = PREFACE.to_vec();
+        let head = Encoder.encode([(":method", "POST"), (":scheme", "http"), (":path", "/")]);
+        Frame::new(FrameType::Headers, END_HEADERS, 1, head.clone()).encode(&mut input);
+        Frame::new(FrameType::Headers, END_HEADERS, 3, head).encode(&mut input);
+        for _ in 0..16
+        {
+            Frame::new(FrameType::Data, 0, 1, vec![1; DEFAULT_MAX_FRAME_SIZE]).encode(&mut input);
+            Frame::new(FrameType::Data, 0, 3, vec![3; DEFAULT_MAX_FRAME_SIZE]).encode(&mut input);
+        }
+        // Stream 3 goes one byte past its window
+        Frame::new(FrameType::Data, 0, 3, vec![3]).encode(&mut input);
+        let (wake, woken) = counter();
+        let mut connection = Connection::new(wake, None);
+        let mut opened = connection.receive(&input);
+        let out = frames(&connection.output());
+        // Nothing comes back before the handler reads the body
+        assert_eq!(out.len(), 3);
+        assert_eq!(out[2], Frame::rst_stream(3, ErrorCode::FlowControlError));
+        let (id, _, mut body) = opened.remove(0);
+        assert_eq!(id, 1);
+        let mut bytes = vec![0; STREAM_WINDOW as usize];
+        body.read_exact(&mut bytes).unwrap();
+        assert_eq!(woken.load(Ordering::SeqCst), 1);
+        connection.release();
+        assert_eq!(frames(&connection.output()), [Frame::window_update(1, STREAM_WINDOW as u32)]);
+        // The reset stream can no longer be read
+        let (_, _, mut reset) = opened.remove(0);
+        assert_eq!(reset.read(&mut bytes).unwrap_err().kind(), ErrorKind::ConnectionReset);
+        drop(connection);
+        assert_eq!(body.read(&mut bytes).unwrap_err().kind(), ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn test_continuation_flood()
+    {
+        let mut input = PREFACE.to_vec();
+        let head = Encoder.encode([(":method", "GET"), (":scheme", "http"), (":path", "/")]);
+        Frame::new(FrameType::Headers, 0, 1, head).encode(&mut input);
+        for _ in 0..8
+        {
+            Frame::new(FrameType::Continuation, 0, 1, vec![0; DEFAULT_MAX_FRAME_SIZE]).encode(&mut input);
+        }
+        Frame::new(FrameType::Continuation, END_HEADERS, 1, Vec::new()).encode(&mut input);
+        let (wake, _) = counter();
+        let mut connection = Connection::new(wake, None);
+        assert!(connection.receive(&input).is_empty());
+        assert!(connection.is_closed());
+        let out = frames(&connection.output());
+        assert_eq!(out[0], Frame::settings(&[
+            (SETTINGS_MAX_CONCURRENT_STREAMS, MAX_CONCURRENT_STREAMS),
+            (SETTINGS_MAX_HEADER_LIST_SIZE, MAX_HEAD_SIZE as u32),
+            (SETTINGS_INITIAL_WINDOW_SIZE, STREAM_WINDOW as u32),
+        ]));
+        assert_eq!(out[out.len() - 1], Frame::goaway(0, ErrorCode::EnhanceYourCalm));
+    }
+
+    #[test]
+    fn test_initial_window_overflow()
+    {
+        let mut input = PREFACE.to_vec();
+        let head = Encoder.encode([(":method", "POST"), (":scheme", "http"), (":path", "/")]);
+        Frame::new(FrameType::Headers, END_HEADERS, 1, head).encode(&mut input);
+        // The stream window grows to 2^31 - 1, the new initial window then pushes it past
+        Frame::window_update(1, (MAX_WINDOW - DEFAULT_WINDOW) as u32).encode(&mut input);
+        Frame::settings(&[(SETTINGS_INITIAL_WINDOW_SIZE, DEFAULT_WINDOW as u32 + 1)]).encode(&mut input);
+        let (wake, _) = counter();
+        let mut connection = Connection::new(wake, None);
+        connection.receive(&input);
+        let out = frames(&connection.output());
+        assert_eq!(out[out.len() - 1], Frame::goaway(1, ErrorCode::FlowControlError));
+    }
+
+    #[test]
+    fn test_settings_header()
+    {
+        let settings = decode_settings_header("AAEAABAAAAQAAP__").unwrap();
+        assert_eq!(settings, [(SETTINGS_HEADER_TABLE_SIZE, 4096), (SETTINGS_INITIAL_WINDOW_SIZE, 65535)]);
+        assert!(decode_settings_header("AAEAABA").is_none());
+    }
+}
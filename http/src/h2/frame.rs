@@ -0,0 +1,249 @@
+use std::io::{Error, ErrorKind, Read};
+
+/// Length of the fixed frame header
+pub const FRAME_HEADER_LEN: usize = 9;
+/// The default and smallest allowed SETTINGS_MAX_FRAME_SIZE
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16_384;
+
+/// DATA and HEADERS: the last frame the sender will send on the stream
+pub const END_STREAM: u8 = 0x1;
+/// SETTINGS and PING: acknowledges the peer's frame
+pub const ACK: u8 = 0x1;
+/// HEADERS and CONTINUATION: the header block is complete
+pub const END_HEADERS: u8 = 0x4;
+/// DATA and HEADERS: the payload is padded
+pub const PADDED: u8 = 0x8;
+/// HEADERS: the payload starts with priority information
+pub const PRIORITY: u8 = 0x20;
+
+pub const SETTINGS_HEADER_TABLE_SIZE: u16 = 0x1;
+pub const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+pub const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+pub const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+pub const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+pub const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameType
+{
+    Data,
+    Headers,
+    Priority,
+    RstStream,
+    Settings,
+    PushPromise,
+    Ping,
+    GoAway,
+    WindowUpdate,
+    Continuation,
+    /// Frame types without a meaning are ignored
+    Unknown(u8),
+}
+
+/// Error codes carried by RST_STREAM and GOAWAY, RFC 9113 section 7
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode
+{
+    NoError = 0x0,
+    ProtocolError = 0x1,
+    InternalError = 0x2,
+    FlowControlError = 0x3,
+    StreamClosed = 0x5,
+    FrameSizeError = 0x6,
+    RefusedStream = 0x7,
+    Cancel = 0x8,
+    CompressionError = 0x9,
+    EnhanceYourCalm = 0xb,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame
+{
+    pub kind: FrameType,
+    pub flags: u8,
+    pub stream_id: u32,
+    pub payload: Vec<u8>,
+}
+
+impl FrameType
+{
+    fn from_u8(kind: u8) -> Self
+    {
+        match kind
+        {
+            0x0 => FrameType::Data,
+            0x1 => FrameType::Headers,
+            0x2 => FrameType::Priority,
+            0x3 => FrameType::RstStream,
+            0x4 => FrameType::Settings,
+            0x5 => FrameType::PushPromise,
+            0x6 => FrameType::Ping,
+            0x7 => FrameType::GoAway,
+            0x8 => FrameType::WindowUpdate,
+            0x9 => FrameType::Continuation,
+            other => FrameType::Unknown(other),
+        }
+    }
+
+    fn to_u8(self) -> u8
+    {
+        match self
+        {
+            FrameType::Data => 0x0,
+            FrameType::Headers => 0x1,
+            FrameType::Priority => 0x2,
+            FrameType::RstStream => 0x3,
+            FrameType::Settings => 0x4,
+            FrameType::PushPromise => 0x5,
+            FrameType::Ping => 0x6,
+            FrameType::GoAway => 0x7,
+            FrameType::WindowUpdate => 0x8,
+            FrameType::Continuation => 0x9,
+            FrameType::Unknown(other) => other,
+        }
+    }
+}
+
+impl Frame
+{
+    pub fn new(kind: FrameType, flags: u8, stream_id: u32, payload: Vec<u8>) -> Self
+    {
+        Self
+        {
+            kind: kind,
+            flags: flags,
+            stream_id: stream_id,
+            payload: payload,
+        }
+    }
+
+    pub fn has(&self, flag: u8) -> bool
+    {
+        self.flags & flag != 0
+    }
+
+    /// Reads the next frame.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Some(Frame))` - The frame
+    /// * `Ok(None)` - If the peer closed the connection between frames
+    /// * `Err(Error)` - With `ErrorKind::InvalidData` if the frame is larger than `max_size`
+    pub fn read<R: Read>(reader: &mut R, max_size: usize) -> Result<Option<Self>, Error>
+    {
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        // A clean close can only happen before the first header byte
+        let mut filled = 0;
+        while filled < FRAME_HEADER_LEN
+        {
+            match reader.read(&mut header[filled..])
+            {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => return Err(Error::new(ErrorKind::UnexpectedEof, "Truncated frame header")),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        if len > max_size
+        {
+            return Err(Error::new(ErrorKind::InvalidData, "Frame larger than SETTINGS_MAX_FRAME_SIZE"));
+        }
+        let stream_id = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff;
+        let mut payload = vec![0u8; len];
+        reader.read_exact(&mut payload)?;
+        Ok(Some(Self::new(FrameType::from_u8(header[3]), header[4], stream_id, payload)))
+    }
+
+    /// Parses the frame at the start of `buf`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Some((Frame, usize)))` - The frame and how many bytes it took
+    /// * `Ok(None)` - If `buf` does not hold the whole frame yet
+    /// * `Err(Error)` - With `ErrorKind::InvalidData` if the frame is larger than `max_size`
+    pub fn parse(buf: &[u8], max_size: usize) -> Result<Option<(Self, usize)>, Error>
+    {
+        if buf.len() < FRAME_HEADER_LEN
+        {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([0, buf[0], buf[1], buf[2]]) as usize;
+        if len > max_size
+        {
+            return Err(Error::new(ErrorKind::InvalidData, "Frame larger than SETTINGS_MAX_FRAME_SIZE"));
+        }
+        let Some(mut bytes) = buf.get(..FRAME_HEADER_LEN + len) else
+        {
+            return Ok(None);
+        };
+        Ok(Self::read(&mut bytes, max_size)?.map(|frame| (frame, FRAME_HEADER_LEN + len)))
+    }
+
+    /// Appends the wire form of the frame to `out`
+    pub fn encode(&self, out: &mut Vec<u8>)
+    {
+        let len = (self.payload.len() as u32).to_be_bytes();
+        out.extend_from_slice(&len[1..]);
+        out.push(self.kind.to_u8());
+        out.push(self.flags);
+        out.extend_from_slice(&(self.stream_id & 0x7fff_ffff).to_be_bytes());
+        out.extend_from_slice(&self.payload);
+    }
+
+    /// Removes the padding of a DATA or HEADERS payload
+    pub fn unpadded(&self) -> Result<&[u8], ErrorCode>
+    {
+        if !self.has(PADDED)
+        {
+            return Ok(&self.payload);
+        }
+        let pad = *self.payload.first().ok_or(ErrorCode::FrameSizeError)? as usize;
+        if pad + 1 > self.payload.len()
+        {
+            return Err(ErrorCode::ProtocolError);
+        }
+        Ok(&self.payload[1..self.payload.len() - pad])
+    }
+
+    pub fn settings(settings: &[(u16, u32)]) -> Self
+    {
+        let mut payload = Vec::with_capacity(settings.len() * 6);
+        for (id, value) in settings
+        {
+            payload.extend_from_slice(&id.to_be_bytes());
+            payload.extend_from_slice(&value.to_be_bytes());
+        }
+        Self::new(FrameType::Settings, 0, 0, payload)
+    }
+
+    /// Parses the parameters of a SETTINGS payload
+    pub fn parse_settings(payload: &[u8]) -> Result<Vec<(u16, u32)>, ErrorCode>
+    {
+        if payload.len() % 6 != 0
+        {
+            return Err(ErrorCode::FrameSizeError);
+        }
+        Ok(payload.chunks(6)
+        .map(|c| (u16::from_be_bytes([c[0], c[1]]), u32::from_be_bytes([c[2], c[3], c[4], c[5]])))
+        .collect())
+    }
+
+    pub fn window_update(stream_id: u32, increment: u32) -> Self
+    {
+        Self::new(FrameType::WindowUpdate, 0, stream_id, increment.to_be_bytes().to_vec())
+    }
+
+    pub fn rst_stream(stream_id: u32, code: ErrorCode) -> Self
+    {
+        Self::new(FrameType::RstStream, 0, stream_id, (code as u32).to_be_bytes().to_vec())
+    }
+
+    pub fn goaway(last_stream_id: u32, code: ErrorCode) -> Self
+    {
+        let mut payload = last_stream_id.to_be_bytes().to_vec();
+        payload.extend_from_slice(&(code as u32).to_be_bytes());
+        Self::new(FrameType::GoAway, 0, 0, payload)
+    }
+}
@@ -0,0 +1,424 @@
+use std::{collections::VecDeque, io::{Error, ErrorKind}};
+
+use super::huffman;
+
+/// The HPACK static table from RFC 7541 Appendix A, index 1 first
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// Per entry overhead counted in the table size, RFC 7541 section 4.1
+const ENTRY_OVERHEAD: usize = 32;
+
+/// The default and initial dynamic table size
+pub const DEFAULT_TABLE_SIZE: usize = 4096;
+
+/// The dynamic table, newest entry first
+#[derive(Debug)]
+struct DynamicTable
+{
+    entries: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize,
+}
+
+/// Decodes header blocks received from the peer
+#[derive(Debug)]
+pub struct Decoder
+{
+    table: DynamicTable,
+    /// The largest table size the peer may ask for, from our SETTINGS
+    limit: usize,
+    /// The largest decoded header list, counted as the table counts entries
+    max_list_size: usize,
+}
+
+/// Encodes header blocks sent to the peer.
+///
+/// Fields are never added to the dynamic table, so the peer's table size
+/// setting never has to be tracked.
+#[derive(Debug, Default)]
+pub struct Encoder;
+
+fn invalid(msg: &str) -> Error
+{
+    Error::new(ErrorKind::InvalidData, format!("HPACK: {msg}"))
+}
+
+impl DynamicTable
+{
+    fn new(max_size: usize) -> Self
+    {
+        Self
+        {
+            entries: VecDeque::new(),
+            size: 0,
+            max_size: max_size,
+        }
+    }
+
+    fn evict(&mut self)
+    {
+        while self.size > self.max_size
+        {
+            match self.entries.pop_back()
+            {
+                Some((name, value)) => self.size -= name.len() + value.len() + ENTRY_OVERHEAD,
+                None => break,
+            }
+        }
+    }
+
+    fn insert(&mut self, name: String, value: String)
+    {
+        self.size += name.len() + value.len() + ENTRY_OVERHEAD;
+        self.entries.push_front((name, value));
+        // An entry larger than the table empties it
+        self.evict();
+    }
+
+    fn resize(&mut self, max_size: usize)
+    {
+        self.max_size = max_size;
+        self.evict();
+    }
+}
+
+/// Decodes an integer with an `n` bit prefix, RFC 7541 section 5.1
+fn decode_int(buf: &[u8], pos: &mut usize, n: u8) -> Result<usize, Error>
+{
+    let mask = (1usize << n) - 1;
+    let first = *buf.get(*pos).ok_or(invalid("truncated integer"))? as usize & mask;
+    *pos += 1;
+    if first < mask
+    {
+        return Ok(first);
+    }
+    let mut value = mask;
+    let mut shift = 0;
+    loop
+    {
+        let byte = *buf.get(*pos).ok_or(invalid("truncated integer"))?;
+        *pos += 1;
+        if shift > 28
+        {
+            return Err(invalid("integer overflow"));
+        }
+        value += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0
+        {
+            return Ok(value);
+        }
+    }
+}
+
+/// Encodes an integer with an `n` bit prefix, OR-ing `flags` into the first octet
+fn encode_int(out: &mut Vec<u8>, value: usize, n: u8, flags: u8)
+{
+    let mask = (1usize << n) - 1;
+    if value < mask
+    {
+        out.push(flags | value as u8);
+        return;
+    }
+    out.push(flags | mask as u8);
+    let mut rest = value - mask;
+    while rest >= 0x80
+    {
+        out.push((rest & 0x7f) as u8 | 0x80);
+        rest >>= 7;
+    }
+    out.push(rest as u8);
+}
+
+/// Decodes a string literal, RFC 7541 section 5.2
+fn decode_string(buf: &[u8], pos: &mut usize) -> Result<String, Error>
+{
+    let huffman = *buf.get(*pos).ok_or(invalid("truncated string"))? & 0x80 != 0;
+    let len = decode_int(buf, pos, 7)?;
+    let raw = buf.get(*pos..*pos + len).ok_or(invalid("truncated string"))?;
+    *pos += len;
+    let bytes = match huffman
+    {
+        true => huffman::decode(raw).ok_or(invalid("invalid Huffman string"))?,
+        false => raw.to_vec(),
+    };
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Encodes a string literal, Huffman coding it when that is shorter
+fn encode_string(out: &mut Vec<u8>, value: &str)
+{
+    let bytes = value.as_bytes();
+    let huffman_len = huffman::encoded_len(bytes);
+    if huffman_len < bytes.len()
+    {
+        encode_int(out, huffman_len, 7, 0x80);
+        out.extend_from_slice(&huffman::encode(bytes));
+    }
+    else
+    {
+        encode_int(out, bytes.len(), 7, 0);
+        out.extend_from_slice(bytes);
+    }
+}
+
+impl Decoder
+{
+    pub fn new() -> Self
+    {
+        Self
+        {
+            table: DynamicTable::new(DEFAULT_TABLE_SIZE),
+            limit: DEFAULT_TABLE_SIZE,
+            max_list_size: usize::MAX,
+        }
+    }
+
+    /// Fails blocks decoding to a larger header list with `ErrorKind::OutOfMemory`,
+    /// since a few bytes can repeat a large dynamic table entry many times
+    pub fn with_max_list_size(mut self, size: usize) -> Self
+    {
+        self.max_list_size = size;
+        self
+    }
+
+    /// Looks up an index in the static then dynamic table
+    fn get(&self, index: usize) -> Result<(String, String), Error>
+    {
+        if index == 0
+        {
+            return Err(invalid("index 0"));
+        }
+        if index <= STATIC_TABLE.len()
+        {
+            let (name, value) = STATIC_TABLE[index - 1];
+            return Ok((name.to_string(), value.to_string()));
+        }
+        self.table.entries.get(index - STATIC_TABLE.len() - 1)
+        .cloned()
+        .ok_or(invalid("index out of range"))
+    }
+
+    /// Decodes a complete header block into its fields in order
+    pub fn decode(&mut self, block: &[u8]) -> Result<Vec<(String, String)>, Error>
+    {
+        let mut fields = Vec::new();
+        let mut list_size = 0;
+        let mut pos = 0;
+        // Size updates are only allowed before the first field
+        let mut first = true;
+        while pos < block.len()
+        {
+            let byte = block[pos];
+            if byte & 0x80 != 0
+            {
+                // Indexed field
+                let index = decode_int(block, &mut pos, 7)?;
+                fields.push(self.get(index)?);
+            }
+            else if byte & 0x40 != 0
+            {
+                // Literal with incremental indexing
+                let (name, value) = self.literal(block, &mut pos, 6)?;
+                self.table.insert(name.clone(), value.clone());
+                fields.push((name, value));
+            }
+            else if byte & 0x20 != 0
+            {
+                // Dynamic table size update
+                if !first
+                {
+                    return Err(invalid("table size update after a field"));
+                }
+                let size = decode_int(block, &mut pos, 5)?;
+                if size > self.limit
+                {
+                    return Err(invalid("table size update above the limit"));
+                }
+                self.table.resize(size);
+                continue;
+            }
+            else
+            {
+                // Literal without indexing or never indexed
+                fields.push(self.literal(block, &mut pos, 4)?);
+            }
+            let (name, value) = &fields[fields.len() - 1];
+            list_size += name.len() + value.len() + ENTRY_OVERHEAD;
+            if list_size > self.max_list_size
+            {
+                return Err(Error::new(ErrorKind::OutOfMemory, "HPACK: header list too large"));
+            }
+            first = false;
+        }
+        Ok(fields)
+    }
+
+    /// Decodes a literal field whose name index has an `n` bit prefix
+    fn literal(&self, block: &[u8], pos: &mut usize, n: u8) -> Result<(String, String), Error>
+    {
+        let index = decode_int(block, pos, n)?;
+        let name = match index
+        {
+            0 => decode_string(block, pos)?,
+            _ => self.get(index)?.0,
+        };
+        let value = decode_string(block, pos)?;
+        Ok((name, value))
+    }
+}
+
+impl Default for Decoder
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl Encoder
+{
+    /// Encodes fields into a header block. Names must already be lowercase.
+    pub fn encode<'f>(&self, fields: impl IntoIterator<Item = (&'f str, &'f str)>) -> Vec<u8>
+    {
+        let mut out = Vec::new();
+        for (name, value) in fields
+        {
+            let exact = STATIC_TABLE.iter().position(|(n, v)| *n == name && *v == value);
+            if let Some(idx) = exact
+            {
+                encode_int(&mut out, idx + 1, 7, 0x80);
+                continue;
+            }
+            // Literal without indexing, with an indexed name when possible
+            match STATIC_TABLE.iter().position(|(n, _)| *n == name)
+            {
+                Some(idx) => encode_int(&mut out, idx + 1, 4, 0),
+                None =>
+                {
+                    out.push(0);
+                    encode_string(&mut out, name);
+                }
+            }
+            encode_string(&mut out, value);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8>
+    {
+        let s: String = s.split_whitespace().collect();
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn pairs(fields: &[(String, String)]) -> Vec<(&str, &str)>
+    {
+        fields.iter().map(|(n, v)| (n.as_str(), v.as_str())).collect()
+    }
+
+    #[test]
+    fn test_decode_rfc_requests()
+    {
+        // RFC 7541 C.4, requests with Huffman coding sharing a dynamic table
+        let mut decoder = Decoder::new();
+        let first = decoder.decode(&hex("8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff")).unwrap();
+        assert_eq!(pairs(&first), [(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com")]);
+        let second = decoder.decode(&hex("8286 84be 5886 a8eb 1064 9cbf")).unwrap();
+        assert_eq!(pairs(&second), [(":method", "GET"), (":scheme", "http"), (":path", "/"),
+            (":authority", "www.example.com"), ("cache-control", "no-cache")]);
+        let third = decoder.decode(&hex("8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf")).unwrap();
+        assert_eq!(pairs(&third), [(":method", "GET"), (":scheme", "https"), (":path", "/index.html"),
+            (":authority", "www.example.com"), ("custom-key", "custom-value")]);
+        assert_eq!(decoder.table.size, 164);
+    }
+
+    #[test]
+    fn test_round_trip()
+    {
+        let fields = [(":status", "200"), ("content-type", "text/html"), ("x-long", "a much longer value that spans past the prefix")];
+        let block = Encoder.encode(fields);
+        let decoded = Decoder::new().decode(&block).unwrap();
+        assert_eq!(pairs(&decoded), fields);
+        assert!(Decoder::new().decode(&[0x80]).is_err());
+        assert!(Decoder::new().decode(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f]).is_err());
+    }
+
+    #[test]
+    fn test_max_list_size()
+    {
+        // One large indexed entry, then a byte per copy of it
+        let mut block = vec![0x40, 0x01, b'x', 0x7f, 0xa1, 0x1e];
+        block.extend_from_slice(&[b'a'; 4000]);
+        block.extend_from_slice(&[0xbe; 100]);
+        let mut decoder = Decoder::new().with_max_list_size(64 * 1024);
+        assert_eq!(decoder.decode(&block).unwrap_err().kind(), ErrorKind::OutOfMemory);
+        assert_eq!(Decoder::new().decode(&block).unwrap().len(), 101);
+    }
+}
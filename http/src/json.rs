@@ -0,0 +1,822 @@
+use std::{collections::BTreeMap, fmt, io::{Error, ErrorKind}};
+
+use super::{HttpRequest, HttpResponse};
+
+/// A JSON value, RFC 8259
+///
+/// ```
+/// use http::{json::Json, HttpResponse};
+///
+/// let order = Json::parse(r#"{"item": "tea", "count": 2}"#).unwrap();
+/// let reply: Json = [("item", order.get("item").cloned().unwrap_or_default()), ("ok", Json::from(true))].into_iter().collect();
+/// let response = HttpResponse::json(reply);
+/// assert_eq!(response.body(), br#"{"item":"tea","ok":true}"#);
+/// ```
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Json
+{
+    #[default]
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(BTreeMap<String, Json>),
+}
+
+/// Limits on the JSON a request body may hold
+#[derive(Debug, Clone)]
+pub struct JsonConfig
+{
+    /// How deeply arrays and objects may nest
+    max_depth: usize,
+    /// The largest body accepted, in bytes
+    max_size: usize,
+}
+
+/// Why a JSON body was refused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JsonError
+{
+    /// The request is not `application/json`, with the type it has
+    UnsupportedMediaType(String),
+    /// The body is larger than the limit in bytes
+    TooLarge(usize),
+    /// The body is not valid JSON. Lines and columns count from 1, columns
+    /// in characters.
+    Syntax
+    {
+        line: usize,
+        column: usize,
+        message: String,
+    },
+}
+
+/// A recursive descent parser over the bytes of a document
+struct Parser<'s>
+{
+    source: &'s str,
+    bytes: &'s [u8],
+    pos: usize,
+    depth: usize,
+    max_depth: usize,
+}
+
+impl Default for JsonConfig
+{
+    fn default() -> Self
+    {
+        Self
+        {
+            max_depth: 64,
+            max_size: 1024 * 1024,
+        }
+    }
+}
+
+impl JsonConfig
+{
+    /// Allows 64 levels of nesting in at most 1 MiB
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+
+    /// Sets how deeply arrays and objects may nest
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self
+    {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Sets the largest body accepted, in bytes
+    pub fn with_max_size(mut self, max_size: usize) -> Self
+    {
+        self.max_size = max_size;
+        self
+    }
+}
+
+impl fmt::Display for JsonError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            JsonError::UnsupportedMediaType(media_type) if media_type.is_empty() => write!(f, "expected Content-Type application/json"),
+            JsonError::UnsupportedMediaType(media_type) => write!(f, "expected Content-Type application/json, got {media_type}"),
+            JsonError::TooLarge(limit) => write!(f, "JSON body is larger than {limit} bytes"),
+            JsonError::Syntax { line, column, message } => write!(f, "invalid JSON at line {line}, column {column}: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for JsonError {}
+
+impl From<JsonError> for Error
+{
+    fn from(e: JsonError) -> Self
+    {
+        Error::new(ErrorKind::InvalidData, e)
+    }
+}
+
+impl JsonError
+{
+    /// Gets the status to answer with: 415, 413 or 400
+    pub fn status(&self) -> u16
+    {
+        match self
+        {
+            JsonError::UnsupportedMediaType(_) => 415,
+            JsonError::TooLarge(_) => 413,
+            JsonError::Syntax { .. } => 400,
+        }
+    }
+
+    /// Builds the response describing the error as JSON, with the position
+    /// of syntax errors
+    pub fn response(&self) -> HttpResponse
+    {
+        let mut body = BTreeMap::from([("error".to_string(), Json::from(self.to_string()))]);
+        if let JsonError::Syntax { line, column, .. } = self
+        {
+            body.insert("line".to_string(), Json::from(*line));
+            body.insert("column".to_string(), Json::from(*column));
+        }
+        HttpResponse::new(self.status()).with_json(Json::Object(body))
+    }
+}
+
+impl Json
+{
+    /// Parses a document with the default limits
+    pub fn parse(source: &str) -> Result<Self, JsonError>
+    {
+        Self::parse_with(source, &JsonConfig::default())
+    }
+
+    /// Parses a document, refusing those over the size or depth limits
+    pub fn parse_with(source: &str, config: &JsonConfig) -> Result<Self, JsonError>
+    {
+        if source.len() > config.max_size
+        {
+            return Err(JsonError::TooLarge(config.max_size));
+        }
+        let mut parser = Parser
+        {
+            source: source,
+            bytes: source.as_bytes(),
+            pos: 0,
+            depth: 0,
+            max_depth: config.max_depth,
+        };
+        // A byte order mark is tolerated, RFC 8259 section 8.1
+        if source.starts_with('\u{feff}')
+        {
+            parser.pos = 3;
+        }
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.pos < parser.bytes.len()
+        {
+            return Err(parser.error("unexpected data after the value"));
+        }
+        Ok(value)
+    }
+
+    pub fn is_null(&self) -> bool
+    {
+        *self == Json::Null
+    }
+
+    pub fn as_bool(&self) -> Option<bool>
+    {
+        match self
+        {
+            Json::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64>
+    {
+        match self
+        {
+            Json::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// Gets a number that is a whole value within range of `i64`
+    pub fn as_i64(&self) -> Option<i64>
+    {
+        self.as_f64().filter(|n| n.fract() == 0.0 && *n >= i64::MIN as f64 && *n < i64::MAX as f64).map(|n| n as i64)
+    }
+
+    pub fn as_str(&self) -> Option<&str>
+    {
+        match self
+        {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]>
+    {
+        match self
+        {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn as_object(&self) -> Option<&BTreeMap<String, Json>>
+    {
+        match self
+        {
+            Json::Object(members) => Some(members),
+            _ => None,
+        }
+    }
+
+    /// Gets a member of an object
+    pub fn get(&self, key: &str) -> Option<&Json>
+    {
+        self.as_object()?.get(key)
+    }
+
+    /// Serializes the value with two space indentation
+    pub fn to_pretty_string(&self) -> String
+    {
+        let mut out = String::new();
+        self.write_pretty(&mut out, 0);
+        out
+    }
+
+    fn write_pretty(&self, out: &mut String, indent: usize)
+    {
+        let pad = |out: &mut String, indent: usize| out.extend(std::iter::repeat_n("  ", indent));
+        match self
+        {
+            Json::Array(items) if !items.is_empty() =>
+            {
+                out.push_str("[\n");
+                for (i, item) in items.iter().enumerate()
+                {
+                    pad(out, indent + 1);
+                    item.write_pretty(out, indent + 1);
+                    out.push_str(if i + 1 < items.len() { ",\n" } else { "\n" });
+                }
+                pad(out, indent);
+                out.push(']');
+            },
+            Json::Object(members) if !members.is_empty() =>
+            {
+                out.push_str("{\n");
+                for (i, (key, value)) in members.iter().enumerate()
+                {
+                    pad(out, indent + 1);
+                    write_string(out, key);
+                    out.push_str(": ");
+                    value.write_pretty(out, indent + 1);
+                    out.push_str(if i + 1 < members.len() { ",\n" } else { "\n" });
+                }
+                pad(out, indent);
+                out.push('}');
+            },
+            value => out.push_str(&value.to_string()),
+        }
+    }
+}
+
+/// Writes a string with the escapes JSON requires
+fn write_string(out: &mut String, s: &str)
+{
+    out.push('"');
+    for c in s.chars()
+    {
+        match c
+        {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+impl fmt::Display for Json
+{
+    /// Serializes the value without whitespace
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            Json::Null => f.write_str("null"),
+            Json::Bool(b) => write!(f, "{b}"),
+            // JSON has no infinities or NaN
+            Json::Number(n) if !n.is_finite() => f.write_str("null"),
+            Json::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", *n as i64),
+            Json::Number(n) => write!(f, "{n:?}"),
+            Json::String(s) =>
+            {
+                let mut out = String::with_capacity(s.len() + 2);
+                write_string(&mut out, s);
+                f.write_str(&out)
+            },
+            Json::Array(items) =>
+            {
+                f.write_str("[")?;
+                for (i, item) in items.iter().enumerate()
+                {
+                    if i > 0
+                    {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                f.write_str("]")
+            },
+            Json::Object(members) =>
+            {
+                f.write_str("{")?;
+                for (i, (key, value)) in members.iter().enumerate()
+                {
+                    let mut out = String::with_capacity(key.len() + 3);
+                    if i > 0
+                    {
+                        out.push(',');
+                    }
+                    write_string(&mut out, key);
+                    out.push(':');
+                    write!(f, "{out}{value}")?;
+                }
+                f.write_str("}")
+            },
+        }
+    }
+}
+
+impl From<&str> for Json
+{
+    fn from(value: &str) -> Self
+    {
+        Json::String(value.to_string())
+    }
+}
+
+impl From<String> for Json
+{
+    fn from(value: String) -> Self
+    {
+        Json::String(value)
+    }
+}
+
+impl From<bool> for Json
+{
+    fn from(value: bool) -> Self
+    {
+        Json::Bool(value)
+    }
+}
+
+/// Implements `From` for numeric types
+macro_rules! from_number
+{
+    ($($t:ty),*) =>
+    {
+        $(
+            impl From<$t> for Json
+            {
+                fn from(value: $t) -> Self
+                {
+                    Json::Number(value as f64)
+                }
+            }
+        )*
+    };
+}
+
+from_number!(i32, i64, u16, u32, u64, usize, f32, f64);
+
+impl<T: Into<Json>> From<Vec<T>> for Json
+{
+    fn from(items: Vec<T>) -> Self
+    {
+        Json::Array(items.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json
+{
+    fn from(value: Option<T>) -> Self
+    {
+        value.map_or(Json::Null, Into::into)
+    }
+}
+
+impl<T: Into<Json>> From<BTreeMap<String, T>> for Json
+{
+    fn from(members: BTreeMap<String, T>) -> Self
+    {
+        Json::Object(members.into_iter().map(|(k, v)| (k, v.into())).collect())
+    }
+}
+
+impl<K: Into<String>, V: Into<Json>> FromIterator<(K, V)> for Json
+{
+    /// Collects pairs into an object
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(pairs: I) -> Self
+    {
+        Json::Object(pairs.into_iter().map(|(k, v)| (k.into(), v.into())).collect())
+    }
+}
+
+impl Parser<'_>
+{
+    /// Builds a syntax error at the current position
+    fn error(&self, message: &str) -> JsonError
+    {
+        let pos = self.pos.min(self.bytes.len());
+        // Count in characters, backing off to a boundary inside a character
+        let mut end = pos;
+        while !self.source.is_char_boundary(end)
+        {
+            end -= 1;
+        }
+        let before = &self.source[..end];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        JsonError::Syntax
+        {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            message: message.to_string(),
+        }
+    }
+
+    fn skip_whitespace(&mut self)
+    {
+        while matches!(self.bytes.get(self.pos), Some(b' ' | b'\t' | b'\n' | b'\r'))
+        {
+            self.pos += 1;
+        }
+    }
+
+    /// Consumes `literal` if the input continues with it
+    fn eat(&mut self, literal: &str) -> bool
+    {
+        let matched = self.bytes[self.pos..].starts_with(literal.as_bytes());
+        if matched
+        {
+            self.pos += literal.len();
+        }
+        matched
+    }
+
+    fn value(&mut self) -> Result<Json, JsonError>
+    {
+        self.skip_whitespace();
+        match self.bytes.get(self.pos)
+        {
+            None => Err(self.error("unexpected end of input")),
+            Some(b'{') => self.nested(|p| p.object()),
+            Some(b'[') => self.nested(|p| p.array()),
+            Some(b'"') => self.string().map(Json::String),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            Some(_) if self.eat("true") => Ok(Json::Bool(true)),
+            Some(_) if self.eat("false") => Ok(Json::Bool(false)),
+            Some(_) if self.eat("null") => Ok(Json::Null),
+            Some(_) => Err(self.error("expected a value")),
+        }
+    }
+
+    /// Parses an array or object one level deeper
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<Json, JsonError>) -> Result<Json, JsonError>
+    {
+        if self.depth >= self.max_depth
+        {
+            return Err(self.error(&format!("nested deeper than {} levels", self.max_depth)));
+        }
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn array(&mut self) -> Result<Json, JsonError>
+    {
+        self.pos += 1;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.eat("]")
+        {
+            return Ok(Json::Array(items));
+        }
+        loop
+        {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            if self.eat("]")
+            {
+                return Ok(Json::Array(items));
+            }
+            if !self.eat(",")
+            {
+                return Err(self.error("expected `,` or `]`"));
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, JsonError>
+    {
+        self.pos += 1;
+        let mut members = BTreeMap::new();
+        self.skip_whitespace();
+        if self.eat("}")
+        {
+            return Ok(Json::Object(members));
+        }
+        loop
+        {
+            self.skip_whitespace();
+            if self.bytes.get(self.pos) != Some(&b'"')
+            {
+                return Err(self.error("expected a member name in quotes"));
+            }
+            let key_pos = self.pos;
+            let key = self.string()?;
+            self.skip_whitespace();
+            if !self.eat(":")
+            {
+                return Err(self.error("expected `:`"));
+            }
+            let value = self.value()?;
+            if members.insert(key, value).is_some()
+            {
+                self.pos = key_pos;
+                return Err(self.error("duplicate member name"));
+            }
+            self.skip_whitespace();
+            if self.eat("}")
+            {
+                return Ok(Json::Object(members));
+            }
+            if !self.eat(",")
+            {
+                return Err(self.error("expected `,` or `}`"));
+            }
+        }
+    }
+
+    /// Reads the four hex digits of a `\u` escape
+    fn hex4(&mut self) -> Result<u32, JsonError>
+    {
+        let digits = self.bytes.get(self.pos..self.pos + 4)
+        .and_then(|digits| std::str::from_utf8(digits).ok())
+        .filter(|digits| digits.bytes().all(|b| b.is_ascii_hexdigit()))
+        .ok_or_else(|| self.error("expected four hex digits after `\\u`"))?;
+        let code = u32::from_str_radix(digits, 16).unwrap();
+        self.pos += 4;
+        Ok(code)
+    }
+
+    fn string(&mut self) -> Result<String, JsonError>
+    {
+        self.pos += 1;
+        let mut out = String::new();
+        loop
+        {
+            // Copy the run of plain characters in one go
+            let run = self.bytes[self.pos..].iter().position(|b| *b == b'"' || *b == b'\\' || *b < 0x20);
+            let Some(run) = run else
+            {
+                self.pos = self.bytes.len();
+                return Err(self.error("unterminated string"));
+            };
+            out.push_str(&self.source[self.pos..self.pos + run]);
+            self.pos += run;
+            match self.bytes[self.pos]
+            {
+                b'"' =>
+                {
+                    self.pos += 1;
+                    return Ok(out);
+                },
+                b'\\' => self.pos += 1,
+                _ => return Err(self.error("control characters must be escaped in strings")),
+            }
+            let escape = self.bytes.get(self.pos).copied();
+            self.pos += 1;
+            match escape
+            {
+                Some(b'"') => out.push('"'),
+                Some(b'\\') => out.push('\\'),
+                Some(b'/') => out.push('/'),
+                Some(b'b') => out.push('\u{8}'),
+                Some(b'f') => out.push('\u{c}'),
+                Some(b'n') => out.push('\n'),
+                Some(b'r') => out.push('\r'),
+                Some(b't') => out.push('\t'),
+                Some(b'u') =>
+                {
+                    let start = self.pos - 2;
+                    let mut code = self.hex4()?;
+                    if (0xd800..0xdc00).contains(&code) && self.eat("\\u")
+                    {
+                        let low = self.hex4()?;
+                        if (0xdc00..0xe000).contains(&low)
+                        {
+                            code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                        }
+                    }
+                    match char::from_u32(code)
+                    {
+                        Some(c) => out.push(c),
+                        None =>
+                        {
+                            self.pos = start;
+                            return Err(self.error("unpaired surrogate in `\\u` escape"));
+                        },
+                    }
+                },
+                _ =>
+                {
+                    self.pos -= 2;
+                    return Err(self.error("invalid escape"));
+                },
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, JsonError>
+    {
+        let start = self.pos;
+        let digits = |p: &mut Self| -> usize
+        {
+            let count = p.bytes[p.pos..].iter().take_while(|b| b.is_ascii_digit()).count();
+            p.pos += count;
+            count
+        };
+        self.eat("-");
+        let int_start = self.pos;
+        match digits(self)
+        {
+            0 => return Err(self.error("expected a digit")),
+            n if n > 1 && self.bytes[int_start] == b'0' =>
+            {
+                self.pos = int_start;
+                return Err(self.error("numbers may not have leading zeros"));
+            },
+            _ => {},
+        }
+        if self.eat(".") && digits(self) == 0
+        {
+            return Err(self.error("expected a digit after the decimal point"));
+        }
+        if matches!(self.bytes.get(self.pos), Some(b'e' | b'E'))
+        {
+            self.pos += 1;
+            if !self.eat("+")
+            {
+                self.eat("-");
+            }
+            if digits(self) == 0
+            {
+                return Err(self.error("expected a digit in the exponent"));
+            }
+        }
+        let number: f64 = self.source[start..self.pos].parse().unwrap();
+        if !number.is_finite()
+        {
+            self.pos = start;
+            return Err(self.error("number is out of range"));
+        }
+        Ok(Json::Number(number))
+    }
+}
+
+impl HttpRequest
+{
+    /// Parses an `application/json` body with the default limits
+    pub fn json(&self) -> Result<Json, JsonError>
+    {
+        self.json_with(&JsonConfig::default())
+    }
+
+    /// Parses an `application/json` body, or any `+json` type.
+    ///
+    /// Only buffered bodies are read, so JSON routes should not stream.
+    pub fn json_with(&self, config: &JsonConfig) -> Result<Json, JsonError>
+    {
+        let content = self.content();
+        let media_type = content.header("Content-Type").unwrap_or("").split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        if media_type != "application/json" && !(media_type.starts_with("application/") && media_type.ends_with("+json"))
+        {
+            return Err(JsonError::UnsupportedMediaType(media_type));
+        }
+        Json::parse_with(&content.body, config)
+    }
+}
+
+impl HttpResponse
+{
+    /// Builds a `200 OK` response with a JSON body
+    pub fn json(value: impl Into<Json>) -> Self
+    {
+        HttpResponse::new(200).with_json(value)
+    }
+
+    /// Sets the body to a JSON value and the matching Content-Type
+    pub fn with_json(self, value: impl Into<Json>) -> Self
+    {
+        self.with_header("Content-Type", "application/json")
+        .with_body(value.into().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn syntax_error(source: &str) -> (usize, usize, String)
+    {
+        match Json::parse(source)
+        {
+            Err(JsonError::Syntax { line, column, message }) => (line, column, message),
+            other => panic!("expected a syntax error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_parse()
+    {
+        let value = Json::parse(" {\"name\": \"Ann \\\"A\\\" \\u00e9\\ud83d\\ude00\", \"tags\": [1, -2.5, 3e2, true, null], \"empty\": {}} ").unwrap();
+        assert_eq!(value.get("name").and_then(Json::as_str), Some("Ann \"A\" é😀"));
+        let tags = value.get("tags").and_then(Json::as_array).unwrap();
+        assert_eq!(tags, [Json::Number(1.0), Json::Number(-2.5), Json::Number(300.0), Json::Bool(true), Json::Null]);
+        assert_eq!(tags[0].as_i64(), Some(1));
+        assert_eq!(value.get("empty"), Some(&Json::Object(BTreeMap::new())));
+        assert_eq!(Json::parse("\u{feff}\"x\"").unwrap(), Json::from("x"));
+    }
+
+    #[test]
+    fn test_errors()
+    {
+        assert_eq!(syntax_error("{\"a\": 1,\n  \"b\" 2}"), (2, 7, "expected `:`".to_string()));
+        assert_eq!(syntax_error("[1, 2"), (1, 6, "expected `,` or `]`".to_string()));
+        assert_eq!(syntax_error("[01]"), (1, 2, "numbers may not have leading zeros".to_string()));
+        assert_eq!(syntax_error("\"é\\x\""), (1, 3, "invalid escape".to_string()));
+        assert_eq!(syntax_error("\"a\nb\""), (1, 3, "control characters must be escaped in strings".to_string()));
+        assert_eq!(syntax_error("{\"a\": 1, \"a\": 2}"), (1, 10, "duplicate member name".to_string()));
+        assert_eq!(syntax_error("1 2"), (1, 3, "unexpected data after the value".to_string()));
+        assert_eq!(syntax_error("[1,]"), (1, 4, "expected a value".to_string()));
+        assert_eq!(syntax_error("\"\\udc00\""), (1, 2, "unpaired surrogate in `\\u` escape".to_string()));
+        assert_eq!(syntax_error("1e999"), (1, 1, "number is out of range".to_string()));
+        let config = JsonConfig::new().with_max_depth(3).with_max_size(32);
+        assert!(Json::parse_with("[[[1]]]", &config).is_ok());
+        assert!(matches!(Json::parse_with("[[[[1]]]]", &config), Err(JsonError::Syntax { column: 4, .. })));
+        assert_eq!(Json::parse_with(&"1".repeat(33), &config), Err(JsonError::TooLarge(32)));
+    }
+
+    #[test]
+    fn test_serialize()
+    {
+        let value: Json = [
+            ("text", Json::from("line\n\"quoted\" \u{1} é")),
+            ("numbers", Json::from(vec![1.0, 0.5, 1e300, f64::NAN])),
+            ("none", Json::from(None::<bool>)),
+        ]
+        .into_iter()
+        .collect();
+        let text = value.to_string();
+        assert_eq!(text, "{\"none\":null,\"numbers\":[1,0.5,1e300,null],\"text\":\"line\\n\\\"quoted\\\" \\u0001 é\"}");
+        assert_eq!(Json::parse(&text).unwrap().get("text"), value.get("text"));
+        assert_eq!(Json::from(vec![Json::from(1), Json::from_iter([("a", true)])]).to_pretty_string(), "[\n  1,\n  {\n    \"a\": true\n  }\n]");
+    }
+
+    #[test]
+    fn test_request()
+    {
+        let request = |content_type: &str, body: &str|
+        {
+            let head = format!("POST /api HTTP/1.1\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\r\n", body.len());
+            let mut request = HttpRequest::new(head.as_bytes()).unwrap();
+            request.content_mut().body = body.to_string();
+            request
+        };
+        assert_eq!(request("application/json; charset=utf-8", "[true]").json().unwrap(), Json::from(vec![true]));
+        assert_eq!(request("application/merge-patch+json", "{}").json().unwrap(), Json::Object(BTreeMap::new()));
+        let refused = request("text/plain", "[]").json().unwrap_err();
+        assert_eq!(refused, JsonError::UnsupportedMediaType("text/plain".to_string()));
+        let response = request("application/json", "{\"a\" 1}").json().unwrap_err().response();
+        assert_eq!((response.status(), response.header("Content-Type")), (400, Some("application/json")));
+        assert_eq!(Json::parse(std::str::from_utf8(response.body()).unwrap()).unwrap().get("column"), Some(&Json::from(6)));
+    }
+}
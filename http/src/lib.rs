@@ -1,7 +1,36 @@
 pub mod server;
 pub mod mp;
+pub mod metrics;
+pub mod net;
+pub mod h2;
+pub mod body;
+pub mod multipart;
+pub mod form;
+pub mod cookie;
+pub mod crypto;
+pub mod date;
+pub mod conditional;
+pub mod files;
+pub mod range;
+pub mod compress;
+pub mod negotiate;
+pub mod template;
+pub mod json;
+pub mod error;
+pub mod panic;
+pub mod extract;
+pub mod cancel;
 
-use std::{io::{BufRead, BufReader, Error, ErrorKind}, net::TcpStream};
+use std::{any::Any, io::{self, BufRead, BufReader, Error, ErrorKind, Read, Write}, sync::Arc, time::SystemTime};
+
+use body::{BodyReader, BodyStream};
+use cancel::Cancellation;
+use conditional::EntityTag;
+use cookie::{parse_cookies, Cookie, CookieKey};
+use date::format_http_date;
+use server::{router::{Router, StateMap}, routes::UrlError, session::Session};
+use form::{Form, FormError, FromForm};
+use multipart::{Multipart, MultipartConfig};
 
 #[derive(Debug)]
 pub enum HttpHeader
@@ -17,12 +46,35 @@ pub struct HttpContent
     route: String,
     headers: Vec<String>,
     body: String,
+    /// The body of a request to a streaming route, read as it arrives
+    body_reader: Option<BodyReader>,
+    /// The session, when the server has sessions enabled
+    session: Option<Session>,
+    /// The state of the routers the request passed through
+    state: StateMap,
+    /// The decoded values of the parameters of the route
+    params: Vec<(String, String)>,
+    /// The routes of the server, to build URLs with
+    router: Option<Arc<Router>>,
+    /// Set once nobody waits for the response anymore
+    cancellation: Cancellation,
+    /// The address of the listener the request arrived on
+    listener: String,
+    /// The address of the client
+    peer: String,
 }
 
 #[derive(Debug)]
 pub struct HttpResponse
 {
-    content: HttpContent
+    status: u16,
+    headers: Vec<String>,
+    body: Vec<u8>,
+    /// A body read while it is sent, in place of `body`
+    stream: Option<BodyStream>,
+    /// The message of the `HttpError` the response was made from, which
+    /// an error page may show instead of the body
+    error: Option<String>,
 }
 
 #[derive(Debug)]
@@ -39,15 +91,549 @@ pub enum HttpRequest
     Patch(HttpContent),
 }
 
+/// The longest request head accepted, in bytes
+pub const MAX_HEAD_SIZE: usize = 64 * 1024;
+/// The largest request body read into memory, in bytes
+pub const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+/// Returns the standard reason phrase for a status code
+pub fn reason_phrase(status: u16) -> &'static str
+{
+    match status
+    {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Content Too Large",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        417 => "Expectation Failed",
+        422 => "Unprocessable Content",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
+
+/// Finds the value of a header in a list of raw `Name: value` lines.
+/// Header names are matched case-insensitively.
+fn find_header<'h>(headers: &'h [String], name: &str) -> Option<&'h str>
+{
+    headers.iter().find_map(|line|
+    {
+        let (key, value) = line.split_once(':')?;
+        if key.trim().eq_ignore_ascii_case(name)
+        {
+            return Some(value.trim());
+        }
+        None
+    })
+}
+
+impl HttpContent
+{
+    pub fn http_version(&self) -> &str
+    {
+        &self.http_version
+    }
+
+    pub fn route(&self) -> &str
+    {
+        &self.route
+    }
+
+    /// Gets the route without its query string
+    pub fn path(&self) -> &str
+    {
+        self.route.split_once('?').map_or(&self.route, |(path, _)| path)
+    }
+
+    /// Gets the raw query string of the route, without the `?`
+    pub fn query_string(&self) -> Option<&str>
+    {
+        self.route.split_once('?').map(|(_, query)| query)
+    }
+
+    pub fn headers(&self) -> &[String]
+    {
+        &self.headers
+    }
+
+    /// Gets the value of the first header named `name`
+    pub fn header(&self, name: &str) -> Option<&str>
+    {
+        find_header(&self.headers, name)
+    }
+
+    pub fn body(&self) -> &str
+    {
+        &self.body
+    }
+
+    /// Gets the session of the request, when the server has sessions enabled
+    pub fn session(&self) -> Option<&Session>
+    {
+        self.session.as_ref()
+    }
+
+    /// Gets the state of type `T` shared by the router handling the
+    /// request, or by a router it is mounted in
+    pub fn state<T: Any>(&self) -> Option<&T>
+    {
+        self.state.get::<T>()
+    }
+
+    /// Gets the decoded value of a parameter of the route, such as `id` in
+    /// `/users/{id}`
+    pub fn param(&self, name: &str) -> Option<&str>
+    {
+        self.params.iter().find(|(param, _)| param == name).map(|(_, value)| value.as_str())
+    }
+
+    pub fn params(&self) -> &[(String, String)]
+    {
+        &self.params
+    }
+
+    /// Gets the signal telling the handler its request was given up on,
+    /// such as when the route has a timeout and the handler ran past it
+    pub fn cancellation(&self) -> &Cancellation
+    {
+        &self.cancellation
+    }
+
+    /// Builds the URL of a named route of the server the request arrived
+    /// at. See [`Router::url_for`].
+    pub fn url_for(&self, name: &str, params: &[(&str, &str)]) -> Result<String, UrlError>
+    {
+        self.url_for_with_query(name, params, &[])
+    }
+
+    /// Builds the URL of a named route followed by a query string
+    pub fn url_for_with_query(&self, name: &str, params: &[(&str, &str)], query: &[(&str, &str)]) -> Result<String, UrlError>
+    {
+        match &self.router
+        {
+            Some(router) => router.url_for_with_query(name, params, query),
+            None => Err(UrlError::UnknownRoute(name.to_string())),
+        }
+    }
+
+    /// Gets the name and value pairs of every `Cookie` header
+    pub fn cookies(&self) -> Vec<(String, String)>
+    {
+        self.headers.iter()
+        .filter_map(|line| line.split_once(':'))
+        .filter(|(name, _)| name.trim().eq_ignore_ascii_case("Cookie"))
+        .flat_map(|(_, value)| parse_cookies(value))
+        .collect()
+    }
+
+    /// Gets the value of the first cookie named `name`
+    pub fn cookie(&self, name: &str) -> Option<String>
+    {
+        self.cookies().into_iter().find(|(key, _)| key == name).map(|(_, value)| value)
+    }
+
+    /// Gets the value of a cookie signed with `key`, ignoring it if the
+    /// signature does not match
+    pub fn signed_cookie(&self, name: &str, key: &CookieKey) -> Option<String>
+    {
+        key.verify(name, &self.cookie(name)?)
+    }
+
+    /// Gets the body reader of a request to a streaming route.
+    /// `body()` is empty for those requests.
+    pub fn body_reader(&mut self) -> Option<&mut BodyReader>
+    {
+        self.body_reader.as_mut()
+    }
+
+    /// Gets the declared length of the body, zero when there is none.
+    /// Repeated `Content-Length` values must all agree, RFC 9112 section 6.3.
+    pub fn content_length(&self) -> Result<usize, Error>
+    {
+        if self.header("Transfer-Encoding").is_some()
+        {
+            return Err(Error::new(ErrorKind::Unsupported, "Transfer-Encoding is not supported"));
+        }
+        let mut length = None;
+        // A list such as `5, 5` is the same as repeating the header
+        for value in self.header_values("Content-Length").flat_map(|value| value.split(','))
+        {
+            let value = value.trim().parse::<usize>()
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid Content-Length"))?;
+            if length.is_some_and(|length| length != value)
+            {
+                return Err(Error::new(ErrorKind::InvalidData, "Conflicting Content-Length"));
+            }
+            length = Some(value);
+        }
+        Ok(length.unwrap_or(0))
+    }
+
+    /// Gets the value of every header named `name`, in order
+    pub(crate) fn header_values<'h>(&'h self, name: &'h str) -> impl Iterator<Item = &'h str>
+    {
+        self.headers.iter()
+        .filter_map(|line| line.split_once(':'))
+        .filter(move |(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+    }
+
+    /// Checks whether the client wants the connection kept open after the response
+    pub fn keep_alive(&self) -> bool
+    {
+        let connection = self.header("Connection").unwrap_or("");
+        let has = |token: &str| connection.split(',').any(|t| t.trim().eq_ignore_ascii_case(token));
+        if self.http_version == "HTTP/1.0"
+        {
+            return has("keep-alive");
+        }
+        !has("close")
+    }
+
+    /// Gets the address of the listener the request arrived on
+    pub fn listener(&self) -> &str
+    {
+        &self.listener
+    }
+
+    /// Gets the address of the client that sent the request
+    pub fn peer(&self) -> &str
+    {
+        &self.peer
+    }
+}
+
+impl HttpResponse
+{
+    pub fn new(status: u16) -> Self
+    {
+        return Self
+        {
+            status: status,
+            headers: Vec::new(),
+            body: Vec::new(),
+            stream: None,
+            error: None,
+        }
+    }
+
+    /// Adds a header to the response
+    pub fn with_header(mut self, name: &str, value: &str) -> Self
+    {
+        self.headers.push(format!("{name}: {value}"));
+        self
+    }
+
+    /// Adds a `Set-Cookie` header
+    pub fn with_cookie(self, cookie: &Cookie) -> Self
+    {
+        self.with_header("Set-Cookie", &cookie.to_string())
+    }
+
+    /// Adds an `ETag` header
+    pub fn with_etag(self, etag: &EntityTag) -> Self
+    {
+        self.with_header("ETag", &etag.to_string())
+    }
+
+    /// Adds a strong `ETag` header computed from the current body
+    pub fn with_content_etag(self) -> Self
+    {
+        let etag = EntityTag::from_content(&self.body);
+        self.with_etag(&etag)
+    }
+
+    /// Adds a `Last-Modified` header
+    pub fn with_last_modified(self, modified: SystemTime) -> Self
+    {
+        self.with_header("Last-Modified", &format_http_date(modified))
+    }
+
+    /// Sets the body of the response
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Self
+    {
+        self.body = body.into();
+        self.stream = None;
+        self
+    }
+
+    /// Sets a body of `len` bytes that is read from `reader` as it is sent,
+    /// so that it is never held in memory whole. The connection is closed
+    /// when the reader fails or ends early.
+    pub fn with_body_stream(mut self, reader: impl Read + Send + 'static, len: u64) -> Self
+    {
+        self.body = Vec::new();
+        self.stream = Some(BodyStream::new(Box::new(reader), len));
+        self
+    }
+
+    pub fn status(&self) -> u16
+    {
+        self.status
+    }
+
+    pub fn headers(&self) -> &[String]
+    {
+        &self.headers
+    }
+
+    /// Gets the value of the first header named `name`
+    pub fn header(&self, name: &str) -> Option<&str>
+    {
+        find_header(&self.headers, name)
+    }
+
+    /// Gets the body held in memory, which is empty for a streamed body
+    pub fn body(&self) -> &[u8]
+    {
+        &self.body
+    }
+
+    /// Gets the length of the body, streamed or not
+    pub fn body_len(&self) -> u64
+    {
+        match &self.stream
+        {
+            Some(stream) => stream.len(),
+            None => self.body.len() as u64,
+        }
+    }
+
+    /// Drops the body of a response to a HEAD request, keeping the
+    /// `Content-Length` a GET would have been sent
+    pub(crate) fn into_head(mut self) -> Self
+    {
+        let bodiless = self.status < 200 || self.status == 204 || self.status == 304;
+        if !bodiless && self.header("Content-Length").is_none()
+        {
+            let len = self.body_len().to_string();
+            self = self.with_header("Content-Length", &len);
+        }
+        self.body.clear();
+        self.stream = None;
+        self
+    }
+
+    /// Serializes the status line, headers and the body held in memory,
+    /// handing back the body stream to send after them
+    pub(crate) fn into_parts(mut self) -> (Vec<u8>, Option<BodyStream>)
+    {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason_phrase(self.status));
+        for header in &self.headers
+        {
+            head.push_str(header);
+            head.push_str("\r\n");
+        }
+        let bodiless = self.status < 200 || self.status == 204 || self.status == 304;
+        if !bodiless && self.header("Content-Length").is_none()
+        {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body_len()));
+        }
+        head.push_str("\r\n");
+        let mut bytes = head.into_bytes();
+        bytes.append(&mut self.body);
+        (bytes, self.stream)
+    }
+
+    /// Serializes the response as HTTP/1.1 and writes it to the stream.
+    /// A `Content-Length` header is added when the handler did not set one
+    /// and the status allows a body.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(u64)` - The number of bytes written
+    pub fn write_to<W: Write>(self, stream: &mut W) -> Result<u64, Error>
+    {
+        let (bytes, body) = self.into_parts();
+        stream.write_all(&bytes)?;
+        let streamed = match body
+        {
+            Some(mut body) => io::copy(&mut body, stream)?,
+            None => 0,
+        };
+        Ok(bytes.len() as u64 + streamed)
+    }
+}
+
 impl HttpRequest
 {
-    pub fn new(stream: &TcpStream) -> Result<Self, Error>
+    /// Gets the method name of the request
+    pub fn method(&self) -> &'static str
+    {
+        match self
+        {
+            HttpRequest::Get(_) => "GET",
+            HttpRequest::Head(_) => "HEAD",
+            HttpRequest::Post(_) => "POST",
+            HttpRequest::Put(_) => "PUT",
+            HttpRequest::Delete(_) => "DELETE",
+            HttpRequest::Connect(_) => "CONNECT",
+            HttpRequest::Options(_) => "OPTIONS",
+            HttpRequest::Trace(_) => "TRACE",
+            HttpRequest::Patch(_) => "PATCH",
+        }
+    }
+
+    /// Gets the content of the request regardless of its method
+    pub fn content(&self) -> &HttpContent
+    {
+        match self
+        {
+            HttpRequest::Get(content) |
+            HttpRequest::Head(content) |
+            HttpRequest::Post(content) |
+            HttpRequest::Put(content) |
+            HttpRequest::Delete(content) |
+            HttpRequest::Connect(content) |
+            HttpRequest::Options(content) |
+            HttpRequest::Trace(content) |
+            HttpRequest::Patch(content) => content,
+        }
+    }
+
+    /// Gets the content of the request mutably, to read a streamed body
+    pub fn content_mut(&mut self) -> &mut HttpContent
+    {
+        match self
+        {
+            HttpRequest::Get(content) |
+            HttpRequest::Head(content) |
+            HttpRequest::Post(content) |
+            HttpRequest::Put(content) |
+            HttpRequest::Delete(content) |
+            HttpRequest::Connect(content) |
+            HttpRequest::Options(content) |
+            HttpRequest::Trace(content) |
+            HttpRequest::Patch(content) => content,
+        }
+    }
+
+    /// Decodes the query string of the route into `T`. A route without a
+    /// query decodes as an empty form.
+    pub fn query<T: FromForm>(&self) -> Result<T, FormError>
+    {
+        Form::parse(self.content().query_string().unwrap_or(""))?.extract()
+    }
+
+    /// Decodes an `application/x-www-form-urlencoded` body into `T`
+    pub fn form<T: FromForm>(&self) -> Result<T, FormError>
+    {
+        let content = self.content();
+        let media_type = content.header("Content-Type").unwrap_or("").split(';').next().unwrap_or("").trim();
+        if !media_type.eq_ignore_ascii_case("application/x-www-form-urlencoded")
+        {
+            return Err(FormError::Malformed("expected Content-Type application/x-www-form-urlencoded".to_string()));
+        }
+        Form::parse(&content.body)?.extract()
+    }
+
+    /// Parses a `multipart/form-data` body of a POST or PUT request.
+    ///
+    /// The body is read as it arrives, so the route must stream its body,
+    /// see `HttpRouteHandler::streaming`. Buffered routes only receive text,
+    /// which would corrupt file parts, and fail.
+    pub fn multipart(&mut self, config: MultipartConfig) -> Result<Multipart<'_>, Error>
+    {
+        let content = match self
+        {
+            HttpRequest::Post(content) | HttpRequest::Put(content) => content,
+            _ => return Err(Error::new(ErrorKind::Unsupported, "Only POST and PUT requests carry forms")),
+        };
+        let content_type = content.header("Content-Type")
+        .ok_or(Error::new(ErrorKind::InvalidData, "Missing Content-Type"))?
+        .to_string();
+        match content.body_reader.as_mut()
+        {
+            Some(reader) => Multipart::new(reader, &content_type, config),
+            None => Err(Error::other("multipart bodies are only read on routes streaming their body")),
+        }
+    }
+
+    pub fn new<R: Read>(stream: R) -> Result<Self, Error>
     {
         let buf_rdr = BufReader::new(stream);
         let mut lines = buf_rdr.lines();
         // Create start line buffer
         let start_line = lines.next()
         .ok_or(Error::new(ErrorKind::InvalidData, "Invalid Buffer"))??;
+        // Get headers
+        let mut headers = Vec::<String>::new();
+        loop
+        {
+            let line = lines.next()
+            .ok_or(Error::new(ErrorKind::InvalidData, "Invalid Buffer"))??;
+            if line.is_empty()
+            {
+                break;
+            }
+            headers.push(line);
+        }
+        Self::from_head(&start_line, headers)
+    }
+
+    /// Incrementally parses a request head from the start of `buf`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Some((HttpRequest, usize)))` - The request, without its body, and the length of the head
+    /// * `Ok(None)` - If the head is not complete yet
+    /// * `Err(Error)` - If the head is malformed or longer than `MAX_HEAD_SIZE`
+    pub fn parse_head(buf: &[u8]) -> Result<Option<(Self, usize)>, Error>
+    {
+        // Find the empty line terminating the head
+        let end = match buf.windows(4).position(|w| w == b"\r\n\r\n")
+        {
+            Some(pos) => pos + 4,
+            None if buf.len() > MAX_HEAD_SIZE =>
+            {
+                return Err(Error::new(ErrorKind::InvalidData, "Request head too large"));
+            },
+            None => return Ok(None),
+        };
+        let head = std::str::from_utf8(&buf[..end])
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let mut lines = head.split("\r\n");
+        let start_line = lines.next()
+        .ok_or(Error::new(ErrorKind::InvalidData, "Invalid Buffer"))?;
+        let headers = lines
+        .take_while(|line| !line.is_empty())
+        .map(|line| line.to_string())
+        .collect();
+        let request = Self::from_head(start_line, headers)?;
+        Ok(Some((request, end)))
+    }
+
+    /// Builds a request from its start line and header lines
+    fn from_head(start_line: &str, headers: Vec<String>) -> Result<Self, Error>
+    {
         // Parse start line by white spaces
         let mut parts = start_line.split_whitespace();
         // Get method
@@ -62,18 +648,6 @@ impl HttpRequest
         let version = parts.next()
         .ok_or(Error::new(ErrorKind::InvalidData, "Invalid start line in version"))?
         .to_string();
-        // Get headers
-        let mut headers = Vec::<String>::new();
-        loop
-        {
-            let line = lines.next()
-            .ok_or(Error::new(ErrorKind::InvalidData, "Invalid Buffer"))??;
-            if line.is_empty()
-            {
-                break;
-            }
-            headers.push(line);            
-        }
         // Create the http content
         let http_content = HttpContent
         {
@@ -81,6 +655,14 @@ impl HttpRequest
             route: route,
             headers: headers,
             body: String::new(),
+            body_reader: None,
+            session: None,
+            state: StateMap::default(),
+            params: Vec::new(),
+            router: None,
+            cancellation: Cancellation::new(),
+            listener: String::new(),
+            peer: String::new(),
         };
         // Match the method with the http content
         match method.as_str() {
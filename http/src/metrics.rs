@@ -0,0 +1,218 @@
+use std::{collections::BTreeMap, fmt::Write, sync::{atomic::{AtomicU64, AtomicUsize, Ordering}, Arc, Mutex}, time::Duration};
+
+use super::mp::PoolStats;
+
+/// Upper bounds of the latency histogram buckets in seconds
+const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// A fixed bucket histogram of durations
+#[derive(Debug, Default)]
+struct Histogram
+{
+    /// Observations per bucket, the last one being `+Inf`
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+/// Server and thread pool counters exported in the Prometheus text format
+#[derive(Debug, Default)]
+pub struct Metrics
+{
+    /// Request counts keyed by method, route and status
+    requests: Mutex<BTreeMap<(&'static str, String, u16), u64>>,
+    latency: Histogram,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    open_connections: AtomicUsize,
+    parse_errors: AtomicU64,
+    handler_panics: AtomicU64,
+    /// Handlers that ran past their timeout, keyed by route
+    handler_timeouts: Mutex<BTreeMap<String, u64>>,
+    pool: Option<Arc<PoolStats>>,
+}
+
+/// Keeps a connection counted as open until it is dropped
+pub struct ConnectionGuard
+{
+    metrics: Arc<Metrics>
+}
+
+impl Histogram
+{
+    fn observe(&self, elapsed: Duration)
+    {
+        let secs = elapsed.as_secs_f64();
+        let idx = LATENCY_BUCKETS.iter()
+        .position(|bound| secs <= *bound)
+        .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str)
+    {
+        // Prometheus buckets are cumulative
+        let mut cumulative = 0;
+        for (i, bound) in LATENCY_BUCKETS.iter().enumerate()
+        {
+            cumulative += self.buckets[i].load(Ordering::Relaxed);
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {cumulative}");
+        }
+        cumulative += self.buckets[LATENCY_BUCKETS.len()].load(Ordering::Relaxed);
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {cumulative}");
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{name}_sum {sum}");
+        let _ = writeln!(out, "{name}_count {}", self.count.load(Ordering::Relaxed));
+    }
+}
+
+impl Drop for ConnectionGuard
+{
+    fn drop(&mut self)
+    {
+        self.metrics.open_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Escapes a label value per the Prometheus text format
+fn escape_label(value: &str) -> String
+{
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Writes the `# HELP` and `# TYPE` lines of a metric family
+fn family(out: &mut String, name: &str, kind: &str, help: &str)
+{
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+impl Metrics
+{
+    /// Creates an empty set of metrics, optionally reporting on a thread pool
+    pub fn new(pool: Option<Arc<PoolStats>>) -> Self
+    {
+        return Self
+        {
+            pool: pool,
+            ..Default::default()
+        }
+    }
+
+    /// Counts a connection as open for the lifetime of the returned guard
+    pub fn connection(self: &Arc<Self>) -> ConnectionGuard
+    {
+        self.open_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard { metrics: Arc::clone(self) }
+    }
+
+    pub fn parse_error(&self)
+    {
+        self.parse_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn handler_panic(&self)
+    {
+        self.handler_panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn handler_timeout(&self, route: &str)
+    {
+        *self.handler_timeouts.lock().unwrap().entry(route.to_string()).or_insert(0) += 1;
+    }
+
+    pub fn bytes_in(&self, bytes: usize)
+    {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn bytes_out(&self, bytes: usize)
+    {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Records a handled request
+    pub fn request(&self, method: &'static str, route: &str, status: u16, elapsed: Duration)
+    {
+        *self.requests.lock().unwrap()
+        .entry((method, route.to_string(), status))
+        .or_insert(0) += 1;
+        self.latency.observe(elapsed);
+    }
+
+    /// Renders every metric in the Prometheus text exposition format
+    pub fn render(&self) -> String
+    {
+        let mut out = String::new();
+        family(&mut out, "http_requests_total", "counter", "HTTP requests by method, route and status.");
+        for ((method, route, status), count) in self.requests.lock().unwrap().iter()
+        {
+            let _ = writeln!(out, "http_requests_total{{method=\"{method}\",route=\"{}\",status=\"{status}\"}} {count}",
+                escape_label(route));
+        }
+        family(&mut out, "http_request_duration_seconds", "histogram", "Time spent handling HTTP requests.");
+        self.latency.render(&mut out, "http_request_duration_seconds");
+        family(&mut out, "http_received_bytes_total", "counter", "Bytes read from clients.");
+        let _ = writeln!(out, "http_received_bytes_total {}", self.bytes_in.load(Ordering::Relaxed));
+        family(&mut out, "http_sent_bytes_total", "counter", "Bytes written to clients.");
+        let _ = writeln!(out, "http_sent_bytes_total {}", self.bytes_out.load(Ordering::Relaxed));
+        family(&mut out, "http_open_connections", "gauge", "Connections currently being served.");
+        let _ = writeln!(out, "http_open_connections {}", self.open_connections.load(Ordering::Relaxed));
+        family(&mut out, "http_parse_errors_total", "counter", "Requests that could not be parsed.");
+        let _ = writeln!(out, "http_parse_errors_total {}", self.parse_errors.load(Ordering::Relaxed));
+        family(&mut out, "http_handler_panics_total", "counter", "Handlers that panicked and were answered with 500.");
+        let _ = writeln!(out, "http_handler_panics_total {}", self.handler_panics.load(Ordering::Relaxed));
+        family(&mut out, "http_handler_timeouts_total", "counter", "Handlers that ran past the timeout of their route, by route.");
+        for (route, count) in self.handler_timeouts.lock().unwrap().iter()
+        {
+            let _ = writeln!(out, "http_handler_timeouts_total{{route=\"{}\"}} {count}", escape_label(route));
+        }
+        if let Some(pool) = &self.pool
+        {
+            family(&mut out, "threadpool_workers", "gauge", "Worker threads in the pool.");
+            let _ = writeln!(out, "threadpool_workers {}", pool.workers());
+            family(&mut out, "threadpool_queue_depth", "gauge", "Jobs waiting for a worker.");
+            let _ = writeln!(out, "threadpool_queue_depth {}", pool.queue_depth());
+            family(&mut out, "threadpool_busy_workers", "gauge", "Workers currently running a job.");
+            let _ = writeln!(out, "threadpool_busy_workers {}", pool.busy_workers());
+            family(&mut out, "threadpool_rejected_total", "counter", "Jobs rejected because the queue was full.");
+            let _ = writeln!(out, "threadpool_rejected_total {}", pool.rejected());
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render()
+    {
+        let metrics = Arc::new(Metrics::new(None));
+        {
+            let _conn = metrics.connection();
+            metrics.request("GET", "/", 200, Duration::from_millis(3));
+            metrics.request("GET", "/", 200, Duration::from_millis(30));
+            metrics.request("POST", "/a\"b", 404, Duration::from_secs(20));
+            metrics.bytes_in(10);
+            metrics.bytes_out(20);
+            metrics.handler_timeout("/users/{id}");
+            assert!(metrics.render().contains("http_open_connections 1\n"));
+        }
+        let text = metrics.render();
+        assert!(text.contains("http_requests_total{method=\"GET\",route=\"/\",status=\"200\"} 2\n"));
+        assert!(text.contains("http_requests_total{method=\"POST\",route=\"/a\\\"b\",status=\"404\"} 1\n"));
+        assert!(text.contains("http_request_duration_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(text.contains("http_request_duration_seconds_bucket{le=\"0.05\"} 2\n"));
+        assert!(text.contains("http_request_duration_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("http_request_duration_seconds_count 3\n"));
+        assert!(text.contains("http_received_bytes_total 10\n"));
+        assert!(text.contains("http_sent_bytes_total 20\n"));
+        assert!(text.contains("http_open_connections 0\n"));
+        assert!(text.contains("http_handler_timeouts_total{route=\"/users/{id}\"} 1\n"));
+        assert!(!text.contains("threadpool"));
+    }
+}
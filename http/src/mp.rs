@@ -1,11 +1,29 @@
-use std::{io::{Error, ErrorKind}, sync::{mpsc::{self, Receiver, SyncSender}, Arc, Mutex}};
+use std::{io::{Error, ErrorKind}, sync::{atomic::{AtomicU64, AtomicUsize, Ordering}, mpsc::{self, Receiver, SyncSender}, Arc, Mutex}};
 use osafe::multiprocessing::posix_thread::Thread;
 
+use super::panic::catch;
+
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
-pub trait Executable
+pub trait Executable: Sync
 {
     fn try_submit(&self, job: Job) -> Result<(), Error>;
+
+    /// Gets the live counters of the executor, if it keeps any
+    fn stats(&self) -> Option<Arc<PoolStats>>
+    {
+        None
+    }
+}
+
+/// Live counters of a `ThreadPool`, shared between the pool and its workers
+#[derive(Debug, Default)]
+pub struct PoolStats
+{
+    workers: usize,
+    queued: AtomicUsize,
+    busy: AtomicUsize,
+    rejected: AtomicU64,
 }
 
 #[allow(dead_code)]
@@ -19,19 +37,54 @@ struct Worker
 pub struct ThreadPool<const J: usize, const N: usize>
 {
     sender: SyncSender<Job>,
-    threads: Vec<Worker>
+    threads: Vec<Worker>,
+    stats: Arc<PoolStats>
+}
+
+impl PoolStats
+{
+    /// Gets the number of worker threads in the pool
+    pub fn workers(&self) -> usize
+    {
+        self.workers
+    }
+
+    /// Gets the number of jobs waiting for a worker
+    pub fn queue_depth(&self) -> usize
+    {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Gets the number of workers currently running a job
+    pub fn busy_workers(&self) -> usize
+    {
+        self.busy.load(Ordering::Relaxed)
+    }
+
+    /// Gets the number of jobs refused because the queue was full
+    pub fn rejected(&self) -> u64
+    {
+        self.rejected.load(Ordering::Relaxed)
+    }
 }
 
 impl Worker
 {
-    pub fn new(id: usize, recvr: Arc<Mutex<Receiver<Job>>>) -> Self
+    pub fn new(id: usize, recvr: Arc<Mutex<Receiver<Job>>>, stats: Arc<PoolStats>) -> Self
     {
         let handle = Thread::new(move || loop
         {
             // Get the job. We can unwrap since this is in a new thread
             let job = recvr.lock().unwrap().recv().unwrap();
-            // Execute the job
-            job();
+            stats.queued.fetch_sub(1, Ordering::Relaxed);
+            stats.busy.fetch_add(1, Ordering::Relaxed);
+            // Execute the job. A panic must not unwind out of the thread,
+            // which would end the worker or abort the process.
+            if let Err(panic) = catch(job)
+            {
+                println!("Worker {id}: job panicked: {panic}");
+            }
+            stats.busy.fetch_sub(1, Ordering::Relaxed);
         }).unwrap();
         // Return the worker
         return Self
@@ -48,19 +101,26 @@ impl<const J: usize, const N: usize> ThreadPool<J, N>
     {
         // Create the mpsc channel
         let (sender, recvr) = mpsc::sync_channel::<Job>(J);
+        // Create the shared counters
+        let stats = Arc::new(PoolStats
+        {
+            workers: N,
+            ..Default::default()
+        });
         // Create the worker vec
         let mut threads = Vec::<Worker>::new();
         let recvr = Arc::new(Mutex::new(recvr));
         for i in 0..N
         {
             // Create the workers
-            threads.push(Worker::new(i, Arc::clone(&recvr)));
+            threads.push(Worker::new(i, Arc::clone(&recvr), Arc::clone(&stats)));
         }
         // Return the instance
         return Self
         {
             sender: sender,
-            threads: threads
+            threads: threads,
+            stats: stats
         };
     }
 }
@@ -69,10 +129,20 @@ impl<const J: usize, const N: usize> Executable for ThreadPool<J,N>
 {
     fn try_submit(&self, job: Job) -> Result<(), Error>
     {
+        // Count the job as queued before a worker can pick it up
+        self.stats.queued.fetch_add(1, Ordering::Relaxed);
         self.sender.try_send(job)
         .map_err(|e|
+        {
+            self.stats.queued.fetch_sub(1, Ordering::Relaxed);
+            self.stats.rejected.fetch_add(1, Ordering::Relaxed);
             Error::new(ErrorKind::ResourceBusy, e.to_string())
-        )
+        })
+    }
+
+    fn stats(&self) -> Option<Arc<PoolStats>>
+    {
+        Some(Arc::clone(&self.stats))
     }
 }
 
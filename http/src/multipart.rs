@@ -0,0 +1,599 @@
+use std::{env, fs::{self, File, OpenOptions}, io::{Error, ErrorKind, Read, Write}, path::{Path, PathBuf}, process, sync::atomic::{AtomicU64, Ordering}, time::{SystemTime, UNIX_EPOCH}};
+
+/// The longest header section accepted for a part
+const MAX_PART_HEAD: usize = 16 * 1024;
+/// Bytes read from the body per call
+const READ_CHUNK: usize = 16 * 1024;
+
+/// Distinguishes spool files created by this process
+static SPOOL_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Limits and spooling of a multipart body
+#[derive(Debug, Clone)]
+pub struct MultipartConfig
+{
+    temp_dir: PathBuf,
+    spool_threshold: usize,
+    max_parts: usize,
+    max_part_size: usize,
+}
+
+/// A file spooled to the temp directory, removed when dropped unless persisted
+#[derive(Debug)]
+pub struct TempFile
+{
+    path: PathBuf,
+    file: Option<File>,
+}
+
+/// Where the contents of a part are kept
+#[derive(Debug)]
+pub enum PartData
+{
+    Memory(Vec<u8>),
+    File(TempFile),
+}
+
+/// A part of a multipart/form-data body
+#[derive(Debug)]
+pub struct Part
+{
+    headers: Vec<String>,
+    name: String,
+    filename: Option<String>,
+    content_type: Option<String>,
+    size: usize,
+    data: PartData,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum State
+{
+    /// Skipping the preamble up to the first delimiter
+    Preamble,
+    /// Right after a delimiter
+    Delimiter,
+    Done,
+}
+
+/// A streaming parser of multipart/form-data bodies, RFC 7578.
+///
+/// Parts are read one at a time. File parts larger than the spool threshold
+/// are written to the temp directory instead of memory.
+pub struct Multipart<'r>
+{
+    reader: Box<dyn Read + 'r>,
+    /// `\r\n--boundary`
+    delimiter: Vec<u8>,
+    buf: Vec<u8>,
+    state: State,
+    config: MultipartConfig,
+    parts: usize,
+}
+
+fn invalid(msg: &str) -> Error
+{
+    Error::new(ErrorKind::InvalidData, format!("Multipart: {msg}"))
+}
+
+/// Gets a parameter of a header value such as `form-data; name="a"`
+pub(crate) fn header_param(value: &str, param: &str) -> Option<String>
+{
+    let mut rest = value.split_once(';')?.1;
+    loop
+    {
+        let (key, after) = rest.split_once('=')?;
+        let after = after.trim_start();
+        let (found, next) = if let Some(quoted) = after.strip_prefix('"')
+        {
+            // Quoted strings may escape quotes and backslashes
+            let mut out = String::new();
+            let mut chars = quoted.char_indices();
+            let mut end = quoted.len();
+            while let Some((i, c)) = chars.next()
+            {
+                match c
+                {
+                    '\\' => out.extend(chars.next().map(|(_, c)| c)),
+                    '"' =>
+                    {
+                        end = i + 1;
+                        break;
+                    },
+                    c => out.push(c),
+                }
+            }
+            let next = quoted[end..].split_once(';').map_or("", |(_, next)| next);
+            (out, next)
+        }
+        else
+        {
+            let (token, next) = after.split_once(';').unwrap_or((after, ""));
+            (token.trim().to_string(), next)
+        };
+        if key.trim().eq_ignore_ascii_case(param)
+        {
+            return Some(found);
+        }
+        rest = next;
+    }
+}
+
+impl Default for MultipartConfig
+{
+    fn default() -> Self
+    {
+        Self
+        {
+            temp_dir: env::temp_dir(),
+            spool_threshold: 64 * 1024,
+            max_parts: 100,
+            max_part_size: 16 * 1024 * 1024,
+        }
+    }
+}
+
+impl MultipartConfig
+{
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+
+    /// Sets the directory file parts are spooled to
+    pub fn with_temp_dir(mut self, temp_dir: impl Into<PathBuf>) -> Self
+    {
+        self.temp_dir = temp_dir.into();
+        self
+    }
+
+    /// Sets the size above which file parts are spooled to disk
+    pub fn with_spool_threshold(mut self, spool_threshold: usize) -> Self
+    {
+        self.spool_threshold = spool_threshold;
+        self
+    }
+
+    /// Sets how many parts a body may have
+    pub fn with_max_parts(mut self, max_parts: usize) -> Self
+    {
+        self.max_parts = max_parts;
+        self
+    }
+
+    /// Sets the largest part accepted, in bytes
+    pub fn with_max_part_size(mut self, max_part_size: usize) -> Self
+    {
+        self.max_part_size = max_part_size;
+        self
+    }
+}
+
+impl TempFile
+{
+    fn create(dir: &Path) -> Result<Self, Error>
+    {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.subsec_nanos());
+        let id = SPOOL_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = dir.join(format!("upload-{}-{id}-{nanos:08x}", process::id()));
+        let file = OpenOptions::new().write(true).create_new(true).open(&path)?;
+        Ok(Self
+        {
+            path: path,
+            file: Some(file),
+        })
+    }
+
+    pub fn path(&self) -> &Path
+    {
+        &self.path
+    }
+
+    /// Moves the file to `to` so it outlives the request
+    pub fn persist(mut self, to: impl AsRef<Path>) -> Result<(), Error>
+    {
+        self.file = None;
+        // Renaming fails across file systems, copy instead
+        if fs::rename(&self.path, to.as_ref()).is_err()
+        {
+            fs::copy(&self.path, to)?;
+            let _ = fs::remove_file(&self.path);
+        }
+        // Nothing is left to remove
+        self.path = PathBuf::new();
+        Ok(())
+    }
+
+    /// Moves the file to `to` unless something exists there already, which
+    /// fails with `AlreadyExists`
+    pub fn persist_new(mut self, to: impl AsRef<Path>) -> Result<(), Error>
+    {
+        self.file = None;
+        // A new link never replaces its target. Linking fails across file
+        // systems, copy instead. The spooled file is removed when dropped.
+        match fs::hard_link(&self.path, to.as_ref())
+        {
+            Err(e) if e.kind() != ErrorKind::AlreadyExists => write_new(to.as_ref(), File::open(&self.path)?),
+            linked => linked,
+        }
+    }
+}
+
+/// Writes `from` to a file that must not exist yet, leaving no partial file
+/// behind when writing fails
+fn write_new(to: &Path, mut from: impl Read) -> Result<(), Error>
+{
+    let mut file = OpenOptions::new().write(true).create_new(true).open(to)?;
+    if let Err(e) = std::io::copy(&mut from, &mut file)
+    {
+        let _ = fs::remove_file(to);
+        return Err(e);
+    }
+    Ok(())
+}
+
+impl Drop for TempFile
+{
+    fn drop(&mut self)
+    {
+        if !self.path.as_os_str().is_empty()
+        {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+impl Part
+{
+    pub fn headers(&self) -> &[String]
+    {
+        &self.headers
+    }
+
+    /// Gets the form field name of the part
+    pub fn name(&self) -> &str
+    {
+        &self.name
+    }
+
+    /// Gets the file name sent with a file part, without any directories
+    pub fn filename(&self) -> Option<&str>
+    {
+        self.filename.as_deref()
+    }
+
+    pub fn content_type(&self) -> Option<&str>
+    {
+        self.content_type.as_deref()
+    }
+
+    /// Gets the size of the contents in bytes
+    pub fn size(&self) -> usize
+    {
+        self.size
+    }
+
+    pub fn data(&self) -> &PartData
+    {
+        &self.data
+    }
+
+    pub fn into_data(self) -> PartData
+    {
+        self.data
+    }
+
+    /// Gets the contents of a part kept in memory
+    pub fn bytes(&self) -> Option<&[u8]>
+    {
+        match &self.data
+        {
+            PartData::Memory(bytes) => Some(bytes),
+            PartData::File(_) => None,
+        }
+    }
+
+    /// Gets the contents of a part kept in memory as text
+    pub fn text(&self) -> Option<String>
+    {
+        self.bytes().map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    /// Writes the contents to `to`, moving a spooled file rather than copying it
+    pub fn save(self, to: impl AsRef<Path>) -> Result<(), Error>
+    {
+        match self.data
+        {
+            PartData::Memory(bytes) => fs::write(to, bytes),
+            PartData::File(file) => file.persist(to),
+        }
+    }
+
+    /// Writes the contents to `to` like `save`, but fails with
+    /// `AlreadyExists` rather than replace a file
+    pub fn save_new(self, to: impl AsRef<Path>) -> Result<(), Error>
+    {
+        match self.data
+        {
+            PartData::Memory(bytes) => write_new(to.as_ref(), &bytes[..]),
+            PartData::File(file) => file.persist_new(to),
+        }
+    }
+}
+
+impl<'r> Multipart<'r>
+{
+    /// Creates a parser for a body with the given `Content-Type` header
+    pub fn new(reader: impl Read + 'r, content_type: &str, config: MultipartConfig) -> Result<Self, Error>
+    {
+        let media_type = content_type.split(';').next().unwrap_or("").trim();
+        if !media_type.eq_ignore_ascii_case("multipart/form-data")
+        {
+            return Err(Error::new(ErrorKind::Unsupported, "Content-Type is not multipart/form-data"));
+        }
+        let boundary = header_param(content_type, "boundary")
+        .filter(|b| !b.is_empty() && b.len() <= 70)
+        .ok_or(invalid("missing or invalid boundary"))?;
+        Ok(Self
+        {
+            reader: Box::new(reader),
+            delimiter: format!("\r\n--{boundary}").into_bytes(),
+            // The first delimiter may open the body without a preceding line break
+            buf: b"\r\n".to_vec(),
+            state: State::Preamble,
+            config: config,
+            parts: 0,
+        })
+    }
+
+    /// Reads more of the body into the buffer
+    fn fill(&mut self) -> Result<(), Error>
+    {
+        let mut chunk = [0u8; READ_CHUNK];
+        let n = self.reader.read(&mut chunk)?;
+        if n == 0
+        {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Multipart: body ended before the closing boundary"));
+        }
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(())
+    }
+
+    fn find(&self, needle: &[u8]) -> Option<usize>
+    {
+        self.buf.windows(needle.len()).position(|w| w == needle)
+    }
+
+    /// Reads the next part, or `None` after the closing boundary.
+    ///
+    /// Errors with `ErrorKind::InvalidData` when the body is malformed or a
+    /// limit is exceeded.
+    pub fn next_part(&mut self) -> Result<Option<Part>, Error>
+    {
+        if self.state == State::Preamble
+        {
+            loop
+            {
+                if let Some(pos) = self.find(&self.delimiter.clone())
+                {
+                    self.buf.drain(..pos + self.delimiter.len());
+                    self.state = State::Delimiter;
+                    break;
+                }
+                // Keep a tail that may hold the start of the delimiter
+                let keep = self.delimiter.len() - 1;
+                if self.buf.len() > keep
+                {
+                    self.buf.drain(..self.buf.len() - keep);
+                }
+                self.fill()?;
+            }
+        }
+        if self.state == State::Done
+        {
+            return Ok(None);
+        }
+        // A delimiter is followed by "--" on the last one, or by a line break
+        while self.buf.len() < 2
+        {
+            self.fill()?;
+        }
+        if self.buf.starts_with(b"--")
+        {
+            self.state = State::Done;
+            return Ok(None);
+        }
+        let head_end = loop
+        {
+            if let Some(pos) = self.find(b"\r\n\r\n")
+            {
+                break pos;
+            }
+            if self.buf.len() > MAX_PART_HEAD
+            {
+                return Err(invalid("part headers too large"));
+            }
+            self.fill()?;
+        };
+        self.parts += 1;
+        if self.parts > self.config.max_parts
+        {
+            return Err(invalid("too many parts"));
+        }
+        let head = String::from_utf8_lossy(&self.buf[..head_end]).into_owned();
+        self.buf.drain(..head_end + 4);
+        let mut lines = head.split("\r\n");
+        // Transport padding may follow the delimiter before the line break
+        if !lines.next().unwrap_or("").trim().is_empty()
+        {
+            return Err(invalid("missing line break after boundary"));
+        }
+        let headers: Vec<String> = lines.map(|line| line.to_string()).collect();
+        let disposition = super::find_header(&headers, "Content-Disposition")
+        .ok_or(invalid("part without Content-Disposition"))?;
+        let name = header_param(disposition, "name").ok_or(invalid("part without a name"))?;
+        // Browsers may send a full path, keep only the last component
+        let filename = header_param(disposition, "filename")
+        .map(|f| f.rsplit(['/', '\\']).next().unwrap_or("").to_string());
+        let content_type = super::find_header(&headers, "Content-Type").map(|t| t.to_string());
+        let (size, data) = self.read_data(filename.is_some())?;
+        Ok(Some(Part
+        {
+            headers: headers,
+            name: name,
+            filename: filename,
+            content_type: content_type,
+            size: size,
+            data: data,
+        }))
+    }
+
+    /// Reads the contents of a part up to the next delimiter
+    fn read_data(&mut self, spoolable: bool) -> Result<(usize, PartData), Error>
+    {
+        let mut memory = Vec::new();
+        let mut spool: Option<TempFile> = None;
+        let mut size = 0;
+        loop
+        {
+            let found = self.find(&self.delimiter.clone());
+            // Without a delimiter, hold back what could be the start of one
+            let end = match found
+            {
+                Some(pos) => pos,
+                None => self.buf.len().saturating_sub(self.delimiter.len() - 1),
+            };
+            size += end;
+            if size > self.config.max_part_size
+            {
+                return Err(invalid("part too large"));
+            }
+            if spool.is_none() && spoolable && memory.len() + end > self.config.spool_threshold
+            {
+                let mut file = TempFile::create(&self.config.temp_dir)?;
+                file.file.as_mut().unwrap().write_all(&memory)?;
+                memory = Vec::new();
+                spool = Some(file);
+            }
+            match spool.as_mut().and_then(|s| s.file.as_mut())
+            {
+                Some(file) => file.write_all(&self.buf[..end])?,
+                None => memory.extend_from_slice(&self.buf[..end]),
+            }
+            self.buf.drain(..end);
+            if found.is_some()
+            {
+                self.buf.drain(..self.delimiter.len());
+                break;
+            }
+            self.fill()?;
+        }
+        let data = match spool
+        {
+            Some(mut file) =>
+            {
+                file.file.take().unwrap().flush()?;
+                PartData::File(file)
+            },
+            None => PartData::Memory(memory),
+        };
+        Ok((size, data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = b"preamble\r\n--XyZ\r\n\
+Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+Hello\r\n--XyZ\r\n\
+Content-Disposition: form-data; name=\"upload\"; filename=\"C:\\\\docs\\\\a \\\"b\\\".txt\"\r\n\
+Content-Type: text/plain\r\n\r\n\
+0123456789abcdef\r\n--XyZ--\r\nepilogue";
+
+    fn config() -> MultipartConfig
+    {
+        MultipartConfig::new().with_spool_threshold(8)
+    }
+
+    #[test]
+    fn test_parts()
+    {
+        // Feed the parser a few bytes at a time so delimiters straddle reads
+        for step in [1, 3, 7, BODY.len()]
+        {
+            let chunks: Vec<&[u8]> = BODY.chunks(step).collect();
+            let reader = chunks.iter().fold(Box::new(std::io::empty()) as Box<dyn Read>, |r, c| Box::new(r.chain(*c)));
+            let mut form = Multipart::new(reader, "multipart/form-data; boundary=\"XyZ\"", config()).unwrap();
+            let title = form.next_part().unwrap().unwrap();
+            assert_eq!((title.name(), title.filename(), title.text().as_deref()), ("title", None, Some("Hello")));
+            let upload = form.next_part().unwrap().unwrap();
+            assert_eq!(upload.name(), "upload");
+            assert_eq!(upload.filename(), Some("a \"b\".txt"));
+            assert_eq!(upload.content_type(), Some("text/plain"));
+            assert_eq!(upload.size(), 16);
+            let PartData::File(file) = upload.data() else
+            {
+                panic!("file part was not spooled");
+            };
+            assert_eq!(fs::read(file.path()).unwrap(), b"0123456789abcdef");
+            let path = file.path().to_path_buf();
+            drop(upload);
+            assert!(!path.exists());
+            assert!(form.next_part().unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_limits()
+    {
+        let mut form = Multipart::new(BODY, "multipart/form-data; boundary=XyZ", config().with_max_parts(1)).unwrap();
+        assert!(form.next_part().is_ok());
+        assert_eq!(form.next_part().unwrap_err().kind(), ErrorKind::InvalidData);
+        let mut form = Multipart::new(BODY, "multipart/form-data; boundary=XyZ", config().with_max_part_size(10)).unwrap();
+        assert!(form.next_part().is_ok());
+        assert!(form.next_part().is_err());
+        let truncated = &BODY[..BODY.len() - 20];
+        let mut form = Multipart::new(truncated, "multipart/form-data; boundary=XyZ", config()).unwrap();
+        assert!(form.next_part().is_ok());
+        assert_eq!(form.next_part().unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        assert!(Multipart::new(BODY, "text/plain", config()).is_err());
+        assert!(Multipart::new(BODY, "multipart/form-data", config()).is_err());
+    }
+
+    #[test]
+    fn test_save_new()
+    {
+        let dir = env::temp_dir().join(format!("multipart-save-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // The title is kept in memory, the upload is spooled
+        for (index, contents) in [(0, "Hello"), (1, "0123456789abcdef")]
+        {
+            let to = dir.join(format!("part-{index}"));
+            let parts = || Multipart::new(BODY, "multipart/form-data; boundary=XyZ", config()).unwrap();
+            let part = |mut form: Multipart| (0..=index).map(|_| form.next_part().unwrap().unwrap()).last().unwrap();
+            part(parts()).save_new(&to).unwrap();
+            assert_eq!(fs::read_to_string(&to).unwrap(), contents);
+            // A second save with the same name leaves the first alone
+            fs::write(&to, "kept").unwrap();
+            assert_eq!(part(parts()).save_new(&to).unwrap_err().kind(), ErrorKind::AlreadyExists);
+            assert_eq!(fs::read_to_string(&to).unwrap(), "kept");
+            part(parts()).save(&to).unwrap();
+            assert_eq!(fs::read_to_string(&to).unwrap(), contents);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_request()
+    {
+        let head = b"POST /upload HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=XyZ\r\n\r\n";
+        let mut request = crate::HttpRequest::new(&head[..]).unwrap();
+        // A buffered body was decoded as text, which file parts do not survive
+        request.content_mut().body = String::from_utf8_lossy(BODY).into_owned();
+        assert_eq!(request.multipart(config()).err().unwrap().kind(), ErrorKind::Other);
+        request.content_mut().body_reader = Some(crate::body::BodyReader::buffered(BODY.to_vec()));
+        assert!(request.multipart(config()).unwrap().next_part().unwrap().is_some());
+    }
+}
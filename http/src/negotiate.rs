@@ -0,0 +1,246 @@
+use super::{HttpRequest, HttpResponse};
+
+/// One member of an `Accept`-style header, such as `text/html;level=1;q=0.8`
+#[derive(Debug, Clone, PartialEq)]
+pub struct QualityItem
+{
+    /// The value, lowercased
+    pub value: String,
+    /// Parameters other than the weight, with lowercased names
+    pub params: Vec<(String, String)>,
+    /// The weight from 0 to 1, where 0 means not acceptable
+    pub quality: f32,
+}
+
+/// Parses a comma separated header of values with optional parameters and
+/// `q` weights, ordered from the highest weight down. Members with an equal
+/// weight keep their order, and a malformed weight counts as 0.
+pub fn parse_quality_list(header: &str) -> Vec<QualityItem>
+{
+    let mut items: Vec<QualityItem> = header.split(',')
+    .filter_map(|member|
+    {
+        let mut parts = member.split(';');
+        let value = parts.next()?.trim().to_ascii_lowercase();
+        if value.is_empty()
+        {
+            return None;
+        }
+        let mut params = Vec::new();
+        let mut quality = 1.0;
+        for param in parts
+        {
+            let Some((name, param_value)) = param.split_once('=') else
+            {
+                continue;
+            };
+            let name = name.trim().to_ascii_lowercase();
+            let param_value = param_value.trim().trim_matches('"');
+            if name == "q"
+            {
+                // Parameters after the weight are extensions, not part of the range
+                quality = param_value.parse::<f32>().ok().filter(|q| (0.0..=1.0).contains(q)).unwrap_or(0.0);
+                break;
+            }
+            params.push((name, param_value.to_string()));
+        }
+        Some(QualityItem
+        {
+            value: value,
+            params: params,
+            quality: quality,
+        })
+    })
+    .collect();
+    items.sort_by(|a, b| b.quality.total_cmp(&a.quality));
+    items
+}
+
+/// Parses an `Accept` header into media ranges. Members that are not
+/// `type/subtype` are dropped.
+pub fn parse_accept(header: &str) -> Vec<QualityItem>
+{
+    let mut ranges = parse_quality_list(header);
+    ranges.retain(|range| matches!(range.value.split_once('/'), Some((t, s)) if !t.is_empty() && !s.is_empty()));
+    ranges
+}
+
+/// Parses an `Accept-Language` header into language ranges
+pub fn parse_accept_language(header: &str) -> Vec<QualityItem>
+{
+    parse_quality_list(header)
+}
+
+/// Parses an `Accept-Charset` header
+pub fn parse_accept_charset(header: &str) -> Vec<QualityItem>
+{
+    parse_quality_list(header)
+}
+
+/// Gets how specifically a media range matches a media type, RFC 9110
+/// section 12.5.1, or `None` when it does not match
+fn media_specificity(range: &QualityItem, offer: &str) -> Option<usize>
+{
+    let mut parts = offer.split(';');
+    let media_type = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+    let (offer_type, offer_subtype) = media_type.split_once('/')?;
+    let (range_type, range_subtype) = range.value.split_once('/')?;
+    let specificity = match (range_type, range_subtype)
+    {
+        ("*", "*") => 0,
+        (t, "*") if t == offer_type => 1,
+        (t, s) if t == offer_type && s == offer_subtype => 2,
+        _ => return None,
+    };
+    // Every parameter of the range must be on the offer
+    let offer_params: Vec<(String, String)> = parts
+    .filter_map(|param| param.split_once('='))
+    .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().trim_matches('"').to_string()))
+    .collect();
+    if !range.params.iter().all(|param| offer_params.contains(param))
+    {
+        return None;
+    }
+    Some(specificity + range.params.len())
+}
+
+/// Gets how specifically a language range matches a tag with the basic
+/// filtering of RFC 4647 section 3.3.1: `en` matches `en` and `en-GB`
+fn language_specificity(range: &QualityItem, offer: &str) -> Option<usize>
+{
+    let offer = offer.to_ascii_lowercase();
+    if range.value == "*"
+    {
+        return Some(0);
+    }
+    let matches = offer == range.value
+    || offer.strip_prefix(range.value.as_str()).is_some_and(|rest| rest.starts_with('-'));
+    matches.then_some(range.value.len())
+}
+
+/// Matches charsets case-insensitively, with `*` matching any
+fn charset_specificity(range: &QualityItem, offer: &str) -> Option<usize>
+{
+    match range.value.as_str()
+    {
+        "*" => Some(0),
+        value if value.eq_ignore_ascii_case(offer) => Some(1),
+        _ => None,
+    }
+}
+
+/// Picks the offer with the highest weight, each weighed by its most
+/// specific matching range. Earlier offers win ties, and every offer is
+/// acceptable when the client sent no usable ranges.
+fn best_offer<'o>(ranges: &[QualityItem], offers: &[&'o str], specificity: fn(&QualityItem, &str) -> Option<usize>) -> Option<&'o str>
+{
+    if ranges.is_empty()
+    {
+        return offers.first().copied();
+    }
+    let mut best: Option<(&str, f32)> = None;
+    for offer in offers
+    {
+        let quality = ranges.iter()
+        .filter_map(|range| specificity(range, offer).map(|s| (s, range.quality)))
+        // The first of equally specific ranges has the highest weight
+        .fold(None, |best: Option<(usize, f32)>, (s, q)| match best
+        {
+            Some((best_s, _)) if best_s >= s => best,
+            _ => Some((s, q)),
+        })
+        .map_or(0.0, |(_, q)| q);
+        if quality > 0.0 && best.is_none_or(|(_, best_q)| quality > best_q)
+        {
+            best = Some((offer, quality));
+        }
+    }
+    best.map(|(offer, _)| offer)
+}
+
+/// Builds the 406 that lists what the resource is available as
+fn not_acceptable(offers: &[&str]) -> HttpResponse
+{
+    HttpResponse::new(406)
+    .with_header("Content-Type", "text/plain; charset=utf-8")
+    .with_body(format!("Available: {}\n", offers.join(", ")))
+}
+
+/// Picks the media type to answer a request with from the offers, most
+/// preferred first, or gives the 406 Not Acceptable to send back.
+///
+/// ```
+/// use http::{negotiate::negotiate, HttpRequest};
+///
+/// let head = "GET /report HTTP/1.1\r\nAccept: text/html;q=0.9, application/json\r\n\r\n";
+/// let request = HttpRequest::new(head.as_bytes()).unwrap();
+/// assert_eq!(negotiate(&request, &["text/html", "application/json"]).unwrap(), "application/json");
+/// ```
+pub fn negotiate<'o>(request: &HttpRequest, offers: &[&'o str]) -> Result<&'o str, HttpResponse>
+{
+    let ranges = request.content().header("Accept").map(parse_accept).unwrap_or_default();
+    best_offer(&ranges, offers, media_specificity).ok_or_else(|| not_acceptable(offers))
+}
+
+/// Picks the language tag to answer a request with, like [`negotiate`]
+pub fn negotiate_language<'o>(request: &HttpRequest, offers: &[&'o str]) -> Result<&'o str, HttpResponse>
+{
+    let ranges = request.content().header("Accept-Language").map(parse_accept_language).unwrap_or_default();
+    best_offer(&ranges, offers, language_specificity).ok_or_else(|| not_acceptable(offers))
+}
+
+/// Picks the charset to answer a request with, like [`negotiate`]
+pub fn negotiate_charset<'o>(request: &HttpRequest, offers: &[&'o str]) -> Result<&'o str, HttpResponse>
+{
+    let ranges = request.content().header("Accept-Charset").map(parse_accept_charset).unwrap_or_default();
+    best_offer(&ranges, offers, charset_specificity).ok_or_else(|| not_acceptable(offers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &str) -> HttpRequest
+    {
+        HttpRequest::new(format!("GET / HTTP/1.1\r\n{headers}\r\n").as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_parse()
+    {
+        let ranges = parse_accept("text/html;level=1;q=0.5, */*;q=0.1, application/JSON, bad, image/*;q=x");
+        let values: Vec<(&str, f32)> = ranges.iter().map(|r| (r.value.as_str(), r.quality)).collect();
+        assert_eq!(values, [("application/json", 1.0), ("text/html", 0.5), ("*/*", 0.1), ("image/*", 0.0)]);
+        assert_eq!(ranges[1].params, [("level".to_string(), "1".to_string())]);
+        let languages = parse_accept_language("da, en-gb;q=0.8, en;q=0.7");
+        assert_eq!(languages.iter().map(|l| l.value.as_str()).collect::<Vec<_>>(), ["da", "en-gb", "en"]);
+    }
+
+    #[test]
+    fn test_negotiate()
+    {
+        let offers = ["text/html", "application/json"];
+        assert_eq!(negotiate(&request(""), &offers).unwrap(), "text/html");
+        assert_eq!(negotiate(&request("Accept: application/json\r\n"), &offers).unwrap(), "application/json");
+        assert_eq!(negotiate(&request("Accept: */*\r\n"), &offers).unwrap(), "text/html");
+        assert_eq!(negotiate(&request("Accept: text/*;q=0.5, */*;q=0.6\r\n"), &offers).unwrap(), "application/json");
+        // The most specific range decides, even when a wildcard weighs more
+        assert_eq!(negotiate(&request("Accept: text/html;q=0, */*\r\n"), &offers).unwrap(), "application/json");
+        assert_eq!(negotiate(&request("Accept: text/html;level=1\r\n"), &["text/html", "text/html;level=1"]).unwrap(), "text/html;level=1");
+        let refused = negotiate(&request("Accept: image/png\r\n"), &offers).unwrap_err();
+        assert_eq!(refused.status(), 406);
+        assert_eq!(refused.body(), b"Available: text/html, application/json\n");
+    }
+
+    #[test]
+    fn test_negotiate_language_and_charset()
+    {
+        let offers = ["en-US", "fr", "de"];
+        assert_eq!(negotiate_language(&request("Accept-Language: fr-CH, fr;q=0.9, en;q=0.8\r\n"), &offers).unwrap(), "fr");
+        assert_eq!(negotiate_language(&request("Accept-Language: en\r\n"), &offers).unwrap(), "en-US");
+        assert_eq!(negotiate_language(&request("Accept-Language: *;q=0.5, de\r\n"), &offers).unwrap(), "de");
+        assert!(negotiate_language(&request("Accept-Language: ja\r\n"), &offers).is_err());
+        assert_eq!(negotiate_charset(&request("Accept-Charset: iso-8859-1;q=0.5, UTF-8\r\n"), &["iso-8859-1", "utf-8"]).unwrap(), "utf-8");
+        assert!(negotiate_charset(&request("Accept-Charset: utf-16\r\n"), &["utf-8"]).is_err());
+    }
+}
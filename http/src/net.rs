@@ -0,0 +1,310 @@
+use std::{fs, io::{Error, ErrorKind, Read, Write}, net::{Shutdown, SocketAddr, TcpListener, TcpStream}, os::{fd::{AsRawFd, FromRawFd, RawFd}, unix::{fs::FileTypeExt, net::{UnixListener, UnixStream}}}, path::PathBuf, time::Duration};
+
+use osafe::ipc::posix_tcp::TcpSocket;
+
+/// Prefix of listener addresses that name a Unix domain socket
+const UNIX_PREFIX: &str = "unix:";
+
+/// A socket the server accepts connections on
+pub enum Listener
+{
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+/// An accepted connection
+pub enum Stream
+{
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Listener
+{
+    /// Binds a listener.
+    ///
+    /// `addr` is either a socket address such as `0.0.0.0:80` or `[::]:80`,
+    /// or a Unix socket path prefixed with `unix:`. A stale socket file left
+    /// at the path is replaced.
+    pub fn bind(addr: &str) -> Result<Self, Error>
+    {
+        if let Some(path) = addr.strip_prefix(UNIX_PREFIX)
+        {
+            let path = PathBuf::from(path);
+            if fs::symlink_metadata(&path).is_ok_and(|meta| meta.file_type().is_socket())
+            {
+                fs::remove_file(&path)?;
+            }
+            let listener = UnixListener::bind(&path)?;
+            return Ok(Listener::Unix(listener, path));
+        }
+        Ok(Listener::Tcp(TcpListener::bind(addr)?))
+    }
+
+    /// Binds an IPv6 listener that leaves IPv4 traffic on the same port to
+    /// another listener
+    pub fn bind_ipv6_only(addr: SocketAddr) -> Result<Self, Error>
+    {
+        let socket = TcpSocket::listen_ipv6_only(&addr.ip().to_string(), addr.port(), 128)
+        .map_err(|e| Error::new(ErrorKind::Other, format!("{e:?}")))?;
+        // The listener takes ownership of the file descriptor
+        let listener = unsafe{TcpListener::from_raw_fd(socket.into_raw_fd())};
+        Ok(Listener::Tcp(listener))
+    }
+
+    /// Gets the address the listener is bound to, in the form accepted by `bind`
+    pub fn local_addr(&self) -> String
+    {
+        match self
+        {
+            Listener::Tcp(listener) => listener.local_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default(),
+            Listener::Unix(_, path) => format!("{UNIX_PREFIX}{}", path.display()),
+        }
+    }
+
+    /// Makes `accept` return `WouldBlock` instead of waiting
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<(), Error>
+    {
+        match self
+        {
+            Listener::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            Listener::Unix(listener, _) => listener.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// Waits for a connection
+    ///
+    /// # Returns
+    ///
+    /// * `Ok((Stream, String))` - The connection and the address of the peer
+    pub fn accept(&self) -> Result<(Stream, String), Error>
+    {
+        match self
+        {
+            Listener::Tcp(listener) =>
+            {
+                let (stream, peer) = listener.accept()?;
+                Ok((Stream::Tcp(stream), peer.to_string()))
+            },
+            Listener::Unix(listener, _) =>
+            {
+                let (stream, peer) = listener.accept()?;
+                // Clients rarely bind their end, so most peers are unnamed
+                let peer = match peer.as_pathname()
+                {
+                    Some(path) => format!("{UNIX_PREFIX}{}", path.display()),
+                    None => UNIX_PREFIX.to_string(),
+                };
+                Ok((Stream::Unix(stream), peer))
+            },
+        }
+    }
+}
+
+impl Drop for Listener
+{
+    fn drop(&mut self)
+    {
+        if let Listener::Unix(_, path) = self
+        {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+impl AsRawFd for Listener
+{
+    fn as_raw_fd(&self) -> RawFd
+    {
+        match self
+        {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener, _) => listener.as_raw_fd(),
+        }
+    }
+}
+
+impl Stream
+{
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<(), Error>
+    {
+        match self
+        {
+            Stream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            Stream::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), Error>
+    {
+        match self
+        {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            Stream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    /// Sets how long a read blocks before failing, `None` blocks forever
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error>
+    {
+        match self
+        {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    /// Sets how long a write blocks before failing, `None` blocks forever
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<(), Error>
+    {
+        match self
+        {
+            Stream::Tcp(stream) => stream.set_write_timeout(timeout),
+            Stream::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+
+    /// Creates another handle to the same connection
+    pub fn try_clone(&self) -> Result<Self, Error>
+    {
+        match self
+        {
+            Stream::Tcp(stream) => Ok(Stream::Tcp(stream.try_clone()?)),
+            Stream::Unix(stream) => Ok(Stream::Unix(stream.try_clone()?)),
+        }
+    }
+}
+
+impl AsRawFd for Stream
+{
+    fn as_raw_fd(&self) -> RawFd
+    {
+        match self
+        {
+            Stream::Tcp(stream) => stream.as_raw_fd(),
+            Stream::Unix(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+impl Read for &Stream
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize>
+    {
+        match self
+        {
+            Stream::Tcp(stream) => (&*stream).read(buf),
+            Stream::Unix(stream) => (&*stream).read(buf),
+        }
+    }
+}
+
+impl Write for &Stream
+{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize>
+    {
+        match self
+        {
+            Stream::Tcp(stream) => (&*stream).write(buf),
+            Stream::Unix(stream) => (&*stream).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()>
+    {
+        match self
+        {
+            Stream::Tcp(stream) => (&*stream).flush(),
+            Stream::Unix(stream) => (&*stream).flush(),
+        }
+    }
+}
+
+impl Read for Stream
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize>
+    {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for Stream
+{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize>
+    {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()>
+    {
+        (&*self).flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, os::unix::net::UnixListener as StdUnixListener, process};
+
+    fn socket_path(name: &str) -> PathBuf
+    {
+        env::temp_dir().join(format!("http-net-{}-{name}.sock", process::id()))
+    }
+
+    #[test]
+    fn test_unix_listener()
+    {
+        let path = socket_path("listener");
+        let addr = format!("{UNIX_PREFIX}{}", path.display());
+        let listener = Listener::bind(&addr).unwrap();
+        assert_eq!(listener.local_addr(), addr);
+        let mut client = UnixStream::connect(&path).unwrap();
+        let (mut stream, peer) = listener.accept().unwrap();
+        // An unbound client has no name
+        assert_eq!(peer, UNIX_PREFIX);
+        client.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        // The socket file goes away with the listener
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_stale_socket()
+    {
+        let path = socket_path("stale");
+        // A socket left behind by a listener that went away without cleaning up
+        drop(StdUnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        let listener = Listener::bind(&format!("{UNIX_PREFIX}{}", path.display())).unwrap();
+        assert!(UnixStream::connect(&path).is_ok());
+        drop(listener);
+        // Anything other than a socket is left alone
+        fs::write(&path, "data").unwrap();
+        let bound = Listener::bind(&format!("{UNIX_PREFIX}{}", path.display()));
+        assert_eq!(bound.err().map(|e| e.kind()), Some(ErrorKind::AddrInUse));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "data");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_ipv6_only()
+    {
+        let v4 = Listener::bind("0.0.0.0:0").unwrap();
+        let port = v4.local_addr().rsplit_once(':').unwrap().1.parse::<u16>().unwrap();
+        // A plain IPv6 wildcard would also claim the IPv4 port
+        assert!(Listener::bind(&format!("[::]:{port}")).is_err());
+        let v6 = Listener::bind_ipv6_only(format!("[::]:{port}").parse().unwrap()).unwrap();
+        assert_eq!(v6.local_addr(), format!("[::]:{port}"));
+        let _client = TcpStream::connect(("::1", port)).unwrap();
+        let (_, peer) = v6.accept().unwrap();
+        assert!(peer.starts_with("[::1]:"));
+        let _client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let (_, peer) = v4.accept().unwrap();
+        assert!(peer.starts_with("127.0.0.1:"));
+    }
+}
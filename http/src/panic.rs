@@ -0,0 +1,117 @@
+use std::{any::Any, cell::{Cell, RefCell}, fmt, panic::{self, AssertUnwindSafe}, sync::Once};
+
+/// A panic caught by [`catch`], with where it happened
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Panic
+{
+    message: String,
+    /// `file:line:column`, when the panic hook saw it
+    location: Option<String>,
+}
+
+thread_local!
+{
+    /// How many calls to `catch` are running on this thread
+    static CATCHING: Cell<usize> = const { Cell::new(0) };
+    /// Where the last panic caught on this thread happened
+    static LOCATION: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+static HOOK: Once = Once::new();
+
+impl Panic
+{
+    pub fn message(&self) -> &str
+    {
+        &self.message
+    }
+
+    pub fn location(&self) -> Option<&str>
+    {
+        self.location.as_deref()
+    }
+}
+
+impl fmt::Display for Panic
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match &self.location
+        {
+            Some(location) => write!(f, "'{}' at {location}", self.message),
+            None => write!(f, "'{}'", self.message),
+        }
+    }
+}
+
+/// Installs a panic hook that records where caught panics happen instead
+/// of printing them. Panics outside `catch` still go to the previous hook.
+fn install_hook()
+{
+    HOOK.call_once(||
+    {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info|
+        {
+            if CATCHING.with(Cell::get) > 0
+            {
+                LOCATION.with(|location| *location.borrow_mut() = info.location().map(|l| l.to_string()));
+            }
+            else
+            {
+                previous(info);
+            }
+        }));
+    });
+}
+
+/// Gets the message a panic was raised with
+fn payload_message(payload: &(dyn Any + Send)) -> String
+{
+    match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>())
+    {
+        (Some(message), _) => message.to_string(),
+        (_, Some(message)) => message.clone(),
+        _ => "Box<dyn Any>".to_string(),
+    }
+}
+
+/// Runs `f`, turning a panic into an error instead of unwinding further.
+///
+/// Whatever `f` shares with the caller may be left half updated by a
+/// panic, and is treated as unwind safe. A hook installed by the
+/// application after the first call takes over reporting the location.
+pub fn catch<R>(f: impl FnOnce() -> R) -> Result<R, Panic>
+{
+    install_hook();
+    CATCHING.with(|catching| catching.set(catching.get() + 1));
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    CATCHING.with(|catching| catching.set(catching.get() - 1));
+    result.map_err(|payload| Panic
+    {
+        message: payload_message(payload.as_ref()),
+        location: LOCATION.with(|location| location.borrow_mut().take()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_catch()
+    {
+        assert_eq!(catch(|| 7), Ok(7));
+        let line = line!() + 1;
+        let caught = catch(|| panic!("bad index {}", 3)).unwrap_err();
+        assert_eq!(caught.message(), "bad index 3");
+        assert!(caught.location().unwrap().starts_with(&format!("{}:{line}:", file!())));
+        // Nested catches each see their own panic
+        let outer = catch(||
+        {
+            assert!(catch(|| panic!("inner")).is_err());
+            panic!("outer");
+        });
+        assert_eq!(outer.unwrap_err().message(), "outer");
+    }
+}
@@ -0,0 +1,202 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{conditional::EntityTag, date::parse_http_date};
+
+/// The most ranges served in one response. Requests for more get the
+/// whole representation instead.
+pub const MAX_RANGES: usize = 32;
+
+/// An inclusive range of byte offsets, clamped to the representation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange
+{
+    pub start: u64,
+    pub end: u64,
+}
+
+/// How to answer a `Range` header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ranges
+{
+    /// Send the whole representation: the header is malformed, uses
+    /// another unit or asks for too many ranges
+    Full,
+    /// Send these ranges, sorted with overlaps merged
+    Partial(Vec<ByteRange>),
+    /// Answer with 416 Range Not Satisfiable
+    Unsatisfiable,
+}
+
+impl ByteRange
+{
+    /// Gets the number of bytes in the range
+    pub fn size(&self) -> u64
+    {
+        self.end - self.start + 1
+    }
+
+    /// Formats the range as a `Content-Range` header value
+    pub fn content_range(&self, complete_length: u64) -> String
+    {
+        format!("bytes {}-{}/{complete_length}", self.start, self.end)
+    }
+}
+
+/// Parses a `Range` header for a representation of `len` bytes, RFC 9110
+/// section 14.1.2
+pub fn parse_range(value: &str, len: u64) -> Ranges
+{
+    let Some((unit, specs)) = value.split_once('=') else
+    {
+        return Ranges::Full;
+    };
+    if !unit.trim().eq_ignore_ascii_case("bytes")
+    {
+        return Ranges::Full;
+    }
+    if specs.trim().is_empty()
+    {
+        return Ranges::Full;
+    }
+    let mut ranges = Vec::new();
+    for spec in specs.split(',').map(str::trim).filter(|spec| !spec.is_empty())
+    {
+        let Some((first, last)) = spec.split_once('-') else
+        {
+            return Ranges::Full;
+        };
+        let (first, last) = (first.trim(), last.trim());
+        let range = if first.is_empty()
+        {
+            // A suffix of the last `last` bytes
+            let Ok(suffix) = last.parse::<u64>() else
+            {
+                return Ranges::Full;
+            };
+            (suffix > 0 && len > 0).then(|| ByteRange
+            {
+                start: len.saturating_sub(suffix),
+                end: len - 1,
+            })
+        }
+        else
+        {
+            let Ok(start) = first.parse::<u64>() else
+            {
+                return Ranges::Full;
+            };
+            let end = match last
+            {
+                "" => u64::MAX,
+                last => match last.parse::<u64>()
+                {
+                    Ok(end) if end >= start => end,
+                    _ => return Ranges::Full,
+                },
+            };
+            (start < len).then(|| ByteRange
+            {
+                start: start,
+                end: end.min(len - 1),
+            })
+        };
+        ranges.extend(range);
+    }
+    if ranges.is_empty()
+    {
+        return Ranges::Unsatisfiable;
+    }
+    // Merge overlapping and adjacent ranges so they cannot amplify a response
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges
+    {
+        match merged.last_mut()
+        {
+            Some(last) if range.start <= last.end.saturating_add(1) => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    if merged.len() > MAX_RANGES
+    {
+        return Ranges::Full;
+    }
+    Ranges::Partial(merged)
+}
+
+/// Evaluates an `If-Range` header, RFC 9110 section 13.1.5.
+///
+/// The range is honoured only when the validator names the current
+/// representation: a strong ETag match or the exact modification date.
+pub fn if_range_matches(value: &str, etag: Option<&EntityTag>, last_modified: Option<SystemTime>) -> bool
+{
+    let value = value.trim();
+    if value.starts_with('"') || value.starts_with("W/")
+    {
+        return match (EntityTag::parse(value), etag)
+        {
+            (Some(tag), Some(etag)) => tag.strong_eq(etag),
+            _ => false,
+        };
+    }
+    match (parse_http_date(value), last_modified)
+    {
+        (Some(date), Some(modified)) => modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).ok()
+            == date.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).ok(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn range(start: u64, end: u64) -> ByteRange
+    {
+        ByteRange
+        {
+            start: start,
+            end: end
+        }
+    }
+
+    #[test]
+    fn test_parse()
+    {
+        assert_eq!(parse_range("bytes=0-499", 1000), Ranges::Partial(vec![range(0, 499)]));
+        assert_eq!(parse_range("bytes=500-", 1000), Ranges::Partial(vec![range(500, 999)]));
+        assert_eq!(parse_range("bytes=-200", 1000), Ranges::Partial(vec![range(800, 999)]));
+        assert_eq!(parse_range("bytes=-2000", 1000), Ranges::Partial(vec![range(0, 999)]));
+        assert_eq!(parse_range("bytes=900-5000", 1000), Ranges::Partial(vec![range(900, 999)]));
+        assert_eq!(parse_range("Bytes = 0-0, -1", 1000), Ranges::Partial(vec![range(0, 0), range(999, 999)]));
+        // Overlapping and adjacent ranges are merged in order
+        assert_eq!(parse_range("bytes=500-600,0-10,11-20,550-700", 1000), Ranges::Partial(vec![range(0, 20), range(500, 700)]));
+        // Unsatisfiable ranges are dropped, and 416 only when none is left
+        assert_eq!(parse_range("bytes=2000-,0-1", 1000), Ranges::Partial(vec![range(0, 1)]));
+        assert_eq!(parse_range("bytes=1000-", 1000), Ranges::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 1000), Ranges::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), Ranges::Unsatisfiable);
+        // Malformed headers and other units are ignored
+        assert_eq!(parse_range("bytes=5-1", 1000), Ranges::Full);
+        assert_eq!(parse_range("bytes=a-b", 1000), Ranges::Full);
+        assert_eq!(parse_range("bytes=", 1000), Ranges::Full);
+        assert_eq!(parse_range("items=0-1", 1000), Ranges::Full);
+        let many: Vec<String> = (0..40).map(|i| format!("{}-{}", i * 10, i * 10)).collect();
+        assert_eq!(parse_range(&format!("bytes={}", many.join(",")), 1000), Ranges::Full);
+    }
+
+    #[test]
+    fn test_if_range()
+    {
+        let etag = EntityTag::strong("v1");
+        let modified = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        assert!(if_range_matches("\"v1\"", Some(&etag), Some(modified)));
+        assert!(!if_range_matches("\"v2\"", Some(&etag), Some(modified)));
+        assert!(!if_range_matches("W/\"v1\"", Some(&etag), Some(modified)));
+        assert!(if_range_matches("Sun, 06 Nov 1994 08:49:37 GMT", Some(&etag), Some(modified)));
+        assert!(!if_range_matches("Sun, 06 Nov 1994 08:49:38 GMT", Some(&etag), Some(modified)));
+        assert!(!if_range_matches("garbage", Some(&etag), Some(modified)));
+    }
+}
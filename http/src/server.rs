@@ -1,16 +1,43 @@
-use std::{fs, io::{Error, Write}, net::{TcpListener, TcpStream}};
+use std::{borrow::Cow, collections::HashMap, io::{Error, ErrorKind, Read, Write}, mem, net::{Shutdown, SocketAddr}, sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex}, thread, time::{Duration, Instant}};
 
-use super::{mp::Executable, HttpRequest, HttpResponse};
+use event_loop::{Adopted, EventLoop, LoopHandle, Protocol};
+use router::{Middleware, Next, RouteTable, Router};
+use session::Sessions;
+use watchdog::Watchdog;
 
-pub type HttpHandler = fn(server: HttpRequest) -> Result<HttpResponse, Error>;
+use super::{body::{BodyReader, BodySource, Framing}, cancel::Cancellation, compress::Compression, conditional::Preconditions, error::IntoResponse, files::StaticFile, h2, metrics::Metrics, mp::Executable, net::{Listener, Stream}, panic::catch, reason_phrase, HttpRequest, HttpResponse, MAX_BODY_SIZE};
 
-#[allow(dead_code)]
+mod event_loop;
+pub mod router;
+pub mod routes;
+pub mod session;
+mod watchdog;
+
+/// Handles the requests of a route. Any function taking the request and
+/// returning something that converts into a response is a handler.
+pub trait HttpHandler: Sync
+{
+    fn call(&self, request: HttpRequest) -> HttpResponse;
+}
+
+/// Builds the page for an error status from the error message, or the
+/// reason phrase when there is none
+pub type ErrorPage = fn(status: u16, message: &str) -> HttpResponse;
+
+#[derive(Clone)]
 pub struct HttpRouteHandler
 {
-    route: &'static str,
-    handler: HttpHandler
+    route: Cow<'static, str>,
+    handler: &'static dyn HttpHandler,
+    /// The body size limit of a route receiving its body as a stream
+    stream_limit: Option<usize>,
+    /// The name URLs for the route are built by
+    name: Option<&'static str>,
+    /// How long the handler may run before the request is answered with 504
+    timeout: Option<Duration>
 }
 
+#[derive(Clone)]
 pub enum HttpMethodHandler
 {
     Get(HttpRouteHandler),
@@ -26,47 +53,799 @@ pub enum HttpMethodHandler
 
 pub struct HttpServer<'a>
 {
-    listener: TcpListener,
-    handlers: &'a [HttpMethodHandler],
-    thread_pool: &'a dyn Executable
+    listeners: Vec<Listener>,
+    routes: RouteTable,
+    thread_pool: &'a dyn Executable,
+    metrics: Option<(String, Arc<Metrics>)>,
+    sessions: Option<Arc<Sessions>>,
+    compression: Option<Compression>,
+    error_pages: HashMap<u16, ErrorPage>
 }
 
 struct HttpProcessor
 {
-    // handlers: &'a [HttpMethodHandler],
+    routes: RouteTable,
+    /// The metrics route and the metrics it exposes
+    metrics: Option<(String, Arc<Metrics>)>,
+    sessions: Option<Arc<Sessions>>,
+    compression: Option<Compression>,
+    error_pages: HashMap<u16, ErrorPage>,
+    /// Handlers that ran past their timeout and are still running
+    timed_out: Arc<AtomicUsize>,
+    /// Answers for handlers running past their timeout
+    watchdog: Watchdog
+}
+
+/// Sends the response to a request from another thread than the one
+/// serving it, for a handler that ran past its timeout
+type Reply = Box<dyn FnOnce(HttpResponse) + Send>;
+
+/// A `Reply` that finishes the response for the route it is given first
+type LateReply = Box<dyn FnOnce(&str, HttpResponse) + Send>;
+
+/// A handler and what it runs with, ready to run on any thread
+struct HandlerJob
+{
+    endpoint: &'static dyn HttpHandler,
+    middleware: Vec<Arc<dyn Middleware>>,
+    sessions: Option<Arc<Sessions>>,
+    metrics: Option<Arc<Metrics>>,
+    /// The route pattern, for the log
+    route: String
+}
+
+/// The interim response inviting a client to send the body it announced
+const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+/// How long an idle keep-alive connection holds a worker
+pub(crate) const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many handlers may keep running past their timeout before routes with
+/// a timeout are answered with 503 instead of starting more
+const MAX_TIMED_OUT_HANDLERS: usize = 32;
+
+/// How long answering for a handler that timed out may wait on the client
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A stream that reports the bytes passing through it
+struct MeteredStream
+{
+    inner: Stream,
+    metrics: Option<Arc<Metrics>>
+}
+
+impl Read for MeteredStream
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize>
+    {
+        let n = self.inner.read(buf)?;
+        if let Some(metrics) = &self.metrics
+        {
+            metrics.bytes_in(n);
+        }
+        Ok(n)
+    }
+}
+
+impl Write for MeteredStream
+{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize>
+    {
+        let n = self.inner.write(buf)?;
+        if let Some(metrics) = &self.metrics
+        {
+            metrics.bytes_out(n);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()>
+    {
+        self.inner.flush()
+    }
+}
+
+/// Gets the status answering a request whose body framing is unusable
+fn body_error_status(e: &Error) -> u16
+{
+    match e.kind()
+    {
+        ErrorKind::Unsupported => 501,
+        _ => 400,
+    }
+}
+
+/// Reads more bytes into `buf`, returning how many were read
+fn read_more<R: Read>(reader: &mut R, buf: &mut Vec<u8>) -> Result<usize, Error>
+{
+    let mut chunk = [0u8; 8192];
+    let n = reader.read(&mut chunk)?;
+    buf.extend_from_slice(&chunk[..n]);
+    Ok(n)
+}
+
+impl<F, R> HttpHandler for F
+where
+    F: Fn(HttpRequest) -> R + Sync,
+    R: IntoResponse,
+{
+    fn call(&self, request: HttpRequest) -> HttpResponse
+    {
+        self(request).into_response()
+    }
+}
+
+impl HttpRouteHandler
+{
+    pub const fn new(route: &'static str, handler: &'static dyn HttpHandler) -> Self
+    {
+        Self
+        {
+            route: Cow::Borrowed(route),
+            handler: handler,
+            stream_limit: None,
+            name: None,
+            timeout: None
+        }
+    }
+
+    /// Creates a route whose path is only known at runtime, such as one
+    /// added to a `RouteTable` while the server is running
+    pub fn owned(route: impl Into<String>, handler: &'static dyn HttpHandler) -> Self
+    {
+        Self
+        {
+            route: Cow::Owned(route.into()),
+            ..Self::new("", handler)
+        }
+    }
+
+    /// Hands the request body to the handler as a `BodyReader` instead of
+    /// buffering it, allowing bodies of up to `limit` bytes
+    pub const fn streaming(mut self, limit: usize) -> Self
+    {
+        self.stream_limit = Some(limit);
+        self
+    }
+
+    /// Names the route, so URLs for it can be built with `url_for`
+    pub const fn named(mut self, name: &'static str) -> Self
+    {
+        self.name = Some(name);
+        self
+    }
+
+    /// Answers with `504 Gateway Timeout` when the handler runs longer than
+    /// `timeout`. The handler is told to stop through
+    /// `HttpContent::cancellation` but not stopped, and holds its worker
+    /// until it returns.
+    pub const fn timeout(mut self, timeout: Duration) -> Self
+    {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub const fn route(&self) -> &str
+    {
+        match &self.route
+        {
+            Cow::Borrowed(route) => route,
+            Cow::Owned(route) => route.as_str(),
+        }
+    }
+
+    pub const fn name(&self) -> Option<&'static str>
+    {
+        self.name
+    }
+
+    /// Gets the body size limit if the route streams its body
+    pub fn stream_limit(&self) -> Option<usize>
+    {
+        self.stream_limit
+    }
+
+    /// Gets how long the handler may run, if the route has a timeout
+    pub fn handler_timeout(&self) -> Option<Duration>
+    {
+        self.timeout
+    }
+}
+
+impl HttpMethodHandler
+{
+    /// Gets the method name the handler answers
+    pub const fn method(&self) -> &'static str
+    {
+        match self
+        {
+            HttpMethodHandler::Get(_) => "GET",
+            HttpMethodHandler::Head(_) => "HEAD",
+            HttpMethodHandler::Post(_) => "POST",
+            HttpMethodHandler::Put(_) => "PUT",
+            HttpMethodHandler::Delete(_) => "DELETE",
+            HttpMethodHandler::Connect(_) => "CONNECT",
+            HttpMethodHandler::Options(_) => "OPTIONS",
+            HttpMethodHandler::Trace(_) => "TRACE",
+            HttpMethodHandler::Patch(_) => "PATCH",
+        }
+    }
+
+    pub const fn route_handler(&self) -> &HttpRouteHandler
+    {
+        match self
+        {
+            HttpMethodHandler::Get(handler) |
+            HttpMethodHandler::Head(handler) |
+            HttpMethodHandler::Post(handler) |
+            HttpMethodHandler::Put(handler) |
+            HttpMethodHandler::Delete(handler) |
+            HttpMethodHandler::Connect(handler) |
+            HttpMethodHandler::Options(handler) |
+            HttpMethodHandler::Trace(handler) |
+            HttpMethodHandler::Patch(handler) => handler,
+        }
+    }
+}
+
+impl HandlerJob
+{
+    /// Runs the handler inside its middleware. Failing or panicking
+    /// handlers are answered with 500.
+    fn run(&self, http_request: HttpRequest) -> HttpResponse
+    {
+        let route = &self.route;
+        let endpoint = |request: HttpRequest| self.call_handler(request)
+        .unwrap_or_else(|e|
+        {
+            println!("Handler for {route} failed: {e}");
+            HttpResponse::new(500)
+        });
+        // A panicking handler fails its own request, not the worker
+        match catch(|| Next::new(&self.middleware, &endpoint).run(http_request))
+        {
+            Ok(response) => response,
+            Err(panic) =>
+            {
+                println!("Handler for {route} panicked: {panic}");
+                if let Some(metrics) = &self.metrics
+                {
+                    metrics.handler_panic();
+                }
+                HttpResponse::new(500)
+            },
+        }
+    }
+
+    /// Runs the handler with the request's session attached, then saves the
+    /// session and adds its cookie to the response
+    fn call_handler(&self, mut http_request: HttpRequest) -> Result<HttpResponse, Error>
+    {
+        let Some(sessions) = &self.sessions else
+        {
+            return Ok(self.endpoint.call(http_request));
+        };
+        let session = sessions.load(http_request.content())?;
+        http_request.content_mut().session = Some(session.clone());
+        let response = self.endpoint.call(http_request);
+        Ok(sessions.commit(&session, response))
+    }
 }
 
 impl HttpProcessor
 {
-    pub fn new() -> Self
+    pub fn new(routes: RouteTable, metrics: Option<(String, Arc<Metrics>)>, sessions: Option<Arc<Sessions>>, compression: Option<Compression>, error_pages: HashMap<u16, ErrorPage>) -> Self
     {
-        // Self
-        // {
-        //     handlers: handlers
-        // }
-        Self{}
+        Self
+        {
+            routes: routes,
+            metrics: metrics,
+            sessions: sessions,
+            compression: compression,
+            error_pages: error_pages,
+            timed_out: Arc::new(AtomicUsize::new(0)),
+            watchdog: Watchdog::new()
+        }
     }
 
-    fn conn_handler(&self, mut stream: TcpStream) -> Result<(), Error>
+    /// Produces the response for a request along with the route it matched
+    /// in `router`, the routes of the moment the request arrived
+    fn respond(&self, router: &Arc<Router>, http_request: HttpRequest, late: Option<LateReply>) -> (String, HttpResponse)
     {
-       
-        let http_request = HttpRequest::new(&stream)?;
-        match http_request {
+        match &http_request {
             HttpRequest::Get(content) =>
             {
                 println!("Http GET Request: {}", content.route)
             },
             _ => {}
         }
-        let status_line = "HTTP/1.1 200 OK";
-        let contents = fs::read_to_string("hello.html").unwrap();
-        let length = contents.len();
+        // Serve the metrics endpoint
+        if let (HttpRequest::Get(content) | HttpRequest::Head(content), Some((route, metrics))) = (&http_request, &self.metrics)
+        {
+            if content.path() == route
+            {
+                let response = HttpResponse::new(200)
+                .with_header("Content-Type", "text/plain; version=0.0.4")
+                .with_body(metrics.render());
+                return (route.clone(), response);
+            }
+        }
+        let resolved = router.resolve(http_request.content().path());
+        let handler = Self::find_handler(router, &http_request);
+        // Answer OPTIONS for paths without a handler of their own
+        if let HttpRequest::Options(content) = &http_request
+        {
+            if handler.is_none()
+            {
+                let route = match resolved.route()
+                {
+                    Some(route) => resolved.pattern(Some(route)),
+                    None => "*".to_string(),
+                };
+                let response = HttpResponse::new(204).with_header("Allow", &self.allowed_methods(router, content.path()).join(", "));
+                return (route, response);
+            }
+        }
+        // Run the handler for the method and route, or the nearest not-found
+        // handler, inside the middleware of the routers passed through
+        if let Some(endpoint) = handler.map(|h| h.route_handler().handler).or(resolved.not_found())
+        {
+            let route = resolved.pattern(handler.map(|h| h.route_handler().route()));
+            // HEAD runs the GET handler when it has none of its own
+            let mut http_request = match http_request
+            {
+                HttpRequest::Head(content) if handler.is_some_and(|h| h.method() == "GET") => HttpRequest::Get(content),
+                http_request => http_request,
+            };
+            let cancellation = http_request.content().cancellation().clone();
+            let content = http_request.content_mut();
+            content.state = resolved.state();
+            content.params = handler.map(|h| resolved.params(h.route_handler().route())).unwrap_or_default();
+            content.router = Some(Arc::clone(router));
+            // Keep the conditional headers to evaluate against the response
+            let preconditions = Preconditions::of(&http_request);
+            let job = HandlerJob
+            {
+                endpoint: endpoint,
+                middleware: resolved.middleware(),
+                sessions: self.sessions.clone(),
+                metrics: self.metrics().cloned(),
+                route: route.clone()
+            };
+            let response = match handler.and_then(|h| h.route_handler().handler_timeout())
+            {
+                Some(timeout) => self.run_with_timeout(job, http_request, timeout, cancellation, late),
+                None => job.run(http_request),
+            };
+            return (route, preconditions.apply(response));
+        }
+        let response = StaticFile::new("hello.html").respond(&http_request)
+        .unwrap_or_else(|e|
+        {
+            println!("Failed to serve hello.html: {e}");
+            HttpResponse::new(if e.kind() == ErrorKind::NotFound { 404 } else { 500 })
+        });
+        ("*".to_string(), response)
+    }
+
+    /// Runs a handler, answering 504 through `late` when it does not finish
+    /// within `timeout`. A handler running late is cancelled and left to
+    /// finish on this thread, and its response is dropped.
+    fn run_with_timeout(&self, job: HandlerJob, http_request: HttpRequest, timeout: Duration, cancellation: Cancellation, late: Option<LateReply>) -> HttpResponse
+    {
+        let route = job.route.clone();
+        let metrics = job.metrics.clone();
+        // Each handler still running past its timeout holds a worker
+        let retry_after = timeout.as_secs().max(1).to_string();
+        if self.timed_out.load(Ordering::Acquire) >= MAX_TIMED_OUT_HANDLERS
+        {
+            println!("Handler for {route} not started, {MAX_TIMED_OUT_HANDLERS} timed out handlers are still running");
+            return HttpResponse::new(503).with_header("Retry-After", &retry_after);
+        }
+        let timed_out = Arc::clone(&self.timed_out);
+        let expire = move ||
+        {
+            cancellation.cancel();
+            timed_out.fetch_add(1, Ordering::AcqRel);
+            println!("Handler for {route} timed out after {timeout:?}");
+            if let Some(metrics) = metrics
+            {
+                metrics.handler_timeout(&route);
+            }
+            if let Some(late) = late
+            {
+                late(&route, HttpResponse::new(504));
+            }
+        };
+        let watch = match self.watchdog.watch(Instant::now() + timeout, expire)
+        {
+            Ok(watch) => watch,
+            Err(e) =>
+            {
+                println!("Failed to watch the handler for {}: {e}", job.route);
+                return HttpResponse::new(503).with_header("Retry-After", &retry_after);
+            }
+        };
+        let response = job.run(http_request);
+        if watch.finish()
+        {
+            return response;
+        }
+        self.timed_out.fetch_sub(1, Ordering::AcqRel);
+        HttpResponse::new(504)
+    }
 
-        let response =
-            format!("{status_line}\r\nContent-Length: {length}\r\n\r\n{contents}");
+    /// Finds the handler registered for the method and route of a request
+    /// in the router it is under. HEAD requests fall back to the GET handler.
+    fn find_handler<'r>(router: &'r Router, http_request: &HttpRequest) -> Option<&'r HttpMethodHandler>
+    {
+        let resolved = router.resolve(http_request.content().path());
+        match http_request
+        {
+            HttpRequest::Head(_) => resolved.find("HEAD").or_else(|| resolved.find("GET")),
+            _ => resolved.find(http_request.method()),
+        }
+    }
 
-        stream.write_all(response.as_bytes()).unwrap();
-        Ok(())
+    /// Gets the methods a path answers, for the `Allow` header. `*` lists
+    /// every method the server answers on any path.
+    fn allowed_methods(&self, router: &Router, path: &str) -> Vec<&'static str>
+    {
+        let registered: Vec<&'static str> = match path
+        {
+            "*" => router.all_routes().into_iter().map(|(_, h)| h.method()).collect(),
+            path => router.resolve(path).methods(),
+        };
+        // Unregistered paths and the metrics route are served to GET
+        let metrics = self.metrics.as_ref().is_some_and(|(route, _)| route == path);
+        let get = path == "*" || metrics || registered.is_empty() || registered.contains(&"GET");
+        let methods = ["GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH"];
+        methods.into_iter()
+        .filter(|method| match *method
+        {
+            "GET" | "HEAD" => get || registered.contains(method),
+            "OPTIONS" => true,
+            _ => registered.contains(method),
+        })
+        .collect()
+    }
+
+    /// Checks the `Expect` header of a request before its body is read,
+    /// RFC 9110 section 10.1.1.
+    ///
+    /// Returns whether to send `100 Continue`, or the response refusing the
+    /// body. The body size must have been checked against its limit already.
+    fn expectation(http_request: &HttpRequest) -> Result<bool, HttpResponse>
+    {
+        let content = http_request.content();
+        let Some(expect) = content.header("Expect") else
+        {
+            return Ok(false);
+        };
+        // HTTP/1.0 clients do not wait for a 100
+        if content.http_version() == "HTTP/1.0"
+        {
+            return Ok(false);
+        }
+        if !expect.eq_ignore_ascii_case("100-continue")
+        {
+            return Err(HttpResponse::new(417));
+        }
+        // Every request gets an answer from a handler, a not-found handler
+        // or the fallback page, so the body is always wanted
+        Ok(true)
+    }
+
+    /// Gets the body size limit of a streaming route
+    fn stream_limit(router: &Router, http_request: &HttpRequest) -> Option<usize>
+    {
+        Self::find_handler(router, http_request).and_then(|h| h.route_handler().stream_limit())
+    }
+
+    fn metrics(&self) -> Option<&Arc<Metrics>>
+    {
+        self.metrics.as_ref().map(|(_, metrics)| metrics)
+    }
+
+    /// Replaces the body of an error response with the page registered for
+    /// its status. Bodies a handler wrote itself are left alone.
+    fn error_page(&self, mut response: HttpResponse) -> HttpResponse
+    {
+        let Some(page) = self.error_pages.get(&response.status) else
+        {
+            return response;
+        };
+        if response.body_len() > 0 && response.error.is_none()
+        {
+            return response;
+        }
+        let message = response.error.take().unwrap_or_else(|| reason_phrase(response.status).to_string());
+        let page = page(response.status, &message);
+        response.headers.retain(|line| !line.split_once(':').is_some_and(|(name, _)|
+            name.trim().eq_ignore_ascii_case("Content-Type") || name.trim().eq_ignore_ascii_case("Content-Length")));
+        response.headers.extend(page.headers);
+        response.body = page.body;
+        response
+    }
+
+    /// Runs a parsed request through the handlers of `router` and records
+    /// it. A handler running past its timeout is answered for through
+    /// `reply` rather than by the response returned, which is then only
+    /// what the late handler left.
+    fn process(self: &Arc<Self>, router: &Arc<Router>, http_request: HttpRequest, reply: Option<Reply>) -> HttpResponse
+    {
+        let start = Instant::now();
+        let method = http_request.method();
+        let accept_encoding = http_request.content().header("Accept-Encoding").map(str::to_string);
+        let cancellation = http_request.content().cancellation().clone();
+        let replied = reply.is_some();
+        let late = reply.map(|reply|
+        {
+            let processor = Arc::clone(self);
+            let accept_encoding = accept_encoding.clone();
+            Box::new(move |route: &str, response| reply(processor.finish(route, method, accept_encoding.as_deref(), start, response))) as LateReply
+        });
+        let (route, response) = self.respond(router, http_request, late);
+        if replied && cancellation.is_cancelled()
+        {
+            return response;
+        }
+        self.finish(&route, method, accept_encoding.as_deref(), start, response)
+    }
+
+    /// Applies the error pages and compression to a response, and records it
+    fn finish(&self, route: &str, method: &'static str, accept_encoding: Option<&str>, start: Instant, mut response: HttpResponse) -> HttpResponse
+    {
+        if response.status >= 400
+        {
+            response = self.error_page(response);
+        }
+        if let Some(compression) = &self.compression
+        {
+            response = compression.apply(accept_encoding, response);
+        }
+        if method == "HEAD"
+        {
+            response = response.into_head();
+        }
+        if let Some(metrics) = self.metrics()
+        {
+            metrics.request(method, route, response.status(), start.elapsed());
+        }
+        response
+    }
+
+    /// Serves a request that arrived on an HTTP/2 stream, with the body
+    /// read from `body` as the client sends it. The body of a request that
+    /// asked for the upgrade to HTTP/2 was read already.
+    fn serve_stream(self: &Arc<Self>, mut http_request: HttpRequest, body: Option<h2::RequestBody>, listener: &str, peer: &str, reply: Reply) -> HttpResponse
+    {
+        // The request is served with the routes of the moment it arrived
+        let router = self.routes.load();
+        let stream_limit = Self::stream_limit(&router, &http_request);
+        let content = http_request.content_mut();
+        content.listener = listener.to_string();
+        content.peer = peer.to_string();
+        let Some(body) = body else
+        {
+            return self.process(&router, http_request, Some(reply));
+        };
+        let limit = stream_limit.unwrap_or(MAX_BODY_SIZE);
+        let body = Arc::new(Mutex::new(BodySource::until_eof(Box::new(body), limit)));
+        if stream_limit.is_some()
+        {
+            content.body_reader = Some(BodyReader::new(Arc::clone(&body)));
+        }
+        else
+        {
+            let mut bytes = Vec::new();
+            let read = body.lock().unwrap().read_to_end(&mut bytes);
+            if let Err(e) = read
+            {
+                // A stream the client reset gets no answer whatever the status
+                let status = match e.kind()
+                {
+                    ErrorKind::InvalidData if body.lock().unwrap().exceeded() => 413,
+                    ErrorKind::TimedOut => 408,
+                    _ => 400,
+                };
+                return HttpResponse::new(status);
+            }
+            content.body = String::from_utf8_lossy(&bytes).into_owned();
+        }
+        let response = self.process(&router, http_request, Some(reply));
+        if body.lock().unwrap().exceeded()
+        {
+            return HttpResponse::new(413);
+        }
+        response
+    }
+
+    /// Serves a connection accepted by a blocking server, returning it when
+    /// it switches to HTTP/2
+    fn conn_handler(self: &Arc<Self>, stream: Stream, listener: &str, peer: &str) -> Result<Option<Adopted>, Error>
+    {
+        let guard = self.metrics().map(|metrics| metrics.connection());
+        let adopted = self.http1_handler(stream, Vec::new(), listener, peer, false)?;
+        Ok(adopted.map(|adopted| adopted.with_guard(guard)))
+    }
+
+    /// Serves HTTP/1.1 requests on a connection until it closes.
+    ///
+    /// `buf` holds bytes already read from the stream. A connection sending
+    /// the HTTP/2 preface or asking for an upgrade is returned instead, for
+    /// an event loop to multiplex its streams. With `hand_back`, so is a
+    /// connection kept alive after its first request.
+    fn http1_handler(self: &Arc<Self>, stream: Stream, mut buf: Vec<u8>, listener: &str, peer: &str, hand_back: bool) -> Result<Option<Adopted>, Error>
+    {
+        stream.set_read_timeout(Some(KEEP_ALIVE_TIMEOUT))?;
+        let mut reader: Box<dyn Read + Send> = Box::new(MeteredStream
+        {
+            inner: stream.try_clone()?,
+            metrics: self.metrics().cloned()
+        });
+        let mut io = MeteredStream
+        {
+            inner: stream,
+            metrics: self.metrics().cloned()
+        };
+        let mut first = true;
+        loop
+        {
+            // Read the request head, or the HTTP/2 connection preface
+            let (mut http_request, head_len) = loop
+            {
+                if first && buf.starts_with(h2::PREFACE)
+                {
+                    return Ok(Some(Adopted::new(io.inner, buf, listener, peer, Protocol::H2(None))));
+                }
+                // A partial preface can not be told apart from a request yet
+                if !first || !h2::PREFACE.starts_with(&buf)
+                {
+                    match HttpRequest::parse_head(&buf)
+                    {
+                        Ok(Some(parsed)) => break parsed,
+                        Ok(None) => {},
+                        Err(e) =>
+                        {
+                            println!("Failed to parse request: {e}");
+                            if let Some(metrics) = self.metrics()
+                            {
+                                metrics.parse_error();
+                            }
+                            HttpResponse::new(400).write_to(&mut io)?;
+                            return Ok(None);
+                        }
+                    }
+                }
+                // Idle keep-alive connections end quietly
+                match read_more(&mut reader, &mut buf)
+                {
+                    Ok(0) => return Ok(None),
+                    Ok(_) => {},
+                    Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => return Ok(None),
+                    Err(e) => return Err(e),
+                }
+            };
+            first = false;
+            buf.drain(..head_len);
+            // The request is served with the routes of the moment it arrived
+            let router = self.routes.load();
+            let framing = match Framing::of(http_request.content())
+            {
+                Ok(framing) => framing,
+                Err(e) =>
+                {
+                    HttpResponse::new(body_error_status(&e)).write_to(&mut io)?;
+                    return Ok(None);
+                }
+            };
+            let stream_limit = Self::stream_limit(&router, &http_request);
+            let limit = stream_limit.unwrap_or(MAX_BODY_SIZE);
+            if matches!(framing, Framing::Length(len) if len > limit as u64)
+            {
+                HttpResponse::new(413).with_header("Connection", "close").write_to(&mut io)?;
+                return Ok(None);
+            }
+            match Self::expectation(&http_request)
+            {
+                // Invite the body unless it is empty or already arriving
+                Ok(true) if buf.is_empty() && framing != Framing::Length(0) =>
+                {
+                    io.write_all(CONTINUE)?;
+                    io.flush()?;
+                },
+                Ok(_) => {},
+                Err(response) =>
+                {
+                    response.with_header("Connection", "close").write_to(&mut io)?;
+                    return Ok(None);
+                }
+            }
+            let body = Arc::new(Mutex::new(BodySource::new(reader, buf, framing, limit)));
+            let content = http_request.content_mut();
+            content.listener = listener.to_string();
+            content.peer = peer.to_string();
+            let keep_alive = content.keep_alive();
+            let cancellation = content.cancellation().clone();
+            if stream_limit.is_some()
+            {
+                content.body_reader = Some(BodyReader::new(Arc::clone(&body)));
+            }
+            else
+            {
+                let mut bytes = Vec::new();
+                let read = body.lock().unwrap().read_to_end(&mut bytes);
+                if let Err(e) = read
+                {
+                    // A client that went away gets no answer
+                    let status = match e.kind()
+                    {
+                        ErrorKind::InvalidData if body.lock().unwrap().exceeded() => 413,
+                        ErrorKind::InvalidData => 400,
+                        _ => return Ok(None),
+                    };
+                    HttpResponse::new(status).write_to(&mut io)?;
+                    return Ok(None);
+                }
+                content.body = String::from_utf8_lossy(&bytes).into_owned();
+            }
+            if h2::is_upgrade(&http_request) && stream_limit.is_none()
+            {
+                let (_, rest) = body.lock().unwrap().finish()?;
+                return Ok(Some(Adopted::new(io.inner, rest, listener, peer, Protocol::H2(Some(Box::new(http_request))))));
+            }
+            // A handler that timed out may still be reading the body, so the
+            // connection is closed under it instead of being reused
+            let mut late = MeteredStream
+            {
+                inner: io.inner.try_clone()?,
+                metrics: self.metrics().cloned()
+            };
+            let reply: Reply = Box::new(move |response|
+            {
+                // The watchdog must not wait on a client that does not read
+                let _ = late.inner.set_write_timeout(Some(REPLY_TIMEOUT));
+                let _ = response.with_header("Connection", "close").write_to(&mut late);
+                let _ = late.flush();
+                let _ = late.inner.shutdown(Shutdown::Both);
+            });
+            let mut response = self.process(&router, http_request, Some(reply));
+            if cancellation.is_cancelled()
+            {
+                return Ok(None);
+            }
+            // Skip what the handler left of the body so the next request can be read
+            let mut source = body.lock().unwrap();
+            let finished = source.finish();
+            let mut keep_alive = keep_alive && finished.is_ok();
+            if source.exceeded()
+            {
+                response = HttpResponse::new(413);
+                keep_alive = false;
+            }
+            drop(source);
+            if !keep_alive
+            {
+                response = response.with_header("Connection", "close");
+            }
+            response.write_to(&mut io)?;
+            io.flush()?;
+            match finished
+            {
+                // The event loop waits for the next request without holding a worker
+                Ok((_, rest)) if keep_alive && hand_back =>
+                {
+                    return Ok(Some(Adopted::new(io.inner, rest, listener, peer, Protocol::Http1)));
+                },
+                Ok((next_reader, rest)) if keep_alive =>
+                {
+                    reader = next_reader;
+                    buf = rest;
+                },
+                _ => return Ok(None),
+            }
+        }
     }
 }
 
@@ -74,28 +853,554 @@ impl<'a> HttpServer<'a>
 {
     pub fn new(addr: &str, handlers: &'a [HttpMethodHandler], thread_pool: &'a dyn Executable) -> Result<Self, Error>
     {
-        let listener = TcpListener::bind(addr)?;
+        Self::bind(&[addr], handlers, thread_pool)
+    }
+
+    /// Creates a server listening on every address in `addrs`.
+    ///
+    /// Addresses are socket addresses such as `0.0.0.0:80` or `[::]:80`, or
+    /// Unix socket paths prefixed with `unix:`. An IPv6 address sharing its
+    /// port with an IPv4 address is bound IPv6-only so that both can coexist.
+    pub fn bind(addrs: &[&str], handlers: &'a [HttpMethodHandler], thread_pool: &'a dyn Executable) -> Result<Self, Error>
+    {
+        let mut listeners = Vec::<Listener>::new();
+        for addr in addrs
+        {
+            let listener = match addr.parse::<SocketAddr>()
+            {
+                Ok(SocketAddr::V6(v6)) if addrs.iter().any(|other|
+                    matches!(other.parse::<SocketAddr>(), Ok(SocketAddr::V4(v4)) if v4.port() == v6.port())) =>
+                {
+                    Listener::bind_ipv6_only(SocketAddr::V6(v6))?
+                },
+                _ => Listener::bind(addr)?,
+            };
+            listeners.push(listener);
+        }
         Ok(Self{
-            listener: listener,
-            handlers: handlers,
-            thread_pool: thread_pool
+            listeners: listeners,
+            routes: RouteTable::new(Router::new(handlers)),
+            thread_pool: thread_pool,
+            metrics: None,
+            sessions: None,
+            compression: None,
+            error_pages: HashMap::new()
         })
     }
 
-    pub fn serve(&self) -> Result<(), Error>
+    /// Exposes server and thread pool metrics in the Prometheus text format on `route`
+    pub fn with_metrics(mut self, route: &str) -> Self
+    {
+        let metrics = Metrics::new(self.thread_pool.stats());
+        self.metrics = Some((route.to_string(), Arc::new(metrics)));
+        self
+    }
+
+    /// Gives handlers a session through `HttpContent::session`
+    pub fn with_sessions(mut self, sessions: Sessions) -> Self
+    {
+        self.sessions = Some(Arc::new(sessions));
+        self
+    }
+
+    /// Compresses responses for clients sending `Accept-Encoding`
+    pub fn with_compression(mut self, compression: Compression) -> Self
+    {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Replaces the routes the server was created with by a router
+    pub fn with_router(self, router: Router) -> Self
     {
-        println!("Serving...");
-        for stream in self.listener.incoming()
+        self.routes.replace(router);
+        self
+    }
+
+    /// Mounts a router under a path prefix such as `/api/v1`
+    pub fn mount(self, prefix: &str, router: Router) -> Self
+    {
+        self.routes.update(|routes| *routes = mem::take(routes).mount(prefix, router));
+        self
+    }
+
+    /// Gets a handle on the routes of the server, to change them while it
+    /// is serving
+    pub fn routes(&self) -> RouteTable
+    {
+        self.routes.clone()
+    }
+
+    /// Serves the page built by `page` for responses with the error status
+    /// `status` that have no body of their own
+    pub fn with_error_page(mut self, status: u16, page: ErrorPage) -> Self
+    {
+        self.error_pages.insert(status, page);
+        self
+    }
+
+    /// Gets the addresses the server is listening on
+    pub fn local_addrs(&self) -> Vec<String>
+    {
+        self.listeners.iter().map(|listener| listener.local_addr()).collect()
+    }
+
+    /// Accepts connections from one listener and hands them to the thread
+    /// pool, and HTTP/2 connections on to the event loop behind `h2_loop`
+    fn accept_loop(&self, listener: &Listener, processor: &Arc<HttpProcessor>, h2_loop: &LoopHandle) -> Result<(), Error>
+    {
+        let local_addr = listener.local_addr();
+        loop
         {
-            let stream = stream?;
+            let (stream, peer) = listener.accept()?;
+            let processor = Arc::clone(processor);
+            let local_addr = local_addr.clone();
+            let h2_loop = h2_loop.clone();
             let job = move ||
             {
-                let processor = HttpProcessor::new();
-                processor.conn_handler(stream).unwrap();
+                match processor.conn_handler(stream, &local_addr, &peer)
+                {
+                    Ok(Some(adopted)) => h2_loop.adopt(adopted),
+                    Ok(None) => {},
+                    Err(e) => println!("Connection from {peer} failed: {e}"),
+                }
             };
             let job = Box::new(job);
-            self.thread_pool.try_submit(job)?;
+            // A full queue drops the connection rather than stopping the server
+            if let Err(e) = self.thread_pool.try_submit(job)
+            {
+                println!("Rejected connection: {e}");
+            }
         }
-        Ok(())
+    }
+
+    /// Serves connections from a few event loop threads instead of one
+    /// worker per connection.
+    ///
+    /// Each of the `loops` threads multiplexes its connections with epoll and
+    /// parses requests as bytes arrive. A request is handed to the thread
+    /// pool only once it is complete, so slow clients do not hold workers.
+    pub fn serve_epoll(&self, loops: usize) -> Result<(), Error>
+    {
+        println!("Serving on {} with {loops} event loops...", self.local_addrs().join(", "));
+        for listener in &self.listeners
+        {
+            listener.set_nonblocking(true)?;
+        }
+        let processor = Arc::new(HttpProcessor::new(self.routes.clone(), self.metrics.clone(), self.sessions.clone(), self.compression.clone(), self.error_pages.clone()));
+        thread::scope(|scope|
+        {
+            let event_loops: Vec<_> = (0..loops.max(1))
+            .map(|_|
+            {
+                let processor = Arc::clone(&processor);
+                scope.spawn(move || EventLoop::new(&self.listeners, processor, self.thread_pool, LoopHandle::new()?)?.run())
+            })
+            .collect();
+            for event_loop in event_loops
+            {
+                event_loop.join().unwrap()?;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn serve(&self) -> Result<(), Error>
+    {
+        println!("Serving on {}...", self.local_addrs().join(", "));
+        let processor = Arc::new(HttpProcessor::new(self.routes.clone(), self.metrics.clone(), self.sessions.clone(), self.compression.clone(), self.error_pages.clone()));
+        let h2_loop = LoopHandle::new()?;
+        // Every listener gets its own accept thread feeding the same pool
+        thread::scope(|scope|
+        {
+            // HTTP/2 streams run on the pool as they arrive, so their
+            // connections are multiplexed by an event loop instead of a worker
+            let event_loop =
+            {
+                let processor = Arc::clone(&processor);
+                let h2_loop = h2_loop.clone();
+                scope.spawn(move || EventLoop::new(&[], processor, self.thread_pool, h2_loop)?.run())
+            };
+            let accepters: Vec<_> = self.listeners.iter()
+            .map(|listener|
+            {
+                let processor = &processor;
+                let h2_loop = &h2_loop;
+                scope.spawn(move || self.accept_loop(listener, processor, h2_loop))
+            })
+            .collect();
+            let served = accepters.into_iter().try_for_each(|accepter| accepter.join().unwrap());
+            h2_loop.stop();
+            event_loop.join().unwrap()?;
+            served
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpStream, os::unix::net::UnixStream, sync::mpsc};
+    use crate::mp::ThreadPool;
+
+    /// What the upload handler's read of the body ended with
+    static UPLOAD: Mutex<Option<ErrorKind>> = Mutex::new(None);
+
+    fn upload(mut request: HttpRequest) -> HttpResponse
+    {
+        let mut body = Vec::new();
+        let read = request.content_mut().body_reader().unwrap().read_to_end(&mut body);
+        *UPLOAD.lock().unwrap() = read.err().map(|e| e.kind());
+        HttpResponse::new(201)
+    }
+
+    /// Counts the bytes of a streamed body
+    fn count(mut request: HttpRequest) -> HttpResponse
+    {
+        let reader = request.content_mut().body_reader().unwrap();
+        match std::io::copy(reader, &mut std::io::sink())
+        {
+            Ok(n) => HttpResponse::new(200).with_body(n.to_string()),
+            Err(e) => HttpResponse::new(500).with_body(e.to_string()),
+        }
+    }
+
+    /// The threads the timed handlers ran on
+    static TIMED: Mutex<Vec<thread::ThreadId>> = Mutex::new(Vec::new());
+
+    /// Sleeps for the number of milliseconds in the query
+    fn sleep(request: HttpRequest) -> HttpResponse
+    {
+        TIMED.lock().unwrap().push(thread::current().id());
+        let millis = request.content().query_string().and_then(|query| query.parse().ok()).unwrap_or(0);
+        thread::sleep(Duration::from_millis(millis));
+        HttpResponse::new(200)
+    }
+
+    static ROUTES: &[HttpMethodHandler] = &[
+        HttpMethodHandler::Post(HttpRouteHandler::new("/upload", &upload).streaming(1024).timeout(Duration::from_millis(100))),
+        HttpMethodHandler::Post(HttpRouteHandler::new("/large", &count).streaming(2 * MAX_BODY_SIZE)),
+        HttpMethodHandler::Get(HttpRouteHandler::new("/sleep", &sleep).timeout(Duration::from_millis(100))),
+    ];
+
+    #[test]
+    fn test_streaming_timeout()
+    {
+        let listener = Listener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr();
+        let processor = Arc::new(HttpProcessor::new(RouteTable::new(Router::new(ROUTES)), None, None, None, HashMap::new()));
+        // The client sends part of the body and then waits for the answer
+        let client = thread::spawn(move ||
+        {
+            let mut client = TcpStream::connect(addr).unwrap();
+            client.write_all(b"POST /upload HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc").unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        });
+        let (stream, peer) = listener.accept().unwrap();
+        processor.conn_handler(stream, "test", &peer).unwrap();
+        let response = client.join().unwrap();
+        assert!(response.starts_with("HTTP/1.1 504"));
+        assert!(response.contains("Connection: close"));
+        // The abandoned handler sees the body cut short, not a complete body
+        let start = Instant::now();
+        while UPLOAD.lock().unwrap().is_none() && start.elapsed() < Duration::from_secs(5)
+        {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(*UPLOAD.lock().unwrap(), Some(ErrorKind::UnexpectedEof));
+    }
+
+    #[test]
+    fn test_timeout_on_worker()
+    {
+        let processor = Arc::new(HttpProcessor::new(RouteTable::new(Router::new(ROUTES)), None, None, None, HashMap::new()));
+        let get = |path: &'static str|
+        {
+            let start = Instant::now();
+            let response = serve_client(&processor, move |mut client|
+            {
+                client.write_all(format!("GET {path} HTTP/1.1\r\nConnection: close\r\n\r\n").as_bytes()).unwrap();
+                let mut response = String::new();
+                client.read_to_string(&mut response).unwrap();
+                (response, start.elapsed())
+            });
+            (response, start.elapsed())
+        };
+        // A handler finishing in time answers as usual
+        let ((response, _), _) = get("/sleep?10");
+        assert!(response.starts_with("HTTP/1.1 200"));
+        // One running late is answered for at its deadline, while it goes on
+        // running on the thread serving the connection
+        let ((response, answered), served) = get("/sleep?500");
+        assert!(response.starts_with("HTTP/1.1 504"));
+        assert!(response.contains("Connection: close"));
+        assert!(answered < Duration::from_millis(400));
+        assert!(served >= Duration::from_millis(500));
+        let timed = TIMED.lock().unwrap();
+        assert_eq!(timed.len(), 2);
+        assert!(timed.iter().all(|id| *id == thread::current().id()));
+        assert_eq!(processor.timed_out.load(Ordering::Acquire), 0);
+    }
+
+    #[test]
+    fn test_h2_stream_limit()
+    {
+        use crate::h2::{frame::{Frame, FrameType, END_HEADERS, END_STREAM}, hpack::Encoder};
+
+        let processor = Arc::new(HttpProcessor::new(RouteTable::new(Router::new(ROUTES)), None, None, None, HashMap::new()));
+        let mut connection = h2::Connection::new(Arc::new(|| {}), None);
+        let mut input = h2::PREFACE.to_vec();
+        let head = Encoder.encode([(":method", "POST"), (":scheme", "http"), (":path", "/large")]);
+        Frame::new(FrameType::Headers, END_HEADERS, 1, head).encode(&mut input);
+        let (_, request, body) = connection.receive(&input).pop().unwrap();
+        let handler = thread::spawn(move || processor.serve_stream(request, Some(body), "test", "peer", Box::new(|_| {})));
+        // The route takes more than MAX_BODY_SIZE, sent as fast as the window allows
+        let frame = vec![0u8; 16 * 1024];
+        let rounds = (MAX_BODY_SIZE + 1024 * 1024) / (8 * frame.len());
+        for round in 0..rounds
+        {
+            let mut input = Vec::new();
+            for i in 0..8
+            {
+                let flags = if round == rounds - 1 && i == 7 { END_STREAM } else { 0 };
+                Frame::new(FrameType::Data, flags, 1, frame.clone()).encode(&mut input);
+            }
+            connection.receive(&input);
+            // Wait for the handler to read the round, which reopens the window
+            while !handler.is_finished()
+            {
+                connection.release();
+                let mut output: &[u8] = &connection.output();
+                let mut updated = false;
+                while let Some(frame) = Frame::read(&mut output, 1 << 24).unwrap()
+                {
+                    updated |= frame.kind == FrameType::WindowUpdate && frame.stream_id == 1;
+                }
+                if updated
+                {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        let response = handler.join().unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.body(), (rounds * 8 * frame.len()).to_string().as_bytes());
+    }
+
+    fn page(_: HttpRequest) -> HttpResponse
+    {
+        HttpResponse::new(200).with_body("hello")
+    }
+
+    fn own_head(_: HttpRequest) -> HttpResponse
+    {
+        HttpResponse::new(200).with_header("X-Handler", "head")
+    }
+
+    fn own_options(_: HttpRequest) -> HttpResponse
+    {
+        HttpResponse::new(200).with_header("X-Handler", "options")
+    }
+
+    static METHODS: &[HttpMethodHandler] = &[
+        HttpMethodHandler::Get(HttpRouteHandler::new("/page", &page)),
+        HttpMethodHandler::Post(HttpRouteHandler::new("/page", &page)),
+        HttpMethodHandler::Get(HttpRouteHandler::new("/own", &page)),
+        HttpMethodHandler::Head(HttpRouteHandler::new("/own", &own_head)),
+        HttpMethodHandler::Options(HttpRouteHandler::new("/own", &own_options)),
+        HttpMethodHandler::Delete(HttpRouteHandler::new("/other", &page)),
+    ];
+
+    fn request(head: &str) -> HttpRequest
+    {
+        HttpRequest::parse_head(head.as_bytes()).unwrap().unwrap().0
+    }
+
+    #[test]
+    fn test_head_and_options()
+    {
+        let processor = Arc::new(HttpProcessor::new(RouteTable::new(Router::new(METHODS)), None, None, None, HashMap::new()));
+        let router = processor.routes.load();
+        // HEAD runs the GET handler and keeps the length of its body
+        let response = processor.process(&router, request("HEAD /page HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.header("Content-Length"), Some("5"));
+        assert!(response.body().is_empty());
+        let response = processor.process(&router, request("OPTIONS /page HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(response.status(), 204);
+        assert_eq!(response.header("Allow"), Some("GET, HEAD, POST, OPTIONS"));
+        // The server as a whole answers every method registered anywhere
+        let response = processor.process(&router, request("OPTIONS * HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(response.status(), 204);
+        assert_eq!(response.header("Allow"), Some("GET, HEAD, POST, DELETE, OPTIONS"));
+        // A route's own HEAD and OPTIONS handlers come first
+        let response = processor.process(&router, request("HEAD /own HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(response.header("X-Handler"), Some("head"));
+        let response = processor.process(&router, request("OPTIONS /own HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.header("X-Handler"), Some("options"));
+    }
+
+    /// Serves the one connection `client` makes, returning what it returns
+    fn serve_client<T: Send + 'static>(processor: &Arc<HttpProcessor>, client: impl FnOnce(TcpStream) -> T + Send + 'static) -> T
+    {
+        let listener = Listener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr();
+        let client = thread::spawn(move || client(TcpStream::connect(addr).unwrap()));
+        let (stream, peer) = listener.accept().unwrap();
+        processor.conn_handler(stream, "test", &peer).unwrap();
+        client.join().unwrap()
+    }
+
+    #[test]
+    fn test_expect_continue()
+    {
+        let processor = Arc::new(HttpProcessor::new(RouteTable::new(Router::new(METHODS)), None, None, None, HashMap::new()));
+        // The client only sends the body once invited
+        let response = serve_client(&processor, |mut client|
+        {
+            client.write_all(b"POST /page HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 5\r\nConnection: close\r\n\r\n").unwrap();
+            let mut interim = [0u8; CONTINUE.len()];
+            client.read_exact(&mut interim).unwrap();
+            assert_eq!(&interim, CONTINUE);
+            client.write_all(b"hello").unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        });
+        assert!(response.starts_with("HTTP/1.1 200"));
+        // A body over the limit is refused before it is sent
+        let response = serve_client(&processor, |mut client|
+        {
+            client.write_all(b"POST /page HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 1000000000\r\n\r\n").unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        });
+        assert!(response.starts_with("HTTP/1.1 413"));
+        // An expectation the server does not know
+        let response = serve_client(&processor, |mut client|
+        {
+            client.write_all(b"POST /page HTTP/1.1\r\nExpect: x-unknown\r\nContent-Length: 5\r\n\r\n").unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        });
+        assert!(response.starts_with("HTTP/1.1 417"));
+        // HTTP/1.0 clients do not wait for a 100, so none is sent
+        let response = serve_client(&processor, |mut client|
+        {
+            client.write_all(b"POST /page HTTP/1.0\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n").unwrap();
+            client.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+            let early = client.read(&mut [0u8; 64]).unwrap_err().kind();
+            assert!(matches!(early, ErrorKind::WouldBlock | ErrorKind::TimedOut));
+            client.set_read_timeout(None).unwrap();
+            client.write_all(b"hello").unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        });
+        assert!(response.starts_with("HTTP/1.1 200"));
+    }
+
+    /// Answers with the listener and peer the request came in on
+    fn whoami(request: HttpRequest) -> HttpResponse
+    {
+        let content = request.content();
+        HttpResponse::new(200).with_body(format!("{} {}", content.listener(), content.peer()))
+    }
+
+    static WHOAMI: &[HttpMethodHandler] = &[
+        HttpMethodHandler::Get(HttpRouteHandler::new("/whoami", &whoami)),
+    ];
+
+    fn whoami_over(mut client: impl Read + Write) -> String
+    {
+        client.write_all(b"GET /whoami HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response.split_once("\r\n\r\n").unwrap().1.to_string()
+    }
+
+    #[test]
+    fn test_several_listeners()
+    {
+        for epoll in [false, true]
+        {
+            let path = std::env::temp_dir().join(format!("http-server-{}-{epoll}.sock", std::process::id()));
+            let unix = format!("unix:{}", path.display());
+            let (addrs, bound) = mpsc::channel();
+            // The server runs for the rest of the test binary
+            thread::spawn(move ||
+            {
+                let pool: &'static ThreadPool<16, 4> = Box::leak(Box::new(ThreadPool::new()));
+                let server = HttpServer::bind(&["127.0.0.1:0", &unix], WHOAMI, pool).unwrap();
+                addrs.send(server.local_addrs()).unwrap();
+                match epoll
+                {
+                    true => server.serve_epoll(1),
+                    false => server.serve(),
+                }
+            });
+            let addrs = bound.recv().unwrap();
+            let client = TcpStream::connect(&addrs[0]).unwrap();
+            let local = client.local_addr().unwrap().to_string();
+            assert_eq!(whoami_over(client), format!("{} {local}", addrs[0]));
+            let client = UnixStream::connect(&path).unwrap();
+            assert_eq!(whoami_over(client), format!("{} unix:", addrs[1]));
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    /// Middleware wrapping the body of the response in its name
+    fn wrap(name: &'static str) -> impl Middleware
+    {
+        move |request: HttpRequest, next: Next<'_>|
+        {
+            let response = next.run(request);
+            let body = format!("{name}({})", String::from_utf8_lossy(response.body()));
+            response.with_body(body)
+        }
+    }
+
+    fn root_missing(_: HttpRequest) -> HttpResponse
+    {
+        HttpResponse::new(404).with_body("root")
+    }
+
+    fn api_missing(_: HttpRequest) -> HttpResponse
+    {
+        HttpResponse::new(404).with_body("api")
+    }
+
+    #[test]
+    fn test_mounted_routers()
+    {
+        let v1 = Router::new(METHODS).with_middleware(wrap("v1"));
+        let api = Router::new(METHODS).with_middleware(wrap("api")).with_not_found(&api_missing).mount("/v1", v1);
+        let root = Router::new(METHODS)
+        .with_middleware(wrap("first"))
+        .with_middleware(wrap("second"))
+        .with_not_found(&root_missing)
+        .mount("/api", api)
+        .mount("/other", Router::new(METHODS));
+        let processor = Arc::new(HttpProcessor::new(RouteTable::new(root), None, None, None, HashMap::new()));
+        let router = processor.routes.load();
+        let get = |path: &str| processor.process(&router, request(&format!("GET {path} HTTP/1.1\r\n\r\n")), None);
+        // Outer routers' middleware runs first, in the order it was added
+        assert_eq!(get("/page").body(), b"first(second(hello))");
+        assert_eq!(get("/api/page").body(), b"first(second(api(hello)))");
+        assert_eq!(get("/api/v1/page").body(), b"first(second(api(v1(hello))))");
+        // Unknown paths go to the nearest not-found handler, inside the
+        // middleware of every router passed through
+        let response = get("/api/v1/nope");
+        assert_eq!(response.status(), 404);
+        assert_eq!(response.body(), b"first(second(api(v1(api))))");
+        assert_eq!(get("/api/nope").body(), b"first(second(api(api)))");
+        assert_eq!(get("/other/nope").body(), b"first(second(root))");
+        assert_eq!(get("/nope").body(), b"first(second(root))");
     }
 }
@@ -0,0 +1,967 @@
+use std::{collections::HashMap, io::{Error, ErrorKind, Read, Write}, mem, net::Shutdown, os::fd::AsRawFd, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use osafe::io::posix_epoll::{Epoll, Event, Interest, Waker};
+
+use crate::{body::{BodyStream, Framing}, metrics::ConnectionGuard, mp::Executable, net::{Listener, Stream}, h2, HttpRequest, HttpResponse, MAX_BODY_SIZE, MAX_HEAD_SIZE};
+
+use super::{body_error_status, HttpProcessor, CONTINUE, KEEP_ALIVE_TIMEOUT};
+
+/// Token reported when a worker wakes the loop
+const WAKER_TOKEN: u64 = u64::MAX;
+/// Events handled per wait
+const EVENT_CAPACITY: usize = 1024;
+/// Bytes read per call
+const READ_CHUNK: usize = 16 * 1024;
+/// Bytes of a streamed response body read at once
+const WRITE_CHUNK: usize = 64 * 1024;
+/// How often connections are checked for having gone quiet
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Work done away from the loop for the loop to pick up
+pub(super) enum Completion
+{
+    /// An HTTP/1.1 response, and whether to keep the connection open
+    Response(u64, Serialized, bool),
+    /// The response to a stream of an HTTP/2 connection
+    Stream(u64, u32, HttpResponse),
+    /// Handlers read request bodies of an HTTP/2 connection
+    Consumed(u64),
+    /// A connection a worker hands to the loop
+    Adopt(Adopted),
+    /// The server is shutting down
+    Stop,
+}
+
+/// Completions waiting for the loop, and the waker telling it about them
+struct Completions
+{
+    queue: Mutex<Vec<Completion>>,
+    waker: Waker
+}
+
+/// Hands work to an event loop from other threads
+#[derive(Clone)]
+pub(super) struct LoopHandle(Arc<Completions>);
+
+/// What a connection handed to the loop speaks
+pub(super) enum Protocol
+{
+    /// HTTP/1.1, between two requests
+    Http1,
+    /// HTTP/2, with the HTTP/1.1 request that asked for the upgrade if any
+    H2(Option<Box<HttpRequest>>),
+}
+
+/// A connection served by a worker until it can be multiplexed by a loop
+pub(super) struct Adopted
+{
+    stream: Stream,
+    /// Bytes already read from the stream
+    input: Vec<u8>,
+    listener: String,
+    peer: String,
+    protocol: Protocol,
+    guard: Option<ConnectionGuard>
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum State
+{
+    /// Accumulating bytes until a request is complete
+    Reading,
+    /// A worker is handling the request
+    Dispatched,
+    /// Writing the response, closing the connection afterwards if `close`
+    Writing { close: bool },
+    /// Serving HTTP/2 streams, handled by workers as they arrive
+    H2,
+}
+
+struct Connection
+{
+    stream: Stream,
+    listener: String,
+    peer: String,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    written: usize,
+    /// The part of a response body read into `output` as it is written
+    body: Option<BodyStream>,
+    state: State,
+    /// Whether the request being read was sent `100 Continue`
+    continued: bool,
+    /// When bytes last moved on the connection
+    active: Instant,
+    h2: Option<Box<h2::Connection>>,
+    _guard: Option<ConnectionGuard>
+}
+
+/// An epoll loop multiplexing connections on one thread
+pub(super) struct EventLoop<'s>
+{
+    epoll: Epoll,
+    listeners: &'s [Listener],
+    listener_addrs: Vec<String>,
+    connections: HashMap<u64, Connection>,
+    next_token: u64,
+    processor: Arc<HttpProcessor>,
+    thread_pool: &'s dyn Executable,
+    handle: LoopHandle
+}
+
+fn os_err(e: osafe::error::Error) -> Error
+{
+    Error::new(ErrorKind::Other, format!("{e:?}"))
+}
+
+/// A response as bytes, followed by the body stream if it has one
+type Serialized = (Vec<u8>, Option<BodyStream>);
+
+/// Serializes a response, telling the client when the connection will close
+fn serialize(response: HttpResponse, keep_alive: bool) -> Serialized
+{
+    let response = match keep_alive
+    {
+        true => response,
+        false => response.with_header("Connection", "close"),
+    };
+    response.into_parts()
+}
+
+impl LoopHandle
+{
+    pub fn new() -> Result<Self, Error>
+    {
+        let completions = Completions
+        {
+            queue: Mutex::new(Vec::new()),
+            waker: Waker::new().map_err(os_err)?
+        };
+        Ok(Self(Arc::new(completions)))
+    }
+
+    /// Moves a connection served by a worker onto the loop
+    pub fn adopt(&self, adopted: Adopted)
+    {
+        self.push(Completion::Adopt(adopted));
+    }
+
+    /// Ends the loop once it picks up the completions queued so far
+    pub fn stop(&self)
+    {
+        self.push(Completion::Stop);
+    }
+
+    fn push(&self, completion: Completion)
+    {
+        self.0.queue.lock().unwrap().push(completion);
+        let _ = self.0.waker.wake();
+    }
+}
+
+impl Adopted
+{
+    pub fn new(stream: Stream, input: Vec<u8>, listener: &str, peer: &str, protocol: Protocol) -> Self
+    {
+        Self
+        {
+            stream: stream,
+            input: input,
+            listener: listener.to_string(),
+            peer: peer.to_string(),
+            protocol: protocol,
+            guard: None
+        }
+    }
+
+    /// Keeps the connection counted in the metrics while the loop serves it
+    pub fn with_guard(mut self, guard: Option<ConnectionGuard>) -> Self
+    {
+        self.guard = guard;
+        self
+    }
+}
+
+impl<'s> EventLoop<'s>
+{
+    /// Creates a loop accepting connections from `listeners`, and taking
+    /// the connections handed to it through `handle`
+    pub fn new(listeners: &'s [Listener], processor: Arc<HttpProcessor>, thread_pool: &'s dyn Executable, handle: LoopHandle) -> Result<Self, Error>
+    {
+        let epoll = Epoll::new(EVENT_CAPACITY).map_err(os_err)?;
+        // Every loop watches every listener, the kernel wakes only one per connection
+        for (i, listener) in listeners.iter().enumerate()
+        {
+            epoll.add(listener.as_raw_fd(), Interest::ReadExclusive, i as u64).map_err(os_err)?;
+        }
+        epoll.add(handle.0.waker.fd(), Interest::Read, WAKER_TOKEN).map_err(os_err)?;
+        Ok(Self
+        {
+            epoll: epoll,
+            listeners: listeners,
+            listener_addrs: listeners.iter().map(|l| l.local_addr()).collect(),
+            connections: HashMap::new(),
+            next_token: listeners.len() as u64,
+            processor: processor,
+            thread_pool: thread_pool,
+            handle: handle
+        })
+    }
+
+    /// Serves connections until the loop is stopped through its handle
+    pub fn run(mut self) -> Result<(), Error>
+    {
+        let mut events = Vec::<Event>::with_capacity(EVENT_CAPACITY);
+        let mut swept = Instant::now();
+        loop
+        {
+            events.clear();
+            self.epoll.wait(&mut events, SWEEP_INTERVAL.as_millis() as i32).map_err(os_err)?;
+            if swept.elapsed() >= SWEEP_INTERVAL
+            {
+                self.sweep();
+                swept = Instant::now();
+            }
+            for event in &events
+            {
+                if event.token == WAKER_TOKEN
+                {
+                    if !self.complete()
+                    {
+                        return Ok(());
+                    }
+                }
+                else if event.token < self.listeners.len() as u64
+                {
+                    self.accept(event.token as usize);
+                }
+                else
+                {
+                    self.ready(event);
+                }
+            }
+        }
+    }
+
+    /// Accepts every pending connection on a listener
+    fn accept(&mut self, listener: usize)
+    {
+        loop
+        {
+            let (stream, peer) = match self.listeners[listener].accept()
+            {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) =>
+                {
+                    println!("Failed to accept connection: {e}");
+                    return;
+                }
+            };
+            let guard = self.processor.metrics().map(|metrics| metrics.connection());
+            let listener = self.listener_addrs[listener].clone();
+            self.register(stream, listener, peer, Vec::new(), guard);
+        }
+    }
+
+    /// Starts watching a connection, returning its token
+    fn register(&mut self, stream: Stream, listener: String, peer: String, input: Vec<u8>, guard: Option<ConnectionGuard>) -> Option<u64>
+    {
+        let token = self.next_token;
+        self.next_token += 1;
+        let registered = stream.set_nonblocking(true)
+        .and_then(|_| self.epoll.add(stream.as_raw_fd(), Interest::Read, token).map_err(os_err));
+        if let Err(e) = registered
+        {
+            println!("Failed to register connection: {e}");
+            return None;
+        }
+        self.connections.insert(token, Connection
+        {
+            stream: stream,
+            listener: listener,
+            peer: peer,
+            input: input,
+            output: Vec::new(),
+            written: 0,
+            body: None,
+            state: State::Reading,
+            continued: false,
+            active: Instant::now(),
+            h2: None,
+            _guard: guard
+        });
+        Some(token)
+    }
+
+    /// Takes over a connection a worker was serving
+    fn adopt(&mut self, adopted: Adopted)
+    {
+        let Some(token) = self.register(adopted.stream, adopted.listener, adopted.peer, adopted.input, adopted.guard) else
+        {
+            return;
+        };
+        match adopted.protocol
+        {
+            Protocol::Http1 => self.dispatch(token),
+            Protocol::H2(upgrade) => self.start_h2(token, upgrade),
+        }
+    }
+
+    /// Handles readiness of a connection
+    fn ready(&mut self, event: &Event)
+    {
+        let Some(conn) = self.connections.get_mut(&event.token) else
+        {
+            return;
+        };
+        if event.readable && matches!(conn.state, State::Reading | State::H2)
+        {
+            let mut chunk = [0u8; READ_CHUNK];
+            loop
+            {
+                match (&conn.stream).read(&mut chunk)
+                {
+                    Ok(0) =>
+                    {
+                        self.close(event.token);
+                        return;
+                    },
+                    Ok(n) =>
+                    {
+                        conn.active = Instant::now();
+                        conn.input.extend_from_slice(&chunk[..n]);
+                        if let Some(metrics) = self.processor.metrics()
+                        {
+                            metrics.bytes_in(n);
+                        }
+                        // Let the parser reject an oversized request before reading on
+                        if conn.input.len() > MAX_HEAD_SIZE + MAX_BODY_SIZE
+                        {
+                            break;
+                        }
+                    },
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(_) =>
+                    {
+                        self.close(event.token);
+                        return;
+                    }
+                }
+            }
+            match conn.state
+            {
+                State::H2 => self.receive(event.token),
+                _ => self.dispatch(event.token),
+            }
+        }
+        else if event.closed
+        {
+            // The peer is gone, a pending response has nowhere to go
+            self.close(event.token);
+            return;
+        }
+        if event.writable
+        {
+            self.flush(event.token);
+        }
+    }
+
+    /// Hands the buffered request to the thread pool once it is complete
+    fn dispatch(&mut self, token: u64)
+    {
+        let Some(conn) = self.connections.get_mut(&token) else
+        {
+            return;
+        };
+        if conn.state != State::Reading
+        {
+            return;
+        }
+        if conn.input.starts_with(h2::PREFACE)
+        {
+            self.start_h2(token, None);
+            return;
+        }
+        // A partial preface can not be told apart from a request yet
+        if h2::PREFACE.starts_with(&conn.input)
+        {
+            return;
+        }
+        let (mut http_request, head_len) = match HttpRequest::parse_head(&conn.input)
+        {
+            Ok(Some(parsed)) => parsed,
+            Ok(None) => return,
+            Err(e) =>
+            {
+                println!("Failed to parse request: {e}");
+                if let Some(metrics) = self.processor.metrics()
+                {
+                    metrics.parse_error();
+                }
+                self.respond(token, HttpResponse::new(400), false);
+                return;
+            }
+        };
+        // The request is served with the routes of the moment it arrived
+        let router = self.processor.routes.load();
+        let body_len = match Framing::of(http_request.content())
+        {
+            // The loop only buffers bodies of a known size for routes that want them whole
+            Ok(Framing::Chunked) =>
+            {
+                self.hand_off(token);
+                return;
+            },
+            Ok(Framing::Length(_)) if HttpProcessor::stream_limit(&router, &http_request).is_some() =>
+            {
+                self.hand_off(token);
+                return;
+            },
+            Ok(Framing::Length(len)) if len > MAX_BODY_SIZE as u64 =>
+            {
+                self.respond(token, HttpResponse::new(413), false);
+                return;
+            },
+            Ok(Framing::Length(len)) => len as usize,
+            Err(e) =>
+            {
+                self.respond(token, HttpResponse::new(body_error_status(&e)), false);
+                return;
+            }
+        };
+        match HttpProcessor::expectation(&http_request)
+        {
+            // Invite the body unless it is empty or already arriving
+            Ok(true) if !conn.continued && body_len > 0 && conn.input.len() == head_len =>
+            {
+                // The socket has just been read from and takes 25 bytes, a
+                // short write only leaves the client to send after its timeout
+                if let (Ok(n), Some(metrics)) = ((&conn.stream).write(CONTINUE), self.processor.metrics())
+                {
+                    metrics.bytes_out(n);
+                }
+                conn.continued = true;
+            },
+            Ok(_) => {},
+            Err(response) =>
+            {
+                self.respond(token, response, false);
+                return;
+            }
+        }
+        if conn.input.len() < head_len + body_len
+        {
+            return;
+        }
+        // Take the request out of the buffer, pipelined bytes stay behind
+        let request_bytes: Vec<u8> = conn.input.drain(..head_len + body_len).collect();
+        conn.continued = false;
+        let content = http_request.content_mut();
+        content.body = String::from_utf8_lossy(&request_bytes[head_len..]).into_owned();
+        content.listener = conn.listener.clone();
+        content.peer = conn.peer.clone();
+        let keep_alive = content.keep_alive();
+        if h2::is_upgrade(&http_request)
+        {
+            self.start_h2(token, Some(Box::new(http_request)));
+            return;
+        }
+        conn.state = State::Dispatched;
+        let _ = self.epoll.modify(conn.stream.as_raw_fd(), Interest::None, token);
+        // Run the handler on the pool and hand the response back to this loop
+        let processor = Arc::clone(&self.processor);
+        let handle = self.handle.clone();
+        let job = move ||
+        {
+            let cancellation = http_request.content().cancellation().clone();
+            let late = handle.clone();
+            let reply = Box::new(move |response| late.push(Completion::Response(token, serialize(response, false), false)));
+            let response = processor.process(&router, http_request, Some(reply));
+            // A handler that timed out was answered for already
+            if !cancellation.is_cancelled()
+            {
+                handle.push(Completion::Response(token, serialize(response, keep_alive), keep_alive));
+            }
+        };
+        if let Err(e) = self.thread_pool.try_submit(Box::new(job))
+        {
+            println!("Rejected request: {e}");
+            self.respond(token, HttpResponse::new(503), false);
+        }
+    }
+
+    /// Switches a connection to HTTP/2, after a preface or an upgrade request
+    fn start_h2(&mut self, token: u64, upgrade: Option<Box<HttpRequest>>)
+    {
+        let settings = match &upgrade
+        {
+            Some(request) => match request.content().header("HTTP2-Settings").and_then(h2::decode_settings_header)
+            {
+                Some(settings) => Some(settings),
+                None =>
+                {
+                    self.respond(token, HttpResponse::new(400), false);
+                    return;
+                }
+            },
+            None => None,
+        };
+        let Some(conn) = self.connections.get_mut(&token) else
+        {
+            return;
+        };
+        let handle = self.handle.clone();
+        let wake: h2::Wake = Arc::new(move || handle.push(Completion::Consumed(token)));
+        if upgrade.is_some()
+        {
+            let (switching, _) = HttpResponse::new(101)
+            .with_header("Connection", "Upgrade")
+            .with_header("Upgrade", "h2c")
+            .into_parts();
+            conn.output = switching;
+            conn.written = 0;
+        }
+        conn.h2 = Some(Box::new(h2::Connection::new(wake, settings.as_deref())));
+        conn.state = State::H2;
+        if let Some(request) = upgrade
+        {
+            self.serve_stream(token, 1, *request, None);
+        }
+        self.receive(token);
+    }
+
+    /// Feeds the bytes read from an HTTP/2 connection to it, handing the
+    /// streams they open to the thread pool
+    fn receive(&mut self, token: u64)
+    {
+        let Some(conn) = self.connections.get_mut(&token) else
+        {
+            return;
+        };
+        let Some(h2) = conn.h2.as_mut() else
+        {
+            return;
+        };
+        let opened = h2.receive(&conn.input);
+        conn.input.clear();
+        for (id, request, body) in opened
+        {
+            self.serve_stream(token, id, request, Some(body));
+        }
+        self.flush(token);
+    }
+
+    /// Runs the handler of an HTTP/2 stream on the pool, while the loop
+    /// goes on serving the other streams of the connection
+    fn serve_stream(&mut self, token: u64, id: u32, request: HttpRequest, body: Option<h2::RequestBody>)
+    {
+        let Some(conn) = self.connections.get(&token) else
+        {
+            return;
+        };
+        let processor = Arc::clone(&self.processor);
+        let handle = self.handle.clone();
+        let listener = conn.listener.clone();
+        let peer = conn.peer.clone();
+        let job = move ||
+        {
+            let cancellation = request.content().cancellation().clone();
+            let late = handle.clone();
+            let reply = Box::new(move |response| late.push(Completion::Stream(token, id, response)));
+            let response = processor.serve_stream(request, body, &listener, &peer, reply);
+            // A handler that timed out was answered for already
+            if !cancellation.is_cancelled()
+            {
+                handle.push(Completion::Stream(token, id, response));
+            }
+        };
+        if let Err(e) = self.thread_pool.try_submit(Box::new(job))
+        {
+            println!("Rejected stream: {e}");
+            if let Some(h2) = self.connections.get_mut(&token).and_then(|conn| conn.h2.as_mut())
+            {
+                h2.respond(id, HttpResponse::new(503));
+            }
+        }
+    }
+
+    /// Moves a connection whose request body is read as it arrives onto a
+    /// worker, which serves the request with blocking reads and hands the
+    /// connection back to the loop
+    fn hand_off(&mut self, token: u64)
+    {
+        let Some(conn) = self.connections.remove(&token) else
+        {
+            return;
+        };
+        let _ = self.epoll.delete(conn.stream.as_raw_fd());
+        if let Err(e) = conn.stream.set_nonblocking(false)
+        {
+            println!("Failed to hand off connection: {e}");
+            return;
+        }
+        let processor = Arc::clone(&self.processor);
+        let handle = self.handle.clone();
+        let job = move ||
+        {
+            match processor.http1_handler(conn.stream, conn.input, &conn.listener, &conn.peer, true)
+            {
+                Ok(Some(adopted)) => handle.adopt(adopted.with_guard(conn._guard)),
+                Ok(None) => {},
+                Err(e) => println!("Connection failed: {e}"),
+            }
+        };
+        if let Err(e) = self.thread_pool.try_submit(Box::new(job))
+        {
+            println!("Rejected connection: {e}");
+        }
+    }
+
+    /// Picks up the work finished by workers, returning false once the
+    /// loop is stopped
+    fn complete(&mut self) -> bool
+    {
+        self.handle.0.waker.reset();
+        let finished = mem::take(&mut *self.handle.0.queue.lock().unwrap());
+        let mut running = true;
+        for completion in finished
+        {
+            match completion
+            {
+                Completion::Response(token, serialized, keep_alive) => self.write(token, serialized, keep_alive),
+                Completion::Stream(token, id, response) =>
+                {
+                    if let Some(h2) = self.connections.get_mut(&token).and_then(|conn| conn.h2.as_mut())
+                    {
+                        h2.respond(id, response);
+                    }
+                    self.flush(token);
+                },
+                Completion::Consumed(token) =>
+                {
+                    if let Some(h2) = self.connections.get_mut(&token).and_then(|conn| conn.h2.as_mut())
+                    {
+                        h2.release();
+                    }
+                    self.flush(token);
+                },
+                Completion::Adopt(adopted) => self.adopt(adopted),
+                Completion::Stop => running = false,
+            }
+        }
+        running
+    }
+
+    /// Answers a request from the loop itself
+    fn respond(&mut self, token: u64, response: HttpResponse, keep_alive: bool)
+    {
+        self.write(token, serialize(response, keep_alive), keep_alive);
+    }
+
+    fn write(&mut self, token: u64, (bytes, body): Serialized, keep_alive: bool)
+    {
+        let Some(conn) = self.connections.get_mut(&token) else
+        {
+            return;
+        };
+        conn.output = bytes;
+        conn.written = 0;
+        conn.body = body;
+        conn.active = Instant::now();
+        conn.state = State::Writing { close: !keep_alive };
+        self.flush(token);
+    }
+
+    /// Writes as much pending output as the socket accepts
+    fn flush(&mut self, token: u64)
+    {
+        let Some(conn) = self.connections.get_mut(&token) else
+        {
+            return;
+        };
+        let close = match conn.state
+        {
+            State::Writing { close } => close,
+            State::H2 => false,
+            _ => return,
+        };
+        loop
+        {
+            if conn.written == conn.output.len()
+            {
+                conn.written = 0;
+                if let Some(h2) = conn.h2.as_mut()
+                {
+                    // Frames are produced as the socket takes them
+                    conn.output = h2.output();
+                    if conn.output.is_empty()
+                    {
+                        break;
+                    }
+                }
+                else
+                {
+                    // Read the next part of a streamed body once the last is out
+                    let Some(body) = conn.body.as_mut().filter(|body| body.remaining() > 0) else
+                    {
+                        break;
+                    };
+                    conn.output.clear();
+                    conn.output.resize(WRITE_CHUNK, 0);
+                    match body.read(&mut conn.output)
+                    {
+                        Ok(n) => conn.output.truncate(n),
+                        Err(e) =>
+                        {
+                            println!("Failed to read response body: {e}");
+                            self.close(token);
+                            return;
+                        }
+                    }
+                }
+            }
+            match (&conn.stream).write(&conn.output[conn.written..])
+            {
+                Ok(n) =>
+                {
+                    conn.active = Instant::now();
+                    conn.written += n;
+                    if let Some(metrics) = self.processor.metrics()
+                    {
+                        metrics.bytes_out(n);
+                    }
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock =>
+                {
+                    // An HTTP/2 client keeps sending frames while it reads
+                    let interest = match conn.h2
+                    {
+                        Some(_) => Interest::ReadWrite,
+                        None => Interest::Write,
+                    };
+                    let _ = self.epoll.modify(conn.stream.as_raw_fd(), interest, token);
+                    return;
+                },
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) =>
+                {
+                    self.close(token);
+                    return;
+                }
+            }
+        }
+        if let Some(h2) = &conn.h2
+        {
+            if h2.is_closed()
+            {
+                let _ = conn.stream.shutdown(Shutdown::Write);
+                self.close(token);
+                return;
+            }
+            let _ = self.epoll.modify(conn.stream.as_raw_fd(), Interest::Read, token);
+            return;
+        }
+        if close
+        {
+            let _ = conn.stream.shutdown(Shutdown::Write);
+            self.close(token);
+            return;
+        }
+        // Wait for the next request, which may already be buffered
+        conn.output.clear();
+        conn.body = None;
+        conn.state = State::Reading;
+        let _ = self.epoll.modify(conn.stream.as_raw_fd(), Interest::Read, token);
+        self.dispatch(token);
+    }
+
+    /// Closes connections that have neither sent a request nor taken a
+    /// response for as long as a blocking connection may stay idle.
+    /// HTTP/2 connections without a stream being handled are sent GOAWAY
+    /// first, and closed once it is written.
+    fn sweep(&mut self)
+    {
+        let stale: Vec<u64> = self.connections.iter()
+        .filter(|(_, conn)| conn.active.elapsed() > KEEP_ALIVE_TIMEOUT)
+        .filter(|(_, conn)| match &conn.h2
+        {
+            Some(h2) => !h2.is_busy(),
+            None => conn.state != State::Dispatched,
+        })
+        .map(|(token, _)| *token)
+        .collect();
+        for token in stale
+        {
+            match self.connections.get_mut(&token).and_then(|conn| conn.h2.as_mut())
+            {
+                Some(h2) if !h2.is_closed() =>
+                {
+                    h2.go_away();
+                    self.flush(token);
+                },
+                _ => self.close(token),
+            }
+        }
+    }
+
+    fn close(&mut self, token: u64)
+    {
+        if let Some(conn) = self.connections.remove(&token)
+        {
+            let _ = self.epoll.delete(conn.stream.as_raw_fd());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpStream, thread};
+    use crate::{mp::ThreadPool, server::{router::{RouteTable, Router}, HttpMethodHandler, HttpRouteHandler}};
+
+    fn page(request: HttpRequest) -> HttpResponse
+    {
+        HttpResponse::new(200).with_body(request.content().path().to_string())
+    }
+
+    fn slow(_: HttpRequest) -> HttpResponse
+    {
+        thread::sleep(Duration::from_millis(100));
+        HttpResponse::new(200).with_body("slow")
+    }
+
+    fn late(_: HttpRequest) -> HttpResponse
+    {
+        thread::sleep(Duration::from_millis(500));
+        HttpResponse::new(200)
+    }
+
+    static ROUTES: &[HttpMethodHandler] = &[
+        HttpMethodHandler::Get(HttpRouteHandler::new("/a", &page)),
+        HttpMethodHandler::Get(HttpRouteHandler::new("/b", &page)),
+        HttpMethodHandler::Get(HttpRouteHandler::new("/slow", &slow)),
+        HttpMethodHandler::Get(HttpRouteHandler::new("/late", &late).timeout(Duration::from_millis(100))),
+    ];
+
+    /// Runs an event loop on a fresh listener while `client` talks to it
+    fn with_loop(client: impl FnOnce(String))
+    {
+        let listeners = [Listener::bind("127.0.0.1:0").unwrap()];
+        listeners[0].set_nonblocking(true).unwrap();
+        let addr = listeners[0].local_addr();
+        let processor = Arc::new(HttpProcessor::new(RouteTable::new(Router::new(ROUTES)), None, None, None, HashMap::new()));
+        // Workers expect the pool to outlive them, as it does in a server
+        let pool: &'static ThreadPool<16, 2> = Box::leak(Box::new(ThreadPool::new()));
+        let handle = LoopHandle::new().unwrap();
+        thread::scope(|s|
+        {
+            let looping = handle.clone();
+            let running = s.spawn(|| EventLoop::new(&listeners, processor, pool, looping)?.run());
+            // Stop the loop even when the client panics, or the scope never ends
+            let stop = Stop(handle);
+            client(addr);
+            drop(stop);
+            running.join().unwrap().unwrap();
+        });
+    }
+
+    struct Stop(LoopHandle);
+
+    impl Drop for Stop
+    {
+        fn drop(&mut self)
+        {
+            self.0.stop();
+        }
+    }
+
+    fn read_all(client: &mut TcpStream) -> String
+    {
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_pipelined_requests()
+    {
+        with_loop(|addr|
+        {
+            let mut client = TcpStream::connect(addr).unwrap();
+            client.write_all(b"GET /a HTTP/1.1\r\nHost: x\r\n\r\nGET /b HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n").unwrap();
+            let response = read_all(&mut client);
+            // Both answers come back, in request order
+            assert_eq!(response.matches("HTTP/1.1 200").count(), 2);
+            let first = response.find("\r\n\r\n/a").unwrap();
+            let second = response.find("\r\n\r\n/b").unwrap();
+            assert!(first < second);
+        });
+    }
+
+    #[test]
+    fn test_partial_head()
+    {
+        with_loop(|addr|
+        {
+            let mut client = TcpStream::connect(addr).unwrap();
+            for part in [&b"GET /a HT"[..], b"TP/1.1\r\nHost: x", b"\r\nConnection: close\r\n", b"\r\n"]
+            {
+                client.write_all(part).unwrap();
+                thread::sleep(Duration::from_millis(50));
+            }
+            let response = read_all(&mut client);
+            assert!(response.starts_with("HTTP/1.1 200"));
+            assert!(response.ends_with("/a"));
+        });
+    }
+
+    #[test]
+    fn test_waker_response()
+    {
+        with_loop(|addr|
+        {
+            // The loop is parked in epoll when the worker finishes, so only the
+            // waker can get the response out before the next sweep tick
+            let mut client = TcpStream::connect(addr).unwrap();
+            let start = Instant::now();
+            client.write_all(b"GET /slow HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n").unwrap();
+            let response = read_all(&mut client);
+            assert!(response.ends_with("slow"));
+            assert!(start.elapsed() < SWEEP_INTERVAL);
+        });
+    }
+
+    #[test]
+    fn test_handler_timeout()
+    {
+        with_loop(|addr|
+        {
+            // The late handler still holds its worker, the 504 comes from the watchdog
+            let mut client = TcpStream::connect(addr).unwrap();
+            let start = Instant::now();
+            client.write_all(b"GET /late HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+            let response = read_all(&mut client);
+            assert!(response.starts_with("HTTP/1.1 504"));
+            assert!(response.contains("Connection: close"));
+            assert!(start.elapsed() < Duration::from_millis(400));
+        });
+    }
+
+    #[test]
+    fn test_idle_sweep()
+    {
+        with_loop(|addr|
+        {
+            let mut client = TcpStream::connect(addr).unwrap();
+            client.set_read_timeout(Some(KEEP_ALIVE_TIMEOUT + 3 * SWEEP_INTERVAL)).unwrap();
+            let start = Instant::now();
+            // A connection that never sends anything is closed by the sweep
+            let mut buf = [0u8; 16];
+            assert_eq!(client.read(&mut buf).unwrap(), 0);
+            assert!(start.elapsed() >= KEEP_ALIVE_TIMEOUT);
+        });
+    }
+}
@@ -0,0 +1,544 @@
+use std::{any::Any, cmp::Reverse, fmt, io::{Error, ErrorKind}, sync::{Arc, PoisonError, RwLock}};
+
+use super::{routes::{fill_route, match_route, path_error, same_pattern, UrlError}, HttpHandler, HttpMethodHandler};
+use crate::{HttpRequest, HttpResponse};
+
+/// Code run around the handlers of a router, such as authentication or
+/// logging. Any function taking the request and the rest of the chain is
+/// middleware.
+///
+/// ```
+/// use http::{server::router::{Next, Router}, HttpRequest, HttpResponse};
+///
+/// fn require_token(request: HttpRequest, next: Next) -> HttpResponse
+/// {
+///     match request.content().header("Authorization")
+///     {
+///         Some("Bearer secret") => next.run(request),
+///         _ => HttpResponse::new(401).with_header("WWW-Authenticate", "Bearer"),
+///     }
+/// }
+///
+/// let admin = Router::new(&[]).with_middleware(require_token);
+/// ```
+pub trait Middleware: Send + Sync
+{
+    fn handle(&self, request: HttpRequest, next: Next<'_>) -> HttpResponse;
+}
+
+/// The rest of a middleware chain, ending with the handler
+pub struct Next<'a>
+{
+    middleware: &'a [Arc<dyn Middleware>],
+    endpoint: &'a dyn Fn(HttpRequest) -> HttpResponse,
+}
+
+/// Values routers share with their handlers, innermost router last
+#[derive(Clone, Default)]
+pub(crate) struct StateMap(Vec<Arc<dyn Any + Send + Sync>>);
+
+/// A group of routes with its own middleware, not-found handler and
+/// state, which can be mounted under a path prefix in another router.
+///
+/// A mounted router owns every path under its prefix: its routes are
+/// matched against the rest of the path, and paths it has no route for go
+/// to the nearest not-found handler.
+#[derive(Clone, Default)]
+pub struct Router
+{
+    routes: Vec<HttpMethodHandler>,
+    /// Mounted routers, longest prefix first
+    mounts: Vec<(String, Router)>,
+    middleware: Vec<Arc<dyn Middleware>>,
+    not_found: Option<&'static dyn HttpHandler>,
+    state: Option<Arc<dyn Any + Send + Sync>>,
+}
+
+/// The routes of a running server, which can be changed while it serves.
+///
+/// Changes swap in a new router at once: requests already being served keep
+/// the routes they arrived with, and later requests see the change. Handles
+/// are cheap to clone and share between threads.
+#[derive(Clone)]
+pub struct RouteTable
+{
+    current: Arc<RwLock<Arc<Router>>>,
+}
+
+/// The routers a request path passes through
+pub(super) struct Resolved<'r>
+{
+    /// Outermost first
+    pub routers: Vec<&'r Router>,
+    /// The mount prefixes passed through
+    pub prefix: String,
+    /// The path left for the innermost router
+    pub path: String,
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(HttpRequest, Next<'_>) -> HttpResponse + Send + Sync,
+{
+    fn handle(&self, request: HttpRequest, next: Next<'_>) -> HttpResponse
+    {
+        self(request, next)
+    }
+}
+
+impl<'a> Next<'a>
+{
+    pub(super) fn new(middleware: &'a [Arc<dyn Middleware>], endpoint: &'a dyn Fn(HttpRequest) -> HttpResponse) -> Self
+    {
+        Self
+        {
+            middleware: middleware,
+            endpoint: endpoint,
+        }
+    }
+
+    /// Passes the request on to the next middleware, or the handler
+    pub fn run(self, request: HttpRequest) -> HttpResponse
+    {
+        match self.middleware.split_first()
+        {
+            Some((first, rest)) => first.handle(request, Next::new(rest, self.endpoint)),
+            None => (self.endpoint)(request),
+        }
+    }
+}
+
+impl StateMap
+{
+    pub(crate) fn new(values: Vec<Arc<dyn Any + Send + Sync>>) -> Self
+    {
+        Self(values)
+    }
+
+    /// Gets the state of type `T` of the innermost router having one
+    pub(crate) fn get<T: Any>(&self) -> Option<&T>
+    {
+        self.0.iter().rev().find_map(|state| state.downcast_ref::<T>())
+    }
+
+    /// Gets a handle on the state of type `T` of the innermost router having one
+    pub(crate) fn get_shared<T: Any + Send + Sync>(&self) -> Option<Arc<T>>
+    {
+        self.0.iter().rev().find_map(|state| state.clone().downcast::<T>().ok())
+    }
+}
+
+impl fmt::Debug for StateMap
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        write!(f, "StateMap({} values)", self.0.len())
+    }
+}
+
+impl fmt::Debug for Router
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        f.debug_struct("Router")
+        .field("routes", &self.routes.iter().map(|h| format!("{} {}", h.method(), h.route_handler().route())).collect::<Vec<_>>())
+        .field("mounts", &self.mounts)
+        .field("middleware", &self.middleware.len())
+        .finish()
+    }
+}
+
+/// Gets the rest of `path` under a mount prefix, on a segment boundary
+fn strip_mount<'p>(path: &'p str, prefix: &str) -> Option<&'p str>
+{
+    match path.strip_prefix(prefix)?
+    {
+        "" => Some("/"),
+        rest if rest.starts_with('/') => Some(rest),
+        _ => None,
+    }
+}
+
+impl Router
+{
+    pub fn new(routes: &[HttpMethodHandler]) -> Self
+    {
+        Self
+        {
+            routes: routes.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    /// Runs `middleware` around every handler of the router and the routers
+    /// mounted in it. Middleware added first runs first.
+    pub fn with_middleware(mut self, middleware: impl Middleware + 'static) -> Self
+    {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Answers paths under the router that no route matches
+    pub fn with_not_found(mut self, handler: &'static dyn HttpHandler) -> Self
+    {
+        self.not_found = Some(handler);
+        self
+    }
+
+    /// Shares a value with the handlers of the router through
+    /// `HttpContent::state`. Mounted routers see it unless they have state
+    /// of the same type.
+    pub fn with_state<T: Any + Send + Sync>(mut self, state: T) -> Self
+    {
+        self.state = Some(Arc::new(state));
+        self
+    }
+
+    /// Mounts a router under a path prefix such as `/api/v1`.
+    ///
+    /// # Panics
+    ///
+    /// When the prefix is not a valid route path, is `/`, has parameters, or
+    /// is mounted already, or when both routers have a route of the same name
+    pub fn mount(mut self, prefix: &str, router: Router) -> Self
+    {
+        if let Some(error) = path_error(prefix)
+        {
+            panic!("{error}: {prefix}");
+        }
+        assert!(prefix != "/", "routers can not be mounted at '/'");
+        assert!(!prefix.contains('{'), "mount prefixes can not have parameters: {prefix}");
+        assert!(self.mounts.iter().all(|(mounted, _)| mounted != prefix), "a router is mounted at {prefix} already");
+        let names: Vec<&str> = self.all_routes().iter().filter_map(|(_, h)| h.route_handler().name()).collect();
+        if let Some(name) = router.all_routes().iter().filter_map(|(_, h)| h.route_handler().name()).find(|name| names.contains(name))
+        {
+            panic!("a route named {name} exists already");
+        }
+        self.mounts.push((prefix.to_string(), router));
+        self.mounts.sort_by_key(|(prefix, _)| Reverse(prefix.len()));
+        self
+    }
+
+    pub fn routes(&self) -> &[HttpMethodHandler]
+    {
+        &self.routes
+    }
+
+    /// Adds a route to the router.
+    ///
+    /// Fails with `InvalidInput` when the path is malformed or under a
+    /// mounted router, and with `AlreadyExists` when the method and path, or
+    /// the name, are taken already.
+    pub fn add_route(&mut self, handler: HttpMethodHandler) -> Result<(), Error>
+    {
+        let route = handler.route_handler().route();
+        if let Some(error) = path_error(route)
+        {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("{error}: {route}")));
+        }
+        if let Some((prefix, _)) = self.mounts.iter().find(|(prefix, _)| strip_mount(route, prefix).is_some())
+        {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("{route} belongs to the router mounted at {prefix}")));
+        }
+        if self.routes.iter().any(|h| h.method() == handler.method() && same_pattern(h.route_handler().route(), route))
+        {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("{} {route} is registered already", handler.method())));
+        }
+        if let Some(name) = handler.route_handler().name()
+        {
+            if self.all_routes().iter().any(|(_, h)| h.route_handler().name() == Some(name))
+            {
+                return Err(Error::new(ErrorKind::AlreadyExists, format!("a route named {name} exists already")));
+            }
+        }
+        self.routes.push(handler);
+        Ok(())
+    }
+
+    /// Removes the route for a method and path, as it was registered
+    pub fn remove_route(&mut self, method: &str, route: &str) -> Option<HttpMethodHandler>
+    {
+        let index = self.routes.iter().position(|h| h.method() == method && h.route_handler().route() == route)?;
+        Some(self.routes.remove(index))
+    }
+
+    /// Gets every route of the router and the routers mounted in it, with
+    /// the full path of each
+    pub fn all_routes(&self) -> Vec<(String, &HttpMethodHandler)>
+    {
+        let mut routes: Vec<(String, &HttpMethodHandler)> = self.routes.iter()
+        .map(|handler| (handler.route_handler().route().to_string(), handler))
+        .collect();
+        for (prefix, router) in &self.mounts
+        {
+            routes.extend(router.all_routes().into_iter().map(|(route, handler)| (join(prefix, &route), handler)));
+        }
+        routes
+    }
+
+    /// Builds the URL of the route named `name` from the values of its
+    /// parameters, which must match the parameters of the route exactly.
+    ///
+    /// ```
+    /// use http::{routes, server::{router::Router, HttpMethodHandler}, HttpRequest};
+    ///
+    /// fn posts(_: HttpRequest) -> &'static str { "[]" }
+    ///
+    /// static ROUTES: &[HttpMethodHandler] = routes![
+    ///     Get "/users/{id}/posts" => posts.named("user_posts"),
+    /// ];
+    ///
+    /// let router = Router::new(&[]).mount("/api", Router::new(ROUTES));
+    /// assert_eq!(router.url_for("user_posts", &[("id", "42")]).unwrap(), "/api/users/42/posts");
+    /// assert!(router.url_for("user_posts", &[]).is_err());
+    /// ```
+    pub fn url_for(&self, name: &str, params: &[(&str, &str)]) -> Result<String, UrlError>
+    {
+        self.url_for_with_query(name, params, &[])
+    }
+
+    /// Builds the URL of a named route like [`url_for`](Self::url_for),
+    /// followed by a query string of the `query` pairs
+    pub fn url_for_with_query(&self, name: &str, params: &[(&str, &str)], query: &[(&str, &str)]) -> Result<String, UrlError>
+    {
+        let (route, _) = self.all_routes().into_iter()
+        .find(|(_, handler)| handler.route_handler().name() == Some(name))
+        .ok_or_else(|| UrlError::UnknownRoute(name.to_string()))?;
+        fill_route(name, &route, params, query)
+    }
+
+    /// Finds the routers a path passes through
+    pub(super) fn resolve(&self, path: &str) -> Resolved<'_>
+    {
+        let mut resolved = Resolved
+        {
+            routers: vec![self],
+            prefix: String::new(),
+            path: path.to_string(),
+        };
+        let mut router = self;
+        while let Some((prefix, mounted, rest)) = router.mounts.iter()
+        .find_map(|(prefix, mounted)| strip_mount(&resolved.path, prefix).map(|rest| (prefix, mounted, rest.to_string())))
+        {
+            resolved.routers.push(mounted);
+            resolved.prefix.push_str(prefix);
+            resolved.path = rest;
+            router = mounted;
+        }
+        resolved
+    }
+}
+
+impl RouteTable
+{
+    pub fn new(router: Router) -> Self
+    {
+        Self
+        {
+            current: Arc::new(RwLock::new(Arc::new(router))),
+        }
+    }
+
+    /// Gets the routes requests are served with now
+    pub fn load(&self) -> Arc<Router>
+    {
+        // The router is only ever swapped whole, so a panic while the lock
+        // was held can not have left it half changed
+        Arc::clone(&self.current.read().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Serves later requests with `router`
+    pub fn replace(&self, router: Router)
+    {
+        *self.current.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(router);
+    }
+
+    /// Changes a copy of the current router and swaps it in. Changes made
+    /// at the same time are applied one after the other, and a change that
+    /// panics leaves the routes as they were.
+    pub fn update<T>(&self, change: impl FnOnce(&mut Router) -> T) -> T
+    {
+        let mut current = self.current.write().unwrap_or_else(PoisonError::into_inner);
+        let mut router = Router::clone(&current);
+        let result = change(&mut router);
+        *current = Arc::new(router);
+        result
+    }
+
+    /// Adds a route, see [`Router::add_route`]
+    pub fn add(&self, handler: HttpMethodHandler) -> Result<(), Error>
+    {
+        self.update(|router| router.add_route(handler))
+    }
+
+    /// Removes the route for a method and path, see [`Router::remove_route`]
+    pub fn remove(&self, method: &str, route: &str) -> Option<HttpMethodHandler>
+    {
+        self.update(|router| router.remove_route(method, route))
+    }
+
+    /// Lists every route with its full path
+    pub fn list(&self) -> Vec<(String, HttpMethodHandler)>
+    {
+        self.load().all_routes().into_iter().map(|(route, handler)| (route, handler.clone())).collect()
+    }
+}
+
+impl fmt::Debug for RouteTable
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        f.debug_tuple("RouteTable").field(&self.load()).finish()
+    }
+}
+
+/// Joins a mount prefix and a route, where `/` is the prefix itself
+fn join(prefix: &str, route: &str) -> String
+{
+    match route
+    {
+        "/" if !prefix.is_empty() => prefix.to_string(),
+        route => format!("{prefix}{route}"),
+    }
+}
+
+impl<'r> Resolved<'r>
+{
+    fn router(&self) -> &'r Router
+    {
+        self.routers[self.routers.len() - 1]
+    }
+
+    /// Finds the handler for a method in the innermost router. Routes
+    /// without parameters are preferred.
+    pub fn find(&self, method: &str) -> Option<&'r HttpMethodHandler>
+    {
+        let routes = || self.router().routes.iter().filter(move |h| h.method() == method);
+        routes().find(|h| h.route_handler().route() == self.path)
+        .or_else(|| routes().find(|h| match_route(h.route_handler().route(), &self.path).is_some()))
+    }
+
+    /// Gets the methods the innermost router has routes for on the path
+    pub fn methods(&self) -> Vec<&'static str>
+    {
+        self.router().routes.iter()
+        .filter(|h| match_route(h.route_handler().route(), &self.path).is_some())
+        .map(|h| h.method())
+        .collect()
+    }
+
+    /// Gets the route of the innermost router matching the path, for any
+    /// method
+    pub fn route(&self) -> Option<&'r str>
+    {
+        let routes = &self.router().routes;
+        routes.iter().map(|h| h.route_handler().route()).find(|route| *route == self.path)
+        .or_else(|| routes.iter().map(|h| h.route_handler().route()).find(|route| match_route(route, &self.path).is_some()))
+    }
+
+    /// Gets the values of the parameters of a route of the innermost router
+    pub fn params(&self, route: &str) -> Vec<(String, String)>
+    {
+        match_route(route, &self.path).unwrap_or_default()
+    }
+
+    /// Gets the nearest not-found handler, from the innermost router out
+    pub fn not_found(&self) -> Option<&'static dyn HttpHandler>
+    {
+        self.routers.iter().rev().find_map(|router| router.not_found)
+    }
+
+    /// Gets the middleware of every router passed through, outermost first
+    pub fn middleware(&self) -> Vec<Arc<dyn Middleware>>
+    {
+        self.routers.iter().flat_map(|router| router.middleware.iter().cloned()).collect()
+    }
+
+    /// Gets the state of every router passed through, outermost first
+    pub fn state(&self) -> StateMap
+    {
+        StateMap::new(self.routers.iter().filter_map(|router| router.state.clone()).collect())
+    }
+
+    /// Gets the full pattern of a route of the innermost router, or of its
+    /// not-found handler when there is none
+    pub fn pattern(&self, route: Option<&str>) -> String
+    {
+        match route
+        {
+            Some(route) => join(&self.prefix, route),
+            None if self.prefix.is_empty() => "*".to_string(),
+            None => format!("{}/*", self.prefix),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{routes, server::HttpRouteHandler};
+
+    fn handler(_: HttpRequest) -> &'static str
+    {
+        ""
+    }
+
+    static ROOT: &[HttpMethodHandler] = routes![
+        Get "/" => handler,
+        Get "/users/me" => handler,
+        Get "/users/{id}" => handler.named("user"),
+        Delete "/users/{id}" => handler,
+    ];
+
+    static API: &[HttpMethodHandler] = routes![
+        Get "/" => handler,
+        Get "/files/{name}" => handler.named("file"),
+    ];
+
+    #[test]
+    fn test_resolve()
+    {
+        let router = Router::new(ROOT).mount("/api/v1", Router::new(API).with_state(7u32));
+        let resolved = router.resolve("/users/42");
+        assert_eq!(resolved.find("GET").map(|h| h.route_handler().route()), Some("/users/{id}"));
+        assert_eq!(resolved.params("/users/{id}"), [("id".to_string(), "42".to_string())]);
+        assert_eq!(resolved.methods(), ["GET", "DELETE"]);
+        assert_eq!(router.resolve("/users/me").find("GET").map(|h| h.route_handler().route()), Some("/users/me"));
+        let mounted = router.resolve("/api/v1");
+        assert_eq!((mounted.prefix.as_str(), mounted.path.as_str()), ("/api/v1", "/"));
+        assert_eq!(mounted.pattern(mounted.find("GET").map(|h| h.route_handler().route())), "/api/v1");
+        assert_eq!(mounted.state().get::<u32>(), Some(&7));
+        // Mounts match whole segments only
+        assert_eq!(router.resolve("/api/v10").prefix, "");
+        assert_eq!(router.resolve("/api/v1/nope").pattern(None), "/api/v1/*");
+        assert_eq!(router.url_for_with_query("file", &[("name", "a b")], &[("v", "2")]).unwrap(), "/api/v1/files/a%20b?v=2");
+        assert_eq!(router.url_for("nope", &[]), Err(UrlError::UnknownRoute("nope".to_string())));
+    }
+
+    #[test]
+    fn test_route_table()
+    {
+        let table = RouteTable::new(Router::new(ROOT).mount("/api", Router::new(API)));
+        let before = table.load();
+        table.add(HttpMethodHandler::Get(HttpRouteHandler::owned("/about", &handler))).unwrap();
+        let kind = |result: Result<(), Error>| result.unwrap_err().kind();
+        assert_eq!(kind(table.add(HttpMethodHandler::Get(HttpRouteHandler::owned("/about", &handler)))), ErrorKind::AlreadyExists);
+        assert_eq!(kind(table.add(HttpMethodHandler::Get(HttpRouteHandler::owned("/users/{name}", &handler)))), ErrorKind::AlreadyExists);
+        assert_eq!(kind(table.add(HttpMethodHandler::Get(HttpRouteHandler::new("/other", &handler).named("file")))), ErrorKind::AlreadyExists);
+        assert_eq!(kind(table.add(HttpMethodHandler::Get(HttpRouteHandler::owned("/api/x", &handler)))), ErrorKind::InvalidInput);
+        assert_eq!(kind(table.add(HttpMethodHandler::Get(HttpRouteHandler::owned("about", &handler)))), ErrorKind::InvalidInput);
+        // Requests holding the old routes keep them
+        assert!(before.resolve("/about").find("GET").is_none());
+        assert!(table.load().resolve("/about").find("GET").is_some());
+        assert!(table.list().iter().any(|(route, h)| route == "/about" && h.method() == "GET"));
+        assert!(table.remove("GET", "/about").is_some());
+        assert!(table.remove("GET", "/about").is_none());
+        assert_eq!(table.list().len(), ROOT.len() + API.len());
+        // A change that panics keeps the routes serving
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(||
+            table.update(|router| *router = std::mem::take(router).mount("api", Router::new(API)))));
+        assert!(panicked.is_err());
+        assert_eq!(table.list().len(), ROOT.len() + API.len());
+        table.add(HttpMethodHandler::Get(HttpRouteHandler::owned("/about", &handler))).unwrap();
+    }
+}
@@ -0,0 +1,472 @@
+use std::fmt;
+
+use super::HttpMethodHandler;
+use crate::form::{percent_decode, percent_encode};
+
+/// Builds a route table, checking at compile time that every path is
+/// well formed and that no method and path pair is registered twice.
+///
+/// Each route is a `HttpMethodHandler` variant, a path, and the handler
+/// function, which takes the request or [extractors](crate::extract::Extractor).
+/// It may be followed by `HttpRouteHandler` options such as
+/// `.streaming(limit)` or `.named(name)`. A path followed by a braced group
+/// prefixes every route in the group, where `""` stands for the prefix
+/// itself. Segments such as `{id}` match any one segment.
+///
+/// ```
+/// use http::{routes, server::HttpMethodHandler, HttpRequest};
+///
+/// fn index(_: HttpRequest) -> &'static str { "home" }
+/// fn users(_: HttpRequest) -> &'static str { "[]" }
+/// fn upload(_: HttpRequest) -> &'static str { "saved" }
+///
+/// static ROUTES: &[HttpMethodHandler] = routes![
+///     Get "/" => index,
+///     "/api" => {
+///         "/v1" => {
+///             Get "" => index,
+///             Get "/users" => users,
+///             Get "/users/{id}/posts" => users.named("user_posts"),
+///         },
+///         Post "/upload" => upload.streaming(1 << 20),
+///     },
+/// ];
+///
+/// let paths: Vec<&str> = ROUTES.iter().map(|h| h.route_handler().route()).collect();
+/// assert_eq!(paths, ["/", "/api/v1", "/api/v1/users", "/api/v1/users/{id}/posts", "/api/upload"]);
+/// ```
+///
+/// Long tables may need a higher `#![recursion_limit]`, as each route is
+/// one step of the expansion.
+#[macro_export]
+macro_rules! routes
+{
+    (@munch [$($acc:expr,)*] [$($prefix:literal)*]) =>
+    {{
+        const ROUTES: &[$crate::server::HttpMethodHandler] = &[$($acc,)*];
+        const _: &[$crate::server::HttpMethodHandler] = $crate::server::routes::check_routes(ROUTES);
+        ROUTES
+    }};
+    (@munch [$($acc:expr,)*] [$($prefix:literal)*] , $($rest:tt)*) =>
+    {
+        $crate::routes!(@munch [$($acc,)*] [$($prefix)*] $($rest)*)
+    };
+    // Ends a group, going back to the prefix outside it
+    (@munch [$($acc:expr,)*] [$($prefix:literal)*] @leave [$($outer:literal)*] $($rest:tt)*) =>
+    {
+        $crate::routes!(@munch [$($acc,)*] [$($outer)*] $($rest)*)
+    };
+    (@munch [$($acc:expr,)*] [$($prefix:literal)*] $group:literal => { $($inner:tt)* } $($rest:tt)*) =>
+    {
+        $crate::routes!(@munch [$($acc,)*] [$($prefix)* $group] $($inner)* , @leave [$($prefix)*] $($rest)*)
+    };
+    (@munch [$($acc:expr,)*] [$($prefix:literal)*] $method:ident $path:literal => $($handler:ident)::+ $(. $option:ident ($($arg:expr),*))* $(, $($rest:tt)*)?) =>
+    {
+        $crate::routes!(@munch
+            [$($acc,)* $crate::server::HttpMethodHandler::$method($crate::server::HttpRouteHandler::new(concat!($($prefix,)* $path), &$crate::extract::Extractor::new($($handler)::+))$(.$option($($arg),*))*),]
+            [$($prefix)*]
+            $($($rest)*)?)
+    };
+    ($($routes:tt)*) =>
+    {
+        $crate::routes!(@munch [] [] $($routes)*)
+    };
+}
+
+const fn is_hex(byte: u8) -> bool
+{
+    byte.is_ascii_hexdigit()
+}
+
+/// Whether a byte may appear in a path segment unescaped, RFC 3986
+/// section 3.3
+const fn is_pchar(byte: u8) -> bool
+{
+    byte.is_ascii_alphanumeric() || matches!(byte,
+        b'-' | b'.' | b'_' | b'~' |
+        b'!' | b'$' | b'&' | b'\'' | b'(' | b')' | b'*' | b'+' | b',' | b';' | b'=' |
+        b':' | b'@')
+}
+
+const fn str_eq(a: &str, b: &str) -> bool
+{
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len()
+    {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len()
+    {
+        if a[i] != b[i]
+        {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Whether a byte may appear in the name of a route parameter
+const fn is_param_char(byte: u8) -> bool
+{
+    byte.is_ascii_alphanumeric() || byte == b'_'
+}
+
+/// Finds the end of the path segment starting at `start`
+const fn segment_end(bytes: &[u8], start: usize) -> usize
+{
+    let mut end = start;
+    while end < bytes.len() && bytes[end] != b'/'
+    {
+        end += 1;
+    }
+    end
+}
+
+/// Whether the segment from `start` to `end` is a parameter such as `{id}`
+const fn is_param(bytes: &[u8], start: usize, end: usize) -> bool
+{
+    end > start && bytes[start] == b'{'
+}
+
+const fn bytes_eq(a: &[u8], a_start: usize, a_end: usize, b: &[u8], b_start: usize, b_end: usize) -> bool
+{
+    if a_end - a_start != b_end - b_start
+    {
+        return false;
+    }
+    let mut i = 0;
+    while a_start + i < a_end
+    {
+        if a[a_start + i] != b[b_start + i]
+        {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Whether two route paths match the same request paths, as they only
+/// differ in the names of their parameters
+pub(crate) const fn same_pattern(a: &str, b: &str) -> bool
+{
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len()
+    {
+        let (a_end, b_end) = (segment_end(a, i + 1), segment_end(b, j + 1));
+        let both_params = is_param(a, i + 1, a_end) && is_param(b, j + 1, b_end);
+        if !both_params && !bytes_eq(a, i + 1, a_end, b, j + 1, b_end)
+        {
+            return false;
+        }
+        i = a_end;
+        j = b_end;
+    }
+    i == a.len() && j == b.len()
+}
+
+/// Finds a parameter name used twice in a route path
+const fn repeats_param(path: &str) -> bool
+{
+    let bytes = path.as_bytes();
+    let mut i = 0;
+    while i < bytes.len()
+    {
+        let end = segment_end(bytes, i + 1);
+        let mut j = end;
+        while is_param(bytes, i + 1, end) && j < bytes.len()
+        {
+            let other_end = segment_end(bytes, j + 1);
+            if bytes_eq(bytes, i + 1, end, bytes, j + 1, other_end)
+            {
+                return true;
+            }
+            j = other_end;
+        }
+        i = end;
+    }
+    false
+}
+
+/// Checks the syntax of a route path, returning what is wrong with it.
+///
+/// Paths start with `/`, have no empty, `.` or `..` segments, no trailing
+/// slash other than the root, and only characters allowed in a path, with
+/// anything else percent-encoded. Parameters such as `{id}` take up a whole
+/// segment and are named with letters, digits and `_`.
+pub const fn path_error(path: &str) -> Option<&'static str>
+{
+    let bytes = path.as_bytes();
+    if bytes.is_empty() || bytes[0] != b'/'
+    {
+        return Some("route paths must start with '/'");
+    }
+    if bytes.len() > 1 && bytes[bytes.len() - 1] == b'/'
+    {
+        return Some("route paths must not end with '/'");
+    }
+    let mut i = 1;
+    let mut segment_start = 1;
+    while i <= bytes.len()
+    {
+        if i == bytes.len() || bytes[i] == b'/'
+        {
+            let segment = i - segment_start;
+            if segment == 0 && bytes.len() > 1
+            {
+                return Some("route paths must not have empty segments");
+            }
+            if (segment == 1 && bytes[segment_start] == b'.') || (segment == 2 && bytes[segment_start] == b'.' && bytes[segment_start + 1] == b'.')
+            {
+                return Some("route paths must not have '.' or '..' segments");
+            }
+            segment_start = i + 1;
+        }
+        else if bytes[i] == b'%'
+        {
+            if i + 2 >= bytes.len() || !is_hex(bytes[i + 1]) || !is_hex(bytes[i + 2])
+            {
+                return Some("route paths must only have '%' in percent escapes");
+            }
+            i += 2;
+        }
+        else if bytes[i] == b'{' && i == segment_start
+        {
+            let end = segment_end(bytes, i);
+            let mut j = i + 1;
+            while j < end && is_param_char(bytes[j])
+            {
+                j += 1;
+            }
+            if j == i + 1 || j != end - 1 || bytes[j] != b'}'
+            {
+                return Some("route parameters must be a whole segment such as '{id}'");
+            }
+            i = j;
+        }
+        else if bytes[i] == b'{' || bytes[i] == b'}'
+        {
+            return Some("route parameters must be a whole segment such as '{id}'");
+        }
+        else if !is_pchar(bytes[i])
+        {
+            return Some("route paths must percent-encode characters such as spaces, '?' and '#'");
+        }
+        i += 1;
+    }
+    if repeats_param(path)
+    {
+        return Some("route parameters must have different names");
+    }
+    None
+}
+
+/// Checks a route table, failing compilation when used in a constant.
+/// Used by [`routes!`](crate::routes).
+///
+/// # Panics
+///
+/// When a path is malformed, a method and path are registered twice, or
+/// two routes have the same name
+pub const fn check_routes(routes: &'static [HttpMethodHandler]) -> &'static [HttpMethodHandler]
+{
+    let mut i = 0;
+    while i < routes.len()
+    {
+        let route = routes[i].route_handler().route();
+        if let Some(error) = path_error(route)
+        {
+            panic!("{}", error);
+        }
+        let mut j = 0;
+        while j < i
+        {
+            if str_eq(routes[j].method(), routes[i].method()) && same_pattern(routes[j].route_handler().route(), route)
+            {
+                panic!("a method and route pair is registered twice");
+            }
+            if let (Some(a), Some(b)) = (routes[j].route_handler().name(), routes[i].route_handler().name())
+            {
+                if str_eq(a, b)
+                {
+                    panic!("a route name is used twice");
+                }
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    routes
+}
+
+/// Matches a request path against a route path, getting the decoded
+/// values of its parameters
+pub(crate) fn match_route(route: &str, path: &str) -> Option<Vec<(String, String)>>
+{
+    if !route.contains('{')
+    {
+        return (route == path).then(Vec::new);
+    }
+    let mut params = Vec::new();
+    let mut segments = path.split('/');
+    for pattern in route.split('/')
+    {
+        let segment = segments.next()?;
+        match pattern.strip_prefix('{').and_then(|name| name.strip_suffix('}'))
+        {
+            Some(_) if segment.is_empty() => return None,
+            Some(name) => params.push((name.to_string(), percent_decode(segment, false).ok()?)),
+            None if pattern != segment => return None,
+            None => {},
+        }
+    }
+    segments.next().is_none().then_some(params)
+}
+
+/// Why a URL could not be built for a route
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UrlError
+{
+    /// No route has the name
+    UnknownRoute(String),
+    /// The route has a parameter no value was given for
+    MissingParam
+    {
+        route: String,
+        param: String,
+    },
+    /// A value was given for a parameter the route does not have
+    ExtraParam
+    {
+        route: String,
+        param: String,
+    },
+}
+
+impl fmt::Display for UrlError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            UrlError::UnknownRoute(name) => write!(f, "no route is named `{name}`"),
+            UrlError::MissingParam { route, param } => write!(f, "route `{route}` needs a value for `{param}`"),
+            UrlError::ExtraParam { route, param } => write!(f, "route `{route}` has no parameter `{param}`"),
+        }
+    }
+}
+
+impl std::error::Error for UrlError {}
+
+/// Builds the path of a route, percent-encoding the parameter values, and
+/// appends the query pairs if there are any
+pub(crate) fn fill_route(name: &str, route: &str, params: &[(&str, &str)], query: &[(&str, &str)]) -> Result<String, UrlError>
+{
+    let mut used = vec![false; params.len()];
+    let mut segments = Vec::new();
+    for segment in route.split('/')
+    {
+        match segment.strip_prefix('{').and_then(|param| param.strip_suffix('}'))
+        {
+            Some(param) =>
+            {
+                let index = params.iter().position(|(name, _)| *name == param).ok_or_else(|| UrlError::MissingParam
+                {
+                    route: name.to_string(),
+                    param: param.to_string(),
+                })?;
+                used[index] = true;
+                segments.push(percent_encode(params[index].1));
+            },
+            None => segments.push(segment.to_string()),
+        }
+    }
+    if let Some(index) = used.iter().position(|used| !used)
+    {
+        return Err(UrlError::ExtraParam
+        {
+            route: name.to_string(),
+            param: params[index].0.to_string(),
+        });
+    }
+    let mut url = segments.join("/");
+    for (i, (name, value)) in query.iter().enumerate()
+    {
+        url.push(if i == 0 { '?' } else { '&' });
+        url.push_str(&format!("{}={}", percent_encode(name), percent_encode(value)));
+    }
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_error()
+    {
+        for path in ["/", "/upload", "/api/v1/users", "/a%20b", "/~user/file.tar.gz", "/x:y@z"]
+        {
+            assert_eq!(path_error(path), None, "{path}");
+        }
+        assert_eq!(path_error(""), Some("route paths must start with '/'"));
+        assert_eq!(path_error("api"), Some("route paths must start with '/'"));
+        assert_eq!(path_error("/api/"), Some("route paths must not end with '/'"));
+        assert_eq!(path_error("/api//v1"), Some("route paths must not have empty segments"));
+        assert_eq!(path_error("/a/../b"), Some("route paths must not have '.' or '..' segments"));
+        assert_eq!(path_error("/a/."), Some("route paths must not have '.' or '..' segments"));
+        assert_eq!(path_error("/a%2"), Some("route paths must only have '%' in percent escapes"));
+        assert_eq!(path_error("/a b"), Some("route paths must percent-encode characters such as spaces, '?' and '#'"));
+        assert_eq!(path_error("/a?b"), Some("route paths must percent-encode characters such as spaces, '?' and '#'"));
+        assert_eq!(path_error("/users/{id}/posts/{post_id}"), None);
+        assert_eq!(path_error("/users/{}"), Some("route parameters must be a whole segment such as '{id}'"));
+        assert_eq!(path_error("/users/x{id}"), Some("route parameters must be a whole segment such as '{id}'"));
+        assert_eq!(path_error("/users/{id}x"), Some("route parameters must be a whole segment such as '{id}'"));
+        assert_eq!(path_error("/users/{a-b}"), Some("route parameters must be a whole segment such as '{id}'"));
+        assert_eq!(path_error("/users/{id}/friends/{id}"), Some("route parameters must have different names"));
+        assert!(same_pattern("/users/{id}", "/users/{name}"));
+        assert!(!same_pattern("/users/{id}", "/users/me"));
+        assert!(!same_pattern("/users/{id}", "/users/{id}/posts"));
+    }
+
+    #[test]
+    fn test_match_route()
+    {
+        assert_eq!(match_route("/upload", "/upload"), Some(vec![]));
+        assert_eq!(match_route("/upload", "/uploads"), None);
+        let params = match_route("/users/{id}/posts/{post}", "/users/42/posts/a%20b").unwrap();
+        assert_eq!(params, [("id".to_string(), "42".to_string()), ("post".to_string(), "a b".to_string())]);
+        assert_eq!(match_route("/users/{id}", "/users/"), None);
+        assert_eq!(match_route("/users/{id}", "/users/1/posts"), None);
+        assert_eq!(match_route("/users/{id}/posts", "/users/1"), None);
+    }
+
+    #[test]
+    fn test_fill_route()
+    {
+        let route = "/users/{id}/posts";
+        assert_eq!(fill_route("posts", route, &[("id", "42")], &[]).unwrap(), "/users/42/posts");
+        assert_eq!(fill_route("posts", route, &[("id", "a/b c")], &[("sort", "new&old")]).unwrap(), "/users/a%2Fb%20c/posts?sort=new%26old");
+        assert_eq!(fill_route("posts", route, &[], &[]), Err(UrlError::MissingParam { route: "posts".to_string(), param: "id".to_string() }));
+        assert_eq!(fill_route("posts", route, &[("id", "1"), ("page", "2")], &[]), Err(UrlError::ExtraParam { route: "posts".to_string(), param: "page".to_string() }));
+    }
+
+    #[test]
+    #[should_panic(expected = "registered twice")]
+    fn test_duplicates()
+    {
+        fn handler(_: crate::HttpRequest) -> &'static str
+        {
+            ""
+        }
+        static ROUTES: &[HttpMethodHandler] = &[
+            HttpMethodHandler::Get(super::super::HttpRouteHandler::new("/a", &handler)),
+            HttpMethodHandler::Post(super::super::HttpRouteHandler::new("/a", &handler)),
+            HttpMethodHandler::Get(super::super::HttpRouteHandler::new("/a", &handler)),
+        ];
+        check_routes(ROUTES);
+    }
+}
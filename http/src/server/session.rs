@@ -0,0 +1,565 @@
+use std::{collections::{BTreeMap, HashMap}, fmt, fs::{self, File}, io::{Error, ErrorKind, Read}, path::PathBuf, process, sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use crate::{cookie::{Cookie, SameSite}, crypto::base64url, form::{percent_decode, percent_encode}, HttpContent, HttpResponse};
+
+/// Random bytes in a session ID
+const ID_BYTES: usize = 32;
+/// Loads between sweeps of expired sessions out of the store
+const PURGE_INTERVAL: usize = 256;
+
+/// Numbers the temporary files of `FileStore` writes
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// A session as kept by a store
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredSession
+{
+    pub data: BTreeMap<String, String>,
+    pub created: SystemTime,
+    pub last_access: SystemTime,
+}
+
+/// Where sessions are kept between requests
+pub trait SessionStore: Send + Sync
+{
+    fn load(&self, id: &str) -> Result<Option<StoredSession>, Error>;
+    fn save(&self, id: &str, session: &StoredSession) -> Result<(), Error>;
+    fn remove(&self, id: &str) -> Result<(), Error>;
+    /// Removes every session for which `expired` returns true
+    fn purge(&self, expired: &dyn Fn(&StoredSession) -> bool) -> Result<(), Error>;
+}
+
+/// Keeps sessions in memory, losing them when the server stops
+#[derive(Debug, Default)]
+pub struct MemoryStore
+{
+    sessions: Mutex<HashMap<String, StoredSession>>,
+}
+
+/// Keeps each session in a file named after its ID
+#[derive(Debug)]
+pub struct FileStore
+{
+    dir: PathBuf,
+}
+
+#[derive(Debug)]
+struct SessionState
+{
+    id: String,
+    data: BTreeMap<String, String>,
+    created: SystemTime,
+    /// The client does not hold the ID yet
+    is_new: bool,
+    /// The ID the client held before `rotate`
+    rotated_from: Option<String>,
+    changed: bool,
+    destroyed: bool,
+}
+
+/// The session of the current request.
+///
+/// Changes are saved and the cookie is sent once the handler returns.
+#[derive(Clone)]
+pub struct Session
+{
+    state: Arc<Mutex<SessionState>>,
+}
+
+/// Issues, loads and expires sessions for a server
+pub struct Sessions
+{
+    store: Box<dyn SessionStore>,
+    cookie_name: String,
+    idle_timeout: Duration,
+    absolute_timeout: Duration,
+    secure: bool,
+    loads: AtomicUsize,
+}
+
+/// Creates a session ID from the system's random source
+fn random_id() -> Result<String, Error>
+{
+    let mut bytes = [0u8; ID_BYTES];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(base64url(&bytes))
+}
+
+/// Checks that an ID from a cookie could have been issued, so it can name a file
+fn valid_id(id: &str) -> bool
+{
+    id.len() == base64url(&[0u8; ID_BYTES]).len() &&
+    id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+fn secs(time: SystemTime) -> u64
+{
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+impl SessionStore for MemoryStore
+{
+    fn load(&self, id: &str) -> Result<Option<StoredSession>, Error>
+    {
+        Ok(self.sessions.lock().unwrap().get(id).cloned())
+    }
+
+    fn save(&self, id: &str, session: &StoredSession) -> Result<(), Error>
+    {
+        self.sessions.lock().unwrap().insert(id.to_string(), session.clone());
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> Result<(), Error>
+    {
+        self.sessions.lock().unwrap().remove(id);
+        Ok(())
+    }
+
+    fn purge(&self, expired: &dyn Fn(&StoredSession) -> bool) -> Result<(), Error>
+    {
+        self.sessions.lock().unwrap().retain(|_, session| !expired(session));
+        Ok(())
+    }
+}
+
+impl MemoryStore
+{
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+}
+
+impl FileStore
+{
+    /// Creates a store in `dir`, creating the directory if needed
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self, Error>
+    {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self
+        {
+            dir: dir
+        })
+    }
+
+    fn path(&self, id: &str) -> Result<PathBuf, Error>
+    {
+        if !valid_id(id)
+        {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid session ID"));
+        }
+        Ok(self.dir.join(id))
+    }
+
+    /// Parses a session file: two timestamps, then one `key=value` line per entry
+    fn parse(contents: &str) -> Option<StoredSession>
+    {
+        let mut lines = contents.lines();
+        let created = lines.next()?.parse::<u64>().ok()?;
+        let last_access = lines.next()?.parse::<u64>().ok()?;
+        let mut data = BTreeMap::new();
+        for line in lines
+        {
+            let (key, value) = line.split_once('=')?;
+            data.insert(percent_decode(key, false).ok()?, percent_decode(value, false).ok()?);
+        }
+        Some(StoredSession
+        {
+            data: data,
+            created: UNIX_EPOCH + Duration::from_secs(created),
+            last_access: UNIX_EPOCH + Duration::from_secs(last_access),
+        })
+    }
+}
+
+impl SessionStore for FileStore
+{
+    fn load(&self, id: &str) -> Result<Option<StoredSession>, Error>
+    {
+        match fs::read_to_string(self.path(id)?)
+        {
+            Ok(contents) => Ok(Self::parse(&contents)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn save(&self, id: &str, session: &StoredSession) -> Result<(), Error>
+    {
+        let mut contents = format!("{}\n{}\n", secs(session.created), secs(session.last_access));
+        for (key, value) in &session.data
+        {
+            contents.push_str(&format!("{}={}\n", percent_encode(key), percent_encode(value)));
+        }
+        // Write then rename so a concurrent load never sees half a file,
+        // through a file of its own so concurrent saves do not share one
+        let path = self.path(id)?;
+        let temp = path.with_extension(format!("{}.{}.tmp", process::id(), TEMP_FILES.fetch_add(1, Ordering::Relaxed)));
+        fs::write(&temp, contents)?;
+        fs::rename(&temp, path).inspect_err(|_|
+        {
+            let _ = fs::remove_file(&temp);
+        })
+    }
+
+    fn remove(&self, id: &str) -> Result<(), Error>
+    {
+        match fs::remove_file(self.path(id)?)
+        {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn purge(&self, expired: &dyn Fn(&StoredSession) -> bool) -> Result<(), Error>
+    {
+        for entry in fs::read_dir(&self.dir)?
+        {
+            let entry = entry?;
+            let Some(id) = entry.file_name().to_str().map(|id| id.to_string()) else
+            {
+                continue;
+            };
+            if valid_id(&id) && self.load(&id)?.is_none_or(|session| expired(&session))
+            {
+                self.remove(&id)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Session
+{
+    fn new(id: String, stored: StoredSession, is_new: bool) -> Self
+    {
+        Self
+        {
+            state: Arc::new(Mutex::new(SessionState
+            {
+                id: id,
+                data: stored.data,
+                created: stored.created,
+                is_new: is_new,
+                rotated_from: None,
+                changed: false,
+                destroyed: false,
+            }))
+        }
+    }
+
+    /// Gets the current session ID
+    pub fn id(&self) -> String
+    {
+        self.state.lock().unwrap().id.clone()
+    }
+
+    pub fn get(&self, key: &str) -> Option<String>
+    {
+        self.state.lock().unwrap().data.get(key).cloned()
+    }
+
+    pub fn insert(&self, key: &str, value: &str)
+    {
+        let mut state = self.state.lock().unwrap();
+        state.data.insert(key.to_string(), value.to_string());
+        state.changed = true;
+    }
+
+    pub fn remove(&self, key: &str) -> Option<String>
+    {
+        let mut state = self.state.lock().unwrap();
+        state.changed = true;
+        state.data.remove(key)
+    }
+
+    /// Issues a new ID for the session, keeping its data.
+    ///
+    /// Call this whenever privileges change, such as on login, so an ID
+    /// planted on the client before cannot be used to ride the new session.
+    pub fn rotate(&self) -> Result<(), Error>
+    {
+        let mut state = self.state.lock().unwrap();
+        let old = std::mem::replace(&mut state.id, random_id()?);
+        if !state.is_new && state.rotated_from.is_none()
+        {
+            state.rotated_from = Some(old);
+        }
+        state.changed = true;
+        Ok(())
+    }
+
+    /// Ends the session, removing it from the store and the client
+    pub fn destroy(&self)
+    {
+        let mut state = self.state.lock().unwrap();
+        state.data.clear();
+        state.destroyed = true;
+    }
+}
+
+impl fmt::Debug for Session
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        // The ID is a credential, keep it out of logs
+        let state = self.state.lock().unwrap();
+        f.debug_struct("Session").field("data", &state.data).finish_non_exhaustive()
+    }
+}
+
+impl Sessions
+{
+    /// Creates sessions kept in `store`, expiring after 30 minutes idle or
+    /// 12 hours in total
+    pub fn new(store: impl SessionStore + 'static) -> Self
+    {
+        Self
+        {
+            store: Box::new(store),
+            cookie_name: "session".to_string(),
+            idle_timeout: Duration::from_secs(30 * 60),
+            absolute_timeout: Duration::from_secs(12 * 60 * 60),
+            secure: false,
+            loads: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn with_cookie_name(mut self, cookie_name: &str) -> Self
+    {
+        self.cookie_name = cookie_name.to_string();
+        self
+    }
+
+    /// Sets how long a session lives without requests
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self
+    {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Sets how long a session lives at most, however active
+    pub fn with_absolute_timeout(mut self, absolute_timeout: Duration) -> Self
+    {
+        self.absolute_timeout = absolute_timeout;
+        self
+    }
+
+    /// Marks the session cookie `Secure`, for servers behind HTTPS
+    pub fn with_secure(mut self, secure: bool) -> Self
+    {
+        self.secure = secure;
+        self
+    }
+
+    fn expired(&self, session: &StoredSession, now: SystemTime) -> bool
+    {
+        let age = |since: SystemTime| now.duration_since(since).unwrap_or(Duration::ZERO);
+        age(session.last_access) > self.idle_timeout || age(session.created) > self.absolute_timeout
+    }
+
+    /// Gets the session named by the request's cookie, or a new one
+    pub(crate) fn load(&self, content: &HttpContent) -> Result<Session, Error>
+    {
+        let now = SystemTime::now();
+        if self.loads.fetch_add(1, Ordering::Relaxed) % PURGE_INTERVAL == 0
+        {
+            // A failed sweep is retried on a later load, the request goes on
+            if let Err(e) = self.store.purge(&|session| self.expired(session, now))
+            {
+                println!("Failed to purge expired sessions: {e}");
+            }
+        }
+        if let Some(id) = content.cookie(&self.cookie_name).filter(|id| valid_id(id))
+        {
+            match self.store.load(&id)?
+            {
+                Some(stored) if !self.expired(&stored, now) => return Ok(Session::new(id, stored, false)),
+                Some(_) => self.store.remove(&id)?,
+                None => {},
+            }
+        }
+        let stored = StoredSession
+        {
+            data: BTreeMap::new(),
+            created: now,
+            last_access: now,
+        };
+        Ok(Session::new(random_id()?, stored, true))
+    }
+
+    fn cookie(&self, value: &str) -> Cookie
+    {
+        Cookie::new(&self.cookie_name, value)
+        .with_path("/")
+        .with_http_only(true)
+        .with_same_site(SameSite::Lax)
+        .with_secure(self.secure)
+    }
+
+    /// Saves the session after the handler ran and tells the client its ID.
+    ///
+    /// The handler's response is sent even when the store fails, which is
+    /// only logged.
+    pub(crate) fn commit(&self, session: &Session, response: HttpResponse) -> HttpResponse
+    {
+        let state = session.state.lock().unwrap();
+        let logged = |result: Result<(), Error>|
+        {
+            if let Err(e) = result
+            {
+                println!("Failed to store session: {e}");
+            }
+        };
+        if let Some(old) = &state.rotated_from
+        {
+            logged(self.store.remove(old));
+        }
+        if state.destroyed
+        {
+            if state.is_new && state.rotated_from.is_none()
+            {
+                return response;
+            }
+            logged(self.store.remove(&state.id));
+            return response.with_cookie(&self.cookie("").with_max_age(Duration::ZERO));
+        }
+        // A new session is only kept once something was stored in it
+        if state.is_new && !state.changed
+        {
+            return response;
+        }
+        let stored = StoredSession
+        {
+            data: state.data.clone(),
+            created: state.created,
+            last_access: SystemTime::now(),
+        };
+        logged(self.store.save(&state.id, &stored));
+        if state.is_new || state.rotated_from.is_some()
+        {
+            return response.with_cookie(&self.cookie(&state.id));
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(cookie: Option<&str>) -> HttpContent
+    {
+        let mut head = "GET / HTTP/1.1\r\n".to_string();
+        if let Some(cookie) = cookie
+        {
+            head.push_str(&format!("Cookie: {cookie}\r\n"));
+        }
+        head.push_str("\r\n");
+        match crate::HttpRequest::parse_head(head.as_bytes()).unwrap().unwrap().0
+        {
+            crate::HttpRequest::Get(content) => content,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Runs one request through the sessions, returning the Set-Cookie header
+    fn round_trip(sessions: &Sessions, cookie: Option<&str>, handler: impl Fn(&Session)) -> Option<String>
+    {
+        let session = sessions.load(&request(cookie)).unwrap();
+        handler(&session);
+        let response = sessions.commit(&session, HttpResponse::new(200));
+        response.header("Set-Cookie").map(|c| c.to_string())
+    }
+
+    fn id_of(set_cookie: &str) -> String
+    {
+        set_cookie.split(';').next().unwrap().split_once('=').unwrap().1.to_string()
+    }
+
+    fn exercise(sessions: Sessions)
+    {
+        // Nothing is stored or sent until the session holds data
+        assert!(round_trip(&sessions, None, |_| {}).is_none());
+        let set = round_trip(&sessions, None, |s| s.insert("user", "ada")).unwrap();
+        assert!(set.contains("HttpOnly") && set.contains("SameSite=Lax"));
+        let id = id_of(&set);
+        let cookie = format!("session={id}");
+        // A known session is read back without a new cookie
+        assert!(round_trip(&sessions, Some(&cookie), |s| assert_eq!(s.get("user").as_deref(), Some("ada"))).is_none());
+        // Rotation moves the data to a new ID and forgets the old one
+        let rotated = id_of(&round_trip(&sessions, Some(&cookie), |s| s.rotate().unwrap()).unwrap());
+        assert_ne!(rotated, id);
+        assert!(sessions.store.load(&id).unwrap().is_none());
+        let cookie = format!("session={rotated}");
+        round_trip(&sessions, Some(&cookie), |s| assert_eq!(s.get("user").as_deref(), Some("ada")));
+        // Destroying removes it from the store and the client
+        let removal = round_trip(&sessions, Some(&cookie), |s| s.destroy()).unwrap();
+        assert!(removal.starts_with("session=;"));
+        assert!(sessions.store.load(&rotated).unwrap().is_none());
+        // Unknown or forged IDs start a fresh session
+        round_trip(&sessions, Some("session=../../etc/passwd"), |s| assert!(s.get("user").is_none()));
+    }
+
+    #[test]
+    fn test_memory_store()
+    {
+        exercise(Sessions::new(MemoryStore::new()));
+    }
+
+    #[test]
+    fn test_file_store()
+    {
+        let dir = std::env::temp_dir().join(format!("sessions-{}", std::process::id()));
+        exercise(Sessions::new(FileStore::new(&dir).unwrap()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_saves()
+    {
+        let dir = std::env::temp_dir().join(format!("sessions-concurrent-{}", std::process::id()));
+        let store = FileStore::new(&dir).unwrap();
+        let id = random_id().unwrap();
+        let stored = StoredSession
+        {
+            data: BTreeMap::from([("k".to_string(), "v".to_string())]),
+            created: UNIX_EPOCH,
+            last_access: UNIX_EPOCH,
+        };
+        std::thread::scope(|scope|
+        {
+            for _ in 0..8
+            {
+                scope.spawn(|| for _ in 0..50
+                {
+                    store.save(&id, &stored).unwrap();
+                });
+            }
+        });
+        assert_eq!(store.load(&id).unwrap(), Some(stored));
+        // Only the session file is left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_expiry()
+    {
+        let sessions = Sessions::new(MemoryStore::new()).with_idle_timeout(Duration::from_secs(60));
+        let id = id_of(&round_trip(&sessions, None, |s| s.insert("k", "v")).unwrap());
+        let mut stored = sessions.store.load(&id).unwrap().unwrap();
+        stored.last_access -= Duration::from_secs(120);
+        sessions.store.save(&id, &stored).unwrap();
+        round_trip(&sessions, Some(&format!("session={id}")), |s| assert!(s.get("k").is_none()));
+        assert!(sessions.store.load(&id).unwrap().is_none());
+        let sessions = sessions.with_absolute_timeout(Duration::from_secs(60));
+        let id = id_of(&round_trip(&sessions, None, |s| s.insert("k", "v")).unwrap());
+        let mut stored = sessions.store.load(&id).unwrap().unwrap();
+        stored.created -= Duration::from_secs(120);
+        sessions.store.save(&id, &stored).unwrap();
+        round_trip(&sessions, Some(&format!("session={id}")), |s| assert!(s.get("k").is_none()));
+    }
+}
@@ -0,0 +1,195 @@
+use std::{collections::BTreeMap, io::Error, sync::{Arc, Condvar, Mutex, MutexGuard}, thread, time::Instant};
+
+use crate::panic::catch;
+
+/// Runs a callback once a deadline passes, from a single thread shared by
+/// every deadline watched. The thread starts with the first deadline.
+pub(super) struct Watchdog
+{
+    shared: Arc<Shared>,
+}
+
+/// A deadline being watched, to be finished once the work it bounds is done
+pub(super) struct Watch
+{
+    shared: Arc<Shared>,
+    key: Key,
+}
+
+/// Deadlines are ordered by time, a counter tells equal times apart
+type Key = (Instant, u64);
+
+type Expire = Box<dyn FnOnce() + Send>;
+
+struct Shared
+{
+    deadlines: Mutex<Deadlines>,
+    /// Signalled when a deadline is added, and when a callback returns
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct Deadlines
+{
+    pending: BTreeMap<Key, Expire>,
+    /// The deadline whose callback is running
+    expiring: Option<Key>,
+    next: u64,
+    running: bool,
+    stopped: bool,
+}
+
+impl Watchdog
+{
+    pub fn new() -> Self
+    {
+        Self
+        {
+            shared: Arc::new(Shared
+            {
+                deadlines: Mutex::new(Deadlines::default()),
+                changed: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Runs `expire` at `deadline` unless the watch is finished first
+    pub fn watch(&self, deadline: Instant, expire: impl FnOnce() + Send + 'static) -> Result<Watch, Error>
+    {
+        let mut deadlines = self.shared.lock();
+        if !deadlines.running
+        {
+            let shared = Arc::clone(&self.shared);
+            thread::Builder::new().name("watchdog".to_string()).spawn(move || shared.run())?;
+            deadlines.running = true;
+        }
+        let key = (deadline, deadlines.next);
+        deadlines.next += 1;
+        deadlines.pending.insert(key, Box::new(expire));
+        self.shared.changed.notify_all();
+        Ok(Watch
+        {
+            shared: Arc::clone(&self.shared),
+            key: key,
+        })
+    }
+}
+
+impl Drop for Watchdog
+{
+    fn drop(&mut self)
+    {
+        self.shared.lock().stopped = true;
+        self.shared.changed.notify_all();
+    }
+}
+
+impl Watch
+{
+    /// Stops watching the deadline, returning false when it passed first.
+    /// A callback already running is waited for, so that whatever it did
+    /// is done on return.
+    pub fn finish(self) -> bool
+    {
+        let mut deadlines = self.shared.lock();
+        if deadlines.pending.remove(&self.key).is_some()
+        {
+            return true;
+        }
+        while deadlines.expiring == Some(self.key)
+        {
+            deadlines = self.shared.changed.wait(deadlines).unwrap_or_else(|e| e.into_inner());
+        }
+        false
+    }
+}
+
+impl Shared
+{
+    /// A callback that panicked leaves nothing half changed in the deadlines
+    fn lock(&self) -> MutexGuard<'_, Deadlines>
+    {
+        self.deadlines.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn run(&self)
+    {
+        let mut deadlines = self.lock();
+        while !deadlines.stopped
+        {
+            let now = Instant::now();
+            let Some(&key) = deadlines.pending.keys().next() else
+            {
+                deadlines = self.changed.wait(deadlines).unwrap_or_else(|e| e.into_inner());
+                continue;
+            };
+            if key.0 > now
+            {
+                deadlines = self.changed.wait_timeout(deadlines, key.0 - now).unwrap_or_else(|e| e.into_inner()).0;
+                continue;
+            }
+            let expire = deadlines.pending.remove(&key).unwrap();
+            deadlines.expiring = Some(key);
+            // Other deadlines can be added and finished meanwhile
+            drop(deadlines);
+            if let Err(panic) = catch(expire)
+            {
+                println!("Watchdog callback panicked: {panic}");
+            }
+            deadlines = self.lock();
+            deadlines.expiring = None;
+            self.changed.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::atomic::{AtomicUsize, Ordering}, time::Duration};
+
+    #[test]
+    fn test_watchdog()
+    {
+        let watchdog = Watchdog::new();
+        let fired = Arc::new(AtomicUsize::new(0));
+        let counter = ||
+        {
+            let fired = Arc::clone(&fired);
+            move || { fired.fetch_add(1, Ordering::SeqCst); }
+        };
+        // Finished in time, the callback never runs
+        let watch = watchdog.watch(Instant::now() + Duration::from_millis(200), counter()).unwrap();
+        assert!(watch.finish());
+        // Deadlines added later but due earlier are not held up
+        let late = watchdog.watch(Instant::now() + Duration::from_secs(60), counter()).unwrap();
+        let start = Instant::now();
+        let soon = watchdog.watch(start + Duration::from_millis(50), counter()).unwrap();
+        while fired.load(Ordering::SeqCst) == 0
+        {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(!soon.finish());
+        assert!(late.finish());
+        thread::sleep(Duration::from_millis(250));
+        assert_eq!(fired.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_finish_waits_for_callback()
+    {
+        let watchdog = Watchdog::new();
+        let done = Arc::new(AtomicUsize::new(0));
+        let expire_done = Arc::clone(&done);
+        let watch = watchdog.watch(Instant::now(), move ||
+        {
+            thread::sleep(Duration::from_millis(100));
+            expire_done.store(1, Ordering::SeqCst);
+        }).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        // The callback is running, so finishing waits for it
+        assert!(!watch.finish());
+        assert_eq!(done.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,647 @@
+use std::{collections::{BTreeMap, HashMap}, fmt, fs, io::{Error, ErrorKind}, path::{Component, Path, PathBuf}, sync::{Arc, Mutex}, time::SystemTime};
+
+use parser::{Cond, Expr, Node};
+
+use super::{error::HttpError, HttpResponse};
+
+mod parser;
+
+/// How deeply includes and layouts may nest before rendering gives up
+const MAX_DEPTH: usize = 32;
+
+/// A value a template can print, test or loop over
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value
+{
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    List(Vec<Value>),
+    Map(BTreeMap<String, Value>),
+}
+
+/// The named values a template is rendered with
+#[derive(Debug, Clone, Default)]
+pub struct Context
+{
+    values: BTreeMap<String, Value>,
+}
+
+/// A template that failed to load, parse or render, with the line it
+/// failed on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateError
+{
+    name: String,
+    /// 1-based, or 0 when the error is not about a line
+    line: usize,
+    message: String,
+}
+
+/// A parsed template
+struct Template
+{
+    name: String,
+    nodes: Vec<Node>,
+    extends: Option<(String, usize)>,
+}
+
+struct Cached
+{
+    template: Arc<Template>,
+    /// The file's modification time, or `None` for templates added from memory
+    modified: Option<SystemTime>,
+}
+
+/// Templates loaded from a directory and cached once parsed.
+///
+/// Templates use `{{ path.to.value }}` for escaped output, `{{{ value }}}`
+/// for raw HTML, `{% if %}`/`{% elif %}`/`{% else %}`/`{% endif %}`,
+/// `{% for item in list %}`/`{% else %}`/`{% endfor %}` with `loop.index`,
+/// `loop.first` and `loop.last`, `{% include "name" %}`, `{# comments #}`,
+/// and layouts through `{% extends "name" %}` and `{% block name %}`.
+///
+/// ```
+/// use http::template::{Context, Templates};
+///
+/// let templates = Templates::new("templates");
+/// templates.add("layout.html", "<title>{% block title %}Site{% endblock %}</title>").unwrap();
+/// templates.add("page.html", "{% extends \"layout.html\" %}{% block title %}{{ name }}{% endblock %}").unwrap();
+/// let html = templates.render("page.html", &Context::new().with("name", "<Home>")).unwrap();
+/// assert_eq!(html, "<title>&lt;Home&gt;</title>");
+/// ```
+pub struct Templates
+{
+    dir: PathBuf,
+    /// Whether changed files are parsed again, for development
+    reload: bool,
+    cache: Mutex<HashMap<String, Cached>>,
+}
+
+/// Variables visible while rendering: loop variables over the context
+struct Scope<'c>
+{
+    context: &'c Context,
+    locals: Vec<(String, Value)>,
+}
+
+impl Value
+{
+    /// Whether the value counts as true in an `if`
+    pub fn is_truthy(&self) -> bool
+    {
+        match self
+        {
+            Value::Null => false,
+            Value::Bool(b) => *b,
+            Value::Number(n) => *n != 0.0,
+            Value::String(s) => !s.is_empty(),
+            Value::List(items) => !items.is_empty(),
+            Value::Map(entries) => !entries.is_empty(),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&Value>
+    {
+        match self
+        {
+            Value::Map(entries) => entries.get(key),
+            Value::List(items) => key.parse::<usize>().ok().and_then(|i| items.get(i)),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Value
+{
+    fn from(value: &str) -> Self
+    {
+        Value::String(value.to_string())
+    }
+}
+
+impl From<String> for Value
+{
+    fn from(value: String) -> Self
+    {
+        Value::String(value)
+    }
+}
+
+impl From<bool> for Value
+{
+    fn from(value: bool) -> Self
+    {
+        Value::Bool(value)
+    }
+}
+
+/// Implements `From` for numeric types
+macro_rules! from_number
+{
+    ($($t:ty),*) =>
+    {
+        $(
+            impl From<$t> for Value
+            {
+                fn from(value: $t) -> Self
+                {
+                    Value::Number(value as f64)
+                }
+            }
+        )*
+    };
+}
+
+from_number!(i32, i64, u16, u32, u64, usize, f32, f64);
+
+impl<T: Into<Value>> From<Vec<T>> for Value
+{
+    fn from(items: Vec<T>) -> Self
+    {
+        Value::List(items.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value
+{
+    fn from(value: Option<T>) -> Self
+    {
+        value.map_or(Value::Null, Into::into)
+    }
+}
+
+impl<K: Into<String>, V: Into<Value>> FromIterator<(K, V)> for Value
+{
+    /// Collects pairs into a map
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(pairs: I) -> Self
+    {
+        Value::Map(pairs.into_iter().map(|(k, v)| (k.into(), v.into())).collect())
+    }
+}
+
+impl Context
+{
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+
+    /// Adds a value, replacing any of the same name
+    pub fn with(mut self, name: &str, value: impl Into<Value>) -> Self
+    {
+        self.insert(name, value);
+        self
+    }
+
+    pub fn insert(&mut self, name: &str, value: impl Into<Value>)
+    {
+        self.values.insert(name.to_string(), value.into());
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Value>
+    {
+        self.values.get(name)
+    }
+}
+
+impl TemplateError
+{
+    fn new(name: &str, line: usize, message: String) -> Self
+    {
+        Self
+        {
+            name: name.to_string(),
+            line: line,
+            message: message,
+        }
+    }
+
+    /// Gets the name of the template the error is in
+    pub fn name(&self) -> &str
+    {
+        &self.name
+    }
+
+    /// Gets the line the error is on, or 0 when it concerns the whole template
+    pub fn line(&self) -> usize
+    {
+        self.line
+    }
+
+    pub fn message(&self) -> &str
+    {
+        &self.message
+    }
+}
+
+impl fmt::Display for TemplateError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self.line
+        {
+            0 => write!(f, "{}: {}", self.name, self.message),
+            line => write!(f, "{}:{line}: {}", self.name, self.message),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+impl From<TemplateError> for Error
+{
+    fn from(e: TemplateError) -> Self
+    {
+        Error::new(ErrorKind::InvalidData, e)
+    }
+}
+
+/// Escapes text for HTML element content and quoted attribute values
+pub fn escape_html(text: &str) -> String
+{
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars()
+    {
+        match c
+        {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Finds the block named `name` anywhere in a template
+fn find_block<'n>(nodes: &'n [Node], name: &str) -> Option<&'n [Node]>
+{
+    nodes.iter().find_map(|node| match node
+    {
+        Node::Block { name: block, body } if block == name => Some(body.as_slice()),
+        Node::Block { body, .. } => find_block(body, name),
+        Node::If { branches, otherwise } => branches.iter()
+            .find_map(|(_, body)| find_block(body, name))
+            .or_else(|| find_block(otherwise, name)),
+        Node::For { body, otherwise, .. } => find_block(body, name).or_else(|| find_block(otherwise, name)),
+        _ => None,
+    })
+}
+
+impl Scope<'_>
+{
+    fn lookup(&self, path: &[String]) -> Option<&Value>
+    {
+        let (first, rest) = path.split_first()?;
+        let value = self.locals.iter().rev()
+        .find(|(name, _)| name == first)
+        .map(|(_, value)| value)
+        .or_else(|| self.context.get(first))?;
+        rest.iter().try_fold(value, |value, key| value.get(key))
+    }
+
+    fn eval(&self, expr: &Expr) -> Option<Value>
+    {
+        match expr
+        {
+            Expr::Path(path) => self.lookup(path).cloned(),
+            Expr::Literal(value) => Some(value.clone()),
+        }
+    }
+
+    /// Evaluates a condition. Missing values are false rather than errors,
+    /// so templates can test whether something is set.
+    fn test(&self, cond: &Cond) -> bool
+    {
+        match cond
+        {
+            Cond::Truthy(expr) => self.eval(expr).is_some_and(|value| value.is_truthy()),
+            Cond::Not(cond) => !self.test(cond),
+            Cond::Eq(left, right) => self.eval(left).unwrap_or(Value::Null) == self.eval(right).unwrap_or(Value::Null),
+            Cond::Ne(left, right) => self.eval(left).unwrap_or(Value::Null) != self.eval(right).unwrap_or(Value::Null),
+        }
+    }
+}
+
+/// Formats a path for messages
+fn path_name(expr: &Expr) -> String
+{
+    match expr
+    {
+        Expr::Path(path) => path.join("."),
+        Expr::Literal(value) => format!("{value:?}"),
+    }
+}
+
+impl Templates
+{
+    /// Loads templates from `dir` by their path relative to it
+    pub fn new(dir: impl AsRef<Path>) -> Self
+    {
+        Self
+        {
+            dir: dir.as_ref().to_path_buf(),
+            reload: false,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Parses a template again when its file changes, for development
+    pub fn with_reload(mut self, reload: bool) -> Self
+    {
+        self.reload = reload;
+        self
+    }
+
+    /// Adds a template from memory, hiding any file of the same name
+    pub fn add(&self, name: &str, source: &str) -> Result<(), TemplateError>
+    {
+        let template = Template::parse(name, source)?;
+        self.cache.lock().unwrap().insert(name.to_string(), Cached
+        {
+            template: Arc::new(template),
+            modified: None,
+        });
+        Ok(())
+    }
+
+    /// Gets the path of a template, refusing names that leave the directory
+    fn path(&self, name: &str) -> Result<PathBuf, TemplateError>
+    {
+        let relative = Path::new(name);
+        if !relative.components().all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(TemplateError::new(name, 0, "template names must be relative paths inside the directory".to_string()));
+        }
+        Ok(self.dir.join(relative))
+    }
+
+    /// Gets a parsed template from the cache, reading it on first use
+    fn load(&self, name: &str) -> Result<Arc<Template>, TemplateError>
+    {
+        let mut cache = self.cache.lock().unwrap();
+        let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+        if let Some(cached) = cache.get(name)
+        {
+            let fresh = match cached.modified
+            {
+                Some(when) if self.reload => modified(&self.path(name)?) == Some(when),
+                _ => true,
+            };
+            if fresh
+            {
+                return Ok(Arc::clone(&cached.template));
+            }
+        }
+        let path = self.path(name)?;
+        let source = fs::read_to_string(&path)
+        .map_err(|e| TemplateError::new(name, 0, format!("cannot read {}: {e}", path.display())))?;
+        let template = Arc::new(Template::parse(name, &source)?);
+        cache.insert(name.to_string(), Cached
+        {
+            template: Arc::clone(&template),
+            modified: modified(&path),
+        });
+        Ok(template)
+    }
+
+    /// Renders a template with a context
+    pub fn render(&self, name: &str, context: &Context) -> Result<String, TemplateError>
+    {
+        let mut out = String::new();
+        let mut scope = Scope
+        {
+            context: context,
+            locals: Vec::new(),
+        };
+        self.render_template(self.load(name)?, &mut Vec::new(), &mut scope, &mut out, 0)?;
+        Ok(out)
+    }
+
+    /// Renders a template as a `200 OK` HTML response
+    pub fn respond(&self, name: &str, context: &Context) -> Result<HttpResponse, HttpError>
+    {
+        let html = self.render(name, context)?;
+        Ok(HttpResponse::new(200)
+        .with_header("Content-Type", "text/html; charset=utf-8")
+        .with_body(html))
+    }
+
+    /// Renders a template, or the layout it extends with its blocks.
+    /// `children` holds the templates extending it, most derived first.
+    fn render_template(&self, template: Arc<Template>, children: &mut Vec<Arc<Template>>, scope: &mut Scope, out: &mut String, depth: usize) -> Result<(), TemplateError>
+    {
+        if depth > MAX_DEPTH
+        {
+            return Err(TemplateError::new(&template.name, 0, format!("includes and layouts nest deeper than {MAX_DEPTH}")));
+        }
+        if let Some((layout, line)) = &template.extends
+        {
+            let layout = self.load(layout).map_err(|e| TemplateError::new(&template.name, *line, e.to_string()))?;
+            children.push(template);
+            return self.render_template(layout, children, scope, out, depth + 1);
+        }
+        self.render_nodes(&template, &template.nodes, children, scope, out, depth)
+    }
+
+    fn render_nodes(&self, template: &Template, nodes: &[Node], children: &[Arc<Template>], scope: &mut Scope, out: &mut String, depth: usize) -> Result<(), TemplateError>
+    {
+        for node in nodes
+        {
+            match node
+            {
+                Node::Text(text) => out.push_str(text),
+                Node::Output { expr, escape, line } =>
+                {
+                    let error = |message: String| TemplateError::new(&template.name, *line, message);
+                    let value = scope.eval(expr).ok_or_else(|| error(format!("`{}` is not defined", path_name(expr))))?;
+                    let text = match value
+                    {
+                        Value::Null => String::new(),
+                        Value::Bool(b) => b.to_string(),
+                        Value::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => (n as i64).to_string(),
+                        Value::Number(n) => n.to_string(),
+                        Value::String(s) => s,
+                        Value::List(_) | Value::Map(_) => return Err(error(format!("`{}` is a list or map and can not be printed", path_name(expr)))),
+                    };
+                    out.push_str(&if *escape { escape_html(&text) } else { text });
+                },
+                Node::If { branches, otherwise } =>
+                {
+                    let body = branches.iter()
+                    .find(|(cond, _)| scope.test(cond))
+                    .map_or(otherwise.as_slice(), |(_, body)| body.as_slice());
+                    self.render_nodes(template, body, children, scope, out, depth)?;
+                },
+                Node::For { var, list, body, otherwise, line } =>
+                {
+                    let items = match scope.eval(list)
+                    {
+                        Some(Value::List(items)) => items,
+                        Some(Value::Null) | None => Vec::new(),
+                        Some(_) => return Err(TemplateError::new(&template.name, *line, format!("`{}` is not a list", path_name(list)))),
+                    };
+                    if items.is_empty()
+                    {
+                        self.render_nodes(template, otherwise, children, scope, out, depth)?;
+                    }
+                    let count = items.len();
+                    for (i, item) in items.into_iter().enumerate()
+                    {
+                        let state = Value::from_iter([
+                            ("index", Value::from(i + 1)),
+                            ("first", Value::from(i == 0)),
+                            ("last", Value::from(i + 1 == count)),
+                        ]);
+                        scope.locals.push((var.clone(), item));
+                        scope.locals.push(("loop".to_string(), state));
+                        let rendered = self.render_nodes(template, body, children, scope, out, depth);
+                        scope.locals.truncate(scope.locals.len() - 2);
+                        rendered?;
+                    }
+                },
+                Node::Include { name, line } =>
+                {
+                    let included = self.load(name).map_err(|e| TemplateError::new(&template.name, *line, e.to_string()))?;
+                    if depth >= MAX_DEPTH
+                    {
+                        return Err(TemplateError::new(&template.name, *line, format!("includes and layouts nest deeper than {MAX_DEPTH}")));
+                    }
+                    self.render_template(included, &mut Vec::new(), scope, out, depth + 1)?;
+                },
+                Node::Block { name, body } =>
+                {
+                    // The most derived template defining the block wins
+                    let overridden = children.iter().find_map(|child| find_block(&child.nodes, name).map(|body| (child, body)));
+                    match overridden
+                    {
+                        Some((child, body)) => self.render_nodes(child, body, children, scope, out, depth)?,
+                        None => self.render_nodes(template, body, children, scope, out, depth)?,
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Template
+{
+    fn parse(name: &str, source: &str) -> Result<Self, TemplateError>
+    {
+        let parsed = parser::parse(name, source)?;
+        Ok(Self
+        {
+            name: name.to_string(),
+            nodes: parsed.nodes,
+            extends: parsed.extends,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn templates(sources: &[(&str, &str)]) -> Templates
+    {
+        let templates = Templates::new("/nonexistent");
+        for (name, source) in sources
+        {
+            templates.add(name, source).unwrap();
+        }
+        templates
+    }
+
+    #[test]
+    fn test_output_and_control()
+    {
+        let templates = templates(&[
+            ("page", "<h1>{{ title }}</h1>\n<ul>\n{% for user in users %}\n  <li>{{ loop.index }}. {{ user.name }}{% if user.admin %} (admin){% endif %}</li>\n{% else %}\n  <li>nobody</li>\n{% endfor %}\n</ul>\n{{{ raw }}} {{ count }} {{ ratio }}"),
+        ]);
+        let users = vec![
+            Value::from_iter([("name", Value::from("Ann")), ("admin", Value::from(true))]),
+            Value::from_iter([("name", Value::from("<Bob>")), ("admin", Value::from(false))]),
+        ];
+        let context = Context::new()
+        .with("title", "A & B")
+        .with("users", users)
+        .with("raw", "<b>bold</b>")
+        .with("count", 3)
+        .with("ratio", 0.5);
+        assert_eq!(templates.render("page", &context).unwrap(),
+            "<h1>A &amp; B</h1>\n<ul>\n  <li>1. Ann (admin)</li>\n  <li>2. &lt;Bob&gt;</li>\n</ul>\n<b>bold</b> 3 0.5");
+        let empty = context.with("users", Vec::<Value>::new());
+        assert!(templates.render("page", &empty).unwrap().contains("<li>nobody</li>"));
+    }
+
+    #[test]
+    fn test_conditions()
+    {
+        let templates = templates(&[
+            ("t", "{% if role == \"admin\" %}A{% elif not role %}N{% elif role != 'guest' %}U{% else %}G{% endif %}"),
+        ]);
+        let render = |role: Option<&str>| templates.render("t", &Context::new().with("role", role)).unwrap();
+        assert_eq!(render(Some("admin")), "A");
+        assert_eq!(render(None), "N");
+        assert_eq!(render(Some("user")), "U");
+        assert_eq!(render(Some("guest")), "G");
+    }
+
+    #[test]
+    fn test_layouts_and_partials()
+    {
+        let templates = templates(&[
+            ("base", "<title>{% block title %}Site{% endblock %}</title>{% include \"nav\" %}<main>{% block main %}{% endblock %}</main>"),
+            ("nav", "<nav>{{ user }}</nav>"),
+            ("section", "{% extends \"base\" %}{% block main %}<section>{% block body %}default{% endblock %}</section>{% endblock %}"),
+            ("page", "{% extends \"section\" %}ignored{% block title %}Page{% endblock %}{% block body %}{{ text }}{% endblock %}"),
+        ]);
+        let context = Context::new().with("user", "ann").with("text", "hi");
+        assert_eq!(templates.render("page", &context).unwrap(),
+            "<title>Page</title><nav>ann</nav><main><section>hi</section></main>");
+        assert_eq!(templates.render("section", &context).unwrap(),
+            "<title>Site</title><nav>ann</nav><main><section>default</section></main>");
+    }
+
+    #[test]
+    fn test_errors()
+    {
+        let parse_error = |source: &str| Template::parse("bad.html", source).err().unwrap().to_string();
+        assert_eq!(parse_error("a\nb {% if x %}\nc"), "bad.html:2: `if` is never closed");
+        assert_eq!(parse_error("a\n\n{% endfor %}"), "bad.html:3: unexpected `endfor`");
+        assert_eq!(parse_error("{{ a b }}"), "bad.html:1: invalid expression `a b`");
+        assert_eq!(parse_error("x\n{{ open"), "bad.html:2: unclosed `{{`");
+        assert_eq!(parse_error("{% frobnicate %}"), "bad.html:1: unknown tag `frobnicate`");
+        let templates = templates(&[("t", "line 1\n{{ user.name }}"), ("i", "\n\n{% include \"missing\" %}"), ("loop", "{% include \"loop\" %}")]);
+        let error = templates.render("t", &Context::new()).unwrap_err();
+        assert_eq!((error.name(), error.line()), ("t", 2));
+        assert_eq!(error.to_string(), "t:2: `user.name` is not defined");
+        assert!(templates.render("i", &Context::new()).unwrap_err().to_string().starts_with("i:3: missing: cannot read"));
+        assert!(templates.render("loop", &Context::new()).unwrap_err().to_string().contains("nest deeper"));
+        assert!(templates.render("../etc/passwd", &Context::new()).is_err());
+    }
+
+    #[test]
+    fn test_reload()
+    {
+        let dir = std::env::temp_dir().join(format!("http-templates-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("t.html"), "one").unwrap();
+        let cached = Templates::new(&dir);
+        let reloading = Templates::new(&dir).with_reload(true);
+        assert_eq!(cached.render("t.html", &Context::new()).unwrap(), "one");
+        assert_eq!(reloading.render("t.html", &Context::new()).unwrap(), "one");
+        // Make sure the modification time moves even on coarse clocks
+        let file = fs::File::options().write(true).open(dir.join("t.html")).unwrap();
+        fs::write(dir.join("t.html"), "two").unwrap();
+        file.set_modified(SystemTime::now() + std::time::Duration::from_secs(5)).unwrap();
+        assert_eq!(cached.render("t.html", &Context::new()).unwrap(), "one");
+        assert_eq!(reloading.render("t.html", &Context::new()).unwrap(), "two");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,427 @@
+use super::{TemplateError, Value};
+
+/// A value in a template: a dotted path into the context or a literal
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum Expr
+{
+    Path(Vec<String>),
+    Literal(Value),
+}
+
+/// The condition of an `if` or `elif`
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum Cond
+{
+    Truthy(Expr),
+    Not(Box<Cond>),
+    Eq(Expr, Expr),
+    Ne(Expr, Expr),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum Node
+{
+    Text(String),
+    /// `{{ expr }}`, or `{{{ expr }}}` without escaping
+    Output
+    {
+        expr: Expr,
+        escape: bool,
+        line: usize,
+    },
+    If
+    {
+        branches: Vec<(Cond, Vec<Node>)>,
+        otherwise: Vec<Node>,
+    },
+    For
+    {
+        var: String,
+        list: Expr,
+        body: Vec<Node>,
+        /// Rendered when the list is empty
+        otherwise: Vec<Node>,
+        line: usize,
+    },
+    Include
+    {
+        name: String,
+        line: usize,
+    },
+    Block
+    {
+        name: String,
+        body: Vec<Node>,
+    },
+}
+
+enum Token
+{
+    Text(String),
+    Output
+    {
+        expr: String,
+        escape: bool,
+        line: usize,
+    },
+    /// The inside of a `{% %}` tag
+    Tag
+    {
+        content: String,
+        line: usize,
+    },
+}
+
+/// A parsed template
+pub(super) struct Parsed
+{
+    pub nodes: Vec<Node>,
+    /// The layout named by `{% extends %}` and the line naming it
+    pub extends: Option<(String, usize)>,
+}
+
+/// Whether the text since the last line break is only spaces and tabs
+fn blank_line_start(text: &str, at_line_start: bool) -> bool
+{
+    match text.rfind('\n')
+    {
+        Some(i) => text[i + 1..].trim_matches([' ', '\t']).is_empty(),
+        None => at_line_start && text.trim_matches([' ', '\t']).is_empty(),
+    }
+}
+
+/// Gets how many bytes of `rest` run up to and including the next line
+/// break if they are blank, or to the end of the template
+fn blank_line_end(rest: &str) -> Option<usize>
+{
+    let end = rest.find('\n').map_or(rest.len(), |i| i + 1);
+    rest[..end].trim_matches([' ', '\t', '\r', '\n']).is_empty().then_some(end)
+}
+
+/// Splits a template into text, outputs and tags. A tag or comment alone
+/// on its line takes the whole line with it.
+fn tokenize(name: &str, source: &str) -> Result<Vec<Token>, TemplateError>
+{
+    let mut tokens = Vec::new();
+    let mut text = String::new();
+    let mut rest = source;
+    let mut line = 1;
+    let mut at_line_start = true;
+    while let Some(start) = rest.find('{')
+    {
+        let opener = &rest[start..];
+        let (close, kind) = if opener.starts_with("{{{")
+        {
+            ("}}}", 3)
+        }
+        else if opener.starts_with("{{")
+        {
+            ("}}", 2)
+        }
+        else if opener.starts_with("{%")
+        {
+            ("%}", 1)
+        }
+        else if opener.starts_with("{#")
+        {
+            ("#}", 0)
+        }
+        else
+        {
+            text.push_str(&rest[..start + 1]);
+            line += rest[..start + 1].matches('\n').count();
+            rest = &rest[start + 1..];
+            continue;
+        };
+        text.push_str(&rest[..start]);
+        line += rest[..start].matches('\n').count();
+        let open_len = if kind == 3 { 3 } else { 2 };
+        let inner = &opener[open_len..];
+        let Some(end) = inner.find(close) else
+        {
+            return Err(TemplateError::new(name, line, format!("unclosed `{}`", &opener[..open_len])));
+        };
+        let content = inner[..end].trim().to_string();
+        let tag_line = line;
+        line += inner[..end].matches('\n').count();
+        rest = &inner[end + close.len()..];
+        if kind >= 2
+        {
+            if !text.is_empty()
+            {
+                tokens.push(Token::Text(std::mem::take(&mut text)));
+            }
+            tokens.push(Token::Output
+            {
+                expr: content,
+                escape: kind == 2,
+                line: tag_line,
+            });
+            at_line_start = false;
+            continue;
+        }
+        // Tags and comments alone on their line leave no blank line behind
+        match (blank_line_start(&text, at_line_start), blank_line_end(rest))
+        {
+            (true, Some(skip)) =>
+            {
+                text.truncate(text.rfind('\n').map_or(0, |i| i + 1));
+                line += rest[..skip].matches('\n').count();
+                rest = &rest[skip..];
+                at_line_start = true;
+            },
+            _ => at_line_start = false,
+        }
+        if !text.is_empty()
+        {
+            tokens.push(Token::Text(std::mem::take(&mut text)));
+        }
+        if kind == 1
+        {
+            tokens.push(Token::Tag
+            {
+                content: content,
+                line: tag_line,
+            });
+        }
+    }
+    text.push_str(rest);
+    if !text.is_empty()
+    {
+        tokens.push(Token::Text(text));
+    }
+    Ok(tokens)
+}
+
+/// Parses a string literal in single or double quotes
+fn parse_string(s: &str) -> Option<String>
+{
+    let quote = s.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let inner = s.strip_prefix(quote)?.strip_suffix(quote)?;
+    (!inner.contains(quote)).then(|| inner.to_string())
+}
+
+fn parse_expr(name: &str, line: usize, s: &str) -> Result<Expr, TemplateError>
+{
+    let s = s.trim();
+    if let Some(string) = parse_string(s)
+    {
+        return Ok(Expr::Literal(Value::String(string)));
+    }
+    match s
+    {
+        "true" => return Ok(Expr::Literal(Value::Bool(true))),
+        "false" => return Ok(Expr::Literal(Value::Bool(false))),
+        "null" => return Ok(Expr::Literal(Value::Null)),
+        _ => {},
+    }
+    if let Ok(number) = s.parse::<f64>()
+    {
+        return Ok(Expr::Literal(Value::Number(number)));
+    }
+    let segments: Vec<String> = s.split('.').map(str::to_string).collect();
+    let valid = segments.iter().all(|segment| !segment.is_empty() && segment.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'));
+    if !valid
+    {
+        return Err(TemplateError::new(name, line, format!("invalid expression `{s}`")));
+    }
+    Ok(Expr::Path(segments))
+}
+
+fn parse_cond(name: &str, line: usize, s: &str) -> Result<Cond, TemplateError>
+{
+    let s = s.trim();
+    if let Some(negated) = s.strip_prefix("not ")
+    {
+        return Ok(Cond::Not(Box::new(parse_cond(name, line, negated)?)));
+    }
+    if let Some((left, right)) = s.split_once("==")
+    {
+        return Ok(Cond::Eq(parse_expr(name, line, left)?, parse_expr(name, line, right)?));
+    }
+    if let Some((left, right)) = s.split_once("!=")
+    {
+        return Ok(Cond::Ne(parse_expr(name, line, left)?, parse_expr(name, line, right)?));
+    }
+    Ok(Cond::Truthy(parse_expr(name, line, s)?))
+}
+
+struct Parser<'n>
+{
+    name: &'n str,
+    tokens: std::vec::IntoIter<Token>,
+    extends: Option<(String, usize)>,
+}
+
+/// The tag that ended a run of nodes
+struct End
+{
+    keyword: String,
+    args: String,
+    line: usize,
+}
+
+impl Parser<'_>
+{
+    fn error(&self, line: usize, message: String) -> TemplateError
+    {
+        TemplateError::new(self.name, line, message)
+    }
+
+    /// Parses nodes up to one of the `ends` tags, or to the end of the
+    /// template when `ends` is empty
+    fn nodes(&mut self, ends: &[&str], opened: Option<(&str, usize)>, top: bool) -> Result<(Vec<Node>, Option<End>), TemplateError>
+    {
+        let mut nodes = Vec::new();
+        while let Some(token) = self.tokens.next()
+        {
+            let (content, line) = match token
+            {
+                Token::Text(text) =>
+                {
+                    nodes.push(Node::Text(text));
+                    continue;
+                },
+                Token::Output { expr, escape, line } =>
+                {
+                    nodes.push(Node::Output
+                    {
+                        expr: parse_expr(self.name, line, &expr)?,
+                        escape: escape,
+                        line: line,
+                    });
+                    continue;
+                },
+                Token::Tag { content, line } => (content, line),
+            };
+            let (keyword, args) = content.split_once(char::is_whitespace).unwrap_or((&content, ""));
+            let args = args.trim();
+            if ends.contains(&keyword)
+            {
+                return Ok((nodes, Some(End { keyword: keyword.to_string(), args: args.to_string(), line: line })));
+            }
+            match keyword
+            {
+                "if" =>
+                {
+                    let mut branches = Vec::new();
+                    let mut cond = parse_cond(self.name, line, args)?;
+                    let otherwise = loop
+                    {
+                        let (body, end) = self.nodes(&["elif", "else", "endif"], Some(("if", line)), false)?;
+                        branches.push((cond, body));
+                        let end = end.unwrap();
+                        match end.keyword.as_str()
+                        {
+                            "elif" => cond = parse_cond(self.name, end.line, &end.args)?,
+                            "else" => break self.nodes(&["endif"], Some(("if", line)), false)?.0,
+                            _ => break Vec::new(),
+                        }
+                    };
+                    nodes.push(Node::If
+                    {
+                        branches: branches,
+                        otherwise: otherwise,
+                    });
+                },
+                "for" =>
+                {
+                    let Some((var, list)) = args.split_once(" in ") else
+                    {
+                        return Err(self.error(line, format!("expected `for item in list`, got `for {args}`")));
+                    };
+                    let var = var.trim().to_string();
+                    if var.is_empty() || !var.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                    {
+                        return Err(self.error(line, format!("invalid loop variable `{var}`")));
+                    }
+                    let list = parse_expr(self.name, line, list)?;
+                    let (body, end) = self.nodes(&["else", "endfor"], Some(("for", line)), false)?;
+                    let otherwise = match end.unwrap().keyword.as_str()
+                    {
+                        "else" => self.nodes(&["endfor"], Some(("for", line)), false)?.0,
+                        _ => Vec::new(),
+                    };
+                    nodes.push(Node::For
+                    {
+                        var: var,
+                        list: list,
+                        body: body,
+                        otherwise: otherwise,
+                        line: line,
+                    });
+                },
+                "include" =>
+                {
+                    let Some(name) = parse_string(args) else
+                    {
+                        return Err(self.error(line, format!("expected a quoted template name, got `{args}`")));
+                    };
+                    nodes.push(Node::Include
+                    {
+                        name: name,
+                        line: line,
+                    });
+                },
+                "block" =>
+                {
+                    let name = args.to_string();
+                    if name.is_empty() || name.contains(char::is_whitespace)
+                    {
+                        return Err(self.error(line, format!("invalid block name `{name}`")));
+                    }
+                    let (body, end) = self.nodes(&["endblock"], Some(("block", line)), false)?;
+                    let end = end.unwrap();
+                    if !end.args.is_empty() && end.args != name
+                    {
+                        return Err(self.error(end.line, format!("`endblock {}` closes block `{name}`", end.args)));
+                    }
+                    nodes.push(Node::Block
+                    {
+                        name: name,
+                        body: body,
+                    });
+                },
+                "extends" =>
+                {
+                    let Some(layout) = parse_string(args) else
+                    {
+                        return Err(self.error(line, format!("expected a quoted template name, got `{args}`")));
+                    };
+                    if !top || self.extends.is_some()
+                    {
+                        return Err(self.error(line, "`extends` must appear once, outside other tags".to_string()));
+                    }
+                    self.extends = Some((layout, line));
+                },
+                "elif" | "else" | "endif" | "endfor" | "endblock" =>
+                {
+                    return Err(self.error(line, format!("unexpected `{keyword}`")));
+                },
+                _ => return Err(self.error(line, format!("unknown tag `{keyword}`"))),
+            }
+        }
+        match opened
+        {
+            Some((tag, line)) => Err(self.error(line, format!("`{tag}` is never closed"))),
+            None => Ok((nodes, None)),
+        }
+    }
+}
+
+/// Parses the source of a template
+pub(super) fn parse(name: &str, source: &str) -> Result<Parsed, TemplateError>
+{
+    let mut parser = Parser
+    {
+        name: name,
+        tokens: tokenize(name, source)?.into_iter(),
+        extends: None,
+    };
+    let (nodes, _) = parser.nodes(&[], None, true)?;
+    Ok(Parsed
+    {
+        nodes: nodes,
+        extends: parser.extends,
+    })
+}
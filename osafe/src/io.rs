@@ -1,6 +1,7 @@
 use alloc::string::String;
 use super::error::Error;
 pub mod posix_print;
+pub mod posix_epoll;
 
 pub trait Printable
 {
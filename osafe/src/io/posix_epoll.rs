@@ -0,0 +1,277 @@
+use core::ffi;
+
+use alloc::{string::String, vec::Vec};
+
+use crate::{error::{ErrNo, Error}, posix::{__errno_location, close, epoll_create1, epoll_ctl, epoll_data, epoll_event, epoll_wait, eventfd, eventfd_read, eventfd_t, eventfd_write, EFD_CLOEXEC, EFD_NONBLOCK, EINTR, EPOLL_CLOEXEC, EPOLL_CTL_ADD, EPOLL_CTL_DEL, EPOLL_CTL_MOD, EPOLL_EVENTS_EPOLLERR, EPOLL_EVENTS_EPOLLEXCLUSIVE, EPOLL_EVENTS_EPOLLHUP, EPOLL_EVENTS_EPOLLIN, EPOLL_EVENTS_EPOLLOUT, EPOLL_EVENTS_EPOLLRDHUP}};
+
+/// The readiness a file descriptor is watched for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interest
+{
+    /// Only hangups and errors are reported
+    None,
+    Read,
+    Write,
+    ReadWrite,
+    /// Read readiness shared between several epoll instances, waking only
+    /// one of them per event. Meant for listening sockets.
+    ReadExclusive,
+}
+
+/// A readiness notification returned by `Epoll::wait`
+#[derive(Debug, Clone, Copy)]
+pub struct Event
+{
+    pub token: u64,
+    pub readable: bool,
+    pub writable: bool,
+    /// The peer hung up or the descriptor is in an error state
+    pub closed: bool,
+}
+
+/// A Linux epoll instance
+pub struct Epoll
+{
+    fd: ffi::c_int,
+    events: Vec<epoll_event>
+}
+
+/// An eventfd used to wake a thread blocked in `Epoll::wait`
+pub struct Waker
+{
+    fd: ffi::c_int
+}
+
+fn errno() -> Error
+{
+    let errno = unsafe{*__errno_location()};
+    Error::IoErr(String::from_errno(errno))
+}
+
+impl Interest
+{
+    fn bits(&self) -> u32
+    {
+        // Hangups and errors are always reported, a half closed peer only
+        // matters while reading
+        let hangup = EPOLL_EVENTS_EPOLLRDHUP;
+        match self
+        {
+            Interest::None => 0,
+            Interest::Read => EPOLL_EVENTS_EPOLLIN | hangup,
+            Interest::Write => EPOLL_EVENTS_EPOLLOUT,
+            Interest::ReadWrite => EPOLL_EVENTS_EPOLLIN | EPOLL_EVENTS_EPOLLOUT | hangup,
+            Interest::ReadExclusive => EPOLL_EVENTS_EPOLLIN | EPOLL_EVENTS_EPOLLEXCLUSIVE,
+        }
+    }
+}
+
+impl Epoll
+{
+    /// Creates an epoll instance reporting at most `capacity` events per wait
+    pub fn new(capacity: usize) -> Result<Self, Error>
+    {
+        let fd = unsafe{epoll_create1(EPOLL_CLOEXEC as i32)};
+        if fd == -1
+        {
+            return Err(errno());
+        }
+        let empty = epoll_event
+        {
+            events: 0,
+            data: epoll_data{u64_: 0}
+        };
+        let mut events = Vec::new();
+        events.resize(capacity.max(1), empty);
+        return Ok(Self
+        {
+            fd: fd,
+            events: events
+        })
+    }
+
+    fn control(&self, op: u32, fd: ffi::c_int, interest: Interest, token: u64) -> Result<(), Error>
+    {
+        let mut event = epoll_event
+        {
+            events: interest.bits(),
+            data: epoll_data{u64_: token}
+        };
+        let ret = unsafe{epoll_ctl(self.fd, op as i32, fd, &mut event as *mut epoll_event)};
+        if ret == -1
+        {
+            return Err(errno());
+        }
+        Ok(())
+    }
+
+    /// Starts watching `fd`, reporting its events with `token`
+    pub fn add(&self, fd: ffi::c_int, interest: Interest, token: u64) -> Result<(), Error>
+    {
+        self.control(EPOLL_CTL_ADD, fd, interest, token)
+    }
+
+    /// Changes what `fd` is watched for
+    pub fn modify(&self, fd: ffi::c_int, interest: Interest, token: u64) -> Result<(), Error>
+    {
+        self.control(EPOLL_CTL_MOD, fd, interest, token)
+    }
+
+    /// Stops watching `fd`
+    pub fn delete(&self, fd: ffi::c_int) -> Result<(), Error>
+    {
+        self.control(EPOLL_CTL_DEL, fd, Interest::None, 0)
+    }
+
+    /// Waits for events and appends them to `out`.
+    /// If timeout is negative it will block indefinetly.
+    /// If timeout is 0 it will return immediatley.
+    /// An interrupted wait returns no events.
+    pub fn wait(&mut self, out: &mut Vec<Event>, timeout_ms: i32) -> Result<usize, Error>
+    {
+        let ret = unsafe
+        {
+            epoll_wait(self.fd, self.events.as_mut_ptr(), self.events.len() as i32, timeout_ms)
+        };
+        if ret == -1
+        {
+            let errno = unsafe{*__errno_location()};
+            if errno == EINTR as i32
+            {
+                return Ok(0);
+            }
+            return Err(Error::IoErr(String::from_errno(errno)));
+        }
+        for event in &self.events[..ret as usize]
+        {
+            // Copy the fields out since the struct may be packed
+            let bits = event.events;
+            let token = unsafe{event.data.u64_};
+            out.push(Event
+            {
+                token: token,
+                readable: bits & EPOLL_EVENTS_EPOLLIN != 0,
+                writable: bits & EPOLL_EVENTS_EPOLLOUT != 0,
+                closed: bits & (EPOLL_EVENTS_EPOLLHUP | EPOLL_EVENTS_EPOLLRDHUP | EPOLL_EVENTS_EPOLLERR) != 0,
+            });
+        }
+        Ok(ret as usize)
+    }
+}
+
+impl Drop for Epoll
+{
+    fn drop(&mut self) {
+        unsafe
+        {
+            close(self.fd);
+        }
+        self.fd = -1;
+    }
+}
+
+impl Waker
+{
+    pub fn new() -> Result<Self, Error>
+    {
+        let fd = unsafe{eventfd(0, (EFD_NONBLOCK | EFD_CLOEXEC) as i32)};
+        if fd == -1
+        {
+            return Err(errno());
+        }
+        return Ok(Self
+        {
+            fd: fd
+        })
+    }
+
+    /// Gets the descriptor to register with an `Epoll`
+    pub fn fd(&self) -> ffi::c_int
+    {
+        self.fd
+    }
+
+    /// Makes the waker readable
+    pub fn wake(&self) -> Result<(), Error>
+    {
+        let ret = unsafe{eventfd_write(self.fd, 1)};
+        if ret == -1
+        {
+            return Err(errno());
+        }
+        Ok(())
+    }
+
+    /// Clears pending wakeups so the waker stops being readable
+    pub fn reset(&self)
+    {
+        let mut value: eventfd_t = 0;
+        unsafe
+        {
+            eventfd_read(self.fd, &mut value as *mut eventfd_t);
+        }
+    }
+}
+
+impl Drop for Waker
+{
+    fn drop(&mut self) {
+        unsafe
+        {
+            close(self.fd);
+        }
+        self.fd = -1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_waker()
+    {
+        let mut epoll = Epoll::new(8).unwrap();
+        let waker = Waker::new().unwrap();
+        epoll.add(waker.fd(), Interest::Read, 7).unwrap();
+        let mut events = Vec::new();
+        // Nothing is ready before the wake
+        assert_eq!(epoll.wait(&mut events, 0).unwrap(), 0);
+        // Several wakes collapse into one readable event
+        waker.wake().unwrap();
+        waker.wake().unwrap();
+        assert_eq!(epoll.wait(&mut events, 1000).unwrap(), 1);
+        assert_eq!(events[0].token, 7);
+        assert!(events[0].readable);
+        assert!(!events[0].closed);
+        // A reset waker stops being readable
+        waker.reset();
+        events.clear();
+        assert_eq!(epoll.wait(&mut events, 0).unwrap(), 0);
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn test_modify_and_delete()
+    {
+        let mut epoll = Epoll::new(8).unwrap();
+        let waker = Waker::new().unwrap();
+        epoll.add(waker.fd(), Interest::None, 1).unwrap();
+        waker.wake().unwrap();
+        let mut events = Vec::new();
+        // Without read interest the pending wake is not reported
+        assert_eq!(epoll.wait(&mut events, 0).unwrap(), 0);
+        epoll.modify(waker.fd(), Interest::ReadWrite, 2).unwrap();
+        assert_eq!(epoll.wait(&mut events, 0).unwrap(), 1);
+        assert_eq!(events[0].token, 2);
+        assert!(events[0].readable && events[0].writable);
+        epoll.delete(waker.fd()).unwrap();
+        events.clear();
+        assert_eq!(epoll.wait(&mut events, 0).unwrap(), 0);
+        // A descriptor can't be added twice or deleted when it isn't watched
+        epoll.add(waker.fd(), Interest::Read, 3).unwrap();
+        assert!(epoll.add(waker.fd(), Interest::Read, 3).is_err());
+        epoll.delete(waker.fd()).unwrap();
+        assert!(epoll.delete(waker.fd()).is_err());
+    }
+}
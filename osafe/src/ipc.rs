@@ -1,6 +1,7 @@
 use crate::error::Error;
 
 pub mod posix_udp;
+pub mod posix_tcp;
 
 pub trait Communicate
 {
@@ -0,0 +1,168 @@
+use core::{ffi, mem::MaybeUninit};
+
+use alloc::{format, string::{String, ToString}};
+
+use crate::{error::{ErrNo, Error}, posix::{__errno_location, __socket_type_SOCK_STREAM, bind, close, htons, in6_addr, inet_pton, listen, setsockopt, sockaddr, sockaddr_in6, socket, AF_INET6, IPPROTO_IPV6, IPV6_V6ONLY, SOL_SOCKET, SO_REUSEADDR}};
+
+/// A listening TCP socket created directly through POSIX calls.
+///
+/// This exists for the socket options that have to be set before `bind`,
+/// which higher level listeners do not expose.
+pub struct TcpSocket
+{
+    fd: ffi::c_int
+}
+
+impl TcpSocket
+{
+    fn errno() -> Error
+    {
+        let errno = unsafe{*__errno_location()};
+        Error::IpcError(String::from_errno(errno))
+    }
+
+    fn set_option(&self, level: u32, name: u32, value: ffi::c_int) -> Result<(), Error>
+    {
+        let ret = unsafe
+        {
+            setsockopt(
+                self.fd,
+                level as i32,
+                name as i32,
+                &value as *const ffi::c_int as *const ffi::c_void,
+                size_of::<ffi::c_int>() as u32
+            )
+        };
+        if ret == -1
+        {
+            return Err(Self::errno());
+        }
+        Ok(())
+    }
+
+    /// Binds and listens on an IPv6 address that only accepts IPv6 traffic,
+    /// so an IPv4 listener can share the same port
+    pub fn listen_ipv6_only(addr: &str, port: u16, backlog: i32) -> Result<Self, Error>
+    {
+        let fd = unsafe{socket(AF_INET6 as i32, __socket_type_SOCK_STREAM as i32, 0)};
+        if fd == -1
+        {
+            return Err(Self::errno());
+        }
+        // Closes the socket if any of the following steps fail
+        let socket = Self
+        {
+            fd: fd
+        };
+        socket.set_option(IPPROTO_IPV6 as u32, IPV6_V6ONLY, 1)?;
+        socket.set_option(SOL_SOCKET, SO_REUSEADDR, 1)?;
+        // Add null terminator for C string compatibility
+        let mut addr_str = addr.to_string();
+        addr_str.push(0 as char);
+        let addr_cstr = ffi::CStr::from_bytes_with_nul(addr_str.as_bytes())
+        .map_err(|e| Error::IpcError(e.to_string()))?;
+        // Convert string address to network address
+        let mut sin6_addr = MaybeUninit::<in6_addr>::zeroed();
+        let ret = unsafe
+        {
+            inet_pton(AF_INET6 as i32, addr_cstr.as_ptr(), sin6_addr.as_mut_ptr() as *mut ffi::c_void)
+        };
+        if ret != 1
+        {
+            return Err(Error::IpcError(format!("Invalid address {}", addr)));
+        }
+        let saddr = sockaddr_in6
+        {
+            sin6_family: AF_INET6 as u16,
+            sin6_port: unsafe{htons(port)},
+            sin6_flowinfo: 0,
+            sin6_addr: unsafe{sin6_addr.assume_init()},
+            sin6_scope_id: 0
+        };
+        let ret = unsafe
+        {
+            bind(socket.fd, &saddr as *const sockaddr_in6 as *const sockaddr, size_of::<sockaddr_in6>() as u32)
+        };
+        if ret == -1
+        {
+            return Err(Self::errno());
+        }
+        let ret = unsafe{listen(socket.fd, backlog)};
+        if ret == -1
+        {
+            return Err(Self::errno());
+        }
+        return Ok(socket)
+    }
+
+    /// Releases ownership of the file descriptor without closing it
+    pub fn into_raw_fd(mut self) -> ffi::c_int
+    {
+        let fd = self.fd;
+        self.fd = -1;
+        fd
+    }
+}
+
+impl Drop for TcpSocket
+{
+    fn drop(&mut self) {
+        if self.fd >= 0
+        {
+            unsafe
+            {
+                close(self.fd);
+            }
+        }
+        self.fd = -1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{net::{TcpListener, TcpStream}, os::fd::FromRawFd};
+
+    use super::*;
+
+    /// Finds a port that is free on both IPv4 and IPv6
+    fn free_port() -> u16
+    {
+        loop
+        {
+            let v4 = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = v4.local_addr().unwrap().port();
+            if TcpListener::bind(("::1", port)).is_ok()
+            {
+                return port;
+            }
+        }
+    }
+
+    #[test]
+    fn test_listen_ipv6_only()
+    {
+        let port = free_port();
+        let socket = TcpSocket::listen_ipv6_only("::", port, 16).unwrap();
+        // The IPv4 side of the port is still free
+        let _v4 = TcpListener::bind(("0.0.0.0", port)).unwrap();
+        // The socket accepts IPv6 connections once handed to std
+        let fd = socket.into_raw_fd();
+        let v6 = unsafe{TcpListener::from_raw_fd(fd)};
+        let _client = TcpStream::connect(("::1", port)).unwrap();
+        let (_, peer) = v6.accept().unwrap();
+        assert!(peer.is_ipv6());
+    }
+
+    #[test]
+    fn test_listen_errors()
+    {
+        assert!(TcpSocket::listen_ipv6_only("127.0.0.1", 0, 16).is_err());
+        assert!(TcpSocket::listen_ipv6_only("not an address", 0, 16).is_err());
+        // A port already taken on IPv6 is refused
+        let taken = TcpListener::bind("[::1]:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+        assert!(TcpSocket::listen_ipv6_only("::1", port, 16).is_err());
+    }
+}
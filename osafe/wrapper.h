@@ -10,3 +10,5 @@
 #include <arpa/inet.h>
 #include <linux/in.h>
 #include <poll.h>
+#include <sys/epoll.h>
+#include <sys/eventfd.h>
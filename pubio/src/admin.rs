@@ -0,0 +1,197 @@
+use std::{collections::BTreeSet, fmt::Write, fs, io::ErrorKind, path::Path as FsPath, sync::Mutex, time::Instant};
+
+use http::{crypto::{constant_time_eq, hmac_sha256}, error::HttpError, extract::{Path, State}, files::StaticFile, form::{percent_decode, percent_encode}, routes, server::{router::{Next, RouteTable, Router}, HttpMethodHandler, HttpRouteHandler}, HttpRequest, HttpResponse};
+
+use super::{is_plain_name, UPLOAD_DIR};
+
+/// Shared with the admin handlers
+struct AdminState
+{
+    token: String,
+    started: Instant,
+    /// The routes of the server, to publish files on
+    routes: RouteTable,
+    /// The uploaded files published so far
+    published: Mutex<BTreeSet<String>>,
+}
+
+static HANDLERS: &[HttpMethodHandler] = routes![
+    Get "/" => status,
+    Post "/clear" => clear,
+    Get "/routes" => list_routes,
+    Post "/publish/{name}" => publish,
+    Delete "/publish/{name}" => unpublish,
+];
+
+/// Builds the admin router, guarded by the bearer token `token`, which
+/// publishes files by adding routes to `routes`
+pub fn router(token: &str, routes: RouteTable) -> Router
+{
+    let state = AdminState
+    {
+        token: token.to_string(),
+        started: Instant::now(),
+        routes: routes,
+        published: Mutex::new(BTreeSet::new()),
+    };
+    Router::new(HANDLERS)
+    .with_middleware(require_token)
+    .with_not_found(&not_found)
+    .with_state(state)
+}
+
+/// Turns away requests without the admin token
+fn require_token(request: HttpRequest, next: Next) -> HttpResponse
+{
+    let expected = request.content().state::<AdminState>().map(|state| format!("Bearer {}", state.token));
+    match (request.content().header("Authorization"), expected)
+    {
+        (Some(given), Some(expected)) if token_matches(given, &expected) => next.run(request),
+        _ => HttpResponse::new(401).with_header("WWW-Authenticate", "Bearer realm=\"admin\""),
+    }
+}
+
+/// Compares tokens without revealing through timing how much of them
+/// matched, hashing first so that their lengths stay hidden as well
+fn token_matches(given: &str, expected: &str) -> bool
+{
+    constant_time_eq(&hmac_sha256(expected.as_bytes(), given.as_bytes()), &hmac_sha256(expected.as_bytes(), expected.as_bytes()))
+}
+
+/// Shows how long the server has been up and how many files it holds
+fn status(state: State<AdminState>) -> String
+{
+    let files = match fs::read_dir(UPLOAD_DIR)
+    {
+        Ok(entries) => entries.count(),
+        Err(_) => 0,
+    };
+    format!("Up for {}s, {files} uploaded files\n", state.started.elapsed().as_secs())
+}
+
+/// Deletes every uploaded file
+fn clear() -> Result<String, HttpError>
+{
+    let mut removed = 0;
+    for entry in fs::read_dir(UPLOAD_DIR)?
+    {
+        fs::remove_file(entry?.path())?;
+        removed += 1;
+    }
+    Ok(format!("Removed {removed} files\n"))
+}
+
+/// Lists the routes the server answers, one per line
+fn list_routes(state: State<AdminState>) -> String
+{
+    let mut out = String::new();
+    for (route, handler) in state.routes.list()
+    {
+        let _ = writeln!(out, "{} {route}", handler.method());
+    }
+    out
+}
+
+/// Gets the route an uploaded file is published on
+fn published_route(name: &str) -> String
+{
+    format!("/{}", percent_encode(name))
+}
+
+/// Serves an uploaded file on the route it was published on
+fn published(request: HttpRequest) -> Result<HttpResponse, HttpError>
+{
+    let name = percent_decode(request.content().path().trim_start_matches('/'), false)?;
+    Ok(StaticFile::new(FsPath::new(UPLOAD_DIR).join(name)).respond(&request)?)
+}
+
+/// Serves an uploaded file at the root of the site, without a restart
+fn publish(Path((name,)): Path<(String,)>, state: State<AdminState>) -> Result<(u16, String), HttpError>
+{
+    if !is_plain_name(&name) || !FsPath::new(UPLOAD_DIR).join(&name).is_file()
+    {
+        return Err(HttpError::not_found(format!("No uploaded file {name}")));
+    }
+    let route = published_route(&name);
+    let mut names = state.published.lock().unwrap();
+    match state.routes.add(HttpMethodHandler::Get(HttpRouteHandler::owned(route.clone(), &published)))
+    {
+        Ok(()) =>
+        {
+            names.insert(name.clone());
+            Ok((201, format!("Published {name} at {route}\n")))
+        },
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => Err(HttpError::new(409, e.to_string())),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Stops serving a published file
+fn unpublish(Path((name,)): Path<(String,)>, state: State<AdminState>) -> Result<(), HttpError>
+{
+    // Only routes serving uploads can be taken down this way
+    if !state.published.lock().unwrap().remove(&name)
+    {
+        return Err(HttpError::not_found(format!("{name} is not published")));
+    }
+    state.routes.remove("GET", &published_route(&name));
+    Ok(())
+}
+
+fn not_found(request: HttpRequest) -> HttpError
+{
+    HttpError::not_found(format!("No admin page at {}", request.content().path()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::{Read, Write as _}, net::TcpStream, sync::mpsc, thread};
+    use http::{mp::ThreadPool, server::HttpServer};
+
+    /// Starts a server with the admin pages under `/admin`, returning its address
+    fn serve_admin(token: &'static str) -> String
+    {
+        let (addr, bound) = mpsc::channel();
+        // The server runs for the rest of the test binary
+        thread::spawn(move ||
+        {
+            let pool: &'static ThreadPool<16, 2> = Box::leak(Box::new(ThreadPool::new()));
+            let server = HttpServer::new("127.0.0.1:0", &[], pool).unwrap();
+            let routes = server.routes();
+            let server = server.mount("/admin", router(token, routes));
+            addr.send(server.local_addrs().remove(0)).unwrap();
+            server.serve()
+        });
+        bound.recv().unwrap()
+    }
+
+    fn get(addr: &str, path: &str, authorization: Option<&str>) -> String
+    {
+        let mut client = TcpStream::connect(addr).unwrap();
+        let authorization = authorization.map(|value| format!("Authorization: {value}\r\n")).unwrap_or_default();
+        write!(client, "GET {path} HTTP/1.1\r\n{authorization}Connection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_require_token()
+    {
+        let addr = serve_admin("secret");
+        let response = get(&addr, "/admin/routes", None);
+        assert!(response.starts_with("HTTP/1.1 401"));
+        assert!(response.contains("WWW-Authenticate: Bearer realm=\"admin\""));
+        for wrong in ["Bearer secre", "Bearer secrets", "Bearer SECRET", "Basic secret", "secret"]
+        {
+            assert!(get(&addr, "/admin/routes", Some(wrong)).starts_with("HTTP/1.1 401"), "{wrong}");
+        }
+        let response = get(&addr, "/admin/routes", Some("Bearer secret"));
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.contains("GET /admin/routes\n"));
+        // Pages that do not exist are guarded too, so they give nothing away
+        assert!(get(&addr, "/admin/nope", None).starts_with("HTTP/1.1 401"));
+        assert!(get(&addr, "/admin/nope", Some("Bearer secret")).starts_with("HTTP/1.1 404"));
+    }
+}
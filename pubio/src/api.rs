@@ -0,0 +1,59 @@
+use std::{fs, io::ErrorKind, time::Duration};
+
+use http::{error::HttpError, extract::State, json::Json, routes, server::{router::{Next, Router}, HttpMethodHandler}, HttpRequest, HttpResponse};
+
+use super::UPLOAD_DIR;
+
+/// Shared with the API handlers
+struct ApiState
+{
+    upload_dir: &'static str,
+}
+
+/// How long an API handler may take before the client is answered with 504
+const API_TIMEOUT: Duration = Duration::from_secs(5);
+
+static HANDLERS: &[HttpMethodHandler] = routes![
+    Get "/files" => files.timeout(API_TIMEOUT),
+];
+
+/// Builds the JSON API router
+pub fn router() -> Router
+{
+    Router::new(HANDLERS)
+    .with_middleware(no_store)
+    .with_not_found(&not_found)
+    .with_state(ApiState { upload_dir: UPLOAD_DIR })
+}
+
+/// Keeps API answers out of caches, as they change with every upload
+fn no_store(request: HttpRequest, next: Next) -> HttpResponse
+{
+    next.run(request).with_header("Cache-Control", "no-store")
+}
+
+/// Lists the uploaded files with their sizes
+fn files(state: State<ApiState>) -> Result<Json, HttpError>
+{
+    let entries = match fs::read_dir(state.upload_dir)
+    {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Json::Array(Vec::new())),
+        Err(e) => return Err(e.into()),
+    };
+    let mut files = Vec::new();
+    for entry in entries
+    {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        files.push(Json::from_iter([("name", Json::from(name)), ("size", Json::from(entry.metadata()?.len()))]));
+    }
+    files.sort_by(|a, b| a.get("name").and_then(Json::as_str).cmp(&b.get("name").and_then(Json::as_str)));
+    Ok(Json::Array(files))
+}
+
+/// Answers unknown API paths with problem details rather than a page
+fn not_found(request: HttpRequest) -> HttpError
+{
+    HttpError::not_found("No such API endpoint").with_instance(request.content().path())
+}
@@ -1,7 +1,135 @@
-use http::{mp::ThreadPool, server::HttpServer};
+use std::{env, fs, io::ErrorKind, path::Path, sync::OnceLock};
+
+use http::{compress::Compression, error::HttpError, extract, mp::ThreadPool, multipart::MultipartConfig, routes, server::{HttpMethodHandler, HttpServer}, files::StaticFile, template::{Context, Templates, Value}, HttpRequest, HttpResponse};
+
+mod admin;
+mod api;
+
+/// Where uploaded files are stored
+const UPLOAD_DIR: &str = "uploads";
+/// The largest upload accepted, in bytes
+const MAX_UPLOAD_SIZE: usize = 64 * 1024 * 1024;
+
+/// Where the page templates are loaded from
+const TEMPLATE_DIR: &str = "templates";
+
+/// Where the token for the admin pages is read from
+const ADMIN_TOKEN_VAR: &str = "PUBIO_ADMIN_TOKEN";
+
+/// The page templates, reloaded when they change in debug builds
+static TEMPLATES: OnceLock<Templates> = OnceLock::new();
+
+static HANDLERS: &[HttpMethodHandler] = routes![
+    Get "/upload" => upload_form.named("upload_form"),
+    Post "/upload" => upload.streaming(MAX_UPLOAD_SIZE).named("upload"),
+    Get "/uploads/{name}" => uploaded_file.named("uploaded_file"),
+];
+
+/// Whether an uploaded file name stays inside the upload directory
+fn is_plain_name(name: &str) -> bool
+{
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\'])
+}
+
+/// Shows the upload form with the files uploaded so far
+fn upload_form(request: HttpRequest) -> Result<HttpResponse, HttpError>
+{
+    let mut names: Vec<String> = match fs::read_dir(UPLOAD_DIR)
+    {
+        Ok(entries) => entries.filter_map(|entry| entry.ok()?.file_name().into_string().ok()).collect(),
+        Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+    names.sort();
+    let mut files = Vec::new();
+    for name in names
+    {
+        let url = request.content().url_for("uploaded_file", &[("name", &name)])?;
+        files.push(Value::from_iter([("name", name), ("url", url)]));
+    }
+    let context = Context::new()
+    .with("upload_url", request.content().url_for("upload", &[])?)
+    .with("files", files);
+    templates().respond("upload.html", &context)
+}
+
+/// Sends back an uploaded file
+fn uploaded_file(extract::Path((name,)): extract::Path<(String,)>, request: HttpRequest) -> Result<HttpResponse, HttpError>
+{
+    if !is_plain_name(&name)
+    {
+        return Err(HttpError::not_found("No such file"));
+    }
+    Ok(StaticFile::new(Path::new(UPLOAD_DIR).join(name)).respond(&request)?)
+}
+
+fn templates() -> &'static Templates
+{
+    TEMPLATES.get_or_init(|| Templates::new(TEMPLATE_DIR).with_reload(cfg!(debug_assertions)))
+}
+
+/// Renders error statuses with the site layout
+fn error_page(status: u16, message: &str) -> HttpResponse
+{
+    let context = Context::new().with("status", status).with("message", message);
+    templates().respond("error.html", &context).unwrap_or_else(|e|
+    {
+        println!("Failed to render the error page: {e}");
+        HttpResponse::new(status).with_body(format!("{message}\n"))
+    })
+}
+
+/// Saves the files of an upload form into the upload directory
+fn upload(mut request: HttpRequest) -> Result<String, HttpError>
+{
+    fs::create_dir_all(UPLOAD_DIR)?;
+    let config = MultipartConfig::new().with_max_part_size(MAX_UPLOAD_SIZE);
+    let mut form = request.multipart(config).map_err(|e| HttpError::bad_request(e.to_string()))?;
+    let mut saved = Vec::<String>::new();
+    loop
+    {
+        let part = match form.next_part()
+        {
+            Ok(Some(part)) => part,
+            Ok(None) => break,
+            Err(e) if matches!(e.kind(), ErrorKind::InvalidData | ErrorKind::UnexpectedEof) => return Err(HttpError::bad_request(e.to_string())),
+            Err(e) => return Err(e.into()),
+        };
+        // Fields without a file and files without a usable name are skipped
+        let name = match part.filename()
+        {
+            Some(name) if is_plain_name(name) => name.to_string(),
+            _ => continue,
+        };
+        // An upload never replaces a file uploaded before
+        match part.save_new(Path::new(UPLOAD_DIR).join(&name))
+        {
+            Ok(()) => {},
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => return Err(HttpError::new(409, format!("{name} exists already"))),
+            Err(e) => return Err(e.into()),
+        }
+        saved.push(name);
+    }
+    Ok(format!("Saved {}\n", saved.join(", ")))
+}
 
 fn main() {
     let thread_pool = ThreadPool::<1000, 4>::new();
-    let server = HttpServer::new("localhost:8080", &[], &thread_pool).unwrap();
+    let mut server = HttpServer::new("localhost:8080", HANDLERS, &thread_pool).unwrap()
+    .mount("/api/v1", api::router())
+    .with_metrics("/metrics")
+    .with_compression(Compression::new())
+    .with_error_page(404, error_page)
+    .with_error_page(500, error_page);
+    // The admin pages are only served when a token is set up for them
+    match env::var(ADMIN_TOKEN_VAR)
+    {
+        Ok(token) if !token.is_empty() =>
+        {
+            let routes = server.routes();
+            server = server.mount("/admin", admin::router(&token, routes));
+        },
+        _ => println!("{ADMIN_TOKEN_VAR} is not set, the admin pages are disabled"),
+    }
     server.serve().unwrap();
 }